@@ -6,10 +6,41 @@
 files=(
     "src/lib.rs" \
     "src/main.rs" \
+    "src/compiler/mod.rs" \
+    "src/compiler/clause.rs" \
+    "src/compiler/control.rs" \
+    "src/compiler/indexing.rs" \
+    "src/compiler/peephole.rs" \
+    "src/compiler/registers.rs" \
     "src/parser/ast.rs" \
     "src/parser/lexer.rs" \
     "src/parser/mod.rs" \
+    "src/parser/operators.rs" \
+    "src/runtime/mod.rs" \
+    "src/runtime/aggregate.rs" \
+    "src/runtime/arithmetic.rs" \
+    "src/runtime/atoms.rs" \
+    "src/runtime/builtins.rs" \
+    "src/runtime/foreign.rs" \
+    "src/runtime/format.rs" \
+    "src/runtime/io.rs" \
+    "src/runtime/meta.rs" \
+    "src/runtime/order.rs" \
+    "src/runtime/terms.rs" \
+    "src/runtime/writer.rs" \
     "src/wam/data_structures.rs" \
+    "src/wam/database.rs" \
+    "src/wam/decoder.rs" \
+    "src/wam/emulator.rs" \
+    "src/wam/error.rs" \
+    "src/wam/exception.rs" \
+    "src/wam/instruction.rs" \
+    "src/wam/jit_index.rs" \
+    "src/wam/library.rs" \
+    "src/wam/loader.rs" \
+    "src/wam/object.rs" \
+    "src/wam/query.rs" \
+    "src/wam/verifier.rs" \
     "src/wam/mod.rs"
 )
 
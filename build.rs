@@ -0,0 +1,72 @@
+// build.rs
+//! Compiles the Prolog library in `library/` with the crate's own compiler.
+//! Each file becomes an object in `OUT_DIR`, and `OUT_DIR/library.rs` lists
+//! them with the predicates they define for `wam::library` to include.
+#![allow(dead_code, unused_imports)]
+
+#[path = "src/compiler/mod.rs"]
+mod compiler;
+#[path = "src/parser/mod.rs"]
+mod parser;
+#[path = "src/runtime/mod.rs"]
+mod runtime;
+#[path = "src/wam/mod.rs"]
+mod wam;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use compiler::CompileOptions;
+use wam::{ObjectFile, WamEmulator};
+
+fn main() {
+    println!("cargo:rerun-if-changed=library");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rustc-cfg=bundled_library");
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let mut sources: Vec<PathBuf> = fs::read_dir("library")
+        .expect("the library directory")
+        .map(|entry| entry.expect("a library file").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "pl"))
+        .collect();
+    sources.sort();
+
+    let mut libraries = String::from("pub const LIBRARIES: &[Library] = &[\n");
+    let mut defined: HashMap<(String, usize), String> = HashMap::new();
+    for source in &sources {
+        let name = source.file_stem().expect("a file name").to_string_lossy().into_owned();
+        let object = compile(source, &name);
+        for predicate in &object.predicates {
+            let key = (predicate.name.clone(), predicate.arity);
+            if let Some(other) = defined.insert(key, name.clone()) {
+                panic!("{}/{} is defined by library({}) and library({})", predicate.name, predicate.arity, other, name);
+            }
+        }
+
+        let path = out_dir.join(format!("{}.wamo", name));
+        fs::write(&path, object.to_bytes()).expect("the object is written");
+        let predicates: Vec<String> =
+            object.predicates.iter().map(|predicate| format!("({:?}, {})", predicate.name, predicate.arity)).collect();
+        libraries.push_str(&format!(
+            "    Library {{ name: {:?}, object: include_bytes!({:?}), predicates: &[{}] }},\n",
+            name,
+            path.display().to_string(),
+            predicates.join(", ")
+        ));
+    }
+    libraries.push_str("];\n");
+    fs::write(out_dir.join("library.rs"), libraries).expect("the library table is written");
+}
+
+/// Compiles `library(name)` from `source`, and loads it into an emulator
+/// to check that it defines no builtin and that its code verifies.
+fn compile(source: &Path, name: &str) -> ObjectFile {
+    let text = fs::read_to_string(source).expect("the library source is readable");
+    let file = format!("library({})", name);
+    let object = ObjectFile::compile(&text, &file, CompileOptions::default())
+        .unwrap_or_else(|error| panic!("{} does not compile: {:?}", file, error));
+    WamEmulator::new().load_object(&object).unwrap_or_else(|error| panic!("{} does not load: {:?}", file, error));
+    object
+}
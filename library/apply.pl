@@ -0,0 +1,129 @@
+% library(apply): calling a closure on the elements of lists.
+%
+% Each predicate puts its list first in a helper, so first-argument
+% indexing tells the empty list from a cons cell and no choice point is
+% left behind on a proper list.
+
+% maplist(:Goal, ?List1, ..., ?ListN): Goal holds for the elements at
+% each position of the lists, which have the same length.
+maplist(Goal, List) :-
+    maplist_(List, Goal).
+
+maplist_([], _).
+maplist_([X|Xs], Goal) :-
+    call(Goal, X),
+    maplist_(Xs, Goal).
+
+maplist(Goal, List1, List2) :-
+    maplist_(List1, List2, Goal).
+
+maplist_([], [], _).
+maplist_([X|Xs], [Y|Ys], Goal) :-
+    call(Goal, X, Y),
+    maplist_(Xs, Ys, Goal).
+
+maplist(Goal, List1, List2, List3) :-
+    maplist_(List1, List2, List3, Goal).
+
+maplist_([], [], [], _).
+maplist_([X|Xs], [Y|Ys], [Z|Zs], Goal) :-
+    call(Goal, X, Y, Z),
+    maplist_(Xs, Ys, Zs, Goal).
+
+maplist(Goal, List1, List2, List3, List4) :-
+    maplist_(List1, List2, List3, List4, Goal).
+
+maplist_([], [], [], [], _).
+maplist_([X|Xs], [Y|Ys], [Z|Zs], [W|Ws], Goal) :-
+    call(Goal, X, Y, Z, W),
+    maplist_(Xs, Ys, Zs, Ws, Goal).
+
+maplist(Goal, List1, List2, List3, List4, List5) :-
+    maplist_(List1, List2, List3, List4, List5, Goal).
+
+maplist_([], [], [], [], [], _).
+maplist_([X|Xs], [Y|Ys], [Z|Zs], [W|Ws], [V|Vs], Goal) :-
+    call(Goal, X, Y, Z, W, V),
+    maplist_(Xs, Ys, Zs, Ws, Vs, Goal).
+
+maplist(Goal, List1, List2, List3, List4, List5, List6) :-
+    maplist_(List1, List2, List3, List4, List5, List6, Goal).
+
+maplist_([], [], [], [], [], [], _).
+maplist_([X|Xs], [Y|Ys], [Z|Zs], [W|Ws], [V|Vs], [U|Us], Goal) :-
+    call(Goal, X, Y, Z, W, V, U),
+    maplist_(Xs, Ys, Zs, Ws, Vs, Us, Goal).
+
+% foldl(:Goal, +List1, ..., +ListN, +V0, -V): folds the lists from the
+% left, calling Goal(X1, ..., XN, V0, V1) on their first elements, and so
+% on to V.
+foldl(Goal, List, V0, V) :-
+    foldl_(List, Goal, V0, V).
+
+foldl_([], _, V, V).
+foldl_([X|Xs], Goal, V0, V) :-
+    call(Goal, X, V0, V1),
+    foldl_(Xs, Goal, V1, V).
+
+foldl(Goal, List1, List2, V0, V) :-
+    foldl_(List1, List2, Goal, V0, V).
+
+foldl_([], [], _, V, V).
+foldl_([X|Xs], [Y|Ys], Goal, V0, V) :-
+    call(Goal, X, Y, V0, V1),
+    foldl_(Xs, Ys, Goal, V1, V).
+
+foldl(Goal, List1, List2, List3, V0, V) :-
+    foldl_(List1, List2, List3, Goal, V0, V).
+
+foldl_([], [], [], _, V, V).
+foldl_([X|Xs], [Y|Ys], [Z|Zs], Goal, V0, V) :-
+    call(Goal, X, Y, Z, V0, V1),
+    foldl_(Xs, Ys, Zs, Goal, V1, V).
+
+foldl(Goal, List1, List2, List3, List4, V0, V) :-
+    foldl_(List1, List2, List3, List4, Goal, V0, V).
+
+foldl_([], [], [], [], _, V, V).
+foldl_([X|Xs], [Y|Ys], [Z|Zs], [W|Ws], Goal, V0, V) :-
+    call(Goal, X, Y, Z, W, V0, V1),
+    foldl_(Xs, Ys, Zs, Ws, Goal, V1, V).
+
+% include(:Pred, +List, -Included): Included is the elements of List
+% Pred holds for, in order; exclude/3 keeps the others.
+include(Pred, List, Included) :-
+    include_(List, Pred, Included).
+
+include_([], _, []).
+include_([X|Xs], Pred, Included) :-
+    (   call(Pred, X)
+    ->  Included = [X|Included1]
+    ;   Included = Included1
+    ),
+    include_(Xs, Pred, Included1).
+
+exclude(Pred, List, Excluded) :-
+    exclude_(List, Pred, Excluded).
+
+exclude_([], _, []).
+exclude_([X|Xs], Pred, Excluded) :-
+    (   call(Pred, X)
+    ->  Excluded = Excluded1
+    ;   Excluded = [X|Excluded1]
+    ),
+    exclude_(Xs, Pred, Excluded1).
+
+% partition(:Pred, +List, -Included, -Excluded): splits List into the
+% elements Pred holds for and the others.
+partition(Pred, List, Included, Excluded) :-
+    partition_(List, Pred, Included, Excluded).
+
+partition_([], _, [], []).
+partition_([X|Xs], Pred, Included, Excluded) :-
+    (   call(Pred, X)
+    ->  Included = [X|Included1],
+        Excluded = Excluded1
+    ;   Included = Included1,
+        Excluded = [X|Excluded1]
+    ),
+    partition_(Xs, Pred, Included1, Excluded1).
@@ -0,0 +1,189 @@
+% library(assoc): association lists as AVL trees, with keys in the
+% standard order of terms.
+%
+% The empty tree is `t`; any other is `t(Key, Value, Balance, Left, Right)`
+% where Balance is `<` when Left is shallower than Right, `=` when they
+% are as deep and `>` when Left is deeper. Inserting and deleting tell
+% their caller whether the tree grew or shrank (`yes` or `no`), and a node
+% whose subtrees end up two apart is rotated.
+
+% empty_assoc(?Assoc)
+empty_assoc(t).
+
+% get_assoc(+Key, +Assoc, -Value)
+get_assoc(Key, t(K, V, _, L, R), Value) :-
+    compare(Order, Key, K),
+    get_assoc_(Order, Key, Value, V, L, R).
+
+get_assoc_(=, _, V, V, _, _).
+get_assoc_(<, Key, Value, _, L, _) :-
+    get_assoc(Key, L, Value).
+get_assoc_(>, Key, Value, _, _, R) :-
+    get_assoc(Key, R, Value).
+
+% put_assoc(+Key, +Assoc0, +Value, -Assoc): Assoc is Assoc0 with Key
+% mapped to Value.
+put_assoc(Key, Assoc0, Value, Assoc) :-
+    put_assoc_(Assoc0, Key, Value, Assoc, _).
+
+put_assoc_(t, Key, Value, t(Key, Value, =, t, t), yes).
+put_assoc_(t(K, V, B, L, R), Key, Value, Assoc, Grown) :-
+    compare(Order, Key, K),
+    put_assoc_node(Order, K, V, B, L, R, Key, Value, Assoc, Grown).
+
+put_assoc_node(=, K, _, B, L, R, _, Value, t(K, Value, B, L, R), no).
+put_assoc_node(<, K, V, B, L, R, Key, Value, Assoc, Grown) :-
+    put_assoc_(L, Key, Value, L1, LeftGrown),
+    left_grown(LeftGrown, K, V, B, L1, R, Assoc, Grown).
+put_assoc_node(>, K, V, B, L, R, Key, Value, Assoc, Grown) :-
+    put_assoc_(R, Key, Value, R1, RightGrown),
+    right_grown(RightGrown, K, V, B, L, R1, Assoc, Grown).
+
+left_grown(no, K, V, B, L, R, t(K, V, B, L, R), no).
+left_grown(yes, K, V, B, L, R, Assoc, Grown) :-
+    left_grown_(B, K, V, L, R, Assoc, Grown).
+
+left_grown_(<, K, V, L, R, t(K, V, =, L, R), no).
+left_grown_(=, K, V, L, R, t(K, V, >, L, R), yes).
+left_grown_(>, K, V, L, R, Assoc, no) :-
+    avl_rotate_right(L, K, V, R, Assoc, _).
+
+right_grown(no, K, V, B, L, R, t(K, V, B, L, R), no).
+right_grown(yes, K, V, B, L, R, Assoc, Grown) :-
+    right_grown_(B, K, V, L, R, Assoc, Grown).
+
+right_grown_(>, K, V, L, R, t(K, V, =, L, R), no).
+right_grown_(=, K, V, L, R, t(K, V, <, L, R), yes).
+right_grown_(<, K, V, L, R, Assoc, no) :-
+    avl_rotate_left(L, K, V, R, Assoc, _).
+
+% del_assoc(+Key, +Assoc0, ?Value, -Assoc): Assoc is Assoc0 without Key,
+% which was mapped to Value. Fails if Key is not in Assoc0.
+del_assoc(Key, Assoc0, Value, Assoc) :-
+    del_assoc_(Assoc0, Key, Value, Assoc, _).
+
+del_assoc_(t(K, V, B, L, R), Key, Value, Assoc, Shrunk) :-
+    compare(Order, Key, K),
+    del_assoc_node(Order, K, V, B, L, R, Key, Value, Assoc, Shrunk).
+
+del_assoc_node(=, _, Value, B, L, R, _, Value, Assoc, Shrunk) :-
+    del_assoc_root(L, R, B, Assoc, Shrunk).
+del_assoc_node(<, K, V, B, L, R, Key, Value, Assoc, Shrunk) :-
+    del_assoc_(L, Key, Value, L1, LeftShrunk),
+    left_shrunk(LeftShrunk, K, V, B, L1, R, Assoc, Shrunk).
+del_assoc_node(>, K, V, B, L, R, Key, Value, Assoc, Shrunk) :-
+    del_assoc_(R, Key, Value, R1, RightShrunk),
+    right_shrunk(RightShrunk, K, V, B, L, R1, Assoc, Shrunk).
+
+% A node whose key is deleted is replaced by the smallest node of its
+% right subtree, unless a subtree is empty.
+del_assoc_root(t, R, _, R, yes).
+del_assoc_root(t(LK, LV, LB, LL, LR), R, B, Assoc, Shrunk) :-
+    del_assoc_root_(R, t(LK, LV, LB, LL, LR), B, Assoc, Shrunk).
+
+del_assoc_root_(t, L, _, L, yes).
+del_assoc_root_(t(RK, RV, RB, RL, RR), L, B, Assoc, Shrunk) :-
+    del_assoc_min(RL, RK, RV, RB, RR, K, V, R1, RightShrunk),
+    right_shrunk(RightShrunk, K, V, B, L, R1, Assoc, Shrunk).
+
+% del_assoc_min(L, K, V, B, R, MinK, MinV, Assoc, Shrunk) removes the smallest
+% node, MinK-MinV, of t(K, V, B, L, R).
+del_assoc_min(t, K, V, _, R, K, V, R, yes).
+del_assoc_min(t(LK, LV, LB, LL, LR), K, V, B, R, MinK, MinV, Assoc, Shrunk) :-
+    del_assoc_min(LL, LK, LV, LB, LR, MinK, MinV, L1, LeftShrunk),
+    left_shrunk(LeftShrunk, K, V, B, L1, R, Assoc, Shrunk).
+
+left_shrunk(no, K, V, B, L, R, t(K, V, B, L, R), no).
+left_shrunk(yes, K, V, B, L, R, Assoc, Shrunk) :-
+    left_shrunk_(B, K, V, L, R, Assoc, Shrunk).
+
+left_shrunk_(>, K, V, L, R, t(K, V, =, L, R), yes).
+left_shrunk_(=, K, V, L, R, t(K, V, <, L, R), no).
+left_shrunk_(<, K, V, L, R, Assoc, Shrunk) :-
+    avl_rotate_left(L, K, V, R, Assoc, Shrunk).
+
+right_shrunk(no, K, V, B, L, R, t(K, V, B, L, R), no).
+right_shrunk(yes, K, V, B, L, R, Assoc, Shrunk) :-
+    right_shrunk_(B, K, V, L, R, Assoc, Shrunk).
+
+right_shrunk_(<, K, V, L, R, t(K, V, =, L, R), yes).
+right_shrunk_(=, K, V, L, R, t(K, V, >, L, R), no).
+right_shrunk_(>, K, V, L, R, Assoc, Shrunk) :-
+    avl_rotate_right(L, K, V, R, Assoc, Shrunk).
+
+% avl_rotate_right(L, K, V, R, Assoc, Shrunk) rebalances t(K, V, _, L, R)
+% when L is two deeper than R, and avl_rotate_left/6 when R is. Shrunk
+% tells whether Assoc is shallower than that tree was.
+avl_rotate_right(t(LK, LV, LB, LL, LR), K, V, R, Assoc, Shrunk) :-
+    avl_rotate_right_(LB, LK, LV, LL, LR, K, V, R, Assoc, Shrunk).
+
+avl_rotate_right_(>, LK, LV, LL, LR, K, V, R, t(LK, LV, =, LL, t(K, V, =, LR, R)), yes).
+avl_rotate_right_(=, LK, LV, LL, LR, K, V, R, t(LK, LV, <, LL, t(K, V, >, LR, R)), no).
+avl_rotate_right_(<, LK, LV, LL, t(MK, MV, MB, ML, MR), K, V, R, t(MK, MV, =, t(LK, LV, B1, LL, ML), t(K, V, B2, MR, R)), yes) :-
+    avl_balances(MB, B1, B2).
+
+avl_rotate_left(L, K, V, t(RK, RV, RB, RL, RR), Assoc, Shrunk) :-
+    avl_rotate_left_(RB, RK, RV, RL, RR, L, K, V, Assoc, Shrunk).
+
+avl_rotate_left_(<, RK, RV, RL, RR, L, K, V, t(RK, RV, =, t(K, V, =, L, RL), RR), yes).
+avl_rotate_left_(=, RK, RV, RL, RR, L, K, V, t(RK, RV, >, t(K, V, <, L, RL), RR), no).
+avl_rotate_left_(>, RK, RV, t(MK, MV, MB, ML, MR), RR, L, K, V, t(MK, MV, =, t(K, V, B1, L, ML), t(RK, RV, B2, MR, RR)), yes) :-
+    avl_balances(MB, B1, B2).
+
+% avl_balances(MB, B1, B2): the balances of the two nodes a double rotation
+% puts below the middle node M, which had balance MB.
+avl_balances(>, =, <).
+avl_balances(=, =, =).
+avl_balances(<, >, =).
+
+% list_to_assoc(+Pairs, -Assoc): Assoc maps the keys of the Key-Value
+% pairs to their values; a later pair replaces an earlier one.
+list_to_assoc(Pairs, Assoc) :-
+    list_to_assoc_(Pairs, t, Assoc).
+
+list_to_assoc_([], Assoc, Assoc).
+list_to_assoc_([Key-Value|Pairs], Assoc0, Assoc) :-
+    put_assoc(Key, Assoc0, Value, Assoc1),
+    list_to_assoc_(Pairs, Assoc1, Assoc).
+
+% assoc_to_list(+Assoc, -Pairs), assoc_to_keys(+Assoc, -Keys) and
+% assoc_to_values(+Assoc, -Values), in the order of the keys.
+assoc_to_list(Assoc, Pairs) :-
+    assoc_to_list_(Assoc, Pairs, []).
+
+assoc_to_list_(t, Pairs, Pairs).
+assoc_to_list_(t(K, V, _, L, R), Pairs, Rest) :-
+    assoc_to_list_(L, Pairs, [K-V|Pairs1]),
+    assoc_to_list_(R, Pairs1, Rest).
+
+assoc_to_keys(Assoc, Keys) :-
+    assoc_to_keys_(Assoc, Keys, []).
+
+assoc_to_keys_(t, Keys, Keys).
+assoc_to_keys_(t(K, _, _, L, R), Keys, Rest) :-
+    assoc_to_keys_(L, Keys, [K|Keys1]),
+    assoc_to_keys_(R, Keys1, Rest).
+
+assoc_to_values(Assoc, Values) :-
+    assoc_to_values_(Assoc, Values, []).
+
+assoc_to_values_(t, Values, Values).
+assoc_to_values_(t(_, V, _, L, R), Values, Rest) :-
+    assoc_to_values_(L, Values, [V|Values1]),
+    assoc_to_values_(R, Values1, Rest).
+
+% min_assoc(+Assoc, -Key, -Value) and max_assoc(+Assoc, -Key, -Value):
+% the smallest and largest key and its value.
+min_assoc(t(K, V, _, L, _), Key, Value) :-
+    min_assoc_(L, K, V, Key, Value).
+
+min_assoc_(t, K, V, K, V).
+min_assoc_(t(K, V, _, L, _), _, _, Key, Value) :-
+    min_assoc_(L, K, V, Key, Value).
+
+max_assoc(t(K, V, _, _, R), Key, Value) :-
+    max_assoc_(R, K, V, Key, Value).
+
+max_assoc_(t, K, V, K, V).
+max_assoc_(t(K, V, _, _, R), _, _, Key, Value) :-
+    max_assoc_(R, K, V, Key, Value).
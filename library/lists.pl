@@ -0,0 +1,237 @@
+% library(lists): predicates on lists.
+%
+% A predicate that counts, such as length/2 or nth0/3, stops at the index
+% it was given by comparing it with `\==`, and enumerates when it was
+% given none.
+
+% append(?List1, ?List2, ?List1AndList2)
+append([], Ys, Ys).
+append([X|Xs], Ys, [X|Zs]) :-
+    append(Xs, Ys, Zs).
+
+% append(+ListOfLists, ?List): List is the concatenation of ListOfLists.
+append([], []).
+append([L|Ls], As) :-
+    append(L, Ws, As),
+    append(Ls, Ws).
+
+% member(?Elem, ?List): the last element is found without leaving a
+% choice point.
+member(X, [Y|Ys]) :-
+    member_(Ys, X, Y).
+
+member_(_, X, X).
+member_([Y|Ys], X, _) :-
+    member_(Ys, X, Y).
+
+% memberchk(?Elem, +List): the first solution of member/2.
+memberchk(X, List) :-
+    member(X, List),
+    !.
+
+% length(?List, ?Length): Length must be unbound or a natural number.
+length(List, Length) :-
+    (   var(Length)
+    ->  length_(List, 0, Length)
+    ;   integer(Length)
+    ->  (   Length >= 0
+        ->  length_(List, 0, Length)
+        ;   throw(error(domain_error(not_less_than_zero, Length), length/2))
+        )
+    ;   throw(error(type_error(integer, Length), length/2))
+    ).
+
+length_([], Length, Length).
+length_([_|Xs], Length0, Length) :-
+    Length0 \== Length,
+    Length1 is Length0 + 1,
+    length_(Xs, Length1, Length).
+
+% nth0(?Index, ?List, ?Elem) and nth1/3: Elem is at Index of List,
+% counting from 0 or 1.
+nth0(Index, List, Elem) :-
+    nth_(List, 0, Index, Elem).
+
+nth1(Index, List, Elem) :-
+    nth_(List, 1, Index, Elem).
+
+nth_([Elem|_], Index, Index, Elem).
+nth_([_|Xs], Index0, Index, Elem) :-
+    Index0 \== Index,
+    Index1 is Index0 + 1,
+    nth_(Xs, Index1, Index, Elem).
+
+% last(?List, ?Last)
+last([X|Xs], Last) :-
+    last_(Xs, X, Last).
+
+last_([], Last, Last).
+last_([X|Xs], _, Last) :-
+    last_(Xs, X, Last).
+
+% nextto(?X, ?Y, ?List): Y follows X in List.
+nextto(X, Y, [X, Y|_]).
+nextto(X, Y, [_|Zs]) :-
+    nextto(X, Y, Zs).
+
+% reverse(?List, ?Reversed)
+reverse(List, Reversed) :-
+    reverse_(List, [], Reversed).
+
+reverse_([], Ys, Ys).
+reverse_([X|Xs], Ys, Reversed) :-
+    reverse_(Xs, [X|Ys], Reversed).
+
+% select(?Elem, ?List, ?Rest): Rest is List without one occurrence of
+% Elem; selectchk/3 removes the first only.
+select(X, [X|Xs], Xs).
+select(X, [Y|Ys], [Y|Zs]) :-
+    select(X, Ys, Zs).
+
+selectchk(X, List, Rest) :-
+    select(X, List, Rest),
+    !.
+
+% delete(+List, @Elem, -Rest): Rest is List without the elements that
+% unify with Elem.
+delete([], _, []).
+delete([X|Xs], Elem, Rest) :-
+    (   \+ X = Elem
+    ->  Rest = [X|Rest1]
+    ;   Rest = Rest1
+    ),
+    delete(Xs, Elem, Rest1).
+
+% subtract(+Set, +Delete, -Rest), intersection(+Set1, +Set2, -Set3) and
+% union(+Set1, +Set2, -Set3) on lists used as sets, testing membership
+% with memberchk/2.
+subtract([], _, []).
+subtract([X|Xs], Delete, Rest) :-
+    (   memberchk(X, Delete)
+    ->  Rest = Rest1
+    ;   Rest = [X|Rest1]
+    ),
+    subtract(Xs, Delete, Rest1).
+
+intersection([], _, []).
+intersection([X|Xs], Set2, Set3) :-
+    (   memberchk(X, Set2)
+    ->  Set3 = [X|Set]
+    ;   Set3 = Set
+    ),
+    intersection(Xs, Set2, Set).
+
+union([], Set2, Set2).
+union([X|Xs], Set2, Set3) :-
+    (   memberchk(X, Set2)
+    ->  Set3 = Set
+    ;   Set3 = [X|Set]
+    ),
+    union(Xs, Set2, Set).
+
+% list_to_set(+List, -Set): the elements of List without duplicates, in
+% the order of their first occurrence. Elements are compared with ==/2.
+list_to_set(List, Set) :-
+    list_to_set_(List, [], Set).
+
+list_to_set_([], _, []).
+list_to_set_([X|Xs], Seen, Set) :-
+    (   memberchk_eq(X, Seen)
+    ->  Set = Set1
+    ;   Set = [X|Set1]
+    ),
+    list_to_set_(Xs, [X|Seen], Set1).
+
+memberchk_eq(X, [Y|Ys]) :-
+    (   X == Y
+    ->  true
+    ;   memberchk_eq(X, Ys)
+    ).
+
+% flatten(+NestedList, -FlatList): FlatList holds the elements of
+% NestedList and of the lists nested in it, in order. Variables are kept
+% as elements.
+flatten(List, FlatList) :-
+    flatten_(List, [], FlatList0),
+    !,
+    FlatList = FlatList0.
+
+flatten_(Var, Tail, [Var|Tail]) :-
+    var(Var),
+    !.
+flatten_([], Tail, Tail) :-
+    !.
+flatten_([X|Xs], Tail, List) :-
+    !,
+    flatten_(X, List1, List),
+    flatten_(Xs, Tail, List1).
+flatten_(X, Tail, [X|Tail]).
+
+% permutation(?List, ?Permutation)
+permutation([], []).
+permutation(List, [X|Xs]) :-
+    select(X, List, Rest),
+    permutation(Rest, Xs).
+
+% sum_list(+List, -Sum), max_list(+List, -Max) and min_list(+List, -Min)
+% on lists of numbers.
+sum_list(List, Sum) :-
+    sum_list_(List, 0, Sum).
+
+sum_list_([], Sum, Sum).
+sum_list_([X|Xs], Sum0, Sum) :-
+    Sum1 is Sum0 + X,
+    sum_list_(Xs, Sum1, Sum).
+
+% sumlist/2 is an old name for sum_list/2.
+sumlist(List, Sum) :-
+    sum_list(List, Sum).
+
+max_list([X|Xs], Max) :-
+    max_list_(Xs, X, Max).
+
+max_list_([], Max, Max).
+max_list_([X|Xs], Max0, Max) :-
+    Max1 is max(Max0, X),
+    max_list_(Xs, Max1, Max).
+
+min_list([X|Xs], Min) :-
+    min_list_(Xs, X, Min).
+
+min_list_([], Min, Min).
+min_list_([X|Xs], Min0, Min) :-
+    Min1 is min(Min0, X),
+    min_list_(Xs, Min1, Min).
+
+% max_member(-Max, +List) and min_member(-Min, +List): the largest and
+% smallest element of List in the standard order of terms.
+max_member(Max, [X|Xs]) :-
+    max_member_(Xs, X, Max).
+
+max_member_([], Max, Max).
+max_member_([X|Xs], Max0, Max) :-
+    (   X @> Max0
+    ->  max_member_(Xs, X, Max)
+    ;   max_member_(Xs, Max0, Max)
+    ).
+
+min_member(Min, [X|Xs]) :-
+    min_member_(Xs, X, Min).
+
+min_member_([], Min, Min).
+min_member_([X|Xs], Min0, Min) :-
+    (   X @< Min0
+    ->  min_member_(Xs, X, Min)
+    ;   min_member_(Xs, Min0, Min)
+    ).
+
+% numlist(+Low, +High, -List): the integers from Low to High.
+numlist(Low, High, List) :-
+    Low =< High,
+    numlist_(Low, High, List).
+
+numlist_(High, High, [High]) :-
+    !.
+numlist_(Low, High, [Low|List]) :-
+    Next is Low + 1,
+    numlist_(Next, High, List).
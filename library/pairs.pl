@@ -0,0 +1,36 @@
+% library(pairs): lists of Key-Value pairs.
+
+% pairs_keys_values(?Pairs, ?Keys, ?Values)
+pairs_keys_values([], [], []).
+pairs_keys_values([K-V|Pairs], [K|Ks], [V|Vs]) :-
+    pairs_keys_values(Pairs, Ks, Vs).
+
+% pairs_keys(?Pairs, ?Keys)
+pairs_keys([], []).
+pairs_keys([K-_|Pairs], [K|Ks]) :-
+    pairs_keys(Pairs, Ks).
+
+% pairs_values(?Pairs, ?Values)
+pairs_values([], []).
+pairs_values([_-V|Pairs], [V|Vs]) :-
+    pairs_values(Pairs, Vs).
+
+% transpose_pairs(+Pairs, -Transposed): the pairs turned round to
+% Value-Key and keysorted.
+transpose_pairs(Pairs, Transposed) :-
+    flip_pairs(Pairs, Flipped),
+    keysort(Flipped, Transposed).
+
+flip_pairs([], []).
+flip_pairs([K-V|Pairs], [V-K|Flipped]) :-
+    flip_pairs(Pairs, Flipped).
+
+% map_list_to_pairs(:Function, +List, -Pairs): pairs each element X of
+% List with the key K given by call(Function, X, K).
+map_list_to_pairs(Function, List, Pairs) :-
+    map_list_to_pairs_(List, Function, Pairs).
+
+map_list_to_pairs_([], _, []).
+map_list_to_pairs_([X|Xs], Function, [K-X|Pairs]) :-
+    call(Function, X, K),
+    map_list_to_pairs_(Xs, Function, Pairs).
@@ -0,0 +1,854 @@
+// src/compiler/clause.rs
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::compiler::control::{body, goal_variables, Disjunction, Goal, CUT_LEVEL};
+use crate::compiler::peephole::optimise;
+use crate::compiler::registers::allocate_registers;
+use crate::compiler::{atomic, CompileError, CompileOptions, Subterm};
+use crate::parser::ast::{Clause, Term};
+use crate::wam::data_structures::PredicateTable;
+use crate::wam::instruction::{ArithOp, Comparison, Constant, Instruction, Operand, Reg};
+
+/// Compiles one clause to WAM code. Variables that must survive a call live
+/// in the environment as permanent variables, numbered so that the ones
+/// needed longest come first and each `call` can trim the ones that are
+/// dead after it. The last goal is compiled as `execute` after `deallocate`,
+/// so tail calls run in constant stack space. Other variables are
+/// temporaries, first numbered above every argument register the clause
+/// uses and then packed into real registers by `allocate_registers`.
+///
+/// Arithmetic whose variables all occur earlier in the clause is compiled
+/// inline and, like a cut, does not end a chunk.
+///
+/// A cut before any call is a `neck_cut`. Later cuts need the cut barrier,
+/// which `get_level` saves on entry in the pseudo-variable `$cut`.
+///
+/// Disjunctions are compiled inline, with a choice point of their own:
+///
+/// ```text
+///         try_me_else L2, N        % saves registers 1..N
+///         <first branch>
+///         jump End
+/// L2:     retry_me_else L3
+///         <second branch>
+///         jump End
+/// L3:     trust_me
+///         <last branch>
+/// End:
+/// ```
+///
+/// Backtracking into a branch restores the registers the disjunction was
+/// entered with, so a temporary only has to be permanent if it is used
+/// after a call on some path through the clause. An if-then-else saves the
+/// choice point level with `get_choice` before its `try_me_else` and cuts
+/// back to it once its condition succeeds. A variable first set inside a
+/// disjunction but used after it is made a fresh variable by
+/// `init_variable` beforehand, so every branch finds it in the same place.
+///
+/// Unless `options` turn it off, the code is finally rewritten by the
+/// peephole optimiser.
+pub fn compile_clause(
+    clause: &Clause,
+    predicates: &mut PredicateTable,
+    options: CompileOptions,
+) -> Result<Vec<Instruction>, CompileError> {
+    let clause = rename_anonymous(clause);
+    let head_args = match &clause.head {
+        Term::Atom(_) => Vec::new(),
+        Term::Structure { args, .. } => args.clone(),
+        head => return Err(CompileError::InvalidHead(head.clone())),
+    };
+    let mut goals = body(&clause.body)?;
+
+    let mut head_names = Vec::new();
+    for arg in &head_args {
+        variables(arg, &mut head_names);
+    }
+    let mut analysis = Analysis {
+        chunk: 0,
+        predecessors: vec![Vec::new()],
+        called: false,
+        known: head_names.iter().cloned().collect(),
+        chunks: head_names.iter().map(|name| (name.clone(), vec![0])).collect(),
+        order: Vec::new(),
+        last_use: HashMap::new(),
+        environment: false,
+        max_arity: head_args.len(),
+    };
+    analysis.goals(&mut goals, true);
+    let needs_level = analysis.chunks.contains_key(CUT_LEVEL);
+    if needs_level {
+        analysis.chunks.get_mut(CUT_LEVEL).unwrap().insert(0, 0);
+    }
+    let permanent = analysis.permanent_variables();
+
+    let mut compiler = ClauseCompiler {
+        code: Vec::new(),
+        labels: Vec::new(),
+        registers: HashMap::new(),
+        seen: HashSet::new(),
+        nested: HashSet::new(),
+        unsafe_variables: HashSet::new(),
+        next_x: analysis.max_arity + 1,
+        environment: analysis.environment || !permanent.is_empty(),
+        last_use: analysis.last_use,
+        permanent,
+        predicates,
+    };
+    for (i, name) in compiler.permanent.iter().enumerate() {
+        compiler.registers.insert(name.clone(), Reg::Y(i + 1));
+    }
+
+    if compiler.environment {
+        compiler.code.push(Instruction::Allocate(compiler.permanent.len()));
+    }
+    if needs_level {
+        let (reg, _) = compiler.variable(CUT_LEVEL);
+        compiler.code.push(Instruction::GetLevel(reg));
+    }
+    compiler.compile_head(&head_args);
+    compiler.body(&goals, true);
+
+    let mut labels = compiler.labels;
+    let mut code = allocate_registers(compiler.code, head_args.len(), analysis.max_arity, &mut labels);
+    if options.peephole {
+        code = optimise(code, &mut labels, predicates);
+    }
+    // A local choice point saves every register the clause uses.
+    let registers = code.iter().map(Instruction::max_register).max().unwrap_or(0);
+    Ok(code
+        .into_iter()
+        .map(|instruction| match instruction {
+            Instruction::TryMeElse(label, _) => Instruction::TryMeElse(labels[label], registers),
+            Instruction::RetryMeElse(label) => Instruction::RetryMeElse(labels[label]),
+            Instruction::Jump(label) => Instruction::Jump(labels[label]),
+            other => other,
+        })
+        .collect())
+}
+
+/// What the compiler needs to know about a clause body before compiling it.
+///
+/// The body is split into chunks, stretches of code without calls; the head
+/// starts chunk 0. A variable is permanent if it occurs in two chunks that
+/// lie on one path through the clause. The branches of a disjunction all
+/// start in the chunk it is entered in, and the code after it starts a new
+/// chunk if some branch made a call.
+struct Analysis {
+    chunk: usize,
+    /// The chunks each chunk can be entered from.
+    predecessors: Vec<Vec<usize>>,
+    /// Whether some path to the current goal makes a call.
+    called: bool,
+    /// Variables that already have a value at the current goal.
+    known: HashSet<String>,
+    /// The chunks each variable occurs in.
+    chunks: HashMap<String, Vec<usize>>,
+    /// Body variables in order of first occurrence.
+    order: Vec<String>,
+    /// Position of the last goal using each body variable.
+    last_use: HashMap<String, usize>,
+    /// Whether some call is not a last goal, so an environment must hold
+    /// the continuation.
+    environment: bool,
+    max_arity: usize,
+}
+
+impl Analysis {
+    fn occur(&mut self, name: &str, position: usize) {
+        let chunks = self.chunks.entry(name.to_string()).or_default();
+        if !chunks.contains(&self.chunk) {
+            chunks.push(self.chunk);
+        }
+        if !self.order.iter().any(|other| other == name) {
+            self.order.push(name.to_string());
+        }
+        let last = self.last_use.entry(name.to_string()).or_insert(position);
+        *last = (*last).max(position);
+        self.known.insert(name.to_string());
+    }
+
+    fn goals(&mut self, goals: &mut [Goal], tail: bool) {
+        let count = goals.len();
+        for (i, goal) in goals.iter_mut().enumerate() {
+            self.goal(goal, tail && i + 1 == count);
+        }
+    }
+
+    fn goal(&mut self, goal: &mut Goal, tail: bool) {
+        match goal {
+            Goal::Cut(_, level) if !self.called && level.as_deref() == Some(CUT_LEVEL) => *level = None,
+            Goal::Cut(position, Some(level)) => self.occur(level, *position),
+            Goal::Cut(_, None) | Goal::Fail => {}
+            Goal::Call(position, name, args) if inline_arithmetic(name, args, &self.known) => {
+                *goal = Goal::Arithmetic(*position, std::mem::take(name), std::mem::take(args));
+                self.goal(goal, tail);
+            }
+            Goal::Arithmetic(position, _, args) => {
+                let mut names = Vec::new();
+                for arg in args.iter() {
+                    variables(arg, &mut names);
+                }
+                for name in names {
+                    self.occur(&name, *position);
+                }
+            }
+            Goal::Call(position, _, args) => {
+                let mut names = Vec::new();
+                for arg in args.iter() {
+                    variables(arg, &mut names);
+                }
+                for name in names {
+                    self.occur(&name, *position);
+                }
+                self.max_arity = self.max_arity.max(args.len());
+                self.environment |= !tail;
+                self.called = true;
+                self.predecessors.push(vec![self.chunk]);
+                self.chunk = self.predecessors.len() - 1;
+            }
+            Goal::Disjunction(disjunction) => self.disjunction(disjunction, tail),
+        }
+    }
+
+    fn disjunction(&mut self, disjunction: &mut Disjunction, tail: bool) {
+        if disjunction.commits() {
+            self.occur(&disjunction.level, disjunction.start);
+        }
+        let (entry, called, known) = (self.chunk, self.called, self.known.clone());
+        let mut exits: Vec<usize> = Vec::new();
+        let mut any_called = called;
+        let mut any_known = known.clone();
+        for branch in &mut disjunction.branches {
+            self.chunk = entry;
+            self.called = called;
+            self.known = known.clone();
+            if let Some(local) = &branch.local_cut {
+                self.occur(local, disjunction.start);
+            }
+            if let Some(condition) = &mut branch.condition {
+                self.goals(condition, false);
+                self.occur(&disjunction.level, branch.commit);
+            }
+            self.goals(&mut branch.body, tail);
+            if !exits.contains(&self.chunk) {
+                exits.push(self.chunk);
+            }
+            any_called |= self.called;
+            any_known.extend(self.known.drain());
+        }
+        self.called = any_called;
+        self.known = any_known;
+        if exits == [entry] {
+            self.chunk = entry;
+        } else {
+            self.predecessors.push(exits);
+            self.chunk = self.predecessors.len() - 1;
+        }
+    }
+
+    /// True if some path through the clause runs chunk `from` and later `to`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        to == from || self.predecessors[to].iter().any(|&p| p >= from && self.reaches(from, p))
+    }
+
+    /// Variables that must survive a call, ordered by their last use,
+    /// latest first, so the environment can be trimmed from the end after
+    /// each call.
+    fn permanent_variables(&self) -> Vec<String> {
+        let mut permanent: Vec<String> = self
+            .order
+            .iter()
+            .filter(|name| {
+                let chunks = &self.chunks[*name];
+                chunks.iter().any(|&a| chunks.iter().any(|&b| a < b && self.reaches(a, b)))
+            })
+            .cloned()
+            .collect();
+        permanent.sort_by_key(|name| std::cmp::Reverse(self.last_use[name]));
+        permanent
+    }
+}
+
+/// True if `name(args)` is an arithmetic goal that can be compiled inline:
+/// `is/2` or a comparison between expressions over integers and variables
+/// in `known`, using the operations of `ArithOp`. Other shapes are called
+/// and evaluated by the builtin.
+fn inline_arithmetic(name: &str, args: &[Term], known: &HashSet<String>) -> bool {
+    if args.len() != 2 {
+        return false;
+    }
+    match name {
+        "is" => matches!(args[0], Term::Variable(_) | Term::Integer(_)) && evaluable(&args[1], known),
+        name => Comparison::from_name(name).is_some() && args.iter().all(|arg| evaluable(arg, known)),
+    }
+}
+
+fn evaluable(term: &Term, known: &HashSet<String>) -> bool {
+    match term {
+        Term::Integer(_) => true,
+        Term::Variable(name) => known.contains(name),
+        Term::Structure { functor, args, .. } => {
+            let inline = match args.len() {
+                1 => functor == "-" || functor == "+",
+                2 => ArithOp::from_name(functor).is_some(),
+                _ => false,
+            };
+            inline && args.iter().all(|arg| evaluable(arg, known))
+        }
+        _ => false,
+    }
+}
+
+struct ClauseCompiler<'a> {
+    code: Vec<Instruction>,
+    /// Where each label of a local choice point or jump is defined, as an
+    /// index into `code`.
+    labels: Vec<usize>,
+    registers: HashMap<String, Reg>,
+    seen: HashSet<String>,
+    /// Variables that have already occurred inside a structure.
+    nested: HashSet<String>,
+    /// Permanent variables first put in a goal argument, which may still be
+    /// unbound in the environment when it is trimmed or deallocated.
+    unsafe_variables: HashSet<String>,
+    next_x: usize,
+    environment: bool,
+    permanent: Vec<String>,
+    last_use: HashMap<String, usize>,
+    predicates: &'a mut PredicateTable,
+}
+
+impl ClauseCompiler<'_> {
+    fn fresh_x(&mut self) -> usize {
+        let x = self.next_x;
+        self.next_x += 1;
+        x
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(usize::MAX);
+        self.labels.len() - 1
+    }
+
+    fn define(&mut self, label: usize) {
+        self.labels[label] = self.code.len();
+    }
+
+    /// Register of variable `name` and whether this is its first occurrence.
+    fn variable(&mut self, name: &str) -> (Reg, bool) {
+        let reg = match self.registers.get(name) {
+            Some(&reg) => reg,
+            None => {
+                let reg = Reg::X(self.fresh_x());
+                self.registers.insert(name.to_string(), reg);
+                reg
+            }
+        };
+        (reg, self.seen.insert(name.to_string()))
+    }
+
+    /// Compiles a sequence of goals. In tail position it leaves the clause,
+    /// with a last call or `proceed`.
+    fn body(&mut self, goals: &[Goal], tail: bool) {
+        for (i, goal) in goals.iter().enumerate() {
+            self.goal(goal, tail && i + 1 == goals.len());
+        }
+        if tail && !matches!(goals.last(), Some(Goal::Call(..) | Goal::Disjunction(_) | Goal::Fail)) {
+            if self.environment {
+                self.code.push(Instruction::Deallocate);
+            }
+            self.code.push(Instruction::Proceed);
+        }
+    }
+
+    fn goal(&mut self, goal: &Goal, tail: bool) {
+        match goal {
+            Goal::Cut(_, None) => self.code.push(Instruction::NeckCut),
+            Goal::Cut(_, Some(level)) => {
+                let (reg, _) = self.variable(level);
+                self.code.push(Instruction::Cut(reg));
+            }
+            Goal::Fail => self.code.push(Instruction::Fail),
+            Goal::Arithmetic(_, name, args) => self.arithmetic(name, args),
+            Goal::Call(position, name, args) => self.call(*position, name, args, tail),
+            Goal::Disjunction(disjunction) => self.disjunction(disjunction, tail),
+        }
+    }
+
+    fn call(&mut self, position: usize, name: &str, args: &[Term], tail: bool) {
+        // A last call discards the environment, so every permanent variable
+        // dies with it.
+        let dying: Vec<String> =
+            self.permanent.iter().filter(|name| tail || self.last_use[*name] == position).cloned().collect();
+        self.put_arguments(args, &dying);
+        let id = self.predicates.id(name, args.len());
+        if tail {
+            if self.environment {
+                self.code.push(Instruction::Deallocate);
+            }
+            self.code.push(Instruction::Execute(id));
+        } else {
+            let live = self.permanent.iter().filter(|name| self.last_use[*name] > position).count();
+            self.code.push(Instruction::Call(id, live));
+        }
+    }
+
+    fn disjunction(&mut self, disjunction: &Disjunction, tail: bool) {
+        if let [branch] = &disjunction.branches[..] {
+            let (level, _) = self.variable(&disjunction.level);
+            self.code.push(Instruction::GetChoice(level));
+            self.body(branch.condition.as_deref().unwrap_or_default(), false);
+            self.code.push(Instruction::Cut(level));
+            self.body(&branch.body, tail);
+            return;
+        }
+
+        let mut inside = Vec::new();
+        for branch in &disjunction.branches {
+            for goal in branch.condition.iter().flatten().chain(&branch.body) {
+                goal_variables(goal, &mut inside);
+            }
+        }
+        for name in inside {
+            if !self.seen.contains(&name) && self.last_use.get(&name).is_some_and(|&last| last > disjunction.end) {
+                let (reg, _) = self.variable(&name);
+                self.code.push(Instruction::InitVariable(reg));
+                // It lives on the heap, so structures can refer to it directly.
+                self.nested.insert(name);
+            }
+        }
+        if disjunction.commits() {
+            let (level, _) = self.variable(&disjunction.level);
+            self.code.push(Instruction::GetChoice(level));
+        }
+
+        let entry = (self.seen.clone(), self.nested.clone(), self.unsafe_variables.clone());
+        let count = disjunction.branches.len();
+        let alternatives: Vec<usize> = (1..count).map(|_| self.label()).collect();
+        let end = self.label();
+        for (k, branch) in disjunction.branches.iter().enumerate() {
+            if k > 0 {
+                (self.seen, self.nested, self.unsafe_variables) = entry.clone();
+                self.define(alternatives[k - 1]);
+            }
+            self.code.push(match k {
+                0 => Instruction::TryMeElse(alternatives[0], 0),
+                k if k + 1 == count => Instruction::TrustMe,
+                k => Instruction::RetryMeElse(alternatives[k]),
+            });
+            if let Some(local) = &branch.local_cut {
+                let (reg, _) = self.variable(local);
+                self.code.push(Instruction::GetChoice(reg));
+            }
+            if let Some(condition) = &branch.condition {
+                self.body(condition, false);
+                let (level, _) = self.variable(&disjunction.level);
+                self.code.push(Instruction::Cut(level));
+            }
+            self.body(&branch.body, tail);
+            if !tail && k + 1 < count && !matches!(branch.body.last(), Some(Goal::Fail)) {
+                self.code.push(Instruction::Jump(end));
+            }
+        }
+        (self.seen, self.nested, self.unsafe_variables) = entry;
+        self.define(end);
+    }
+
+    /// Unify instruction for a variable argument of a structure. The first
+    /// occurrence inside a structure of a variable already seen elsewhere
+    /// uses `unify_local_value`, since it may be an unbound stack variable.
+    fn unify_variable(&mut self, name: &str) -> Instruction {
+        let (reg, first) = self.variable(name);
+        let nested = !self.nested.insert(name.to_string());
+        match (first, nested) {
+            (true, _) => Instruction::UnifyVariable(reg),
+            (false, true) => Instruction::UnifyValue(reg),
+            (false, false) => Instruction::UnifyLocalValue(reg),
+        }
+    }
+
+    fn compile_head(&mut self, args: &[Term]) {
+        let mut pending = VecDeque::new();
+        for (i, arg) in args.iter().enumerate() {
+            let ai = i + 1;
+            if let Term::Variable(name) = arg {
+                let (reg, first) = self.variable(name);
+                self.code.push(if first { Instruction::GetVariable(reg, ai) } else { Instruction::GetValue(reg, ai) });
+            } else if let Some(constant) = atomic(arg) {
+                self.code.push(Instruction::GetConstant(constant, ai));
+            } else {
+                self.get_structure(Subterm::Term(arg), ai, &mut pending);
+            }
+        }
+        while let Some((x, term)) = pending.pop_front() {
+            self.get_structure(term, x, &mut pending);
+        }
+    }
+
+    // Nested structures are matched breadth first: their argument slot is
+    // loaded into a temporary and unified by a later get_structure.
+    fn get_structure<'a>(&mut self, term: Subterm<'a>, register: usize, pending: &mut VecDeque<(usize, Subterm<'a>)>) {
+        let (functor, args) = term.compound().expect("get_structure on a non-compound term");
+        self.code.push(Instruction::GetStructure(functor.to_string(), args.len(), register));
+        for arg in args {
+            if let Some(name) = arg.variable() {
+                let instruction = self.unify_variable(name);
+                self.code.push(instruction);
+            } else if let Some(constant) = arg.atomic() {
+                self.code.push(Instruction::UnifyConstant(constant));
+            } else {
+                let x = self.fresh_x();
+                self.code.push(Instruction::UnifyVariable(Reg::X(x)));
+                pending.push_back((x, arg));
+            }
+        }
+    }
+
+    /// Loads the arguments of a goal. `dying` are the permanent variables
+    /// this goal uses for the last time.
+    fn put_arguments(&mut self, args: &[Term], dying: &[String]) {
+        for (i, arg) in args.iter().enumerate() {
+            let ai = i + 1;
+            if let Term::Variable(name) = arg {
+                let (reg, first) = self.variable(name);
+                let instruction = match reg {
+                    _ if first => {
+                        if let Reg::Y(_) = reg {
+                            self.unsafe_variables.insert(name.clone());
+                        }
+                        Instruction::PutVariable(reg, ai)
+                    }
+                    Reg::Y(y) if self.unsafe_variables.contains(name) && dying.contains(name) => {
+                        Instruction::PutUnsafeValue(y, ai)
+                    }
+                    _ => Instruction::PutValue(reg, ai),
+                };
+                self.code.push(instruction);
+            } else if let Some(constant) = atomic(arg) {
+                self.code.push(Instruction::PutConstant(constant, ai));
+            } else {
+                self.put_structure(Subterm::Term(arg), ai);
+            }
+        }
+    }
+
+    /// Compiles an inline arithmetic goal. The result of `is/2` goes straight
+    /// into its left-hand variable when this is its first occurrence, and is
+    /// otherwise unified with it from a temporary.
+    fn arithmetic(&mut self, name: &str, args: &[Term]) {
+        if let Some(comparison) = Comparison::from_name(name) {
+            let x = self.expression(&args[0]);
+            let y = self.expression(&args[1]);
+            self.code.push(Instruction::Compare(comparison, x, y));
+            return;
+        }
+        match &args[0] {
+            Term::Variable(name) if !self.seen.contains(name) => {
+                let (reg, _) = self.variable(name);
+                self.evaluate(&args[1], reg);
+            }
+            result => {
+                let x = self.fresh_x();
+                self.evaluate(&args[1], Reg::X(x));
+                let instruction = match result {
+                    Term::Variable(name) => Instruction::GetValue(self.variable(name).0, x),
+                    Term::Integer(value) => Instruction::GetConstant(Constant::Integer(*value), x),
+                    result => unreachable!("inline is/2 with result {:?}", result),
+                };
+                self.code.push(instruction);
+            }
+        }
+    }
+
+    /// Operand holding the value of an arithmetic expression.
+    fn expression(&mut self, term: &Term) -> Operand {
+        match term {
+            Term::Integer(value) => Operand::Integer(*value),
+            Term::Variable(name) => Operand::Reg(self.variable(name).0),
+            term => {
+                let x = self.fresh_x();
+                self.evaluate(term, Reg::X(x));
+                Operand::Reg(Reg::X(x))
+            }
+        }
+    }
+
+    /// Emits code leaving the value of expression `term` in `target`.
+    fn evaluate(&mut self, term: &Term, target: Reg) {
+        let instruction = match term {
+            Term::Structure { functor, args, .. } if args.len() == 2 => {
+                let op = ArithOp::from_name(functor).expect("inline arithmetic with an unknown operation");
+                let x = self.expression(&args[0]);
+                let y = self.expression(&args[1]);
+                Instruction::Arith(op, x, y, target)
+            }
+            // Multiplying by -1 rather than subtracting from 0 keeps -(0.0) = -0.0.
+            Term::Structure { functor, args, .. } if functor == "-" => {
+                Instruction::Arith(ArithOp::Mul, self.expression(&args[0]), Operand::Integer(-1), target)
+            }
+            Term::Structure { args, .. } => Instruction::Evaluate(self.expression(&args[0]), target),
+            term => Instruction::Evaluate(self.expression(term), target),
+        };
+        self.code.push(instruction);
+    }
+
+    // Structures are built bottom-up: nested arguments are built into
+    // temporaries first so the outer structure's arguments are contiguous.
+    fn put_structure(&mut self, term: Subterm, register: usize) {
+        let (functor, args) = term.compound().expect("put_structure on a non-compound term");
+        let nested: Vec<Option<usize>> = args
+            .iter()
+            .map(|&arg| match arg.compound() {
+                Some(_) => {
+                    let x = self.fresh_x();
+                    self.put_structure(arg, x);
+                    Some(x)
+                }
+                None => None,
+            })
+            .collect();
+
+        self.code.push(Instruction::PutStructure(functor.to_string(), args.len(), register));
+        for (arg, nested) in args.into_iter().zip(nested) {
+            if let Some(x) = nested {
+                self.code.push(Instruction::UnifyValue(Reg::X(x)));
+            } else if let Some(name) = arg.variable() {
+                let instruction = self.unify_variable(name);
+                self.code.push(instruction);
+            } else if let Some(constant) = arg.atomic() {
+                self.code.push(Instruction::UnifyConstant(constant));
+            }
+        }
+    }
+}
+
+/// Predicate name and arguments of a body goal. A variable goal `G` is
+/// compiled as `call(G)`.
+pub(crate) fn goal_parts(goal: &Term) -> Result<(String, Vec<Term>), CompileError> {
+    match goal {
+        Term::Variable(_) => Ok(("call".to_string(), vec![goal.clone()])),
+        Term::Atom(name) => Ok((name.clone(), Vec::new())),
+        Term::Structure { functor, args, .. } => Ok((functor.clone(), args.clone())),
+        _ => Err(CompileError::InvalidGoal(goal.clone())),
+    }
+}
+
+/// Appends the variables of `term` to `names` in order of first occurrence.
+pub(crate) fn variables(term: &Term, names: &mut Vec<String>) {
+    match term {
+        Term::Variable(name) if !names.contains(name) => names.push(name.clone()),
+        Term::Structure { args, .. } | Term::List(args) => {
+            for arg in args {
+                variables(arg, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Gives every `_` in the clause its own name so each occurrence is a distinct variable.
+fn rename_anonymous(clause: &Clause) -> Clause {
+    fn rename(term: &Term, counter: &mut usize) -> Term {
+        match term {
+            Term::Variable(name) if name == "_" => {
+                *counter += 1;
+                Term::Variable(format!("_#{}", counter))
+            }
+            Term::Structure { functor, arity, args } => Term::Structure {
+                functor: functor.clone(),
+                arity: *arity,
+                args: args.iter().map(|arg| rename(arg, counter)).collect(),
+            },
+            Term::List(elements) => Term::List(elements.iter().map(|element| rename(element, counter)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    let mut counter = 0;
+    Clause {
+        head: rename(&clause.head, &mut counter),
+        body: clause.body.iter().map(|goal| rename(goal, &mut counter)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::parse;
+    use Instruction::*;
+
+    fn compile(source: &str) -> (Vec<Instruction>, PredicateTable) {
+        let mut predicates = PredicateTable::default();
+        let clause = &parse(source).unwrap()[0];
+        // The peephole optimiser is tested on its own.
+        let options = CompileOptions { peephole: false };
+        (compile_clause(clause, &mut predicates, options).unwrap(), predicates)
+    }
+
+    #[test]
+    fn test_compile_fact() {
+        let (code, _) = compile("p(a, X, f(X, g(b))).");
+        assert_eq!(code, vec![
+            GetConstant(Constant::Atom("a".into()), 1),
+            GetStructure("f".into(), 2, 3),
+            UnifyLocalValue(Reg::X(2)),
+            UnifyVariable(Reg::X(1)),
+            GetStructure("g".into(), 1, 1),
+            UnifyConstant(Constant::Atom("b".into())),
+            Proceed,
+        ]);
+    }
+
+    #[test]
+    fn test_neck_cut_and_deep_cut() {
+        let (code, predicates) = compile("p(X) :- !, q(X).");
+        let q = predicates.lookup("q", 1).unwrap();
+        assert_eq!(code, vec![NeckCut, Execute(q)]);
+
+        let (code, predicates) = compile("p(X) :- q(X), !, r(X).");
+        let (q, r) = (predicates.lookup("q", 1).unwrap(), predicates.lookup("r", 1).unwrap());
+        assert_eq!(code, vec![
+            Allocate(2),
+            GetLevel(Reg::Y(2)),
+            GetVariable(Reg::Y(1), 1),
+            PutValue(Reg::Y(1), 1),
+            Call(q, 2),
+            Cut(Reg::Y(2)),
+            PutValue(Reg::Y(1), 1),
+            Deallocate,
+            Execute(r),
+        ]);
+    }
+
+    #[test]
+    fn test_inline_arithmetic() {
+        let (code, _) = compile("inc(X, Y) :- Z is X * 2, Y is Z + 1.");
+        assert_eq!(code, vec![
+            Arith(ArithOp::Mul, Operand::Reg(Reg::X(1)), Operand::Integer(2), Reg::X(1)),
+            Arith(ArithOp::Add, Operand::Reg(Reg::X(1)), Operand::Integer(1), Reg::X(1)),
+            GetValue(Reg::X(2), 1),
+            Proceed,
+        ]);
+
+        // A variable without a value yet falls back to calling is/2.
+        let (code, predicates) = compile("p(X) :- X is Y + 1.");
+        assert_eq!(code.last(), Some(&Execute(predicates.lookup("is", 2).unwrap())));
+    }
+
+    #[test]
+    fn test_if_then_else_compiled_inline() {
+        // The else branch finds X and Y where the head left them, restored by
+        // the local choice point, so no environment is needed.
+        let (code, _) = compile("max(X, Y, Z) :- (X >= Y -> Z is X ; Z is Y).");
+        assert_eq!(code, vec![
+            GetChoice(Reg::X(4)),
+            TryMeElse(7, 4),
+            Compare(Comparison::GreaterOrEqual, Operand::Reg(Reg::X(1)), Operand::Reg(Reg::X(2))),
+            Cut(Reg::X(4)),
+            Evaluate(Operand::Reg(Reg::X(1)), Reg::X(1)),
+            GetValue(Reg::X(3), 1),
+            Proceed,
+            TrustMe,
+            Evaluate(Operand::Reg(Reg::X(2)), Reg::X(1)),
+            GetValue(Reg::X(3), 1),
+            Proceed,
+        ]);
+    }
+
+    #[test]
+    fn test_disjunction_joins_before_next_goal() {
+        let (code, predicates) = compile("p(X, Y) :- (X = a ; X = b), q(Y).");
+        let (unify, q) = (predicates.lookup("=", 2).unwrap(), predicates.lookup("q", 1).unwrap());
+        assert_eq!(code, vec![
+            Allocate(1),
+            GetVariable(Reg::Y(1), 2),
+            TryMeElse(6, 2),
+            PutConstant(Constant::Atom("a".into()), 2),
+            Call(unify, 1),
+            Jump(9),
+            TrustMe,
+            PutConstant(Constant::Atom("b".into()), 2),
+            Call(unify, 1),
+            PutValue(Reg::Y(1), 1),
+            Deallocate,
+            Execute(q),
+        ]);
+
+        // A variable only the first branch binds is created before the
+        // disjunction, so both branches leave it in the same register.
+        let (code, _) = compile("p(Y) :- (X = 1 ; true), Y = f(X).");
+        assert_eq!(code[2], InitVariable(Reg::Y(1)));
+        assert_eq!(code[11], UnifyValue(Reg::Y(1)));
+    }
+
+    #[test]
+    fn test_compile_rule() {
+        let (code, predicates) = compile("grandparent(X, Z) :- parent(X, Y), parent(Y, f(Z)).");
+        let parent = predicates.lookup("parent", 2).unwrap();
+        assert_eq!(code, vec![
+            Allocate(2),
+            GetVariable(Reg::Y(2), 2),
+            PutVariable(Reg::Y(1), 2),
+            Call(parent, 2),
+            PutUnsafeValue(1, 1),
+            PutStructure("f".into(), 1, 2),
+            UnifyLocalValue(Reg::Y(2)),
+            Deallocate,
+            Execute(parent),
+        ]);
+    }
+
+    #[test]
+    fn test_environment_trimmed_after_each_call() {
+        let (code, predicates) = compile("p(A, B, C) :- q(A), r(B), s(C).");
+        let (q, r, s) = (predicates.lookup("q", 1).unwrap(), predicates.lookup("r", 1).unwrap(), predicates.lookup("s", 1).unwrap());
+        assert_eq!(code, vec![
+            Allocate(2),
+            GetVariable(Reg::Y(2), 2),
+            GetVariable(Reg::Y(1), 3),
+            Call(q, 2),
+            PutValue(Reg::Y(2), 1),
+            Call(r, 1),
+            PutValue(Reg::Y(1), 1),
+            Deallocate,
+            Execute(s),
+        ]);
+    }
+
+    #[test]
+    fn test_single_goal_needs_no_environment() {
+        let (code, predicates) = compile("p(X) :- q(X, Y).");
+        let q = predicates.lookup("q", 2).unwrap();
+        assert_eq!(code, vec![PutVariable(Reg::X(2), 2), Execute(q)]);
+    }
+
+    #[test]
+    fn test_swapped_arguments_do_not_clobber() {
+        let (code, predicates) = compile("p(X, Y) :- q(Y, X).");
+        let q = predicates.lookup("q", 2).unwrap();
+        assert_eq!(code, vec![
+            GetVariable(Reg::X(3), 1),
+            PutValue(Reg::X(2), 1),
+            PutValue(Reg::X(3), 2),
+            Execute(q),
+        ]);
+    }
+
+    #[test]
+    fn test_anonymous_variables_are_distinct() {
+        let (code, _) = compile("p(_, _).");
+        assert_eq!(code, vec![Proceed]);
+
+        let mut wam = crate::wam::WamEmulator::new();
+        wam.consult("q(f(_, _)).").unwrap();
+        assert_eq!(crate::wam::emulator::tests::answers(&mut wam, "q(f(a, b))"), vec!["true"]);
+    }
+
+    #[test]
+    fn test_invalid_head() {
+        let mut predicates = PredicateTable::default();
+        let clause = Clause { head: Term::Integer(1), body: vec![] };
+        assert_eq!(compile_clause(&clause, &mut predicates, CompileOptions::default()), Err(CompileError::InvalidHead(Term::Integer(1))));
+    }
+}
@@ -0,0 +1,266 @@
+// src/compiler/control.rs
+use crate::compiler::clause::{goal_parts, variables};
+use crate::compiler::CompileError;
+use crate::parser::ast::{conjunction, Term};
+
+/// Name of the pseudo-variable holding the cut barrier of the clause being
+/// compiled: the choice point level when its predicate was called.
+pub(crate) const CUT_LEVEL: &str = "$cut";
+
+/// A body goal, with disjunctions, if-then-else and negation kept as a tree
+/// so the clause compiler can lay them out inline. Goals are numbered by a
+/// position in code order, which the compiler uses to tell when a variable
+/// is used for the last time.
+pub(crate) enum Goal {
+    /// A cut at a position, restoring the choice point level held in the
+    /// named pseudo-variable, or a `neck_cut` when that is `None`.
+    Cut(usize, Option<String>),
+    Fail,
+    /// `is/2` or an arithmetic comparison compiled to arithmetic instructions.
+    Arithmetic(usize, String, Vec<Term>),
+    Call(usize, String, Vec<Term>),
+    Disjunction(Disjunction),
+}
+
+/// Alternatives tried in order through a choice point local to the clause.
+/// A single alternative is an if-then-else without else, which needs none.
+pub(crate) struct Disjunction {
+    /// Pseudo-variable saving the choice point level before the disjunction,
+    /// which a condition cuts back to when it succeeds.
+    pub level: String,
+    pub branches: Vec<Branch>,
+    /// Positions of the disjunction's start and of the first goal after it.
+    pub start: usize,
+    pub end: usize,
+}
+
+pub(crate) struct Branch {
+    /// The condition of an if-then-else alternative.
+    pub condition: Option<Vec<Goal>>,
+    /// Pseudo-variable with the level a cut inside the condition cuts to,
+    /// when it has one.
+    pub local_cut: Option<String>,
+    /// Position of the cut committing to the branch after its condition.
+    pub commit: usize,
+    pub body: Vec<Goal>,
+}
+
+impl Disjunction {
+    /// True if some branch has a condition to commit to.
+    pub fn commits(&self) -> bool {
+        self.branches.iter().any(|branch| branch.condition.is_some())
+    }
+}
+
+/// Turns a clause body into goals. `;`, `->`, `\+`, `once/1` and
+/// `ignore/1` become disjunctions (`\+ G` is `(G -> fail ; true)`,
+/// `once(G)` is `(G -> true)` and `ignore(G)` is `(G -> true ; true)`),
+/// `true` disappears and `fail` and `false` become `Fail`. A cut cuts the clause, except inside a condition,
+/// where it only cuts the condition.
+pub(crate) fn body(body: &[Term]) -> Result<Vec<Goal>, CompileError> {
+    let mut builder = Builder { position: 0, disjunctions: 0 };
+    let terms: Vec<Term> = body.iter().cloned().flat_map(conjunction).collect();
+    builder.goals(&terms, CUT_LEVEL)
+}
+
+struct Builder {
+    position: usize,
+    disjunctions: usize,
+}
+
+/// The arguments of a control construct named `functor`.
+fn arguments<'a>(goal: &'a Term, functor: &str) -> Option<&'a [Term]> {
+    match goal {
+        Term::Structure { functor: f, args, .. } if f == functor => Some(args),
+        _ => None,
+    }
+}
+
+/// True if a cut in `goals` restores the level in `level`.
+fn cuts_to(goals: &[Goal], level: &str) -> bool {
+    goals.iter().any(|goal| match goal {
+        Goal::Cut(_, Some(name)) => name == level,
+        Goal::Disjunction(disjunction) => {
+            disjunction.branches.iter().any(|branch| cuts_to(&branch.body, level))
+        }
+        _ => false,
+    })
+}
+
+impl Builder {
+    fn next(&mut self) -> usize {
+        self.position += 1;
+        self.position
+    }
+
+    /// Goals of a conjunction whose cuts restore the level in `scope`.
+    fn goals(&mut self, terms: &[Term], scope: &str) -> Result<Vec<Goal>, CompileError> {
+        let mut goals = Vec::new();
+        for term in terms {
+            match term {
+                Term::Atom(name) if name == "true" => {}
+                Term::Atom(name) if name == "fail" || name == "false" => goals.push(Goal::Fail),
+                Term::Atom(name) if name == "!" => goals.push(Goal::Cut(self.next(), Some(scope.to_string()))),
+                term => goals.push(self.goal(term, scope)?),
+            }
+        }
+        Ok(goals)
+    }
+
+    fn goal(&mut self, term: &Term, scope: &str) -> Result<Goal, CompileError> {
+        if let Some([negated]) = arguments(term, "\\+") {
+            let fail = vec![Term::Atom("fail".to_string())];
+            return self.disjunction(vec![(Some(negated), fail), (None, Vec::new())], scope);
+        }
+        if let Some([goal]) = arguments(term, "once") {
+            return self.disjunction(vec![(Some(goal), Vec::new())], scope);
+        }
+        if let Some([goal]) = arguments(term, "ignore") {
+            return self.disjunction(vec![(Some(goal), Vec::new()), (None, Vec::new())], scope);
+        }
+        if let Some([condition, then]) = arguments(term, "->") {
+            return self.disjunction(vec![(Some(condition), conjunction(then.clone()))], scope);
+        }
+        if arguments(term, ";").is_some_and(|args| args.len() == 2) {
+            let mut alternatives = Vec::new();
+            let mut rest = term;
+            while let Some([left, right]) = arguments(rest, ";") {
+                alternatives.push(alternative(left));
+                rest = right;
+            }
+            alternatives.push(alternative(rest));
+            return self.disjunction(alternatives, scope);
+        }
+        let (name, args) = goal_parts(term)?;
+        Ok(Goal::Call(self.next(), name, args))
+    }
+
+    fn disjunction(&mut self, alternatives: Vec<(Option<&Term>, Vec<Term>)>, scope: &str) -> Result<Goal, CompileError> {
+        self.disjunctions += 1;
+        let id = self.disjunctions;
+        let level = format!("$choice{}", id);
+        let start = self.next();
+        let single = alternatives.len() == 1;
+        let mut branches = Vec::new();
+        for (k, (condition, body)) in alternatives.into_iter().enumerate() {
+            let (condition, local_cut) = match condition {
+                // Without other alternatives nothing lies between the saved
+                // level and the condition, so its cuts can use that level.
+                Some(condition) if single => (Some(self.goals(&conjunction(condition.clone()), &level)?), None),
+                Some(condition) => {
+                    let local = format!("$choice{}.{}", id, k + 1);
+                    let goals = self.goals(&conjunction(condition.clone()), &local)?;
+                    let local_cut = cuts_to(&goals, &local).then_some(local);
+                    (Some(goals), local_cut)
+                }
+                None => (None, None),
+            };
+            let commit = self.next();
+            let body = self.goals(&body, scope)?;
+            branches.push(Branch { condition, local_cut, commit, body });
+        }
+        let end = self.next();
+        Ok(Goal::Disjunction(Disjunction { level, branches, start, end }))
+    }
+}
+
+/// Condition and goals of one alternative of a disjunction.
+fn alternative(term: &Term) -> (Option<&Term>, Vec<Term>) {
+    match arguments(term, "->") {
+        Some([condition, then]) => (Some(condition), conjunction(then.clone())),
+        _ => (None, conjunction(term.clone())),
+    }
+}
+
+/// Appends the variables a goal uses, pseudo-variables included, to `names`.
+pub(crate) fn goal_variables(goal: &Goal, names: &mut Vec<String>) {
+    fn add(name: &String, names: &mut Vec<String>) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    match goal {
+        Goal::Cut(_, Some(level)) => add(level, names),
+        Goal::Cut(_, None) | Goal::Fail => {}
+        Goal::Arithmetic(_, _, args) | Goal::Call(_, _, args) => {
+            for arg in args {
+                variables(arg, names);
+            }
+        }
+        Goal::Disjunction(disjunction) => {
+            if disjunction.commits() {
+                add(&disjunction.level, names);
+            }
+            for branch in &disjunction.branches {
+                if let Some(local) = &branch.local_cut {
+                    add(local, names);
+                }
+                for goal in branch.condition.iter().flatten().chain(&branch.body) {
+                    goal_variables(goal, names);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::parse;
+
+    fn compile_body(source: &str) -> Vec<Goal> {
+        body(&parse(source).unwrap()[0].body).unwrap()
+    }
+
+    #[test]
+    fn test_disjunction_alternatives_are_flattened() {
+        let goals = compile_body("p(X) :- a, (X = 1, ! ; X = 2 ; true), b.");
+        assert_eq!(goals.len(), 3);
+        let disjunction = match &goals[1] {
+            Goal::Disjunction(disjunction) => disjunction,
+            _ => panic!("expected a disjunction"),
+        };
+        assert_eq!(disjunction.branches.len(), 3);
+        assert!(!disjunction.commits());
+        assert!(matches!(&disjunction.branches[0].body[1], Goal::Cut(_, Some(level)) if level == CUT_LEVEL));
+        assert!(disjunction.branches[2].body.is_empty());
+    }
+
+    #[test]
+    fn test_negation_is_if_then_else() {
+        let goals = compile_body("p(X) :- \\+ q(X).");
+        let disjunction = match &goals[0] {
+            Goal::Disjunction(disjunction) => disjunction,
+            _ => panic!("expected a disjunction"),
+        };
+        assert!(disjunction.commits());
+        assert!(matches!(disjunction.branches[0].body[..], [Goal::Fail]));
+        assert!(disjunction.branches[1].condition.is_none());
+    }
+
+    #[test]
+    fn test_once_and_ignore_are_if_then_else() {
+        let goals = compile_body("p(X) :- once(q(X)), ignore(r(X)).");
+        let [Goal::Disjunction(once), Goal::Disjunction(ignore)] = &goals[..] else { panic!("expected disjunctions") };
+        assert_eq!(once.branches.len(), 1);
+        assert!(matches!(once.branches[0].condition.as_deref(), Some([Goal::Call(_, name, _)]) if name == "q"));
+        assert!(once.branches[0].body.is_empty());
+        assert_eq!(ignore.branches.len(), 2);
+        assert!(ignore.branches[1].condition.is_none() && ignore.branches[1].body.is_empty());
+    }
+
+    #[test]
+    fn test_cut_in_condition_is_local() {
+        let goals = compile_body("p :- ((a, !) -> b ; c).");
+        let Goal::Disjunction(disjunction) = &goals[0] else { panic!("expected a disjunction") };
+        let local = disjunction.branches[0].local_cut.clone().unwrap();
+        let condition = disjunction.branches[0].condition.as_ref().unwrap();
+        assert!(matches!(&condition[1], Goal::Cut(_, Some(level)) if *level == local));
+
+        // Without an else, the condition cuts back to the saved level.
+        let goals = compile_body("p :- ((a, !) -> b).");
+        let Goal::Disjunction(disjunction) = &goals[0] else { panic!("expected a disjunction") };
+        assert!(disjunction.branches[0].local_cut.is_none());
+        assert!(cuts_to(disjunction.branches[0].condition.as_ref().unwrap(), &disjunction.level));
+    }
+}
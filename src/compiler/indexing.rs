@@ -0,0 +1,384 @@
+// src/compiler/indexing.rs
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::compiler::{atomic, compile_clause, compound, predicate_key, CompileError, CompileOptions};
+use crate::parser::ast::{Clause, Term};
+use crate::wam::data_structures::PredicateTable;
+use crate::wam::instruction::{Constant, Instruction, Label, SwitchTable};
+
+/// Code for a whole predicate. Labels are relative to the start of `code`;
+/// `clause_entries` holds where each clause's own code begins, after its
+/// `try_me_else`/`retry_me_else`/`trust_me` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledPredicate {
+    pub code: Vec<Instruction>,
+    pub clause_entries: Vec<usize>,
+}
+
+/// What the first argument of a clause head can match.
+#[derive(Debug, Clone, PartialEq)]
+enum Key {
+    Variable,
+    Constant(Constant),
+    List,
+    Structure(String, usize),
+}
+
+fn first_argument_key(clause: &Clause) -> Key {
+    let first = match &clause.head {
+        Term::Structure { args, .. } if !args.is_empty() => &args[0],
+        _ => return Key::Variable,
+    };
+    if let Some(constant) = atomic(first) {
+        return Key::Constant(constant.key());
+    }
+    match compound(first) {
+        Some((".", 2)) => Key::List,
+        Some((functor, arity)) => Key::Structure(functor.to_string(), arity),
+        None => Key::Variable,
+    }
+}
+
+/// Compiles the clauses of one predicate. All clauses are chained with
+/// `try_me_else`/`retry_me_else`/`trust_me`; when some clause has a non-variable
+/// first argument, a `switch_on_term` in front dispatches on A1 so that calls
+/// with a bound first argument only try the clauses that can match it.
+pub fn compile_predicate(
+    clauses: &[Clause],
+    predicates: &mut PredicateTable,
+    options: CompileOptions,
+) -> Result<CompiledPredicate, CompileError> {
+    let bodies = clauses
+        .iter()
+        .map(|clause| compile_clause(clause, predicates, options))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if bodies.len() == 1 {
+        let code = bodies.into_iter().next().unwrap();
+        return Ok(CompiledPredicate { code, clause_entries: vec![0] });
+    }
+
+    let (_, arity) = predicate_key(&clauses[0].head)?;
+    let keys: Vec<Key> = clauses.iter().map(first_argument_key).collect();
+    let indexed = keys.iter().any(|key| *key != Key::Variable);
+
+    let mut code = Vec::new();
+    if indexed {
+        code.push(Instruction::Fail); // patched with switch_on_term below
+    }
+    let mut headers = Vec::new();
+    let mut clause_entries = Vec::new();
+    for body in bodies {
+        headers.push(code.len());
+        code.push(Instruction::Fail); // patched with the clause header below
+        let entry = code.len();
+        clause_entries.push(entry);
+        code.extend(body.iter().map(|instruction| instruction.relocate(entry)));
+    }
+    for (i, &header) in headers.iter().enumerate() {
+        code[header] = match i {
+            0 => Instruction::TryMeElse(headers[1], arity),
+            i if i + 1 == headers.len() => Instruction::TrustMe,
+            i => Instruction::RetryMeElse(headers[i + 1]),
+        };
+    }
+
+    if indexed {
+        let mut index = IndexBuilder {
+            code,
+            clause_entries: &clause_entries,
+            all_clauses: headers[0],
+            chains: HashMap::new(),
+            variable_tail: None,
+        };
+        let switch = index.switch_on_term(&keys);
+        code = index.code;
+        code[0] = switch;
+    }
+    Ok(CompiledPredicate { code, clause_entries })
+}
+
+struct IndexBuilder<'a> {
+    code: Vec<Instruction>,
+    clause_entries: &'a [usize],
+    /// Label of the full try_me_else chain, used when every clause applies.
+    all_clauses: Label,
+    chains: HashMap<Vec<usize>, Label>,
+    /// Label of the retry/trust block over the variable clauses, which the
+    /// blocks of the groups jump to for the variable clauses after theirs.
+    variable_tail: Option<Label>,
+}
+
+impl IndexBuilder<'_> {
+    fn switch_on_term(&mut self, keys: &[Key]) -> Instruction {
+        let variables: Vec<usize> = (0..keys.len()).filter(|&i| keys[i] == Key::Variable).collect();
+        let mut lists = Vec::new();
+        let mut constants: Vec<(Constant, Vec<usize>)> = Vec::new();
+        let mut constant_groups: HashMap<Constant, usize> = HashMap::new();
+        let mut structures: Vec<((String, usize), Vec<usize>)> = Vec::new();
+        let mut structure_groups: HashMap<(String, usize), usize> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            match key {
+                Key::Variable => {}
+                Key::List => lists.push(i),
+                Key::Constant(c) => {
+                    let group = *constant_groups.entry(c.clone()).or_insert_with(|| {
+                        constants.push((c.clone(), Vec::new()));
+                        constants.len() - 1
+                    });
+                    constants[group].1.push(i);
+                }
+                Key::Structure(f, n) => {
+                    let group = *structure_groups.entry((f.clone(), *n)).or_insert_with(|| {
+                        structures.push(((f.clone(), *n), Vec::new()));
+                        structures.len() - 1
+                    });
+                    structures[group].1.push(i);
+                }
+            }
+        }
+
+        let constant = if constants.is_empty() {
+            self.chain(&variables)
+        } else {
+            let cases = constants
+                .into_iter()
+                .filter_map(|(c, clauses)| self.group_chain(&clauses, &variables).map(|label| (c, label)))
+                .collect();
+            let default = self.chain(&variables);
+            Some(self.emit(Instruction::SwitchOnConstant(Rc::new(SwitchTable { cases, default }))))
+        };
+
+        let list = self.group_chain(&lists, &variables);
+
+        let structure = if structures.is_empty() {
+            self.chain(&variables)
+        } else {
+            let cases = structures
+                .into_iter()
+                .filter_map(|(key, clauses)| self.group_chain(&clauses, &variables).map(|label| (key, label)))
+                .collect();
+            let default = self.chain(&variables);
+            Some(self.emit(Instruction::SwitchOnStructure(Rc::new(SwitchTable { cases, default }))))
+        };
+
+        Instruction::SwitchOnTerm { variable: self.all_clauses, constant, list, structure }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Label {
+        self.code.push(instruction);
+        self.code.len() - 1
+    }
+
+    /// Label that tries `clauses` in order: nothing for no clauses, the
+    /// clause itself for one, and a try/retry/trust block for several.
+    fn chain(&mut self, clauses: &[usize]) -> Option<Label> {
+        match clauses {
+            [] => None,
+            [clause] => Some(self.clause_entries[*clause]),
+            _ if clauses.len() == self.clause_entries.len() => Some(self.all_clauses),
+            _ => {
+                if let Some(&label) = self.chains.get(clauses) {
+                    return Some(label);
+                }
+                let label = self.code.len();
+                for (i, &clause) in clauses.iter().enumerate() {
+                    let entry = self.clause_entries[clause];
+                    self.code.push(match i {
+                        0 => Instruction::Try(entry),
+                        i if i + 1 == clauses.len() => Instruction::Trust(entry),
+                        _ => Instruction::Retry(entry),
+                    });
+                }
+                self.chains.insert(clauses.to_vec(), label);
+                Some(label)
+            }
+        }
+    }
+
+    /// Label that tries the `clauses` of one key together with the
+    /// `variables` clauses, in source order. Rather than repeat the variable
+    /// clauses after the key's last clause, its block jumps into the block
+    /// of them all keys share.
+    fn group_chain(&mut self, clauses: &[usize], variables: &[usize]) -> Option<Label> {
+        let last = match clauses.last() {
+            Some(&last) => last,
+            None => return self.chain(variables),
+        };
+        let shared = variables.partition_point(|&clause| clause < last);
+        if variables.len() - shared < 2 || clauses.len() + variables.len() == self.clause_entries.len() {
+            return self.chain(&merge(clauses, variables));
+        }
+        let tail = self.variable_tail(variables);
+        let label = self.code.len();
+        for (i, &clause) in merge(clauses, &variables[..shared]).iter().enumerate() {
+            let entry = self.clause_entries[clause];
+            self.code.push(if i == 0 { Instruction::Try(entry) } else { Instruction::Retry(entry) });
+        }
+        self.code.push(Instruction::Jump(tail + shared));
+        Some(label)
+    }
+
+    /// Label of the retry/trust block over `variables`, emitted once.
+    fn variable_tail(&mut self, variables: &[usize]) -> Label {
+        if let Some(label) = self.variable_tail {
+            return label;
+        }
+        let label = self.code.len();
+        for (i, &clause) in variables.iter().enumerate() {
+            let entry = self.clause_entries[clause];
+            self.code.push(if i + 1 == variables.len() { Instruction::Trust(entry) } else { Instruction::Retry(entry) });
+        }
+        self.variable_tail = Some(label);
+        label
+    }
+}
+
+/// Merges two ascending lists of clause numbers, keeping source order.
+fn merge(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] < b[j] {
+            merged.push(a[i]);
+            i += 1;
+        } else {
+            merged.push(b[j]);
+            j += 1;
+        }
+    }
+    merged.extend_from_slice(&a[i..]);
+    merged.extend_from_slice(&b[j..]);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::parse;
+    use crate::wam::emulator::tests::answers;
+    use crate::wam::WamEmulator;
+
+    fn compile(source: &str) -> CompiledPredicate {
+        let mut predicates = PredicateTable::default();
+        compile_predicate(&parse(source).unwrap(), &mut predicates, CompileOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_single_clause_has_no_index() {
+        let compiled = compile("p(a).");
+        assert_eq!(compiled.clause_entries, vec![0]);
+        assert!(!compiled.code.iter().any(|i| matches!(i, Instruction::SwitchOnTerm { .. } | Instruction::TryMeElse(..))));
+    }
+
+    #[test]
+    fn test_variable_first_arguments_are_not_indexed() {
+        let compiled = compile("p(X, a). p(Y, b).");
+        assert!(matches!(compiled.code[0], Instruction::TryMeElse(..)));
+    }
+
+    #[test]
+    fn test_switch_layout() {
+        let compiled = compile("p(a). p(f(X)). p([H]). p(X). p(b).");
+        let entries = &compiled.clause_entries;
+        let (constant, list, structure) = match &compiled.code[0] {
+            Instruction::SwitchOnTerm { variable: 1, constant, list, structure } => (*constant, *list, *structure),
+            other => panic!("expected switch_on_term, got {:?}", other),
+        };
+
+        let table = match &compiled.code[constant.unwrap()] {
+            Instruction::SwitchOnConstant(table) => table.clone(),
+            other => panic!("expected switch_on_constant, got {:?}", other),
+        };
+        let try_block = |label: Label| -> Vec<Instruction> { compiled.code[label..label + 2].to_vec() };
+        let a = table.cases[&Constant::Atom("a".into())];
+        assert_eq!(try_block(a), vec![Instruction::Try(entries[0]), Instruction::Trust(entries[3])]);
+        let b = table.cases[&Constant::Atom("b".into())];
+        assert_eq!(try_block(b), vec![Instruction::Try(entries[3]), Instruction::Trust(entries[4])]);
+        assert_eq!(table.default, Some(entries[3]));
+
+        assert_eq!(try_block(list.unwrap()), vec![Instruction::Try(entries[2]), Instruction::Trust(entries[3])]);
+        match &compiled.code[structure.unwrap()] {
+            Instruction::SwitchOnStructure(table) => {
+                let f = table.cases[&("f".to_string(), 1)];
+                assert_eq!(try_block(f), vec![Instruction::Try(entries[1]), Instruction::Trust(entries[3])]);
+            }
+            other => panic!("expected switch_on_structure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_keys_share_the_variable_clauses_after_them() {
+        let compiled = compile("p(a, 1). p(b, 2). p(c, 3). p(X, any). p(_, last).");
+        let entries = &compiled.clause_entries;
+        let table = compiled.code.iter().find_map(|instruction| match instruction {
+            Instruction::SwitchOnConstant(table) => Some(table.clone()),
+            _ => None,
+        });
+        let cases = &table.unwrap().cases;
+        let tail = match compiled.code[cases[&Constant::Atom("a".into())] + 1] {
+            Instruction::Jump(tail) => tail,
+            ref other => panic!("expected a jump, got {:?}", other),
+        };
+        assert_eq!(compiled.code[tail..tail + 2], [Instruction::Retry(entries[3]), Instruction::Trust(entries[4])]);
+        for (key, clause) in [("b", 1), ("c", 2)] {
+            let label = cases[&Constant::Atom(key.into())];
+            assert_eq!(compiled.code[label..label + 2], [Instruction::Try(entries[clause]), Instruction::Jump(tail)]);
+        }
+
+        let mut wam = WamEmulator::new();
+        wam.consult("p(a, 1). p(b, 2). p(c, 3). p(X, any). p(_, last).").unwrap();
+        assert_eq!(answers(&mut wam, "p(b, V)"), vec!["V = 2", "V = any", "V = last"]);
+        assert_eq!(answers(&mut wam, "p(d, V)"), vec!["V = any", "V = last"]);
+    }
+
+    #[test]
+    fn test_zero_is_keyed_without_its_sign() {
+        let mut wam = WamEmulator::new();
+        wam.consult("r(0.0, zero). r(1.0, one). s(-0.0, zero). s(a, a).").unwrap();
+        assert_eq!(answers(&mut wam, "r(-0.0, X)"), vec!["X = zero"]);
+        assert_eq!(answers(&mut wam, "s(0.0, X)"), vec!["X = zero"]);
+        let rows: String = (0..8).map(|i| format!("t(x, {}.0, v{}). ", i, i)).collect();
+        wam.consult(&rows).unwrap();
+        assert_eq!(answers(&mut wam, "t(_, -0.0, V)"), vec!["V = v0"]);
+        assert_eq!(answers(&mut wam, "assertz(d(0.0)), assertz(d(1.0)), d(-0.0)"), vec!["true"]);
+    }
+
+    #[test]
+    fn test_bound_first_argument_leaves_no_choice_point() {
+        let mut wam = WamEmulator::new();
+        wam.consult("capital(france, paris). capital(spain, madrid). capital(italy, rome).").unwrap();
+
+        assert_eq!(answers(&mut wam, "capital(spain, C)"), vec!["C = madrid"]);
+        let clauses = parse("query :- capital(italy, C).").unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+        assert!(wam.run().unwrap());
+        assert!(wam.choicepoints.is_empty());
+        assert_eq!(answers(&mut wam, "capital(X, rome)"), vec!["X = italy"]);
+        assert_eq!(answers(&mut wam, "capital(X, _)").len(), 3);
+    }
+
+    #[test]
+    fn test_large_fact_table() {
+        let rows = 20_000;
+        let clauses: Vec<Clause> = (0..rows)
+            .map(|i| Clause {
+                head: Term::Structure {
+                    functor: "row".to_string(),
+                    arity: 2,
+                    args: vec![Term::Integer(i), Term::Atom(format!("value{}", i))],
+                },
+                body: vec![],
+            })
+            .collect();
+        let mut wam = WamEmulator::new();
+        wam.load_clauses(clauses).unwrap();
+
+        let clauses = parse("query :- row(12345, V).").unwrap();
+        let bindings = wam.start_query(&clauses[0].body).unwrap();
+        assert!(wam.run().unwrap());
+        assert!(wam.choicepoints.is_empty());
+        assert_eq!(crate::runtime::writer::format_answer(&wam, &bindings), "V = value12345");
+    }
+}
@@ -0,0 +1,97 @@
+// src/compiler/mod.rs
+pub mod clause;
+mod control;
+pub mod indexing;
+mod peephole;
+mod registers;
+
+pub use clause::compile_clause;
+pub use indexing::{compile_predicate, CompiledPredicate};
+
+use crate::parser::ast::Term;
+use crate::wam::instruction::Constant;
+
+/// Settings for compiling clauses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// Run the peephole optimiser over each clause's code.
+    pub peephole: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions { peephole: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    InvalidHead(Term),
+    InvalidGoal(Term),
+}
+
+/// Name and arity of the predicate a clause head defines.
+pub fn predicate_key(head: &Term) -> Result<(String, usize), CompileError> {
+    match head {
+        Term::Atom(name) => Ok((name.clone(), 0)),
+        Term::Structure { functor, args, .. } => Ok((functor.clone(), args.len())),
+        _ => Err(CompileError::InvalidHead(head.clone())),
+    }
+}
+
+/// The constant an atomic term compiles to, or `None` for variables and compound terms.
+pub(crate) fn atomic(term: &Term) -> Option<Constant> {
+    match term {
+        Term::Atom(name) => Some(Constant::Atom(name.clone())),
+        Term::Integer(value) => Some(Constant::Integer(*value)),
+        Term::BigInteger(value) => Some(Constant::BigInteger(value.clone())),
+        Term::Float(value) => Some(Constant::Float(value.to_bits())),
+        Term::List(elements) if elements.is_empty() => Some(Constant::Atom("[]".to_string())),
+        _ => None,
+    }
+}
+
+/// A term being compiled, borrowed from its clause. The tail of a list is a
+/// slice of the list's elements, so walking a list does not copy it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Subterm<'a> {
+    Term(&'a Term),
+    /// The list of these elements, `[]` when there are none.
+    Tail(&'a [Term]),
+}
+
+impl<'a> Subterm<'a> {
+    pub(crate) fn variable(self) -> Option<&'a str> {
+        match self {
+            Subterm::Term(Term::Variable(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn atomic(self) -> Option<Constant> {
+        match self {
+            Subterm::Term(term) => atomic(term),
+            Subterm::Tail([]) => Some(Constant::Atom("[]".to_string())),
+            Subterm::Tail(_) => None,
+        }
+    }
+
+    /// Functor and arguments of a compound term; lists are seen as `'.'/2` cells.
+    pub(crate) fn compound(self) -> Option<(&'a str, Vec<Subterm<'a>>)> {
+        match self {
+            Subterm::Term(Term::Structure { functor, args, .. }) => {
+                Some((functor, args.iter().map(Subterm::Term).collect()))
+            }
+            Subterm::Term(Term::List(elements)) => Subterm::Tail(elements).compound(),
+            Subterm::Tail(elements) => {
+                elements.split_first().map(|(head, tail)| (".", vec![Subterm::Term(head), Subterm::Tail(tail)]))
+            }
+            Subterm::Term(_) => None,
+        }
+    }
+}
+
+/// Functor and arity of a compound term; lists are seen as `'.'/2` cells.
+pub(crate) fn compound(term: &Term) -> Option<(&str, usize)> {
+    Subterm::Term(term).compound().map(|(functor, args)| (functor, args.len()))
+}
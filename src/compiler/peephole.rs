@@ -0,0 +1,285 @@
+// src/compiler/peephole.rs
+use crate::compiler::registers::accesses;
+use crate::wam::data_structures::PredicateTable;
+use crate::wam::decoder::{LIST_FUNCTOR, NIL};
+use crate::wam::instruction::{Constant, Instruction, Reg};
+use crate::wam::verifier::registers;
+
+/// Rewrites the code of one clause, after register allocation, into fewer
+/// or cheaper instructions:
+///
+/// - `put_value R, Ai` is dropped while Ai still holds R, e.g. right after
+///   `get_variable R, Ai`;
+/// - an `allocate` whose environment is deallocated again before any call
+///   or permanent variable is dropped together with the `deallocate`;
+/// - lists and `[]` are matched with `get_list` and `get_nil`, and
+///   structures without arguments are constants;
+/// - a `unify_variable` whose register is never read again is a
+///   `unify_void`, and runs of them are merged into one `unify_void N`.
+///
+/// `labels` hold code positions, which are updated for the removed
+/// instructions.
+pub(crate) fn optimise(code: Vec<Instruction>, labels: &mut [usize], predicates: &PredicateTable) -> Vec<Instruction> {
+    let targets: Vec<bool> = (0..=code.len()).map(|k| labels.contains(&k)).collect();
+    let mut code: Vec<Option<Instruction>> = code.into_iter().map(|instruction| Some(specialise(instruction))).collect();
+    drop_moves(&mut code, &targets);
+    drop_environment(&mut code, &targets);
+    void_variables(&mut code, &targets, predicates);
+
+    let mut kept = Vec::new();
+    let mut optimised: Vec<Instruction> = Vec::new();
+    for (k, instruction) in code.into_iter().enumerate() {
+        kept.push(optimised.len());
+        match (optimised.last_mut(), instruction) {
+            (_, None) => {}
+            (Some(Instruction::UnifyVoid(n)), Some(Instruction::UnifyVoid(m))) if !targets[k] => *n += m,
+            (_, Some(instruction)) => optimised.push(instruction),
+        }
+    }
+    kept.push(optimised.len());
+    for label in labels.iter_mut() {
+        *label = kept[*label];
+    }
+    optimised
+}
+
+fn specialise(instruction: Instruction) -> Instruction {
+    match instruction {
+        Instruction::GetStructure(functor, 0, ai) => specialise(Instruction::GetConstant(Constant::Atom(functor), ai)),
+        Instruction::GetStructure(functor, 2, ai) if functor == LIST_FUNCTOR => Instruction::GetList(ai),
+        Instruction::GetConstant(Constant::Atom(name), ai) if name == NIL => Instruction::GetNil(ai),
+        Instruction::PutStructure(functor, 0, ai) => Instruction::PutConstant(Constant::Atom(functor), ai),
+        other => other,
+    }
+}
+
+/// Registers an instruction overwrites, permanent variables included.
+fn writes(instruction: &Instruction) -> Vec<Reg> {
+    let mut written: Vec<Reg> = accesses(instruction).1.into_iter().map(Reg::X).collect();
+    match instruction {
+        Instruction::GetVariable(reg @ Reg::Y(_), _)
+        | Instruction::PutVariable(reg @ Reg::Y(_), _)
+        | Instruction::UnifyVariable(reg @ Reg::Y(_))
+        | Instruction::GetLevel(reg @ Reg::Y(_))
+        | Instruction::GetChoice(reg @ Reg::Y(_))
+        | Instruction::InitVariable(reg @ Reg::Y(_))
+        | Instruction::Evaluate(_, reg @ Reg::Y(_))
+        | Instruction::Arith(_, _, _, reg @ Reg::Y(_)) => written.push(*reg),
+        _ => {}
+    }
+    written
+}
+
+/// Drops `put_value R, Ai` when Ai is known to hold R already. What is
+/// known is forgotten at labels, which can be reached from elsewhere, and
+/// at calls, which overwrite the argument registers.
+fn drop_moves(code: &mut [Option<Instruction>], targets: &[bool]) {
+    let mut held: Vec<(Reg, usize)> = Vec::new();
+    for (k, slot) in code.iter_mut().enumerate() {
+        if targets[k] {
+            held.clear();
+        }
+        let instruction = slot.as_ref().unwrap();
+        if let Instruction::PutValue(reg, ai) = instruction {
+            if held.contains(&(*reg, *ai)) {
+                *slot = None;
+                continue;
+            }
+        }
+        match instruction {
+            Instruction::Call(..) | Instruction::Deallocate => held.clear(),
+            _ => {
+                let written = writes(instruction);
+                held.retain(|(reg, ai)| !written.contains(reg) && !written.contains(&Reg::X(*ai)));
+            }
+        }
+        if let Instruction::GetVariable(reg, ai) | Instruction::PutValue(reg, ai) | Instruction::PutVariable(reg, ai) =
+            instruction
+        {
+            held.push((*reg, *ai));
+        }
+    }
+}
+
+/// Drops an `allocate` and the `deallocate` it runs straight into when the
+/// code between them calls nothing and uses no permanent variable.
+fn drop_environment(code: &mut [Option<Instruction>], targets: &[bool]) {
+    for start in 0..code.len() {
+        if !matches!(code[start], Some(Instruction::Allocate(_))) {
+            continue;
+        }
+        for k in start + 1..code.len() {
+            let instruction = match &code[k] {
+                Some(_) if targets[k] => break,
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            match instruction {
+                Instruction::Deallocate => {
+                    code[start] = None;
+                    code[k] = None;
+                    break;
+                }
+                Instruction::Call(..)
+                | Instruction::Execute(_)
+                | Instruction::Proceed
+                | Instruction::PutUnsafeValue(..)
+                | Instruction::TryMeElse(..)
+                | Instruction::RetryMeElse(_)
+                | Instruction::TrustMe
+                | Instruction::Jump(_)
+                | Instruction::Fail => break,
+                instruction if uses_permanent(instruction) => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn uses_permanent(instruction: &Instruction) -> bool {
+    registers(instruction).iter().any(|reg| matches!(reg, Reg::Y(_)))
+}
+
+/// Turns `unify_variable Xi` into `unify_void 1` when Xi is not read
+/// before it is overwritten. Clause code only jumps forward, but past a
+/// label or a choice point the register may come back with this value, so
+/// from there on any later read counts.
+fn void_variables(code: &mut [Option<Instruction>], targets: &[bool], predicates: &PredicateTable) {
+    for k in 0..code.len() {
+        let x = match code[k] {
+            Some(Instruction::UnifyVariable(Reg::X(x))) => x,
+            _ => continue,
+        };
+        let mut straight = true;
+        let mut read = false;
+        for later in k + 1..code.len() {
+            straight &= !targets[later];
+            let instruction = match &code[later] {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let (reads, written) = match instruction {
+                Instruction::Call(id, _) | Instruction::Execute(id) => ((1..=predicates.get(*id).arity).collect(), None),
+                instruction => {
+                    let (reads, writes) = accesses(instruction);
+                    (reads, Some(writes))
+                }
+            };
+            if reads.contains(&x) {
+                read = true;
+                break;
+            }
+            // A call leaves nothing in the temporaries.
+            if straight && written.is_none_or(|written| written.contains(&x)) {
+                break;
+            }
+            straight &= !matches!(
+                instruction,
+                Instruction::TryMeElse(..) | Instruction::RetryMeElse(_) | Instruction::TrustMe | Instruction::Jump(_)
+            );
+        }
+        if !read {
+            code[k] = Some(Instruction::UnifyVoid(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_clause, compile_predicate, CompileOptions};
+    use crate::parser::ast::parse;
+    use crate::wam::emulator::tests::answers;
+    use crate::wam::WamEmulator;
+    use Instruction::*;
+
+    const UNOPTIMISED: CompileOptions = CompileOptions { peephole: false };
+
+    /// Instruction counts of `source`'s predicates compiled without and with
+    /// the peephole pass.
+    fn counts(source: &str) -> (usize, usize) {
+        let clauses = parse(source).unwrap();
+        let count = |options| {
+            let mut predicates = PredicateTable::default();
+            compile_predicate(&clauses, &mut predicates, options).unwrap().code.len()
+        };
+        (count(UNOPTIMISED), count(CompileOptions::default()))
+    }
+
+    #[test]
+    fn test_void_variables_are_merged() {
+        assert_eq!(counts("p(f(_, _, _, X)) :- q(X)."), (6, 4));
+        let mut predicates = PredicateTable::default();
+        let clause = &parse("p(f(_, _, _, X)) :- q(X).").unwrap()[0];
+        let code = compile_clause(clause, &mut predicates, CompileOptions::default()).unwrap();
+        let q = predicates.lookup("q", 1).unwrap();
+        assert_eq!(code, vec![GetStructure("f".into(), 4, 1), UnifyVoid(3), UnifyVariable(Reg::X(1)), Execute(q)]);
+    }
+
+    #[test]
+    fn test_lists_use_specialised_instructions() {
+        let source = "app([], L, L). app([H|T], L, [H|R]) :- app(T, L, R).";
+        let (before, after) = counts(source);
+        assert_eq!(before, after);
+        let mut predicates = PredicateTable::default();
+        let code = compile_predicate(&parse(source).unwrap(), &mut predicates, CompileOptions::default()).unwrap().code;
+        assert!(code.contains(&GetNil(1)));
+        assert!(code.contains(&GetList(1)) && code.contains(&GetList(3)));
+        assert!(!code.iter().any(|instruction| matches!(instruction, GetStructure(..) | GetConstant(..))));
+    }
+
+    #[test]
+    fn test_redundant_moves_are_dropped() {
+        assert_eq!(counts("p(X) :- q(X), r(X)."), (7, 6));
+        let mut predicates = PredicateTable::default();
+        let clause = &parse("p(X) :- q(X), r(X).").unwrap()[0];
+        let code = compile_clause(clause, &mut predicates, CompileOptions::default()).unwrap();
+        assert_eq!(code[..3], [Allocate(1), GetVariable(Reg::Y(1), 1), Call(predicates.lookup("q", 1).unwrap(), 1)]);
+    }
+
+    #[test]
+    fn test_empty_environment_is_dropped() {
+        let mut labels = [4];
+        let code = vec![
+            Allocate(0),
+            PutConstant(Constant::Atom("a".into()), 1),
+            Deallocate,
+            Execute(0),
+            Proceed,
+        ];
+        let mut predicates = PredicateTable::default();
+        predicates.id("q", 1);
+        assert_eq!(optimise(code, &mut labels, &predicates), vec![
+            PutConstant(Constant::Atom("a".into()), 1),
+            Execute(0),
+            Proceed,
+        ]);
+        assert_eq!(labels, [2]);
+
+        // A permanent variable keeps the environment.
+        let code = vec![Allocate(1), GetVariable(Reg::Y(1), 1), Deallocate, Proceed];
+        assert_eq!(optimise(code.clone(), &mut [], &predicates), code);
+    }
+
+    #[test]
+    fn test_optimised_code_gives_the_same_answers() {
+        let source = "
+            app([], L, L).
+            app([H|T], L, [H|R]) :- app(T, L, R).
+            second([_, X|_], X).
+            first(f(X, _, _), X).
+            pairs([], []).
+            pairs([K-_|T], [K|Ks]) :- pairs(T, Ks).
+        ";
+        let goals = ["app(X, Y, [a, b])", "second([1, 2, 3], X)", "pairs([a-1, b-2], Ks)", "second(L, z)", "first(T, a)"];
+        let mut optimised = WamEmulator::new();
+        optimised.consult(source).unwrap();
+        let mut plain = WamEmulator::new();
+        plain.set_compile_options(UNOPTIMISED);
+        plain.consult(source).unwrap();
+        assert!(plain.code.len() > optimised.code.len());
+        for goal in goals {
+            assert_eq!(answers(&mut optimised, goal), answers(&mut plain, goal));
+        }
+    }
+}
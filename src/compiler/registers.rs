@@ -0,0 +1,333 @@
+// src/compiler/registers.rs
+use std::collections::HashMap;
+
+use crate::wam::instruction::{Instruction, Operand, Reg};
+
+/// The gaps between instructions a register value is live across, both ends
+/// inclusive. Gap `g` lies just before instruction `g`.
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    start: usize,
+    end: usize,
+}
+
+impl Range {
+    fn overlaps(&self, other: &Range) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// One value held in an X register: a head argument as the clause is
+/// entered, an argument loaded for a call, or a temporary.
+#[derive(Debug)]
+struct Value {
+    register: Option<usize>,
+    range: Range,
+}
+
+/// The values each register holds, for finding a register free across the
+/// range of a temporary. Temporaries are placed in the order their ranges
+/// start, so a register is free of earlier temporaries when the last one
+/// placed in it has ended, and of argument values when the first one that
+/// has not ended starts after the range.
+struct Occupancy {
+    /// The argument values each register holds, in code order.
+    arguments: Vec<Vec<usize>>,
+    /// For each register, the first of its argument values that may still
+    /// overlap the temporaries left to place.
+    next: Vec<usize>,
+    /// For each register, the last gap a temporary placed in it is live across.
+    temporaries: Vec<Option<usize>>,
+}
+
+impl Occupancy {
+    fn new(values: &[Value]) -> Self {
+        let mut arguments: Vec<Vec<usize>> = Vec::new();
+        for (value, v) in values.iter().enumerate() {
+            if let Some(register) = v.register {
+                if register >= arguments.len() {
+                    arguments.resize(register + 1, Vec::new());
+                }
+                arguments[register].push(value);
+            }
+        }
+        let registers = arguments.len();
+        Occupancy { arguments, next: vec![0; registers], temporaries: vec![None; registers] }
+    }
+
+    /// Whether `register` is free across `range`, except for `partners`.
+    fn free(&mut self, register: usize, range: Range, partners: &[usize], values: &[Value]) -> bool {
+        if register >= self.temporaries.len() {
+            self.arguments.resize(register + 1, Vec::new());
+            self.next.resize(register + 1, 0);
+            self.temporaries.resize(register + 1, None);
+        }
+        if self.temporaries[register].is_some_and(|end| end >= range.start) {
+            return false;
+        }
+        let arguments = &self.arguments[register];
+        let next = &mut self.next[register];
+        while *next < arguments.len() && values[arguments[*next]].range.end < range.start {
+            *next += 1;
+        }
+        arguments[*next..]
+            .iter()
+            .take_while(|&&other| values[other].range.start <= range.end)
+            .all(|other| partners.contains(other) || !values[*other].range.overlaps(&range))
+    }
+}
+
+/// Assigns real registers to the temporaries of one compiled clause.
+///
+/// The clause compiler numbers temporaries above `max_arity`, one per
+/// variable or nested structure, so they never clash with argument
+/// registers. Here each temporary is given the lowest register whose other
+/// values are not live at the same time, preferring an argument register it
+/// is moved from or to (`get_variable Xi, Aj`, `put_value Xi, Aj`), since
+/// the two then hold the same value. Moves that end up between the same
+/// register are dropped. This is the conflict-avoiding allocation of
+/// Debray's "Register allocation in a Prolog machine", done with live ranges
+/// over the clause's code.
+///
+/// The branches of a disjunction are laid out one after the other, so a
+/// value live from before a disjunction into a later branch keeps its
+/// register through the earlier ones. `labels` hold code positions, which
+/// are updated for the dropped moves.
+pub(crate) fn allocate_registers(
+    code: Vec<Instruction>,
+    arity: usize,
+    max_arity: usize,
+    labels: &mut [usize],
+) -> Vec<Instruction> {
+    let mut values: Vec<Value> = Vec::new();
+    let mut temporaries: HashMap<usize, usize> = HashMap::new();
+    let mut moves: Vec<(usize, usize)> = Vec::new();
+
+    // The value each argument register currently holds, and the arguments
+    // loaded for the next call.
+    let mut current: HashMap<usize, usize> = HashMap::new();
+    let mut loaded: Vec<usize> = Vec::new();
+    for ai in 1..=arity {
+        current.insert(ai, values.len());
+        values.push(Value { register: Some(ai), range: Range { start: 0, end: 0 } });
+    }
+
+    for (k, instruction) in code.iter().enumerate() {
+        let (reads, writes) = accesses(instruction);
+        for r in reads {
+            let value = if r > max_arity { temporaries[&r] } else { current[&r] };
+            values[value].range.end = values[value].range.end.max(k);
+        }
+        for r in writes {
+            let value = values.len();
+            let register = if r > max_arity {
+                temporaries.insert(r, value);
+                None
+            } else {
+                current.insert(r, value);
+                loaded.push(value);
+                Some(r)
+            };
+            values.push(Value { register, range: Range { start: k + 1, end: k + 1 } });
+        }
+        match *instruction {
+            Instruction::GetVariable(Reg::X(x), ai) | Instruction::PutValue(Reg::X(x), ai)
+            | Instruction::PutVariable(Reg::X(x), ai) => moves.push((temporaries[&x], current[&ai])),
+            Instruction::Call(..) | Instruction::Execute(_) => {
+                for value in loaded.drain(..) {
+                    values[value].range.end = k;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut partners: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (temporary, argument) in moves {
+        partners.entry(temporary).or_default().push(argument);
+    }
+    let mut occupancy = Occupancy::new(&values);
+    let mut order: Vec<usize> = temporaries.values().copied().collect();
+    order.sort_unstable();
+    for value in order {
+        let range = values[value].range;
+        let partners = partners.get(&value).map(Vec::as_slice).unwrap_or_default();
+        let mut free = |register: usize| occupancy.free(register, range, partners, &values);
+        let preferred = partners.iter().filter_map(|&a| values[a].register).find(|&r| free(r));
+        let register = preferred.unwrap_or_else(|| (1..).find(|&r| free(r)).unwrap());
+        occupancy.temporaries[register] = Some(range.end);
+        values[value].register = Some(register);
+    }
+
+    let register = |x: usize| match temporaries.get(&x) {
+        Some(&value) => values[value].register.unwrap(),
+        None => x,
+    };
+    let mut kept = Vec::new();
+    let mut allocated = Vec::new();
+    for instruction in code {
+        kept.push(allocated.len());
+        let instruction = rename(instruction, &register);
+        if !matches!(
+            instruction,
+            Instruction::GetVariable(Reg::X(x), ai) | Instruction::PutValue(Reg::X(x), ai) if x == ai
+        ) {
+            allocated.push(instruction);
+        }
+    }
+    kept.push(allocated.len());
+    for label in labels.iter_mut() {
+        *label = kept[*label];
+    }
+    allocated
+}
+
+/// X registers an instruction reads and writes.
+pub(crate) fn accesses(instruction: &Instruction) -> (Vec<usize>, Vec<usize>) {
+    let x = |reg: &Reg| match reg {
+        Reg::X(i) => vec![*i],
+        Reg::Y(_) => vec![],
+    };
+    let operand = |operand: &Operand| match operand {
+        Operand::Reg(reg) => x(reg),
+        Operand::Integer(_) => vec![],
+    };
+    match instruction {
+        Instruction::GetVariable(reg, ai) => (vec![*ai], x(reg)),
+        Instruction::GetValue(reg, ai) => ([x(reg), vec![*ai]].concat(), vec![]),
+        Instruction::GetStructure(_, _, ai) | Instruction::GetConstant(_, ai) => (vec![*ai], vec![]),
+        Instruction::GetList(ai) | Instruction::GetNil(ai) => (vec![*ai], vec![]),
+        Instruction::UnifyVariable(reg) | Instruction::GetLevel(reg) | Instruction::GetChoice(reg) => (vec![], x(reg)),
+        Instruction::InitVariable(reg) => (vec![], x(reg)),
+        Instruction::UnifyValue(reg) | Instruction::UnifyLocalValue(reg) | Instruction::Cut(reg) => (x(reg), vec![]),
+        Instruction::PutVariable(reg, ai) => (vec![], [x(reg), vec![*ai]].concat()),
+        Instruction::PutValue(reg, ai) => (x(reg), vec![*ai]),
+        Instruction::PutUnsafeValue(_, ai) | Instruction::PutConstant(_, ai) | Instruction::PutStructure(_, _, ai) => {
+            (vec![], vec![*ai])
+        }
+        Instruction::Evaluate(a, reg) => (operand(a), x(reg)),
+        Instruction::Arith(_, a, b, reg) => ([operand(a), operand(b)].concat(), x(reg)),
+        Instruction::Compare(_, a, b) => ([operand(a), operand(b)].concat(), vec![]),
+        _ => (vec![], vec![]),
+    }
+}
+
+fn rename(instruction: Instruction, register: &impl Fn(usize) -> usize) -> Instruction {
+    let reg = |reg: Reg| match reg {
+        Reg::X(i) => Reg::X(register(i)),
+        y => y,
+    };
+    let operand = |operand: Operand| match operand {
+        Operand::Reg(r) => Operand::Reg(reg(r)),
+        integer => integer,
+    };
+    // Argument registers keep their number, but the result of an inline
+    // `is/2` is unified from a temporary with get_value or get_constant.
+    match instruction {
+        Instruction::GetVariable(r, ai) => Instruction::GetVariable(reg(r), ai),
+        Instruction::GetValue(r, ai) => Instruction::GetValue(reg(r), register(ai)),
+        Instruction::GetConstant(c, ai) => Instruction::GetConstant(c, register(ai)),
+        Instruction::GetStructure(f, n, ai) => Instruction::GetStructure(f, n, register(ai)),
+        Instruction::GetList(ai) => Instruction::GetList(register(ai)),
+        Instruction::GetNil(ai) => Instruction::GetNil(register(ai)),
+        Instruction::UnifyVariable(r) => Instruction::UnifyVariable(reg(r)),
+        Instruction::UnifyValue(r) => Instruction::UnifyValue(reg(r)),
+        Instruction::UnifyLocalValue(r) => Instruction::UnifyLocalValue(reg(r)),
+        Instruction::PutVariable(r, ai) => Instruction::PutVariable(reg(r), ai),
+        Instruction::PutValue(r, ai) => Instruction::PutValue(reg(r), ai),
+        Instruction::PutStructure(f, n, ai) => Instruction::PutStructure(f, n, register(ai)),
+        Instruction::GetLevel(r) => Instruction::GetLevel(reg(r)),
+        Instruction::GetChoice(r) => Instruction::GetChoice(reg(r)),
+        Instruction::InitVariable(r) => Instruction::InitVariable(reg(r)),
+        Instruction::Cut(r) => Instruction::Cut(reg(r)),
+        Instruction::Evaluate(a, r) => Instruction::Evaluate(operand(a), reg(r)),
+        Instruction::Arith(op, a, b, r) => Instruction::Arith(op, operand(a), operand(b), reg(r)),
+        Instruction::Compare(comparison, a, b) => Instruction::Compare(comparison, operand(a), operand(b)),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::CompileOptions;
+    use crate::wam::emulator::tests::answers;
+    use crate::wam::instruction::{Instruction, Reg};
+    use crate::wam::WamEmulator;
+
+    const NREV: &str = "
+        app(nil, L, L).
+        app(cons(H, T), L, cons(H, R)) :- app(T, L, R).
+        nrev(nil, nil).
+        nrev(cons(H, T), R) :- nrev(T, RT), app(RT, cons(H, nil), R).
+    ";
+
+    const DERIV: &str = "
+        d(plus(U, V), X, plus(DU, DV)) :- d(U, X, DU), d(V, X, DV).
+        d(minus(U, V), X, minus(DU, DV)) :- d(U, X, DU), d(V, X, DV).
+        d(times(U, V), X, plus(times(DU, V), times(U, DV))) :- d(U, X, DU), d(V, X, DV).
+        d(x, x, 1).
+        d(num(C), x, 0).
+    ";
+
+    const PERM: &str = "
+        sel(X, cons(X, T), T).
+        sel(X, cons(H, T), cons(H, R)) :- sel(X, T, R).
+        perm(nil, nil).
+        perm(L, cons(H, T)) :- sel(H, L, R), perm(R, T).
+    ";
+
+    /// Loads `source` without peephole optimisation and returns the
+    /// emulator with the number of instructions its predicates compiled to.
+    fn load(source: &str) -> (WamEmulator, usize) {
+        let mut wam = WamEmulator::new();
+        wam.set_compile_options(CompileOptions { peephole: false });
+        let before = wam.code.len();
+        wam.consult(source).unwrap();
+        let count = wam.code.len() - before;
+        assert!(!wam.code.iter().any(|instruction| matches!(
+            instruction,
+            Instruction::GetVariable(Reg::X(x), ai) | Instruction::PutValue(Reg::X(x), ai) if x == ai
+        )));
+        (wam, count)
+    }
+
+    #[test]
+    fn test_nrev_instruction_count() {
+        let (mut wam, count) = load(NREV);
+        assert_eq!(count, 38);
+        assert_eq!(
+            answers(&mut wam, "nrev(cons(a, cons(b, cons(c, nil))), R)"),
+            vec!["R = cons(c, cons(b, cons(a, nil)))"]
+        );
+    }
+
+    #[test]
+    fn test_deriv_instruction_count() {
+        let (mut wam, count) = load(DERIV);
+        assert_eq!(count, 69);
+        assert_eq!(
+            answers(&mut wam, "d(plus(times(x, x), minus(x, num(3))), x, D)"),
+            vec!["D = plus(plus(times(1, x), times(x, 1)), minus(1, 0))"]
+        );
+    }
+
+    #[test]
+    fn test_large_clause_compiles_quickly() {
+        // Compile time was once quadratic in the number of temporaries, and
+        // this took minutes.
+        let elements: Vec<String> = (0..20_000).map(|i| i.to_string()).collect();
+        let source = format!("big([{}]).", elements.join(", "));
+        let start = std::time::Instant::now();
+        let mut wam = WamEmulator::new();
+        wam.consult(&source).unwrap();
+        assert!(start.elapsed().as_secs() < 5, "took {:?}", start.elapsed());
+        assert_eq!(answers(&mut wam, "findall(N, (big(L), length(L, N)), Ns)"), vec!["Ns = [20000]"]);
+    }
+
+    #[test]
+    fn test_perm_instruction_count() {
+        let (mut wam, count) = load(PERM);
+        assert_eq!(count, 34);
+        assert_eq!(answers(&mut wam, "perm(cons(a, cons(b, cons(c, nil))), P)").len(), 6);
+    }
+}
@@ -1,8 +1,10 @@
 // src/lib.rs
+pub mod compiler;
 pub mod wam;
 pub mod parser;
+pub mod runtime;
 
-pub use wam::{WamEmulator, Term, HeapCell};
+pub use wam::{WamEmulator, Machine, Solution, Term, HeapCell};
 pub use parser::lexer;
 
 #[cfg(test)]
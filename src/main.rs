@@ -1,11 +1,50 @@
 // src/main.rs
-use prolog_wam_compiler::{WamEmulator, Term};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use prolog_wam_compiler::compiler::CompileOptions;
+use prolog_wam_compiler::wam::ObjectFile;
+
+const USAGE: &str = "usage: prolog_wam_compiler compile <input.pl> [-o <output.wamo>] [--no-peephole]";
 
 fn main() {
-    let term = Term::Atom("example".to_string());
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some("compile") {
+        usage();
+    }
+    let mut input = None;
+    let mut output = None;
+    let mut options = CompileOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            "--no-peephole" => options.peephole = false,
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(PathBuf::from(arg)),
+            _ => usage(),
+        }
+    }
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| input.with_extension("wamo"));
+    compile(&input, &output, options);
+}
 
-    let mut emulator = WamEmulator::new();
-    let index = emulator.push_term(&term);
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
-    println!("Term pushed to heap at index: {}", index);
+/// Compiles a Prolog source file to an object file.
+fn compile(input: &Path, output: &Path, options: CompileOptions) {
+    let source = std::fs::read_to_string(input).unwrap_or_else(|error| {
+        eprintln!("cannot read {}: {}", input.display(), error);
+        process::exit(1);
+    });
+    let object = ObjectFile::compile(&source, &input.display().to_string(), options).unwrap_or_else(|error| {
+        eprintln!("{}: {}", input.display(), error);
+        process::exit(1);
+    });
+    if let Err(error) = std::fs::write(output, object.to_bytes()) {
+        eprintln!("cannot write {}: {}", output.display(), error);
+        process::exit(1);
+    }
 }
@@ -1,5 +1,10 @@
 // src/parser/ast.rs
-use crate::parser::lexer::{tokenize, Token};
+use std::fmt;
+
+use num_bigint::BigInt;
+
+use crate::parser::lexer::{tokenize_located, LexerError, Token};
+use crate::parser::operators::{infix, prefix};
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct Clause {
@@ -17,50 +22,99 @@ pub enum Term {
         args: Vec<Term>,
     },
     List(Vec<Term>),
+    Integer(i64),
+    BigInteger(BigInt),
+    Float(f64),
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
 pub fn parse(input: &str) -> ParseResult<Vec<Clause>> {
-    let tokens = tokenize(input)?;
-    println!("Tokens: {:?}", tokens); // Debug output added here
+    Ok(parse_with_lines(input)?.into_iter().map(|(clause, _)| clause).collect())
+}
+
+/// Like `parse`, but pairs each clause with the line it starts on,
+/// counting from 1.
+pub fn parse_with_lines(input: &str) -> ParseResult<Vec<(Clause, usize)>> {
+    let located = tokenize_located(input).map_err(|(error, offset)| at(input, offset, ParseError::LexerError(error)))?;
+    let (tokens, offsets): (Vec<Token>, Vec<usize>) = located.into_iter().unzip();
+    let mut newlines = input.chars().enumerate().filter(|&(_, c)| c == '\n').map(|(i, _)| i).peekable();
+    let mut line = 1;
     let mut clauses = Vec::new();
 
     let mut remaining_tokens = &tokens[..];
     while !remaining_tokens.is_empty() {
-        let (head, rest) = parse_term(remaining_tokens)?;
+        let start = offsets[tokens.len() - remaining_tokens.len()];
+        while newlines.next_if(|&newline| newline < start).is_some() {
+            line += 1;
+        }
+        let (term, rest) = parse_expression(remaining_tokens, 1200)
+            .and_then(|(term, rest)| Ok((term, expect(Token::Dot, rest)?)))
+            .map_err(|error| locate(input, &offsets, error))?;
         remaining_tokens = rest;
-
-        let body = if let Ok(new_remaining_tokens) = expect_token(Token::If, remaining_tokens) {
-            remaining_tokens = new_remaining_tokens;
-            let mut body_terms = Vec::new();
-
-            while let Ok((term, new_remaining_tokens)) = parse_term(remaining_tokens) {
-                remaining_tokens = new_remaining_tokens;
-                body_terms.push(term);
-
-                // Change this part
-                if let Ok(new_remaining_tokens) = expect_token(Token::And, remaining_tokens)
-                    .or(expect_token(Token::Comma, remaining_tokens))
-                {
-                    remaining_tokens = new_remaining_tokens;
-                } else {
-                    break;
-                }
+        let clause = match term {
+            Term::Structure { functor, mut args, .. } if functor == ":-" && args.len() == 2 => {
+                let body = args.pop().unwrap();
+                Clause { head: args.pop().unwrap(), body: conjunction(body) }
             }
-
-            body_terms
-        } else {
-            Vec::new()
+            head => Clause { head, body: Vec::new() },
         };
+        clauses.push((clause, line));
+    }
+
+    Ok(clauses)
+}
 
-        println!("Head: {:?}, Body: {:?}", head, body); // Debug output added here
+/// Parses the text of a single term, such as `term_to_atom/2` reads. The
+/// `.` ending it is optional.
+pub fn parse_term_text(input: &str) -> ParseResult<Term> {
+    let located = tokenize_located(input).map_err(|(error, offset)| at(input, offset, ParseError::LexerError(error)))?;
+    let (tokens, offsets): (Vec<Token>, Vec<usize>) = located.into_iter().unzip();
+    parse_expression(&tokens, 1200)
+        .and_then(|(term, rest)| match rest {
+            [] | [Token::Dot] => Ok(term),
+            _ => Err(near(rest, ParseError::UnexpectedToken(rest[0].clone()))),
+        })
+        .map_err(|error| locate(input, &offsets, error))
+}
 
-        remaining_tokens = expect_token(Token::Dot, remaining_tokens)?;
-        clauses.push(Clause { head, body });
+/// Turns an error found at a token into one at a line and column of
+/// `input`, whose tokens start at `offsets`.
+fn locate(input: &str, offsets: &[usize], error: ParseError) -> ParseError {
+    match error {
+        ParseError::Near { remaining, error } => {
+            let offset = offsets.get(offsets.len() - remaining).copied().unwrap_or_else(|| input.chars().count());
+            at(input, offset, *error)
+        }
+        error => error,
     }
+}
 
-    Ok(clauses)
+/// `error` at character `offset` of `input`.
+fn at(input: &str, offset: usize, error: ParseError) -> ParseError {
+    let before: Vec<char> = input.chars().take(offset).collect();
+    let line = 1 + before.iter().filter(|&&c| c == '\n').count();
+    let column = 1 + before.iter().rev().take_while(|&&c| c != '\n').count();
+    ParseError::At { line, column, error: Box::new(error) }
+}
+
+/// The goals of a (possibly nested) `','/2` conjunction, in order.
+pub fn conjunction(term: Term) -> Vec<Term> {
+    let mut goals = Vec::new();
+    let mut current = term;
+    loop {
+        match current {
+            Term::Structure { functor, mut args, .. } if functor == "," && args.len() == 2 => {
+                let right = args.pop().unwrap();
+                goals.extend(conjunction(args.pop().unwrap()));
+                current = right;
+            }
+            goal => {
+                goals.push(goal);
+                return goals;
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -69,7 +123,41 @@ pub enum ParseError {
     UnexpectedToken(Token),
     UnexpectedEndOfInput,
     InvalidToken,
-    // Other error variants will be added as needed
+    /// `error` at the token `remaining` tokens before the end of the
+    /// tokens being parsed.
+    Near { remaining: usize, error: Box<ParseError> },
+    /// `error` at a line and column of the source text, both counted from
+    /// 1, as `parse` reports every error.
+    At { line: usize, column: usize, error: Box<ParseError> },
+}
+
+impl ParseError {
+    /// A short name for what went wrong, used as the argument of ISO
+    /// `syntax_error/1`.
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::LexerError(LexerError::UnexpectedChar(_)) => "illegal_character",
+            ParseError::LexerError(LexerError::InvalidInteger(_)) => "illegal_number",
+            ParseError::LexerError(LexerError::UnexpectedEndOfInput) | ParseError::UnexpectedEndOfInput => "end_of_file",
+            ParseError::UnexpectedToken(Token::Dot) => "end_of_clause",
+            ParseError::UnexpectedToken(_) => "unexpected_token",
+            ParseError::InvalidToken => "invalid_token",
+            ParseError::Near { error, .. } | ParseError::At { error, .. } => error.message(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::At { line, column, error } => write!(f, "{} at line {}, column {}", error, line, column),
+            ParseError::Near { error, .. } => write!(f, "{}", error),
+            ParseError::LexerError(LexerError::UnexpectedChar(c)) => write!(f, "illegal character {:?}", c),
+            ParseError::LexerError(LexerError::InvalidInteger(digits)) => write!(f, "illegal number {}", digits),
+            ParseError::UnexpectedToken(token) => write!(f, "unexpected {:?}", token),
+            error => write!(f, "{}", error.message().replace('_', " ")),
+        }
+    }
 }
 
 // This allows us to convert LexerError into ParseError
@@ -79,7 +167,17 @@ impl From<crate::parser::lexer::LexerError> for ParseError {
     }
 }
 
-fn expect_token<'a>(expected: Token, tokens: &'a [Token]) -> ParseResult<&'a [Token]> {
+/// `error` at the first of `tokens`, or at the end when there are none.
+fn near(tokens: &[Token], error: ParseError) -> ParseError {
+    ParseError::Near { remaining: tokens.len(), error: Box::new(error) }
+}
+
+/// Like `expect_token`, with the error located.
+fn expect(expected: Token, tokens: &[Token]) -> ParseResult<&[Token]> {
+    expect_token(expected, tokens).map_err(|error| near(tokens, error))
+}
+
+fn expect_token(expected: Token, tokens: &[Token]) -> ParseResult<&[Token]> {
     if let Some(token) = tokens.first() {
         if *token == expected {
             Ok(&tokens[1..])
@@ -91,75 +189,162 @@ fn expect_token<'a>(expected: Token, tokens: &'a [Token]) -> ParseResult<&'a [To
     }
 }
 
-fn parse_term<'a>(tokens: &'a [Token]) -> ParseResult<(Term, &'a [Token])> {
-    println!("parse_term: {:?}", tokens);
+/// Parses one argument-level term (priority 999), as found between commas.
+fn parse_term(tokens: &[Token]) -> ParseResult<(Term, &[Token])> {
+    parse_expression(tokens, 999)
+}
+
+/// The name a token stands for when it is used as an atom or operator.
+fn token_name(token: &Token) -> Option<String> {
+    Some(match token {
+        Token::Atom(name) => name.clone(),
+        Token::If => ":-".to_string(),
+        Token::And => ";".to_string(),
+        Token::Is => "is".to_string(),
+        Token::Plus => "+".to_string(),
+        Token::Minus => "-".to_string(),
+        Token::Multiply => "*".to_string(),
+        Token::Divide => "/".to_string(),
+        _ => return None,
+    })
+}
+
+/// Parses a term of priority at most `max_priority` by operator precedence.
+fn parse_expression(tokens: &[Token], max_priority: usize) -> ParseResult<(Term, &[Token])> {
+    let (mut left, mut left_priority, mut rest) = parse_primary(tokens, max_priority)?;
+    while let Some(token) = rest.first() {
+        let name = match token {
+            Token::Comma => ",".to_string(),
+            Token::Bar => ";".to_string(),
+            token => match token_name(token) {
+                Some(name) => name,
+                None => break,
+            },
+        };
+        let operator = match infix(&name) {
+            Some(operator) if operator.priority <= max_priority => operator,
+            _ => break,
+        };
+        let (left_max, right_max) = operator.argument_priorities();
+        if left_priority > left_max {
+            break;
+        }
+        let (right, after) = parse_expression(&rest[1..], right_max)?;
+        left = Term::Structure { functor: name, arity: 2, args: vec![left, right] };
+        left_priority = operator.priority;
+        rest = after;
+    }
+    Ok((left, rest))
+}
+
+/// True if `token` can begin the argument of a prefix operator.
+fn starts_term(token: Option<&Token>) -> bool {
+    match token {
+        Some(Token::Variable(_) | Token::Number(_) | Token::BigNumber(_) | Token::Float(_) | Token::Str(_)) => true,
+        Some(Token::LParen | Token::SpacedLParen | Token::LBracket | Token::LBrace) => true,
+        Some(token) => match token_name(token) {
+            Some(name) => infix(&name).is_none() || prefix(&name).is_some(),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+/// Parses a term that does not start with an infix operator, returning it
+/// with its priority.
+fn parse_primary(tokens: &[Token], max_priority: usize) -> ParseResult<(Term, usize, &[Token])> {
     let (token, rest) = expect_any_token(tokens)?;
     match token {
-        Token::Atom(atom) => {
-            let (next_token, next_rest) = expect_any_token(rest)?;
-            if next_token == Token::LParen {
-                let mut args = Vec::new();
-                let mut remaining_tokens = next_rest;
-                loop {
-                    let (arg, new_remaining_tokens) = parse_term(remaining_tokens)?;
-                    args.push(arg);
-                    remaining_tokens = new_remaining_tokens;
-
-                    let (next_token, new_remaining_tokens) = expect_any_token(remaining_tokens)?;
-                    if next_token == Token::RParen {
-                        return Ok((Term::Structure {
-                            functor: atom,
-                            arity: args.len(),
-                            args,
-                        }, new_remaining_tokens));
-                    } else if next_token != Token::Comma {
-                        return Err(ParseError::UnexpectedToken(next_token));
-                    } else {
-                        remaining_tokens = new_remaining_tokens;
-                    }
+        Token::Variable(variable) => Ok((Term::Variable(variable), 0, rest)),
+        Token::Number(number) => Ok((Term::Integer(number), 0, rest)),
+        Token::BigNumber(number) => Ok((Term::BigInteger(number), 0, rest)),
+        Token::Float(number) => Ok((Term::Float(number), 0, rest)),
+        Token::Str(text) => Ok((Term::List(text.chars().map(|c| Term::Integer(c as i64)).collect()), 0, rest)),
+        Token::LParen | Token::SpacedLParen => {
+            let (term, rest) = parse_expression(rest, 1200)?;
+            Ok((term, 0, expect(Token::RParen, rest)?))
+        }
+        Token::LBracket => {
+            let (list, rest) = parse_list(rest)?;
+            Ok((list, 0, rest))
+        }
+        Token::LBrace if rest.first() == Some(&Token::RBrace) => Ok((Term::Atom("{}".to_string()), 0, &rest[1..])),
+        Token::LBrace => {
+            let (term, rest) = parse_expression(rest, 1200)?;
+            let rest = expect(Token::RBrace, rest)?;
+            Ok((Term::Structure { functor: "{}".to_string(), arity: 1, args: vec![term] }, 0, rest))
+        }
+        token => {
+            let name = token_name(&token).ok_or_else(|| near(tokens, ParseError::UnexpectedToken(token.clone())))?;
+            if rest.first() == Some(&Token::LParen) {
+                let (args, rest) = parse_arguments(&rest[1..])?;
+                return Ok((Term::Structure { functor: name, arity: args.len(), args }, 0, rest));
+            }
+            match prefix(&name) {
+                Some(operator) if operator.priority <= max_priority && starts_term(rest.first()) => {
+                    let (_, argument_max) = operator.argument_priorities();
+                    let (argument, rest) = parse_expression(rest, argument_max)?;
+                    let term = Term::Structure { functor: name, arity: 1, args: vec![argument] };
+                    Ok((term, operator.priority, rest))
                 }
-            } else {
-                Ok((Term::Atom(atom), rest))
+                _ => Ok((Term::Atom(name), 0, rest)),
             }
         }
-        Token::Variable(variable) => Ok((Term::Variable(variable), rest)),
-        Token::LParen => {
-            let (term, rest) = parse_term(rest)?;
-            let rest = expect_token(Token::RParen, rest)?;
-            Ok((term, rest))
-        }
-        Token::LBracket => {
-            let (list_elements, rest) = parse_list_elements(rest)?;
-            let rest = expect_token(Token::RBracket, rest)?;
-            Ok((Term::List(list_elements), rest))
+    }
+}
+
+/// Parses `Arg, ..., Arg)` after the opening parenthesis of a compound term.
+fn parse_arguments(tokens: &[Token]) -> ParseResult<(Vec<Term>, &[Token])> {
+    let mut args = Vec::new();
+    let mut remaining_tokens = tokens;
+    loop {
+        let (arg, after) = parse_term(remaining_tokens)?;
+        args.push(arg);
+        let (next_token, rest) = expect_any_token(after)?;
+        match next_token {
+            Token::RParen => return Ok((args, rest)),
+            Token::Comma => remaining_tokens = rest,
+            token => return Err(near(after, ParseError::UnexpectedToken(token))),
         }
-        Token::Number(number) => Ok((Term::Atom(number.to_string()), rest)),
-        _ => Err(ParseError::UnexpectedToken(token)),
     }
 }
 
-fn parse_list_elements<'a>(tokens: &'a [Token]) -> ParseResult<(Vec<Term>, &'a [Token])> {
+/// Parses the rest of a list after `[`. A list with a `|Tail` that is not
+/// itself a proper list is built from `'.'/2` cells.
+fn parse_list(tokens: &[Token]) -> ParseResult<(Term, &[Token])> {
+    if let Ok(rest) = expect_token(Token::RBracket, tokens) {
+        return Ok((Term::List(Vec::new()), rest));
+    }
     let mut elements = Vec::new();
     let mut remaining_tokens = tokens;
-
-    while let Ok((term, new_remaining_tokens)) = parse_term(remaining_tokens) {
-        elements.push(term);
-        remaining_tokens = new_remaining_tokens;
-
-        if let Ok(new_remaining_tokens) = expect_token(Token::Comma, remaining_tokens) {
-            remaining_tokens = new_remaining_tokens;
-        } else {
-            break;
+    loop {
+        let (element, after) = parse_term(remaining_tokens)?;
+        elements.push(element);
+        let (next_token, rest) = expect_any_token(after)?;
+        match next_token {
+            Token::RBracket => return Ok((Term::List(elements), rest)),
+            Token::Comma => remaining_tokens = rest,
+            Token::Bar => {
+                let (tail, rest) = parse_term(rest)?;
+                let rest = expect(Token::RBracket, rest)?;
+                let list = match tail {
+                    Term::List(tail) => Term::List(elements.into_iter().chain(tail).collect()),
+                    tail => elements.into_iter().rev().fold(tail, |tail, head| Term::Structure {
+                        functor: ".".to_string(),
+                        arity: 2,
+                        args: vec![head, tail],
+                    }),
+                };
+                return Ok((list, rest));
+            }
+            token => return Err(near(after, ParseError::UnexpectedToken(token))),
         }
     }
-    println!("Parsed list elements: {:?}, remaining tokens: {:?}", elements, remaining_tokens);
-
-    Ok((elements, remaining_tokens))
 }
 
-fn expect_any_token<'a>(tokens: &'a [Token]) -> ParseResult<(Token, &'a [Token])> {
+fn expect_any_token(tokens: &[Token]) -> ParseResult<(Token, &[Token])> {
     if tokens.is_empty() {
-        Err(ParseError::UnexpectedEndOfInput)
+        Err(near(tokens, ParseError::UnexpectedEndOfInput))
     } else {
         Ok((tokens[0].clone(), &tokens[1..]))
     }
@@ -168,7 +353,7 @@ fn expect_any_token<'a>(tokens: &'a [Token]) -> ParseResult<(Token, &'a [Token])
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser::lexer::Token;
+    use crate::parser::lexer::{tokenize, Token};
 
     #[test]
     fn test_parse_term1() {
@@ -464,5 +649,50 @@ mod tests {
         assert_eq!(clauses, vec![expected_clause]);
         println!("Ending test_parse_clause_with_double_body");
     }
+
+    fn structure(functor: &str, args: Vec<Term>) -> Term {
+        Term::Structure { functor: functor.to_string(), arity: args.len(), args }
+    }
+
+    #[test]
+    fn test_parse_operators() {
+        let clauses = parse("p(X) :- X = a - b - c, \\+ X == 1 * 2 + 3.").unwrap();
+        let x = || Term::Variable("X".to_string());
+        let atom = |name: &str| Term::Atom(name.to_string());
+        assert_eq!(clauses[0].body, vec![
+            structure("=", vec![x(), structure("-", vec![structure("-", vec![atom("a"), atom("b")]), atom("c")])]),
+            structure("\\+", vec![structure("==", vec![
+                x(),
+                structure("+", vec![structure("*", vec![Term::Integer(1), Term::Integer(2)]), Term::Integer(3)]),
+            ])]),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_term_text() {
+        assert_eq!(parse_term_text("f(X, -1)").unwrap(), structure("f", vec![Term::Variable("X".to_string()), Term::Integer(-1)]));
+        assert_eq!(parse_term_text(" 2.5 .").unwrap(), Term::Float(2.5));
+        assert!(parse_term_text("a b").is_err());
+        assert!(parse_term_text("").is_err());
+    }
+
+    #[test]
+    fn test_parse_control_constructs() {
+        let clauses = parse("p :- (a -> ! ; b), c.").unwrap();
+        let atom = |name: &str| Term::Atom(name.to_string());
+        assert_eq!(clauses[0].body, vec![
+            structure(";", vec![structure("->", vec![atom("a"), atom("!")]), atom("b")]),
+            atom("c"),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_list_tail() {
+        let (term, _) = parse_term(&tokenize("[a, b|T]").unwrap()).unwrap();
+        let tail = structure(".", vec![Term::Atom("b".to_string()), Term::Variable("T".to_string())]);
+        assert_eq!(term, structure(".", vec![Term::Atom("a".to_string()), tail]));
+
+        let (term, _) = parse_term(&tokenize("[a|[b]]").unwrap()).unwrap();
+        assert_eq!(term, Term::List(vec![Term::Atom("a".to_string()), Term::Atom("b".to_string())]));
+    }
 }
-    
\ No newline at end of file
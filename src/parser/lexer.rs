@@ -1,192 +1,288 @@
 // src/parser/lexer.rs
-use std::iter::Peekable;
+use num_bigint::BigInt;
 
-fn parse_atom_or_variable<I: Iterator<Item = char>>(first_char: char, iter: &mut Peekable<I>) -> Result<Token, LexerError> {
-    let mut name = String::new();
-    name.push(first_char);
+/// Characters that make up symbolic atoms such as `:-`, `=..` or `\+`.
+pub fn is_symbol_char(c: char) -> bool {
+    "+-*/\\^<>=~:.?@#&$".contains(c)
+}
+
+pub fn skip_whitespace(input: &str) -> &str {
+    input.trim_start()
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.position += 1;
+        c
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek().filter(|&c| predicate(c)) {
+            text.push(c);
+            self.position += 1;
+        }
+        text
+    }
 
-    while let Some(&c) = iter.peek() {
-        match c {
-            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => {
-                name.push(c);
-                iter.next();
+    /// Skips whitespace and comments. Returns true if anything was skipped.
+    fn skip_layout(&mut self) -> Result<bool, LexerError> {
+        let start = self.position;
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.position += 1,
+                Some('%') => {
+                    self.take_while(|c| c != '\n');
+                }
+                Some('/') if self.peek_at(1) == Some('*') => {
+                    self.position += 2;
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match self.next() {
+                            Some('*') if self.peek() == Some('/') => {
+                                self.position += 1;
+                                depth -= 1;
+                            }
+                            Some('/') if self.peek() == Some('*') => {
+                                self.position += 1;
+                                depth += 1;
+                            }
+                            Some(_) => {}
+                            None => return Err(LexerError::UnexpectedEndOfInput),
+                        }
+                    }
+                }
+                _ => return Ok(self.position > start),
             }
-            _ => break,
         }
     }
 
-    let token = if name.chars().next().unwrap().is_uppercase() || name.starts_with('_') {
-        Token::Variable(name)
-    } else {
-        Token::Atom(name)
-    };
+    /// Reads an integer or a float such as `1.5` or `2.0e-3`. A fraction
+    /// needs a digit after the `.`, so `X = 1.` ends with an integer.
+    /// Integers can also be written in hexadecimal, octal or binary, as
+    /// `0x1A`, `0o17` or `0b101`.
+    fn number(&mut self, sign: &str) -> Result<Token, LexerError> {
+        let radix = match (self.peek(), self.peek_at(1)) {
+            (Some('0'), Some('x')) => 16,
+            (Some('0'), Some('o')) => 8,
+            (Some('0'), Some('b')) => 2,
+            _ => 10,
+        };
+        if radix != 10 && self.peek_at(2).is_some_and(|c| c.is_digit(radix)) {
+            self.position += 2;
+            let digits = format!("{}{}", sign, self.take_while(|c| c.is_digit(radix)));
+            return match i64::from_str_radix(&digits, radix) {
+                Ok(value) => Ok(Token::Number(value)),
+                Err(_) => BigInt::parse_bytes(digits.as_bytes(), radix)
+                    .map(Token::BigNumber)
+                    .ok_or(LexerError::InvalidInteger(digits)),
+            };
+        }
+        let mut digits = format!("{}{}", sign, self.take_while(|c| c.is_ascii_digit()));
+        if self.peek() != Some('.') || !self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+            return match digits.parse::<i64>() {
+                Ok(value) => Ok(Token::Number(value)),
+                Err(_) => digits.parse::<BigInt>().map(Token::BigNumber).map_err(|_| LexerError::InvalidInteger(digits)),
+            };
+        }
+        self.position += 1;
+        digits.push('.');
+        digits.push_str(&self.take_while(|c| c.is_ascii_digit()));
+        let exponent = matches!(
+            (self.peek_at(1), self.peek_at(2)),
+            (Some('+' | '-'), Some(c)) | (Some(c), _) if c.is_ascii_digit()
+        );
+        if matches!(self.peek(), Some('e' | 'E')) && exponent {
+            digits.push(self.next().unwrap());
+            if let Some(sign @ ('+' | '-')) = self.peek() {
+                digits.push(sign);
+                self.position += 1;
+            }
+            digits.push_str(&self.take_while(|c| c.is_ascii_digit()));
+        }
+        digits.parse::<f64>().map(Token::Float).map_err(|_| LexerError::InvalidInteger(digits))
+    }
 
-    Ok(token)
-}
+    /// Reads one character of a quoted item, handling escape sequences.
+    /// Returns `None` at the closing quote.
+    fn quoted_char(&mut self, quote: char) -> Result<Option<char>, LexerError> {
+        match self.next() {
+            None => Err(LexerError::UnexpectedEndOfInput),
+            Some(c) if c == quote => {
+                if self.peek() == Some(quote) {
+                    self.position += 1;
+                    Ok(Some(quote))
+                } else {
+                    Ok(None)
+                }
+            }
+            Some('\\') => self.escape().map(Some),
+            Some(c) => Ok(Some(c)),
+        }
+    }
 
-fn parse_integer<I: Iterator<Item = char>>(first_digit: char, iter: &mut Peekable<I>) -> Result<Token, LexerError> {
-    let mut value = String::new();
-    value.push(first_digit);
+    fn escape(&mut self) -> Result<char, LexerError> {
+        let c = self.next().ok_or(LexerError::UnexpectedEndOfInput)?;
+        Ok(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            '0'..='7' | 'x' => {
+                let (radix, mut digits) = if c == 'x' { (16, String::new()) } else { (8, c.to_string()) };
+                digits.push_str(&self.take_while(|d| d.is_digit(radix)));
+                if self.peek() == Some('\\') {
+                    self.position += 1;
+                }
+                u32::from_str_radix(&digits, radix)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(LexerError::UnexpectedChar(c))?
+            }
+            '\\' | '\'' | '"' | '`' => c,
+            _ => return Err(LexerError::UnexpectedChar(c)),
+        })
+    }
 
-    while let Some(&c) = iter.peek() {
-        if c.is_digit(10) {
-            value.push(c);
-            iter.next();
-        } else {
-            break;
+    fn quoted(&mut self, quote: char) -> Result<String, LexerError> {
+        self.position += 1;
+        let mut text = String::new();
+        while let Some(c) = self.quoted_char(quote)? {
+            text.push(c);
         }
+        Ok(text)
     }
+}
 
-    if value.is_empty() {
-        return Err(LexerError::InvalidInteger(value));
+/// True if a token can end a term, so that a following `-` is an infix
+/// minus rather than the sign of a negative number.
+fn ends_term(token: Option<&Token>) -> bool {
+    match token {
+        Some(Token::Atom(name)) => name == "!" || name.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        Some(Token::Variable(_) | Token::Number(_) | Token::BigNumber(_) | Token::Float(_) | Token::Str(_)) => true,
+        Some(Token::RParen | Token::RBracket | Token::RBrace) => true,
+        _ => false,
     }
+}
 
-    value
-        .parse::<i64>()
-        .map(Token::Number)
-        .map_err(|_| LexerError::InvalidInteger(value))
+pub fn tokenize(input: &str) -> Result<Vec<Token>, LexerError> {
+    Ok(tokenize_with_offsets(input)?.into_iter().map(|(token, _)| token).collect())
 }
 
-pub fn skip_whitespace(input: &str) -> &str {
-    input.trim_start()
+/// Like `tokenize`, but pairs each token with the character offset it
+/// starts at.
+pub fn tokenize_with_offsets(input: &str) -> Result<Vec<(Token, usize)>, LexerError> {
+    tokenize_located(input).map_err(|(error, _)| error)
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, LexerError> {
-    let mut tokens = Vec::new();
-    let mut iter = input.chars().peekable();
+/// Like `tokenize_with_offsets`, but an error comes with the character
+/// offset it was found at.
+pub fn tokenize_located(input: &str) -> Result<Vec<(Token, usize)>, (LexerError, usize)> {
+    let mut lexer = Lexer { chars: input.chars().collect(), position: 0 };
+    lexer.tokens().map_err(|error| (error, lexer.position))
+}
 
-    while let Some(&c) = iter.peek() {
-        println!("DEBUG: Current character: {:?}", c);
-    
-        match c {
-            ' ' | '\t' | '\n' | '\r' => {
-                iter.next(); // skip whitespace
-            }
-            ',' => {
-                iter.next();
-                tokens.push(Token::Comma);
-                while let Some(&c) = iter.peek() {
-                    if c == ' ' || c == ',' {
-                        iter.next();
-                    } else {
-                        break;
-                    }
+impl Lexer {
+    /// Reads tokens up to the end of the input.
+    fn tokens(&mut self) -> Result<Vec<(Token, usize)>, LexerError> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut offsets = Vec::new();
+        // A quoted atom ends a term, so `'a'-1` is `'a' - 1`.
+        let mut after_quoted = false;
+
+        loop {
+            let layout = self.skip_layout()? || tokens.is_empty();
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Ok(tokens.into_iter().zip(offsets).collect()),
+            };
+            offsets.push(self.position);
+            let token = match c {
+                '(' if layout => {
+                    self.position += 1;
+                    Token::SpacedLParen
                 }
-            }
-            'A'..='Z' | 'a'..='z' | '_' => {
-                let first_char = iter.next().unwrap();
-                if first_char == 'i' && iter.peek() == Some(&'s') {
-                    iter.next(); // skip the 's'
-                    tokens.push(Token::Is);
-                } else {
-                    tokens.push(parse_atom_or_variable(first_char, &mut iter)?);
-                }
-            }
-            '0'..='9' => {
-                let first_digit = iter.next().unwrap();
-                tokens.push(parse_integer(first_digit, &mut iter)?);
-            }
-            '(' => {
-                iter.next();
-                tokens.push(Token::LParen);
-            }
-            ')' => {
-                iter.next();
-                tokens.push(Token::RParen);
-            }
-            '.' => {
-                iter.next();
-                tokens.push(Token::Dot);
-            }
-            ':' => {
-                iter.next();
-                if let Some(&'-') = iter.peek() {
-                    iter.next();
-                    tokens.push(Token::If);
-                } else {
-                    return Err(LexerError::UnexpectedChar(c));
-                }
-            }
-            ';' => {
-                iter.next();
-                tokens.push(Token::And);
-            }
-            '[' => {
-                iter.next();
-                tokens.push(Token::LBracket);
-            }
-            ']' => {
-                iter.next();
-                tokens.push(Token::RBracket);
-            }
-            '%' => {
-                iter.next(); // skip the '%'
-                while let Some(&c) = iter.peek() {
-                    if c != '\n' {
-                        iter.next(); // skip non-newline characters in the comment
-                    } else {
-                        break; // break out of the loop when reaching a newline
+                '(' | ')' | '[' | ']' | '{' | '}' | ',' | '|' | ';' | '!' => {
+                    self.position += 1;
+                    match c {
+                        '(' => Token::LParen,
+                        ')' => Token::RParen,
+                        '[' => Token::LBracket,
+                        ']' => Token::RBracket,
+                        '{' => Token::LBrace,
+                        '}' => Token::RBrace,
+                        ',' => Token::Comma,
+                        '|' => Token::Bar,
+                        ';' => Token::And,
+                        _ => Token::Atom("!".to_string()),
                     }
                 }
-            }
-            '/' => {
-                iter.next(); // skip the '/'
-                if let Some(&'*') = iter.peek() {
-                    iter.next(); // skip the '*'
-                    let mut comment_level = 1; // track nested multi-line comments
-            
-                    while comment_level > 0 {
-                        if let Some(c) = iter.next() {
-                            match c {
-                                '*' => {
-                                    if let Some(&'/') = iter.peek() {
-                                        iter.next(); // skip the '/'
-                                        comment_level -= 1;
-                                    }
-                                }
-                                '/' => {
-                                    if let Some(&'*') = iter.peek() {
-                                        iter.next(); // skip the '*'
-                                        comment_level += 1;
-                                    }
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            return Err(LexerError::UnexpectedChar(c));
+                '0' if self.peek_at(1) == Some('\'') => {
+                    self.position += 2;
+                    let code = match self.next() {
+                        Some('\\') => self.escape()?,
+                        Some('\'') if self.peek() == Some('\'') => {
+                            self.position += 1;
+                            '\''
                         }
+                        Some(c) => c,
+                        None => return Err(LexerError::UnexpectedEndOfInput),
+                    };
+                    Token::Number(code as i64)
+                }
+                '0'..='9' => self.number("")?,
+                '\'' => Token::Atom(self.quoted('\'')?),
+                '"' => Token::Str(self.quoted('"')?),
+                c if c.is_alphabetic() || c == '_' => {
+                    let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                    if c.is_uppercase() || c == '_' {
+                        Token::Variable(name)
+                    } else if name == "is" {
+                        Token::Is
+                    } else {
+                        Token::Atom(name)
                     }
-                } else {
-                    return Err(LexerError::UnexpectedChar(c));
                 }
-            }
-            '+' => {
-                iter.next();
-                tokens.push(Token::Plus);
-            }
-            '-' => {
-                iter.next();
-                match iter.peek() {
-                    Some(c) if c.is_digit(10) => tokens.push(parse_integer('-', &mut iter)?),
-                    _ => tokens.push(Token::Minus),
+                '-' if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) && !after_quoted && !ends_term(tokens.last()) => {
+                    self.position += 1;
+                    self.number("-")?
                 }
-            }
-            '*' => {
-                iter.next();
-                tokens.push(Token::Multiply);
-            }
-            _ => {
-                iter.next();
-                if c.is_whitespace() {
-                } else {
-                    return Err(LexerError::UnexpectedChar(c));
+                c if is_symbol_char(c) => {
+                    let symbols = self.take_while(is_symbol_char);
+                    let at_end = self.peek().is_none_or(|c| c.is_whitespace() || c == '%');
+                    match symbols.as_str() {
+                        "." if at_end => Token::Dot,
+                        ":-" => Token::If,
+                        "+" => Token::Plus,
+                        "-" => Token::Minus,
+                        "*" => Token::Multiply,
+                        "/" => Token::Divide,
+                        _ => Token::Atom(symbols),
+                    }
                 }
-            }
+                c => return Err(LexerError::UnexpectedChar(c)),
+            };
+            after_quoted = c == '\'';
+            tokens.push(token);
         }
     }
-
-    println!("DEBUG: Final tokens: {:?}", tokens);
-    Ok(tokens)
 }
 
 pub fn is_valid_atom(input: &str) -> bool {
@@ -207,6 +303,9 @@ pub enum Token {
     Atom(String),
     Variable(String),
     Number(i64),
+    /// An integer literal too large for `Number`.
+    BigNumber(BigInt),
+    Float(f64),
     LParen,
     RParen,
     LBracket,
@@ -220,6 +319,14 @@ pub enum Token {
     Minus,
     Multiply,
     Divide,
+    Bar,
+    LBrace,
+    RBrace,
+    /// A double-quoted string.
+    Str(String),
+    /// An opening parenthesis preceded by layout. Only `LParen`, directly
+    /// after a name, starts the arguments of a compound term.
+    SpacedLParen,
 }
 
 
@@ -357,5 +464,59 @@ mod tests {
             Token::Multiply,
             Token::Number(3),
         ]);
-    }    
-}
\ No newline at end of file
+    }
+
+    #[test]
+    fn tokenize_cut_and_symbolic_atoms() {
+        let result = tokenize("p :- !, X \\= 0 ; [H|T] = 'a b'.");
+        assert_eq!(result.unwrap(), vec![
+            Token::Atom("p".to_string()),
+            Token::If,
+            Token::Atom("!".to_string()),
+            Token::Comma,
+            Token::Variable("X".to_string()),
+            Token::Atom("\\=".to_string()),
+            Token::Number(0),
+            Token::And,
+            Token::LBracket,
+            Token::Variable("H".to_string()),
+            Token::Bar,
+            Token::Variable("T".to_string()),
+            Token::RBracket,
+            Token::Atom("=".to_string()),
+            Token::Atom("a b".to_string()),
+            Token::Dot,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_negative_numbers() {
+        assert_eq!(tokenize("f(-1)").unwrap()[2], Token::Number(-1));
+        assert_eq!(tokenize("X-1").unwrap()[1], Token::Minus);
+        assert_eq!(tokenize("'a'-1").unwrap()[1], Token::Minus);
+        assert_eq!(tokenize("! -1").unwrap()[1], Token::Minus);
+    }
+
+    #[test]
+    fn tokenize_floats_and_big_integers() {
+        assert_eq!(tokenize("1.5 2.0e3 1.0E-2").unwrap(), vec![Token::Float(1.5), Token::Float(2000.0), Token::Float(0.01)]);
+        assert_eq!(tokenize("X = 1.").unwrap()[2..], [Token::Number(1), Token::Dot]);
+        assert_eq!(tokenize("-2.5").unwrap(), vec![Token::Float(-2.5)]);
+        assert_eq!(
+            tokenize("99999999999999999999").unwrap(),
+            vec![Token::BigNumber("99999999999999999999".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn tokenize_radix_integers() {
+        assert_eq!(tokenize("0x1A 0o17 0b101").unwrap(), vec![Token::Number(26), Token::Number(15), Token::Number(5)]);
+        assert_eq!(tokenize("f(-0xff)").unwrap()[2], Token::Number(-255));
+        assert_eq!(
+            tokenize("0xFFFFFFFFFFFFFFFFFF").unwrap(),
+            vec![Token::BigNumber("4722366482869645213695".parse().unwrap())]
+        );
+        // Without a digit of the radix after it, the letter starts an atom.
+        assert_eq!(tokenize("0b2").unwrap(), vec![Token::Number(0), Token::Atom("b2".to_string())]);
+    }
+}
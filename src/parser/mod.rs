@@ -1,3 +1,4 @@
 // mod.rs
 pub mod ast;
 pub mod lexer;
+pub mod operators;
@@ -0,0 +1,118 @@
+// src/parser/operators.rs
+
+/// Operator types as in ISO Prolog: `f` is the operator, `x` an argument of
+/// strictly lower priority and `y` one of lower or equal priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpType {
+    Xfx,
+    Xfy,
+    Yfx,
+    Fy,
+    Fx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operator {
+    pub priority: usize,
+    pub kind: OpType,
+}
+
+impl Operator {
+    /// Highest priority allowed for the left and right arguments. Prefix
+    /// operators only have a right argument.
+    pub fn argument_priorities(&self) -> (usize, usize) {
+        let p = self.priority;
+        match self.kind {
+            OpType::Xfx => (p - 1, p - 1),
+            OpType::Xfy => (p - 1, p),
+            OpType::Yfx => (p, p - 1),
+            OpType::Fy => (0, p),
+            OpType::Fx => (0, p - 1),
+        }
+    }
+}
+
+/// The standard operator table: ISO operators plus the common extensions
+/// of SWI-Prolog.
+const OPERATORS: &[(usize, OpType, &str)] = &[
+    (1200, OpType::Xfx, ":-"),
+    (1200, OpType::Xfx, "-->"),
+    (1200, OpType::Fx, ":-"),
+    (1200, OpType::Fx, "?-"),
+    (1150, OpType::Fx, "dynamic"),
+    (1150, OpType::Fx, "discontiguous"),
+    (1150, OpType::Fx, "initialization"),
+    (1150, OpType::Fx, "multifile"),
+    (1100, OpType::Xfy, ";"),
+    (1100, OpType::Xfy, "|"),
+    (1050, OpType::Xfy, "->"),
+    (1050, OpType::Xfy, "*->"),
+    (1000, OpType::Xfy, ","),
+    (900, OpType::Fy, "\\+"),
+    (700, OpType::Xfx, "="),
+    (700, OpType::Xfx, "\\="),
+    (700, OpType::Xfx, "=="),
+    (700, OpType::Xfx, "\\=="),
+    (700, OpType::Xfx, "@<"),
+    (700, OpType::Xfx, "@>"),
+    (700, OpType::Xfx, "@=<"),
+    (700, OpType::Xfx, "@>="),
+    (700, OpType::Xfx, "=.."),
+    (700, OpType::Xfx, "is"),
+    (700, OpType::Xfx, "=:="),
+    (700, OpType::Xfx, "=\\="),
+    (700, OpType::Xfx, "<"),
+    (700, OpType::Xfx, ">"),
+    (700, OpType::Xfx, "=<"),
+    (700, OpType::Xfx, ">="),
+    (600, OpType::Xfy, ":"),
+    (500, OpType::Yfx, "+"),
+    (500, OpType::Yfx, "-"),
+    (500, OpType::Yfx, "/\\"),
+    (500, OpType::Yfx, "\\/"),
+    (500, OpType::Yfx, "xor"),
+    (400, OpType::Yfx, "*"),
+    (400, OpType::Yfx, "/"),
+    (400, OpType::Yfx, "//"),
+    (400, OpType::Yfx, "rem"),
+    (400, OpType::Yfx, "mod"),
+    (400, OpType::Yfx, "div"),
+    (400, OpType::Yfx, "<<"),
+    (400, OpType::Yfx, ">>"),
+    (200, OpType::Xfx, "**"),
+    (200, OpType::Xfy, "^"),
+    (200, OpType::Fy, "-"),
+    (200, OpType::Fy, "+"),
+    (200, OpType::Fy, "\\"),
+];
+
+fn lookup(name: &str, prefix: bool) -> Option<Operator> {
+    OPERATORS
+        .iter()
+        .find(|(_, kind, op)| *op == name && matches!(kind, OpType::Fx | OpType::Fy) == prefix)
+        .map(|&(priority, kind, _)| Operator { priority, kind })
+}
+
+/// The infix operator named `name`, if any.
+pub fn infix(name: &str) -> Option<Operator> {
+    lookup(name, false)
+}
+
+/// The prefix operator named `name`, if any.
+pub fn prefix(name: &str) -> Option<Operator> {
+    lookup(name, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operator_lookup() {
+        assert_eq!(infix("-"), Some(Operator { priority: 500, kind: OpType::Yfx }));
+        assert_eq!(prefix("-"), Some(Operator { priority: 200, kind: OpType::Fy }));
+        assert_eq!(infix(":-").unwrap().argument_priorities(), (1199, 1199));
+        assert_eq!(infix(",").unwrap().argument_priorities(), (999, 1000));
+        assert_eq!(prefix("foo"), None);
+    }
+}
@@ -0,0 +1,406 @@
+// src/runtime/aggregate.rs
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::runtime::arithmetic::{binary, Number};
+use crate::runtime::builtins::call;
+use crate::wam::data_structures::{HeapCell, WamEmulator, ALTERNATIVES, HALT};
+use crate::wam::decoder::{StoredTerm, LIST_FUNCTOR, NIL};
+use crate::wam::error::{domain_error, instantiation_error, type_error, WamError};
+use crate::wam::instruction::ArithOp;
+
+impl WamEmulator {
+    /// Runs `goal` as `call/1` does and calls `solution` after each of its
+    /// solutions, until it returns false or the goal has no more. Everything
+    /// the goal did is undone afterwards, the argument registers included,
+    /// so a builtin can go on using its arguments. Exceptions the goal does
+    /// not catch itself are returned.
+    pub(crate) fn solve<F>(&mut self, goal: HeapCell, mut solution: F) -> Result<(), WamError>
+    where
+        F: FnMut(&mut WamEmulator) -> Result<bool, WamError>,
+    {
+        let (p, query_base) = (self.p, self.query_base);
+        let depth = self.choicepoints.len();
+        // Below the goal's choice points sits one restoring the machine;
+        // raising the query base keeps backtracking and catchers above it.
+        self.push_choicepoint(HALT, self.num_args);
+        self.query_base = depth + 1;
+        self.cp = HALT;
+        self.registers[1] = goal;
+        self.num_args = 1;
+        let mut result = match call(self) {
+            Err(WamError::Exception(ball)) => self.handle_exception(ball),
+            result => result,
+        };
+        let outcome = loop {
+            let found = match result {
+                Ok(true) => self.run(),
+                Ok(false) if self.backtrack() => self.run(),
+                other => other,
+            };
+            match found.and_then(|found| if found { solution(self) } else { Ok(false) }) {
+                Ok(true) => result = Ok(false),
+                other => break other.map(|_| ()),
+            }
+        };
+        self.choicepoints.truncate(depth + 1);
+        self.redo_states.retain(|(level, _)| *level < depth);
+        self.query_base = query_base;
+        self.backtrack();
+        self.choicepoints.pop();
+        self.p = p;
+        outcome
+    }
+
+    /// Copies of `template` for each solution of `goal`, taken off the heap.
+    fn collect(&mut self, template: &HeapCell, goal: HeapCell) -> Result<Vec<StoredTerm>, WamError> {
+        let mut instances = Vec::new();
+        self.solve(goal, |wam| {
+            instances.push(wam.store_term(template));
+            Ok(true)
+        })?;
+        Ok(instances)
+    }
+
+    /// Checks that `cell` can be unified with a list: it is a list, a
+    /// partial list or a variable.
+    pub(crate) fn list_or_partial_list(&self, cell: &HeapCell) -> Result<(), WamError> {
+        let mut current = self.deref(cell);
+        loop {
+            match &current {
+                _ if self.is_unbound(&current) => return Ok(()),
+                HeapCell::Constant(name) if name == NIL => return Ok(()),
+                HeapCell::Reference(a) => match &self.heap[*a] {
+                    HeapCell::Structure(functor, args) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                        current = self.deref(&HeapCell::Reference(args[1]));
+                    }
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+        Err(type_error("list", self.decode_cell(cell)?))
+    }
+
+    /// Sorts `cells` in the standard order of terms and drops duplicates.
+    fn sort_unique(&self, cells: &mut Vec<HeapCell>) {
+        cells.sort_by(|a, b| self.compare_cells(a, b));
+        cells.dedup_by(|a, b| self.compare_cells(a, b) == Ordering::Equal);
+    }
+
+    /// `findall(?Template, +Goal, ?Instances)` and
+    /// `findall(?Template, +Goal, ?Instances, ?Tail)`: Instances is the list
+    /// of a copy of Template for each solution of Goal, ending in Tail.
+    fn findall(&mut self, tail: HeapCell) -> Result<bool, WamError> {
+        let instances = self.registers[3].clone();
+        self.list_or_partial_list(&instances)?;
+        let instances_found = self.collect(&self.registers[1].clone(), self.registers[2].clone())?;
+        let cells = instances_found.iter().map(|instance| self.restore_term(instance)).collect();
+        let list = self.build_list(cells, tail);
+        Ok(self.unify(instances, list))
+    }
+
+    pub(crate) fn findall3(&mut self) -> Result<bool, WamError> {
+        self.findall(HeapCell::Constant(NIL.to_string()))
+    }
+
+    pub(crate) fn findall4(&mut self) -> Result<bool, WamError> {
+        self.findall(self.registers[4].clone())
+    }
+
+    /// `forall(+Condition, +Action)`: Action succeeds for every solution of
+    /// Condition. Binds nothing.
+    pub(crate) fn forall(&mut self) -> Result<bool, WamError> {
+        let (condition, action) = (self.registers[1].clone(), self.registers[2].clone());
+        let mut holds = true;
+        self.solve(condition, |wam| {
+            let mut succeeded = false;
+            wam.solve(action.clone(), |_| {
+                succeeded = true;
+                Ok(false)
+            })?;
+            holds = succeeded;
+            Ok(succeeded)
+        })?;
+        Ok(holds)
+    }
+
+    /// `bagof(?Template, +Goal, ?Instances)` and `setof/3`: like `findall/3`,
+    /// but failing when Goal has no solutions, and with one answer for each
+    /// binding of the free variables of Goal, those not in Template nor
+    /// bound by `Var^` in front of Goal. `setof/3` sorts each Instances and
+    /// drops duplicates.
+    fn bagof(&mut self, set: bool) -> Result<bool, WamError> {
+        let (template, instances) = (self.registers[1].clone(), self.registers[3].clone());
+        self.list_or_partial_list(&instances)?;
+        let mut goal = self.deref(&self.registers[2]);
+        let mut quantified = vec![template.clone()];
+        while let Some((variables, inner)) = self.existential(&goal) {
+            quantified.push(variables);
+            goal = inner;
+        }
+        let bound: Vec<HeapCell> = quantified.iter().flat_map(|cell| self.term_variables(cell)).collect();
+        let free: Vec<HeapCell> =
+            self.term_variables(&goal).into_iter().filter(|variable| !bound.contains(variable)).collect();
+        if self.is_unbound(&goal) {
+            return Err(instantiation_error());
+        }
+
+        if free.is_empty() {
+            let found = self.collect(&template, goal)?;
+            if found.is_empty() {
+                return Ok(false);
+            }
+            let mut cells: Vec<HeapCell> = found.iter().map(|instance| self.restore_term(instance)).collect();
+            if set {
+                self.sort_unique(&mut cells);
+            }
+            let list = self.build_list(cells, HeapCell::Constant(NIL.to_string()));
+            return Ok(self.unify(instances, list));
+        }
+
+        let witness = self.push_built_structure("$w", free);
+        let pair = self.push_built_structure("-", vec![witness.clone(), template]);
+        let found = self.collect(&pair, goal)?;
+        let mut pairs: Vec<(HeapCell, HeapCell)> = found
+            .iter()
+            .map(|pair| match self.restore_term(pair) {
+                HeapCell::Reference(a) => match &self.heap[a] {
+                    HeapCell::Structure(_, args) => (HeapCell::Reference(args[0]), HeapCell::Reference(args[1])),
+                    cell => unreachable!("solution pair is {:?}", cell),
+                },
+                cell => unreachable!("solution pair is {:?}", cell),
+            })
+            .collect();
+
+        // Solutions whose witnesses are variants make one group, in the
+        // order of their first solution.
+        let mut groups = Vec::new();
+        while !pairs.is_empty() {
+            let first = pairs[0].0.clone();
+            let (group, rest): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|(w, _)| self.variant(&first, w));
+            pairs = rest;
+            let mut cells = Vec::new();
+            for (w, instance) in group {
+                self.unify(first.clone(), w);
+                cells.push(instance);
+            }
+            if set {
+                self.sort_unique(&mut cells);
+            }
+            let list = self.build_list(cells, HeapCell::Constant(NIL.to_string()));
+            groups.push(self.push_built_structure("-", vec![first, list]));
+        }
+        let groups = self.build_list(groups, HeapCell::Constant(NIL.to_string()));
+        self.registers[1] = self.push_built_structure("-", vec![witness, instances]);
+        self.registers[2] = groups;
+        alternatives(self)
+    }
+
+    pub(crate) fn bagof3(&mut self) -> Result<bool, WamError> {
+        self.bagof(false)
+    }
+
+    pub(crate) fn setof3(&mut self) -> Result<bool, WamError> {
+        self.bagof(true)
+    }
+
+    /// The variables and goal of `Var^Goal`.
+    fn existential(&self, goal: &HeapCell) -> Option<(HeapCell, HeapCell)> {
+        match goal {
+            HeapCell::Reference(a) => match &self.heap[*a] {
+                HeapCell::Structure(functor, args) if functor == "^" && args.len() == 2 => {
+                    Some((HeapCell::Reference(args[0]), self.deref(&HeapCell::Reference(args[1]))))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether `a` and `b` are variants: equal up to a renaming of their
+    /// variables.
+    fn variant(&self, a: &HeapCell, b: &HeapCell) -> bool {
+        let mut forward = HashMap::new();
+        let mut backward = HashMap::new();
+        let mut pending = vec![(a.clone(), b.clone())];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.deref(&a), self.deref(&b));
+            match (self.is_unbound(&a), self.is_unbound(&b)) {
+                (true, true) => {
+                    let (x, y) = (format!("{:?}", a), format!("{:?}", b));
+                    if forward.entry(x.clone()).or_insert_with(|| y.clone()) != &y
+                        || backward.entry(y).or_insert(x.clone()) != &x
+                    {
+                        return false;
+                    }
+                }
+                (false, false) => match (&a, &b) {
+                    (HeapCell::Reference(x), HeapCell::Reference(y)) => match (&self.heap[*x], &self.heap[*y]) {
+                        (HeapCell::Structure(f, xs), HeapCell::Structure(g, ys)) if f == g && xs.len() == ys.len() => {
+                            pending.extend(xs.iter().zip(ys).map(|(&x, &y)| (HeapCell::Reference(x), HeapCell::Reference(y))));
+                        }
+                        _ => return false,
+                    },
+                    _ if self.compare_cells(&a, &b) == Ordering::Equal => {}
+                    _ => return false,
+                },
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// `aggregate_all(+Spec, +Goal, -Result)`: aggregates the solutions of
+    /// Goal as Spec says: `count`, `sum(Expr)`, `max(Expr)`, `min(Expr)`,
+    /// `bag(Template)` or `set(Template)`. `max` and `min` fail when Goal
+    /// has no solutions.
+    pub(crate) fn aggregate_all(&mut self) -> Result<bool, WamError> {
+        let spec = self.deref(&self.registers[1]);
+        let (goal, result) = (self.registers[2].clone(), self.registers[3].clone());
+        let (name, argument) = match &spec {
+            _ if self.is_unbound(&spec) => return Err(instantiation_error()),
+            HeapCell::Constant(name) => (name.clone(), None),
+            HeapCell::Reference(a) => match &self.heap[*a] {
+                HeapCell::Structure(name, args) if args.len() == 1 => (name.clone(), Some(HeapCell::Reference(args[0]))),
+                _ => return Err(domain_error("aggregate_spec", self.decode_cell(&spec)?)),
+            },
+            _ => return Err(domain_error("aggregate_spec", self.decode_cell(&spec)?)),
+        };
+        let value = match (name.as_str(), argument) {
+            ("count", None) => {
+                let mut count = 0;
+                self.solve(goal, |_| {
+                    count += 1;
+                    Ok(true)
+                })?;
+                HeapCell::Integer(count)
+            }
+            ("sum", Some(expression)) => {
+                let mut sum = Number::Integer(0);
+                self.solve(goal, |wam| {
+                    sum = binary(ArithOp::Add, sum.clone(), wam.evaluate(&expression)?)?;
+                    Ok(true)
+                })?;
+                sum.into_cell()
+            }
+            (extreme @ ("max" | "min"), Some(expression)) => {
+                let wanted = if extreme == "max" { Ordering::Greater } else { Ordering::Less };
+                let mut best: Option<Number> = None;
+                self.solve(goal, |wam| {
+                    let value = wam.evaluate(&expression)?;
+                    if best.as_ref().is_none_or(|best| value.compare(best) == Some(wanted)) {
+                        best = Some(value);
+                    }
+                    Ok(true)
+                })?;
+                match best {
+                    Some(best) => best.into_cell(),
+                    None => return Ok(false),
+                }
+            }
+            (kind @ ("bag" | "set"), Some(template)) => {
+                let found = self.collect(&template, goal)?;
+                let mut cells: Vec<HeapCell> = found.iter().map(|instance| self.restore_term(instance)).collect();
+                if kind == "set" {
+                    self.sort_unique(&mut cells);
+                }
+                self.build_list(cells, HeapCell::Constant(NIL.to_string()))
+            }
+            _ => return Err(domain_error("aggregate_spec", self.decode_cell(&spec)?)),
+        };
+        Ok(self.unify(result, value))
+    }
+}
+
+/// `'$member'(?X, +List)`: X is an element of List, enumerated on
+/// backtracking through the choice point continuing at `ALTERNATIVES`.
+pub(crate) fn alternatives(wam: &mut WamEmulator) -> Result<bool, WamError> {
+    let list = wam.deref(&wam.registers[2]);
+    let (head, tail) = match &list {
+        HeapCell::Reference(a) => match &wam.heap[*a] {
+            HeapCell::Structure(functor, args) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                (HeapCell::Reference(args[0]), wam.deref(&HeapCell::Reference(args[1])))
+            }
+            _ => return Ok(false),
+        },
+        _ => return Ok(false),
+    };
+    if tail != HeapCell::Constant(NIL.to_string()) {
+        wam.registers[2] = tail;
+        wam.push_choicepoint(ALTERNATIVES, 2);
+    }
+    Ok(wam.unify(wam.registers[1].clone(), head))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    const PROGRAM: &str = "
+        age(peter, 7). age(ann, 11). age(pat, 8). age(tom, 5). age(mike, 11).
+        class(a, peter). class(b, ann). class(a, pat). class(b, tom). class(b, ann).
+    ";
+
+    #[test]
+    fn test_findall_and_forall() {
+        let mut wam = WamEmulator::new();
+        wam.consult(PROGRAM).unwrap();
+        assert_eq!(answers(&mut wam, "findall(N-A, age(N, A), L)"), vec![
+            "L = [peter-7, ann-11, pat-8, tom-5, mike-11]"
+        ]);
+        assert_eq!(answers(&mut wam, "findall(X, fail, L)"), vec!["L = []"]);
+        assert_eq!(answers(&mut wam, "findall(A, age(_, A), L, [end])"), vec!["L = [7, 11, 8, 5, 11, end]"]);
+        // Instances are copies, and the goal's bindings are undone.
+        assert_eq!(answers(&mut wam, "findall(f(X), (X = 1 ; X = 2), L), X = 3"), vec!["X = 3,\nL = [f(1), f(2)]"]);
+        assert_eq!(answers(&mut wam, "findall(Y, (true ; true), [A, B]), A = 1, B = 2"), vec!["A = 1,\nB = 2"]);
+        assert_eq!(answers(&mut wam, "forall(age(_, A), A > 4)"), vec!["true"]);
+        assert!(answers(&mut wam, "forall(age(_, A), A > 5)").is_empty());
+        assert_eq!(exception(&mut wam, "findall(X, age(X, _), foo)"), "error(type_error(list, foo), _)");
+        assert_eq!(exception(&mut wam, "findall(X, Goal, L)"), "error(instantiation_error, _)");
+        assert_eq!(answers(&mut wam, "catch(findall(X, throw(oops), L), E, true)"), vec!["E = oops"]);
+        // Solutions are copied without recursion, however deep or cyclic.
+        wam.consult("deep(0, L, L) :- !. deep(N, L, f(T)) :- N1 is N - 1, deep(N1, L, T).").unwrap();
+        let goal = "findall(T, deep(300000, a, T), [C]), bagof(T, K^deep(300000, K, T), [B]), B = C, \
+            deep(300000, a, D), C == D, findall(X, X = f(X), [Y]), Y = f(Z), Z == Y";
+        assert_eq!(answers(&mut wam, &format!("\\+ \\+ ({})", goal)), vec!["true"]);
+    }
+
+    #[test]
+    fn test_bagof_and_setof() {
+        let mut wam = WamEmulator::new();
+        wam.consult(PROGRAM).unwrap();
+        assert_eq!(answers(&mut wam, "bagof(N, class(C, N), L)"), vec![
+            "C = a,\nL = [peter, pat]",
+            "C = b,\nL = [ann, tom, ann]",
+        ]);
+        assert_eq!(answers(&mut wam, "setof(N, class(C, N), L)"), vec![
+            "C = a,\nL = [pat, peter]",
+            "C = b,\nL = [ann, tom]",
+        ]);
+        assert_eq!(answers(&mut wam, "setof(N, C^class(C, N), L)"), vec!["L = [ann, pat, peter, tom]"]);
+        assert_eq!(answers(&mut wam, "setof(A-N, age(N, A), [Youngest|_])"), vec!["Youngest = 5-tom"]);
+        assert!(answers(&mut wam, "bagof(X, fail, L)").is_empty());
+        wam.consult("pupils(C, L) :- bagof(N, class(C, N), L), succ(1, _), L = [_|_], C = C.").unwrap();
+        assert_eq!(answers(&mut wam, "pupils(C, L)"), vec!["C = a,\nL = [peter, pat]", "C = b,\nL = [ann, tom, ann]"]);
+        assert_eq!(answers(&mut wam, "setof(X, (X = b ; X = 1.0 ; X = f(a) ; X = 1 ; X = a ; X = _), [_|L])"), vec![
+            "L = [1.0, 1, a, b, f(a)]"
+        ]);
+    }
+
+    #[test]
+    fn test_aggregate_all() {
+        let mut wam = WamEmulator::new();
+        wam.consult(PROGRAM).unwrap();
+        assert_eq!(answers(&mut wam, "aggregate_all(count, age(_, _), C)"), vec!["C = 5"]);
+        assert_eq!(answers(&mut wam, "aggregate_all(count, fail, C)"), vec!["C = 0"]);
+        assert_eq!(answers(&mut wam, "aggregate_all(sum(A), age(_, A), S)"), vec!["S = 42"]);
+        assert_eq!(answers(&mut wam, "aggregate_all(max(A), age(_, A), M)"), vec!["M = 11"]);
+        assert_eq!(answers(&mut wam, "aggregate_all(min(A * 2), age(_, A), M)"), vec!["M = 10"]);
+        assert!(answers(&mut wam, "aggregate_all(max(A), fail, M)").is_empty());
+        assert_eq!(answers(&mut wam, "aggregate_all(bag(C), class(C, _), B)"), vec!["B = [a, b, a, b, b]"]);
+        assert_eq!(answers(&mut wam, "aggregate_all(set(C), class(C, _), S)"), vec!["S = [a, b]"]);
+        assert_eq!(exception(&mut wam, "aggregate_all(avg(1), age(_, _), M)"), "error(domain_error(aggregate_spec, avg(1)), _)");
+    }
+}
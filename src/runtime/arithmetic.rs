@@ -0,0 +1,515 @@
+// src/runtime/arithmetic.rs
+use std::cmp::Ordering;
+
+use num_bigint::BigInt;
+use num_integer::Integer as _;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+
+use crate::parser::ast::Term;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::error::{evaluation_error, indicator, instantiation_error, resource_error, type_error, WamError};
+use crate::wam::instruction::{ArithOp, Comparison, Operand, Reg};
+#[cfg(test)]
+use crate::wam::instruction::Instruction;
+
+/// A number as arithmetic sees it. Integers that fit in an `i64` are always
+/// `Integer`, so results only become bignums when they overflow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    BigInteger(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    fn from_big(value: BigInt) -> Number {
+        match value.to_i64() {
+            Some(value) => Number::Integer(value),
+            None => Number::BigInteger(value),
+        }
+    }
+
+    /// A float result, checked for overflow and undefined values.
+    fn float(value: f64) -> Result<Number, WamError> {
+        if value.is_nan() {
+            Err(evaluation_error("undefined"))
+        } else if value.is_infinite() {
+            Err(evaluation_error("float_overflow"))
+        } else {
+            Ok(Number::Float(value))
+        }
+    }
+
+    pub fn into_cell(self) -> HeapCell {
+        match self {
+            Number::Integer(value) => HeapCell::Integer(value),
+            Number::BigInteger(value) => HeapCell::BigInteger(value),
+            Number::Float(value) => HeapCell::Float(value),
+        }
+    }
+
+    fn to_term(&self) -> Term {
+        match self {
+            Number::Integer(value) => Term::Integer(*value),
+            Number::BigInteger(value) => Term::BigInteger(value.clone()),
+            Number::Float(value) => Term::Float(*value),
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, Number::Float(_))
+    }
+
+    /// The integer value, or a type error for floats.
+    fn to_big(&self) -> Result<BigInt, WamError> {
+        match self {
+            Number::Integer(value) => Ok(BigInt::from(*value)),
+            Number::BigInteger(value) => Ok(value.clone()),
+            Number::Float(_) => Err(type_error("integer", self.to_term())),
+        }
+    }
+
+    fn to_f64(&self) -> Result<f64, WamError> {
+        match self {
+            Number::Integer(value) => Ok(*value as f64),
+            Number::BigInteger(value) => match value.to_f64() {
+                Some(value) if value.is_finite() => Ok(value),
+                _ => Err(evaluation_error("float_overflow")),
+            },
+            Number::Float(value) => Ok(*value),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(value) => *value == 0,
+            Number::BigInteger(value) => value.is_zero(),
+            Number::Float(value) => *value == 0.0,
+        }
+    }
+
+    /// Compares two numbers by value. Integers are compared exactly; a float
+    /// against an integer compares as floats. `None` if either is NaN.
+    pub(crate) fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Integer(x), Number::Integer(y)) => Some(x.cmp(y)),
+            (Number::Float(_), _) | (_, Number::Float(_)) => self.to_f64().ok()?.partial_cmp(&other.to_f64().ok()?),
+            (x, y) => Some(x.to_big().ok()?.cmp(&y.to_big().ok()?)),
+        }
+    }
+}
+
+/// Applies `op` to two numbers. Integer operations are done on `i64` and
+/// redone on bignums when they overflow; a float operand makes the result
+/// a float, except for the operations only defined on integers.
+pub(crate) fn binary(op: ArithOp, x: Number, y: Number) -> Result<Number, WamError> {
+    if let (Number::Integer(a), Number::Integer(b)) = (&x, &y) {
+        if let Some(result) = small_binary(op, *a, *b)? {
+            return Ok(Number::Integer(result));
+        }
+    }
+    match op {
+        ArithOp::Add | ArithOp::Sub | ArithOp::Mul if x.is_float() || y.is_float() => {
+            let (a, b) = (x.to_f64()?, y.to_f64()?);
+            Number::float(match op {
+                ArithOp::Add => a + b,
+                ArithOp::Sub => a - b,
+                _ => a * b,
+            })
+        }
+        ArithOp::Add => Ok(Number::from_big(x.to_big()? + y.to_big()?)),
+        ArithOp::Sub => Ok(Number::from_big(x.to_big()? - y.to_big()?)),
+        ArithOp::Mul => Ok(Number::from_big(x.to_big()? * y.to_big()?)),
+        ArithOp::Div => {
+            if y.is_zero() {
+                return Err(evaluation_error("zero_divisor"));
+            }
+            if !x.is_float() && !y.is_float() {
+                let (a, b) = (x.to_big()?, y.to_big()?);
+                if (&a % &b).is_zero() {
+                    return Ok(Number::from_big(a / b));
+                }
+            }
+            Number::float(x.to_f64()? / y.to_f64()?)
+        }
+        ArithOp::IntDiv | ArithOp::FloorDiv | ArithOp::Mod | ArithOp::Rem => {
+            let (a, b) = (x.to_big()?, y.to_big()?);
+            if b.is_zero() {
+                return Err(evaluation_error("zero_divisor"));
+            }
+            Ok(Number::from_big(match op {
+                ArithOp::IntDiv => a / b,
+                ArithOp::FloorDiv => a.div_floor(&b),
+                ArithOp::Mod => a.mod_floor(&b),
+                _ => a % b,
+            }))
+        }
+        ArithOp::Min | ArithOp::Max => {
+            let ordering = x.compare(&y).ok_or_else(|| evaluation_error("undefined"))?;
+            let first = (ordering == Ordering::Less) == (op == ArithOp::Min);
+            Ok(if first { x } else { y })
+        }
+        ArithOp::Gcd => Ok(Number::from_big(x.to_big()?.gcd(&y.to_big()?))),
+        ArithOp::BitAnd => Ok(Number::from_big(x.to_big()? & y.to_big()?)),
+        ArithOp::BitOr => Ok(Number::from_big(x.to_big()? | y.to_big()?)),
+        ArithOp::Xor => Ok(Number::from_big(x.to_big()? ^ y.to_big()?)),
+        ArithOp::ShiftLeft | ArithOp::ShiftRight => {
+            let (a, shift) = (x.to_big()?, y.to_big()?);
+            let left = (op == ArithOp::ShiftLeft) != shift.is_negative();
+            let shift = shift.abs().to_usize().ok_or_else(|| resource_error("memory"))?;
+            Ok(Number::from_big(if left { a << shift } else { a >> shift }))
+        }
+        ArithOp::Power if x.is_float() || y.is_float() || y.compare(&Number::Integer(0)) == Some(Ordering::Less) => {
+            if x.is_zero() && y.compare(&Number::Integer(0)) == Some(Ordering::Less) {
+                return Err(evaluation_error("zero_divisor"));
+            }
+            Number::float(x.to_f64()?.powf(y.to_f64()?))
+        }
+        ArithOp::Power | ArithOp::IntPower => integer_power(x, y),
+        ArithOp::Atan2 => {
+            let (a, b) = (x.to_f64()?, y.to_f64()?);
+            if a == 0.0 && b == 0.0 {
+                return Err(evaluation_error("undefined"));
+            }
+            Number::float(a.atan2(b))
+        }
+        ArithOp::LogBase => {
+            let (base, a) = (x.to_f64()?, y.to_f64()?);
+            if base <= 0.0 || a <= 0.0 {
+                return Err(evaluation_error("undefined"));
+            }
+            Number::float(a.ln() / base.ln())
+        }
+    }
+}
+
+/// The common case of `binary` on two small integers. `None` when the
+/// result does not fit in an `i64` or the operation is not done here.
+fn small_binary(op: ArithOp, a: i64, b: i64) -> Result<Option<i64>, WamError> {
+    if b == 0 && matches!(op, ArithOp::IntDiv | ArithOp::FloorDiv | ArithOp::Mod | ArithOp::Rem) {
+        return Err(evaluation_error("zero_divisor"));
+    }
+    Ok(match op {
+        ArithOp::Add => a.checked_add(b),
+        ArithOp::Sub => a.checked_sub(b),
+        ArithOp::Mul => a.checked_mul(b),
+        ArithOp::IntDiv => a.checked_div(b),
+        ArithOp::FloorDiv => a.checked_div(b).map(|q| if (a % b != 0) && ((a < 0) != (b < 0)) { q - 1 } else { q }),
+        ArithOp::Mod => a.checked_rem(b).map(|r| if r != 0 && (r < 0) != (b < 0) { r + b } else { r }),
+        ArithOp::Rem => a.checked_rem(b),
+        ArithOp::Min => Some(a.min(b)),
+        ArithOp::Max => Some(a.max(b)),
+        ArithOp::BitAnd => Some(a & b),
+        ArithOp::BitOr => Some(a | b),
+        ArithOp::Xor => Some(a ^ b),
+        _ => None,
+    })
+}
+
+/// `X ^ Y` on integers. A negative exponent is only allowed for bases whose
+/// powers are all integers: 1 and -1.
+fn integer_power(x: Number, y: Number) -> Result<Number, WamError> {
+    if x.is_float() || y.is_float() {
+        return Number::float(x.to_f64()?.powf(y.to_f64()?));
+    }
+    let (base, exponent) = (x.to_big()?, y.to_big()?);
+    if exponent.is_negative() {
+        return match base.to_i64() {
+            Some(1) => Ok(Number::Integer(1)),
+            Some(-1) => Ok(Number::Integer(if exponent.is_even() { 1 } else { -1 })),
+            Some(0) => Err(evaluation_error("zero_divisor")),
+            _ => Err(type_error("float", x.to_term())),
+        };
+    }
+    // 0, 1 and -1 can be raised to any power without building it.
+    if base.abs() <= BigInt::from(1) {
+        if exponent.is_zero() || base.is_negative() && exponent.is_even() {
+            return Ok(Number::Integer(1));
+        }
+        return Ok(Number::from_big(base));
+    }
+    let exponent = exponent.to_u32().ok_or_else(|| resource_error("memory"))?;
+    Ok(Number::from_big(base.pow(exponent)))
+}
+
+/// Applies the evaluable functor `name` of arity 1.
+fn unary(name: &str, x: Number) -> Result<Number, WamError> {
+    let float = |f: fn(f64) -> f64| Number::float(f(x.to_f64()?));
+    match name {
+        "-" => match &x {
+            Number::Integer(value) => Ok(value.checked_neg().map_or_else(|| Number::from_big(-BigInt::from(*value)), Number::Integer)),
+            Number::BigInteger(value) => Ok(Number::from_big(-value)),
+            Number::Float(value) => Ok(Number::Float(-value)),
+        },
+        "+" => Ok(x),
+        "abs" => match &x {
+            Number::Integer(value) => Ok(value.checked_abs().map_or_else(|| Number::from_big(BigInt::from(*value).abs()), Number::Integer)),
+            Number::BigInteger(value) => Ok(Number::from_big(value.abs())),
+            Number::Float(value) => Ok(Number::Float(value.abs())),
+        },
+        "sign" => match &x {
+            Number::Integer(value) => Ok(Number::Integer(value.signum())),
+            Number::BigInteger(value) => Ok(Number::Integer(if value.is_negative() { -1 } else { 1 })),
+            Number::Float(value) if *value == 0.0 => Ok(Number::Float(0.0)),
+            Number::Float(value) => Ok(Number::Float(value.signum())),
+        },
+        "\\" => Ok(Number::from_big(!x.to_big()?)),
+        "msb" => match x.to_big()? {
+            value if value.is_positive() => Ok(Number::Integer(value.bits() as i64 - 1)),
+            _ => Err(type_error("not_less_than_one", x.to_term())),
+        },
+        "sqrt" if x.to_f64()? < 0.0 => Err(evaluation_error("undefined")),
+        "sqrt" => float(f64::sqrt),
+        "log" if x.to_f64()? <= 0.0 => Err(evaluation_error("undefined")),
+        "log" => float(f64::ln),
+        "exp" => float(f64::exp),
+        "sin" => float(f64::sin),
+        "cos" => float(f64::cos),
+        "tan" => float(f64::tan),
+        "asin" | "acos" if x.to_f64()?.abs() > 1.0 => Err(evaluation_error("undefined")),
+        "asin" => float(f64::asin),
+        "acos" => float(f64::acos),
+        "atan" => float(f64::atan),
+        "float" => float(|value| value),
+        "float_integer_part" => float(f64::trunc),
+        "float_fractional_part" => float(f64::fract),
+        "floor" => to_integer(x, f64::floor),
+        "ceiling" => to_integer(x, f64::ceil),
+        "round" | "integer" => to_integer(x, f64::round),
+        "truncate" => to_integer(x, f64::trunc),
+        name => Err(type_error("evaluable", indicator(name, 1))),
+    }
+}
+
+/// Rounds a float to an integer with `round`; integers are left as they are.
+fn to_integer(x: Number, round: fn(f64) -> f64) -> Result<Number, WamError> {
+    match x {
+        Number::Float(value) => match BigInt::from_f64(round(value)) {
+            Some(value) => Ok(Number::from_big(value)),
+            None => Err(evaluation_error("undefined")),
+        },
+        integer => Ok(integer),
+    }
+}
+
+/// The evaluable atoms.
+fn constant(name: &str) -> Result<Number, WamError> {
+    match name {
+        "pi" => Ok(Number::Float(std::f64::consts::PI)),
+        "e" => Ok(Number::Float(std::f64::consts::E)),
+        "epsilon" => Ok(Number::Float(f64::EPSILON)),
+        "inf" | "infinite" => Ok(Number::Float(f64::INFINITY)),
+        "nan" => Ok(Number::Float(f64::NAN)),
+        // Integers beyond these are bignums.
+        "max_tagged_integer" => Ok(Number::Integer(i64::MAX)),
+        "min_tagged_integer" => Ok(Number::Integer(i64::MIN)),
+        "[]" => Err(type_error("evaluable", Term::Atom(name.to_string()))),
+        name => Err(type_error("evaluable", indicator(name, 0))),
+    }
+}
+
+impl WamEmulator {
+    /// Evaluates `cell` as an arithmetic expression.
+    pub(crate) fn evaluate(&self, cell: &HeapCell) -> Result<Number, WamError> {
+        let cell = self.deref(cell);
+        match cell {
+            HeapCell::Integer(value) => Ok(Number::Integer(value)),
+            HeapCell::BigInteger(value) => Ok(Number::BigInteger(value)),
+            HeapCell::Float(value) => Ok(Number::Float(value)),
+            _ if self.is_unbound(&cell) => Err(instantiation_error()),
+            HeapCell::Constant(name) => constant(&name),
+            HeapCell::Reference(a) => match &self.heap[a] {
+                HeapCell::Structure(name, args) => self.evaluate_compound(name, args),
+                cell => unreachable!("dereferenced to {:?}", cell),
+            },
+            cell => unreachable!("dereferenced to {:?}", cell),
+        }
+    }
+
+    fn evaluate_compound(&self, name: &str, args: &[usize]) -> Result<Number, WamError> {
+        let argument = |i: usize| self.evaluate(&HeapCell::Reference(args[i]));
+        match (name, args.len()) {
+            // A one-element list such as "a" evaluates its element.
+            (".", 2) if self.deref(&HeapCell::Reference(args[1])) == HeapCell::Constant("[]".to_string()) => argument(0),
+            (name, 1) => unary(name, argument(0)?),
+            (name, 2) => match ArithOp::from_name(name) {
+                Some(op) => binary(op, argument(0)?, argument(1)?),
+                None => Err(type_error("evaluable", indicator(name, 2))),
+            },
+            (name, arity) => Err(type_error("evaluable", indicator(name, arity))),
+        }
+    }
+
+    /// The value of an operand of an arithmetic instruction.
+    pub(crate) fn operand(&self, operand: &Operand) -> Result<Number, WamError> {
+        match operand {
+            Operand::Integer(value) => Ok(Number::Integer(*value)),
+            Operand::Reg(reg) => self.evaluate(&self.register(*reg)),
+        }
+    }
+
+    /// Evaluates two operands and checks `comparison` between them.
+    pub(crate) fn compare_operands(&self, comparison: Comparison, x: &Operand, y: &Operand) -> Result<bool, WamError> {
+        let x = self.operand(x)?;
+        let y = self.operand(y)?;
+        Ok(comparison.holds(x.compare(&y)))
+    }
+}
+
+/// `?X is +Expr`: evaluates Expr and unifies the result with X. Calls that
+/// the compiler could not inline, such as `call(X is Y)`, end up here.
+pub(crate) fn is(wam: &mut WamEmulator) -> Result<bool, WamError> {
+    let value = wam.evaluate(&wam.registers[2])?;
+    Ok(wam.unify(wam.registers[1].clone(), value.into_cell()))
+}
+
+/// The arithmetic comparison predicates, when not inlined.
+pub(crate) fn compare(wam: &mut WamEmulator, comparison: Comparison) -> Result<bool, WamError> {
+    wam.compare_operands(comparison, &Operand::Reg(Reg::X(1)), &Operand::Reg(Reg::X(2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::emulator::tests::{answers, exception};
+
+    #[test]
+    fn test_is_and_comparisons() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "X is 7 - 2 * 3 + -(4)"), vec!["X = -3"]);
+        assert_eq!(answers(&mut wam, "X is -7 // 2, Y is -7 mod 2, Z is -7 rem 2"), vec!["X = -3,\nY = 1,\nZ = -1"]);
+        wam.consult("double(X) :- E = 1 + 2, X is E * 2.").unwrap();
+        assert_eq!(answers(&mut wam, "double(X)"), vec!["X = 6"]);
+        assert_eq!(answers(&mut wam, "1 + 2 =:= 3, 2 < 3, 3 >= 3, 1 =\\= 2"), vec!["true"]);
+        assert!(answers(&mut wam, "3 =< 2").is_empty());
+    }
+
+    #[test]
+    fn test_overflow_to_bignum() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            fact(0, 1) :- !.
+            fact(N, F) :- N1 is N - 1, fact(N1, F1), F is N * F1.
+        ").unwrap();
+        assert_eq!(answers(&mut wam, "fact(25, F)"), vec!["F = 15511210043330985984000000"]);
+        assert_eq!(answers(&mut wam, "fact(25, F), X is F // 1000000 - F // 1000000, X =:= 0"), vec![
+            "F = 15511210043330985984000000,\nX = 0",
+        ]);
+        assert_eq!(answers(&mut wam, "X is 9223372036854775807 + 1 - 1"), vec!["X = 9223372036854775807"]);
+    }
+
+    fn error_text(error: WamError) -> String {
+        match error {
+            WamError::Exception(term) => term.to_string(),
+            error => panic!("not an exception: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let mut wam = WamEmulator::new();
+        wam.consult("inc(X, Y) :- Y is X + 1.").unwrap();
+        assert_eq!(exception(&mut wam, "inc(_, Y)"), error_text(instantiation_error()));
+        assert_eq!(exception(&mut wam, "inc(a, Y)"), error_text(type_error("evaluable", indicator("a", 0))));
+        assert_eq!(exception(&mut wam, "X is foo(1)"), error_text(type_error("evaluable", indicator("foo", 1))));
+        assert_eq!(exception(&mut wam, "X is 1 // 0"), error_text(evaluation_error("zero_divisor")));
+        assert_eq!(exception(&mut wam, "1 < X"), error_text(instantiation_error()));
+    }
+
+    #[test]
+    fn test_numeric_loop_runs_inline() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            sum(0, S, S) :- !.
+            sum(N, Acc, S) :- N > 0, Acc1 is Acc + N, N1 is N - 1, sum(N1, Acc1, S).
+        ").unwrap();
+        // The second clause makes no call before its last goal.
+        let id = wam.predicates.lookup("sum", 3).unwrap();
+        let entry = wam.predicates.get(id).clause_entries[1];
+        let body: Vec<&Instruction> =
+            wam.code[entry..].iter().take_while(|instruction| !matches!(instruction, Instruction::Execute(_))).collect();
+        assert!(body.iter().any(|instruction| matches!(instruction, Instruction::Compare(..))));
+        assert!(!body.iter().any(|instruction| matches!(instruction, Instruction::Call(..) | Instruction::Allocate(_))));
+
+        assert_eq!(answers(&mut wam, "sum(1000000, 0, S)"), vec!["S = 500000500000"]);
+        assert!(wam.frames.is_empty());
+    }
+
+    /// The value of `Expr`, as printed.
+    fn value(wam: &mut WamEmulator, expression: &str) -> String {
+        let answers = answers(wam, &format!("X is {}", expression));
+        answers[0].strip_prefix("X = ").unwrap().to_string()
+    }
+
+    #[test]
+    fn test_integer_and_float_operations() {
+        let mut wam = WamEmulator::new();
+        let cases = [
+            ("7 / 2", "3.5"),
+            ("4 / 2", "2"),
+            ("2.0 * 3", "6.0"),
+            ("1 + 0.5", "1.5"),
+            ("-(2.5)", "-2.5"),
+            ("2 ** 3", "8"),
+            ("2 ** -1", "0.5"),
+            ("2 ^ 100", "1267650600228229401496703205376"),
+            ("(-1) ^ -3", "-1"),
+            ("div(-7, 2)", "-4"),
+            ("-7 // 2", "-3"),
+            ("min(2, 3.0)", "2"),
+            ("max(2, 3.0)", "3.0"),
+            ("abs(-5)", "5"),
+            ("sign(-3)", "-1"),
+            ("sign(2.5)", "1.0"),
+            ("gcd(12, -18)", "6"),
+            ("5 /\\ 3", "1"),
+            ("5 \\/ 3", "7"),
+            ("5 xor 3", "6"),
+            ("\\ 5", "-6"),
+            ("1 << 70", "1180591620717411303424"),
+            ("-16 >> 2", "-4"),
+            ("sqrt(16)", "4.0"),
+            ("floor(-2.5)", "-3"),
+            ("ceiling(2.1)", "3"),
+            ("round(2.5)", "3"),
+            ("truncate(-2.7)", "-2"),
+            ("float_integer_part(3.75)", "3.0"),
+            ("float_fractional_part(3.75)", "0.75"),
+            ("float(7)", "7.0"),
+            ("exp(0)", "1.0"),
+            ("log(1)", "0.0"),
+            ("log(2, 8)", "3.0"),
+            ("atan2(0, 1)", "0.0"),
+            ("cos(0)", "1.0"),
+            ("max_tagged_integer", "9223372036854775807"),
+            ("1.0e10", "10000000000.0"),
+            ("2 ** 200 // 2 ** 199", "2"),
+            ("123456789012345678901234567890 - 123456789012345678901234567889", "1"),
+            ("truncate(1.0e20)", "100000000000000000000"),
+        ];
+        for (expression, expected) in cases {
+            assert_eq!(value(&mut wam, expression), expected, "X is {}", expression);
+        }
+        assert_eq!(answers(&mut wam, "1 =:= 1.0, 1 < 1.5, 2 ^ 64 > 2.0, 0.1 + 0.2 =\\= 0.3"), vec!["true"]);
+    }
+
+    #[test]
+    fn test_iso_evaluation_errors() {
+        let mut wam = WamEmulator::new();
+        let cases = [
+            ("1 / 0", evaluation_error("zero_divisor")),
+            ("1 mod 0", evaluation_error("zero_divisor")),
+            ("1.0 // 2", type_error("integer", Term::Float(1.0))),
+            ("1 >> 2.0", type_error("integer", Term::Float(2.0))),
+            ("2 ^ -1", type_error("float", Term::Integer(2))),
+            ("sqrt(-1)", evaluation_error("undefined")),
+            ("log(0)", evaluation_error("undefined")),
+            ("foo + 1", type_error("evaluable", indicator("foo", 0))),
+            ("succ(1)", type_error("evaluable", indicator("succ", 1))),
+            ("1.0e308 * 10", evaluation_error("float_overflow")),
+        ];
+        for (expression, error) in cases {
+            assert_eq!(exception(&mut wam, &format!("X is {}", expression)), error_text(error), "X is {}", expression);
+        }
+    }
+}
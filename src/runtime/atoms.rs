@@ -0,0 +1,387 @@
+// src/runtime/atoms.rs
+use std::convert::TryFrom;
+
+use crate::parser::ast::{parse_term_text, Term};
+use crate::runtime::aggregate::alternatives;
+use crate::runtime::writer::{format_float, WriteOptions};
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::decoder::NIL;
+use crate::wam::error::{
+    domain_error, illegal_number, instantiation_error, representation_error, syntax_error, type_error, WamError,
+};
+
+impl WamEmulator {
+    /// The text of the atomic term `cell` stands for: the name of an atom
+    /// or the digits of a number. `None` for variables and compound terms.
+    pub(crate) fn text_of(&self, cell: &HeapCell) -> Option<String> {
+        match self.deref(cell) {
+            HeapCell::Constant(name) => Some(name),
+            HeapCell::Integer(value) => Some(value.to_string()),
+            HeapCell::BigInteger(value) => Some(value.to_string()),
+            HeapCell::Float(value) => Some(format_float(value)),
+            _ => None,
+        }
+    }
+
+    /// The text of `cell`, which must be atomic.
+    pub(crate) fn atomic_text(&self, cell: &HeapCell) -> Result<String, WamError> {
+        match self.text_of(cell) {
+            Some(text) => Ok(text),
+            None if self.is_unbound(&self.deref(cell)) => Err(instantiation_error()),
+            None => Err(type_error("atomic", self.decode_cell(cell)?)),
+        }
+    }
+
+    /// The text spelled by the list of codes, or with `chars` of
+    /// one-character atoms, that `cell` stands for.
+    pub(crate) fn list_text(&self, cell: &HeapCell, chars: bool) -> Result<String, WamError> {
+        let mut text = String::new();
+        for element in self.list_elements(cell)? {
+            let element = self.deref(&element);
+            match &element {
+                _ if self.is_unbound(&element) => return Err(instantiation_error()),
+                HeapCell::Constant(name) if chars && name.chars().count() == 1 => text.push_str(name),
+                HeapCell::Integer(code) if !chars => text.push(character(*code)?),
+                _ if chars => return Err(type_error("character", self.decode_cell(&element)?)),
+                _ => return Err(representation_error("character_code")),
+            }
+        }
+        Ok(text)
+    }
+
+    /// `text` as a list of codes, or with `chars` of one-character atoms.
+    fn text_list(&mut self, text: &str, chars: bool) -> HeapCell {
+        let cells = text
+            .chars()
+            .map(|c| match chars {
+                true => HeapCell::Constant(c.to_string()),
+                false => HeapCell::Integer(c as i64),
+            })
+            .collect();
+        self.build_list(cells, HeapCell::Constant(NIL.to_string()))
+    }
+
+    /// `atom_codes(?Atom, ?Codes)` and `atom_chars(?Atom, ?Chars)`: Codes
+    /// is the list of the character codes of Atom, Chars that of its
+    /// characters.
+    fn atom_list(&mut self, chars: bool) -> Result<bool, WamError> {
+        let atom = self.deref(&self.registers[1]);
+        let list = self.registers[2].clone();
+        if self.is_unbound(&atom) {
+            let text = self.list_text(&list, chars)?;
+            return Ok(self.unify(atom, HeapCell::Constant(text)));
+        }
+        let text = self.atomic_text(&atom)?;
+        self.list_or_partial_list(&list)?;
+        let cells = self.text_list(&text, chars);
+        Ok(self.unify(list, cells))
+    }
+
+    pub(crate) fn atom_codes(&mut self) -> Result<bool, WamError> {
+        self.atom_list(false)
+    }
+
+    pub(crate) fn atom_chars(&mut self) -> Result<bool, WamError> {
+        self.atom_list(true)
+    }
+
+    /// `char_code(?Char, ?Code)`: Code is the character code of the
+    /// one-character atom Char.
+    pub(crate) fn char_code(&mut self) -> Result<bool, WamError> {
+        let char = self.deref(&self.registers[1]);
+        let code = self.deref(&self.registers[2]);
+        match &char {
+            HeapCell::Constant(name) if name.chars().count() == 1 => {
+                let c = name.chars().next().unwrap();
+                return Ok(self.unify(code, HeapCell::Integer(c as i64)));
+            }
+            _ if !self.is_unbound(&char) => return Err(type_error("character", self.decode_cell(&char)?)),
+            _ => {}
+        }
+        let c = match code {
+            HeapCell::Integer(code) => character(code)?,
+            _ if self.is_unbound(&code) => return Err(instantiation_error()),
+            _ => return Err(type_error("integer", self.decode_cell(&code)?)),
+        };
+        Ok(self.unify(char, HeapCell::Constant(c.to_string())))
+    }
+
+    /// `atom_length(+Atom, ?Length)`: Length is the number of characters
+    /// of Atom.
+    pub(crate) fn atom_length(&mut self) -> Result<bool, WamError> {
+        let text = self.atomic_text(&self.registers[1])?;
+        let length = self.deref(&self.registers[2]);
+        match length {
+            HeapCell::Integer(n) if n < 0 => return Err(domain_error("not_less_than_zero", Term::Integer(n))),
+            HeapCell::Integer(_) => {}
+            _ if self.is_unbound(&length) => {}
+            _ => return Err(type_error("integer", self.decode_cell(&length)?)),
+        }
+        Ok(self.unify(length, HeapCell::Integer(text.chars().count() as i64)))
+    }
+
+    /// `atom_concat(?Atom1, ?Atom2, ?Atom3)`: Atom3 is Atom1 followed by
+    /// Atom2. With Atom1 or Atom2 unbound, the ways of splitting Atom3 are
+    /// enumerated on backtracking.
+    pub(crate) fn atom_concat(&mut self) -> Result<bool, WamError> {
+        let (first, second) = (self.deref(&self.registers[1]), self.deref(&self.registers[2]));
+        let whole = self.registers[3].clone();
+        if !self.is_unbound(&first) && !self.is_unbound(&second) {
+            let text = self.atomic_text(&first)? + &self.atomic_text(&second)?;
+            return Ok(self.unify(whole, HeapCell::Constant(text)));
+        }
+        let text = self.atomic_text(&whole)?;
+        let splits = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(Some(text.len()))
+            .map(|i| {
+                let parts = vec![HeapCell::Constant(text[..i].to_string()), HeapCell::Constant(text[i..].to_string())];
+                self.push_built_structure("-", parts)
+            })
+            .collect();
+        self.registers[1] = self.push_built_structure("-", vec![first, second]);
+        self.registers[2] = self.build_list(splits, HeapCell::Constant(NIL.to_string()));
+        alternatives(self)
+    }
+
+    /// `sub_atom(+Atom, ?Before, ?Length, ?After, ?Sub)`: Sub is the part
+    /// of Atom that starts after Before characters, is Length characters
+    /// long and leaves After characters. The matching parts are
+    /// enumerated on backtracking, leftmost and shortest first.
+    pub(crate) fn sub_atom(&mut self) -> Result<bool, WamError> {
+        let text: Vec<char> = self.atomic_text(&self.registers[1])?.chars().collect();
+        let mut bounds = [None; 3];
+        for (i, bound) in bounds.iter_mut().enumerate() {
+            let cell = self.deref(&self.registers[i + 2]);
+            *bound = match cell {
+                HeapCell::Integer(n) if n < 0 => return Err(domain_error("not_less_than_zero", Term::Integer(n))),
+                HeapCell::Integer(n) => Some(n as usize),
+                _ if self.is_unbound(&cell) => None,
+                _ => return Err(type_error("integer", self.decode_cell(&cell)?)),
+            };
+        }
+        let sub = self.deref(&self.registers[5]);
+        let sub: Option<Vec<char>> = match &sub {
+            HeapCell::Constant(name) => Some(name.chars().collect()),
+            _ if self.is_unbound(&sub) => None,
+            _ => return Err(type_error("atom", self.decode_cell(&sub)?)),
+        };
+        let n = text.len();
+        let mut matches = Vec::new();
+        for before in 0..=n {
+            for length in 0..=n - before {
+                let after = n - before - length;
+                let part = &text[before..before + length];
+                let fits = [before, length, after].iter().zip(&bounds).all(|(value, bound)| bound.is_none_or(|b| b == *value));
+                if fits && sub.as_ref().is_none_or(|sub| sub.as_slice() == part) {
+                    let args = vec![
+                        HeapCell::Integer(before as i64),
+                        HeapCell::Integer(length as i64),
+                        HeapCell::Integer(after as i64),
+                        HeapCell::Constant(part.iter().collect()),
+                    ];
+                    matches.push(self.push_built_structure("sub", args));
+                }
+            }
+        }
+        let pattern = self.registers[2..=5].to_vec();
+        self.registers[1] = self.push_built_structure("sub", pattern);
+        self.registers[2] = self.build_list(matches, HeapCell::Constant(NIL.to_string()));
+        alternatives(self)
+    }
+
+    /// `number_codes(?Number, ?Codes)`: Codes is the list of the
+    /// character codes of Number as Prolog writes it. Codes is parsed
+    /// whenever it is a list of codes.
+    pub(crate) fn number_codes(&mut self) -> Result<bool, WamError> {
+        let number = self.deref(&self.registers[1]);
+        let list = self.registers[2].clone();
+        match &number {
+            HeapCell::Integer(_) | HeapCell::BigInteger(_) | HeapCell::Float(_) => {}
+            _ if self.is_unbound(&number) => {}
+            _ => return Err(type_error("number", self.decode_cell(&number)?)),
+        }
+        if self.is_unbound(&number) || self.list_elements(&list).is_ok() {
+            let text = self.list_text(&list, false)?;
+            let parsed = parse_number(&text).ok_or_else(illegal_number)?;
+            let parsed = self.build_copy(&parsed);
+            return Ok(self.unify(number, parsed));
+        }
+        self.list_or_partial_list(&list)?;
+        let text = self.text_of(&number).unwrap();
+        let codes = self.text_list(&text, false);
+        Ok(self.unify(list, codes))
+    }
+
+    /// `atom_number(?Atom, ?Number)`: Atom is the text of Number. Fails
+    /// when Atom is not the text of a number.
+    pub(crate) fn atom_number(&mut self) -> Result<bool, WamError> {
+        let atom = self.deref(&self.registers[1]);
+        let number = self.deref(&self.registers[2]);
+        match &atom {
+            HeapCell::Constant(name) => match parse_number(name) {
+                Some(parsed) => {
+                    let parsed = self.build_copy(&parsed);
+                    Ok(self.unify(number, parsed))
+                }
+                None => Ok(false),
+            },
+            _ if !self.is_unbound(&atom) => Err(type_error("atom", self.decode_cell(&atom)?)),
+            _ => match &number {
+                HeapCell::Integer(_) | HeapCell::BigInteger(_) | HeapCell::Float(_) => {
+                    let text = self.text_of(&number).unwrap();
+                    Ok(self.unify(atom, HeapCell::Constant(text)))
+                }
+                _ if self.is_unbound(&number) => Err(instantiation_error()),
+                _ => Err(type_error("number", self.decode_cell(&number)?)),
+            },
+        }
+    }
+
+    /// `upcase_atom(+Atom, ?Upper)`: Upper is Atom with every letter in
+    /// upper case.
+    pub(crate) fn upcase_atom(&mut self) -> Result<bool, WamError> {
+        let text = self.atomic_text(&self.registers[1])?;
+        Ok(self.unify(self.registers[2].clone(), HeapCell::Constant(text.to_uppercase())))
+    }
+
+    /// `term_to_atom(?Term, ?Atom)`: Atom is the text of Term. With Atom
+    /// bound, it is parsed and the term unified with Term.
+    pub(crate) fn term_to_atom(&mut self) -> Result<bool, WamError> {
+        let term = self.registers[1].clone();
+        let atom = self.deref(&self.registers[2]);
+        if self.is_unbound(&atom) {
+            if self.is_unbound(&self.deref(&term)) {
+                return Err(instantiation_error());
+            }
+            let text = self.write_cell(&term, &WriteOptions::writeq())?;
+            return Ok(self.unify(atom, HeapCell::Constant(text)));
+        }
+        let text = self.atomic_text(&atom)?;
+        let parsed = parse_term_text(&text).map_err(|error| syntax_error(&error))?;
+        let parsed = self.build_copy(&parsed);
+        Ok(self.unify(term, parsed))
+    }
+
+    /// `atomic_list_concat(+List, ?Atom)`: Atom is the texts of the atomic
+    /// terms of List joined together.
+    pub(crate) fn atomic_list_concat2(&mut self) -> Result<bool, WamError> {
+        let list = self.registers[1].clone();
+        let text = self.joined_text(&list, "")?;
+        Ok(self.unify(self.registers[2].clone(), HeapCell::Constant(text)))
+    }
+
+    /// `atomic_list_concat(?List, +Separator, ?Atom)`: Atom is the texts
+    /// of List joined with Separator between them. When List is not fully
+    /// instantiated, Atom is split at each Separator instead.
+    pub(crate) fn atomic_list_concat3(&mut self) -> Result<bool, WamError> {
+        let list = self.registers[1].clone();
+        let separator = self.atomic_text(&self.registers[2])?;
+        let atom = self.deref(&self.registers[3]);
+        match self.joined_text(&list, &separator) {
+            Err(WamError::Exception(error)) if !self.is_unbound(&atom) && is_instantiation_error(&error) => {}
+            joined => return Ok(self.unify(atom, HeapCell::Constant(joined?))),
+        }
+        if separator.is_empty() {
+            return Err(domain_error("non_empty_atom", Term::Atom(separator)));
+        }
+        self.list_or_partial_list(&list)?;
+        let text = self.atomic_text(&self.registers[3])?;
+        let parts = text.split(separator.as_str()).map(|part| HeapCell::Constant(part.to_string())).collect();
+        let parts = self.build_list(parts, HeapCell::Constant(NIL.to_string()));
+        Ok(self.unify(list, parts))
+    }
+
+    /// The texts of the atomic terms of the list `cell` stands for, joined
+    /// with `separator`.
+    fn joined_text(&self, cell: &HeapCell, separator: &str) -> Result<String, WamError> {
+        let texts = self.list_elements(cell)?.iter().map(|element| self.atomic_text(element)).collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join(separator))
+    }
+}
+
+fn is_instantiation_error(error: &Term) -> bool {
+    matches!(error, Term::Structure { args, .. } if args[0] == Term::Atom("instantiation_error".to_string()))
+}
+
+/// The character with code `code`.
+pub(crate) fn character(code: i64) -> Result<char, WamError> {
+    u32::try_from(code).ok().and_then(char::from_u32).ok_or_else(|| representation_error("character_code"))
+}
+
+/// The number `text` is the Prolog syntax of, allowing leading layout;
+/// `None` when it is not the text of a number.
+fn parse_number(text: &str) -> Option<Term> {
+    match parse_term_text(text) {
+        Ok(term @ (Term::Integer(_) | Term::BigInteger(_) | Term::Float(_))) => Some(term),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    #[test]
+    fn test_atoms_and_lists_of_codes() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "atom_codes(abc, L)"), vec!["L = [97, 98, 99]"]);
+        assert_eq!(answers(&mut wam, "atom_codes(A, [0'h, 0'i])"), vec!["A = hi"]);
+        assert_eq!(answers(&mut wam, "atom_chars(A, [h, 'é']), atom_length(A, N)"), vec!["A = hé,\nN = 2"]);
+        assert_eq!(answers(&mut wam, "atom_chars(12, L)"), vec!["L = ['1', '2']"]);
+        assert_eq!(answers(&mut wam, "char_code(C, 955), char_code(a, X)"), vec!["C = λ,\nX = 97"]);
+        assert_eq!(answers(&mut wam, "upcase_atom('straße', U)"), vec!["U = 'STRASSE'"]);
+        assert_eq!(exception(&mut wam, "atom_codes(_, [0'a|_])"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "atom_chars(_, [ab])"), "error(type_error(character, ab), _)");
+        assert_eq!(exception(&mut wam, "char_code(_, -1)"), "error(representation_error(character_code), _)");
+        assert_eq!(exception(&mut wam, "atom_length(f(x), _)"), "error(type_error(atomic, f(x)), _)");
+    }
+
+    #[test]
+    fn test_atom_concat_and_sub_atom() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "atom_concat(abc, 12, A)"), vec!["A = abc12"]);
+        assert_eq!(answers(&mut wam, "atom_concat(X, Y, 'aé')"), vec![
+            "X = '',\nY = aé",
+            "X = a,\nY = é",
+            "X = aé,\nY = ''"
+        ]);
+        assert_eq!(answers(&mut wam, "atom_concat(X, c, abc)"), vec!["X = ab"]);
+        assert_eq!(answers(&mut wam, "sub_atom(abcab, B, _, A, ab)"), vec!["B = 0,\nA = 3", "B = 3,\nA = 0"]);
+        assert_eq!(answers(&mut wam, "sub_atom(hello, 1, 3, A, S)"), vec!["A = 1,\nS = ell"]);
+        assert_eq!(answers(&mut wam, "sub_atom(abc, B, 2, A, S)"), vec!["B = 0,\nA = 1,\nS = ab", "B = 1,\nA = 0,\nS = bc"]);
+        assert_eq!(exception(&mut wam, "atom_concat(_, b, _)"), "error(instantiation_error, _)");
+    }
+
+    #[test]
+    fn test_numbers_and_terms_as_text() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "number_codes(N, \" 42\")"), vec!["N = 42"]);
+        assert_eq!(answers(&mut wam, "number_codes(1.5, L), atom_codes(A, L)"), vec!["L = [49, 46, 53],\nA = '1.5'"]);
+        assert_eq!(answers(&mut wam, "atom_number('-7', N), atom_number(A, 2.5)"), vec!["N = -7,\nA = '2.5'"]);
+        assert!(answers(&mut wam, "atom_number(foo, _)").is_empty());
+        assert_eq!(answers(&mut wam, "atom_number('0x1A', H), atom_number('0o17', O), atom_number('0b101', B)"), vec![
+            "H = 26,\nO = 15,\nB = 5",
+        ]);
+        assert_eq!(exception(&mut wam, "number_codes(_, \"foo\")"), "error(syntax_error(illegal_number), _)");
+        assert_eq!(answers(&mut wam, "term_to_atom(f('A b', [1]), A)"), vec!["A = 'f(\\'A b\\',[1])'"]);
+        assert_eq!(answers(&mut wam, "term_to_atom(T, 'g(X, Y, X)'), T = g(1, 2, Z)"), vec!["T = g(1, 2, 1),\nZ = 1"]);
+        // Variables in the text are always new, even when named as decoding
+        // names existing ones.
+        assert_eq!(answers(&mut wam, "X = a, term_to_atom(T, 'f(_G0)'), T = f(Y)"), vec!["X = a,\nT = f(Y)"]);
+        assert_eq!(answers(&mut wam, "term_to_atom(f(X), A), term_to_atom(T, A), T == f(X)"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_atomic_list_concat() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "atomic_list_concat([a, 1, 'b c'], A)"), vec!["A = 'a1b c'"]);
+        assert_eq!(answers(&mut wam, "atomic_list_concat([a, b, c], '-', A)"), vec!["A = 'a-b-c'"]);
+        assert_eq!(answers(&mut wam, "atomic_list_concat(L, ',', 'x,,y')"), vec!["L = [x, '', y]"]);
+        assert_eq!(answers(&mut wam, "atomic_list_concat([x, Y], '-', 'x-y')"), vec!["Y = y"]);
+        assert_eq!(exception(&mut wam, "atomic_list_concat(_, '', abc)"), "error(domain_error(non_empty_atom, ''), _)");
+        assert_eq!(exception(&mut wam, "atomic_list_concat([a, _], _)"), "error(instantiation_error, _)");
+    }
+}
@@ -0,0 +1,413 @@
+// src/runtime/builtins.rs
+use std::cmp::Ordering;
+
+use crate::compiler::clause::variables;
+use crate::parser::ast::{Clause, Term};
+use crate::runtime::writer::WriteOptions;
+use crate::runtime::{aggregate, arithmetic, meta, order};
+use crate::wam::data_structures::{Builtin, HeapCell, PredicateTable, WamEmulator};
+use crate::wam::error::{instantiation_error, representation_error, type_error, WamError};
+use crate::wam::instruction::Comparison;
+
+/// Built-in predicates, installed into every new emulator.
+const BUILTINS: &[(&str, usize, Builtin)] = &[
+    ("true", 0, |_| Ok(true)),
+    ("fail", 0, |_| Ok(false)),
+    ("false", 0, |_| Ok(false)),
+    ("=", 2, unify),
+    ("call", 1, call),
+    ("call", 2, |wam| meta::call_n(wam, 2)),
+    ("call", 3, |wam| meta::call_n(wam, 3)),
+    ("call", 4, |wam| meta::call_n(wam, 4)),
+    ("call", 5, |wam| meta::call_n(wam, 5)),
+    ("call", 6, |wam| meta::call_n(wam, 6)),
+    ("call", 7, |wam| meta::call_n(wam, 7)),
+    ("call", 8, |wam| meta::call_n(wam, 8)),
+    (">>", 2, |wam| meta::lambda(wam, 2)),
+    (">>", 3, |wam| meta::lambda(wam, 3)),
+    (">>", 4, |wam| meta::lambda(wam, 4)),
+    (">>", 5, |wam| meta::lambda(wam, 5)),
+    (">>", 6, |wam| meta::lambda(wam, 6)),
+    (">>", 7, |wam| meta::lambda(wam, 7)),
+    (">>", 8, |wam| meta::lambda(wam, 8)),
+    (">>", 9, |wam| meta::lambda(wam, 9)),
+    ("/", 2, |wam| meta::free_lambda(wam, 2)),
+    ("/", 3, |wam| meta::free_lambda(wam, 3)),
+    ("/", 4, |wam| meta::free_lambda(wam, 4)),
+    ("/", 5, |wam| meta::free_lambda(wam, 5)),
+    ("/", 6, |wam| meta::free_lambda(wam, 6)),
+    ("/", 7, |wam| meta::free_lambda(wam, 7)),
+    ("/", 8, |wam| meta::free_lambda(wam, 8)),
+    ("/", 9, |wam| meta::free_lambda(wam, 9)),
+    ("\\", 1, |wam| meta::local_lambda(wam, 1)),
+    ("\\", 2, |wam| meta::local_lambda(wam, 2)),
+    ("\\", 3, |wam| meta::local_lambda(wam, 3)),
+    ("\\", 4, |wam| meta::local_lambda(wam, 4)),
+    ("\\", 5, |wam| meta::local_lambda(wam, 5)),
+    ("\\", 6, |wam| meta::local_lambda(wam, 6)),
+    ("\\", 7, |wam| meta::local_lambda(wam, 7)),
+    ("\\", 8, |wam| meta::local_lambda(wam, 8)),
+    ("^", 3, |wam| meta::lambda_parameter(wam, 3)),
+    ("^", 4, |wam| meta::lambda_parameter(wam, 4)),
+    ("^", 5, |wam| meta::lambda_parameter(wam, 5)),
+    ("^", 6, |wam| meta::lambda_parameter(wam, 6)),
+    ("^", 7, |wam| meta::lambda_parameter(wam, 7)),
+    ("^", 8, |wam| meta::lambda_parameter(wam, 8)),
+    ("^", 9, |wam| meta::lambda_parameter(wam, 9)),
+    ("catch", 3, WamEmulator::catch),
+    ("throw", 1, WamEmulator::throw),
+    ("asserta", 1, WamEmulator::asserta),
+    ("assertz", 1, WamEmulator::assertz),
+    ("assert", 1, WamEmulator::assertz),
+    ("retract", 1, WamEmulator::retract),
+    ("retractall", 1, WamEmulator::retractall),
+    ("abolish", 1, WamEmulator::abolish),
+    ("clause", 2, WamEmulator::clause),
+    ("dynamic", 1, WamEmulator::dynamic),
+    ("findall", 3, WamEmulator::findall3),
+    ("findall", 4, WamEmulator::findall4),
+    ("forall", 2, WamEmulator::forall),
+    ("bagof", 3, WamEmulator::bagof3),
+    ("setof", 3, WamEmulator::setof3),
+    ("aggregate_all", 3, WamEmulator::aggregate_all),
+    ("$member", 2, aggregate::alternatives),
+    ("atom_codes", 2, WamEmulator::atom_codes),
+    ("atom_chars", 2, WamEmulator::atom_chars),
+    ("char_code", 2, WamEmulator::char_code),
+    ("atom_length", 2, WamEmulator::atom_length),
+    ("atom_concat", 3, WamEmulator::atom_concat),
+    ("sub_atom", 5, WamEmulator::sub_atom),
+    ("number_codes", 2, WamEmulator::number_codes),
+    ("atom_number", 2, WamEmulator::atom_number),
+    ("upcase_atom", 2, WamEmulator::upcase_atom),
+    ("term_to_atom", 2, WamEmulator::term_to_atom),
+    ("atomic_list_concat", 2, WamEmulator::atomic_list_concat2),
+    ("atomic_list_concat", 3, WamEmulator::atomic_list_concat3),
+    ("open", 3, |wam| wam.open(3)),
+    ("open", 4, |wam| wam.open(4)),
+    ("open_string", 2, WamEmulator::open_string),
+    ("close", 1, |wam| wam.close(1)),
+    ("close", 2, |wam| wam.close(2)),
+    ("current_input", 1, |wam| wam.current_stream(true)),
+    ("current_output", 1, |wam| wam.current_stream(false)),
+    ("set_input", 1, |wam| wam.set_stream(true)),
+    ("set_output", 1, |wam| wam.set_stream(false)),
+    ("get_char", 1, |wam| wam.get_char(1, false)),
+    ("get_char", 2, |wam| wam.get_char(2, false)),
+    ("peek_char", 1, |wam| wam.get_char(1, true)),
+    ("peek_char", 2, |wam| wam.get_char(2, true)),
+    ("put_char", 1, |wam| wam.put_char(1)),
+    ("put_char", 2, |wam| wam.put_char(2)),
+    ("get_byte", 1, |wam| wam.get_byte(1, false)),
+    ("get_byte", 2, |wam| wam.get_byte(2, false)),
+    ("peek_byte", 1, |wam| wam.get_byte(1, true)),
+    ("peek_byte", 2, |wam| wam.get_byte(2, true)),
+    ("put_byte", 1, |wam| wam.put_byte(1)),
+    ("put_byte", 2, |wam| wam.put_byte(2)),
+    ("read", 1, |wam| wam.read(1)),
+    ("read", 2, |wam| wam.read(2)),
+    ("write", 1, |wam| wam.write(1, WriteOptions::write())),
+    ("write", 2, |wam| wam.write(2, WriteOptions::write())),
+    ("print", 1, |wam| wam.write(1, WriteOptions::writeq())),
+    ("print", 2, |wam| wam.write(2, WriteOptions::writeq())),
+    ("writeq", 1, |wam| wam.write(1, WriteOptions::writeq())),
+    ("writeq", 2, |wam| wam.write(2, WriteOptions::writeq())),
+    ("write_canonical", 1, |wam| wam.write(1, WriteOptions::canonical())),
+    ("write_canonical", 2, |wam| wam.write(2, WriteOptions::canonical())),
+    ("write_term", 2, |wam| wam.write_term(2)),
+    ("write_term", 3, |wam| wam.write_term(3)),
+    ("format", 1, |wam| wam.format(1)),
+    ("format", 2, |wam| wam.format(2)),
+    ("format", 3, |wam| wam.format(3)),
+    ("format_atom", 3, WamEmulator::format_atom),
+    ("sformat", 3, WamEmulator::sformat),
+    ("with_output_to", 2, WamEmulator::with_output_to),
+    ("nl", 0, |wam| wam.nl(0)),
+    ("nl", 1, |wam| wam.nl(1)),
+    ("flush_output", 0, |wam| wam.flush_output(0)),
+    ("flush_output", 1, |wam| wam.flush_output(1)),
+    ("stream_property", 2, WamEmulator::stream_property),
+    ("compare", 3, WamEmulator::compare),
+    ("==", 2, |wam| order::compare_terms(wam, Ordering::is_eq)),
+    ("\\==", 2, |wam| order::compare_terms(wam, Ordering::is_ne)),
+    ("@<", 2, |wam| order::compare_terms(wam, Ordering::is_lt)),
+    ("@>", 2, |wam| order::compare_terms(wam, Ordering::is_gt)),
+    ("@=<", 2, |wam| order::compare_terms(wam, Ordering::is_le)),
+    ("@>=", 2, |wam| order::compare_terms(wam, Ordering::is_ge)),
+    ("sort", 2, WamEmulator::sort2),
+    ("sort", 4, WamEmulator::sort4),
+    ("msort", 2, WamEmulator::msort),
+    ("keysort", 2, WamEmulator::keysort),
+    ("predsort", 3, WamEmulator::predsort),
+    ("var", 1, |wam| type_test(wam, WamEmulator::is_unbound)),
+    ("nonvar", 1, |wam| type_test(wam, |wam, cell| !wam.is_unbound(cell))),
+    ("integer", 1, |wam| type_test(wam, |_, cell| matches!(cell, HeapCell::Integer(_) | HeapCell::BigInteger(_)))),
+    ("float", 1, |wam| type_test(wam, |_, cell| matches!(cell, HeapCell::Float(_)))),
+    ("number", 1, |wam| type_test(wam, |_, cell| is_number(cell))),
+    ("atom", 1, |wam| type_test(wam, |_, cell| matches!(cell, HeapCell::Constant(_)))),
+    ("atomic", 1, |wam| type_test(wam, |_, cell| matches!(cell, HeapCell::Constant(_)) || is_number(cell))),
+    ("compound", 1, |wam| type_test(wam, |wam, cell| wam.compound_cell(cell).is_some())),
+    ("callable", 1, |wam| type_test(wam, |wam, cell| matches!(cell, HeapCell::Constant(_)) || wam.compound_cell(cell).is_some())),
+    ("is_list", 1, |wam| type_test(wam, |wam, cell| wam.list_elements(cell).is_ok())),
+    ("functor", 3, WamEmulator::functor),
+    ("arg", 3, WamEmulator::arg),
+    ("=..", 2, WamEmulator::univ),
+    ("copy_term", 2, WamEmulator::copy_term),
+    ("setarg", 3, WamEmulator::setarg),
+    ("nb_setarg", 3, WamEmulator::nb_setarg),
+    ("term_variables", 2, WamEmulator::term_variables2),
+    ("numbervars", 3, WamEmulator::numbervars),
+    ("is", 2, arithmetic::is),
+    ("=:=", 2, |wam| arithmetic::compare(wam, Comparison::Equal)),
+    ("=\\=", 2, |wam| arithmetic::compare(wam, Comparison::NotEqual)),
+    ("<", 2, |wam| arithmetic::compare(wam, Comparison::Less)),
+    (">", 2, |wam| arithmetic::compare(wam, Comparison::Greater)),
+    ("=<", 2, |wam| arithmetic::compare(wam, Comparison::LessOrEqual)),
+    (">=", 2, |wam| arithmetic::compare(wam, Comparison::GreaterOrEqual)),
+    ("succ", 2, succ),
+];
+
+pub(crate) fn register(predicates: &mut PredicateTable) {
+    for &(name, arity, builtin) in BUILTINS {
+        let id = predicates.id(name, arity);
+        predicates.get_mut(id).builtin = Some(builtin);
+    }
+}
+
+/// Dereferenced argument register Ai.
+fn argument(wam: &WamEmulator, i: usize) -> HeapCell {
+    wam.deref(&wam.registers[i])
+}
+
+/// Checks that `cell` is unbound or a non-negative integer.
+fn natural_or_variable(wam: &WamEmulator, cell: &HeapCell) -> Result<(), WamError> {
+    match cell {
+        HeapCell::Integer(n) if *n < 0 => Err(type_error("not_less_than_zero", wam.decode_cell(cell)?)),
+        HeapCell::Integer(_) => Ok(()),
+        _ if wam.is_unbound(cell) => Ok(()),
+        _ => Err(type_error("integer", wam.decode_cell(cell)?)),
+    }
+}
+
+/// `var(@X)`, `atom(@X)` and the other type tests: succeeds when `test`
+/// holds for the dereferenced X.
+fn type_test(wam: &mut WamEmulator, test: fn(&WamEmulator, &HeapCell) -> bool) -> Result<bool, WamError> {
+    Ok(test(wam, &argument(wam, 1)))
+}
+
+fn is_number(cell: &HeapCell) -> bool {
+    matches!(cell, HeapCell::Integer(_) | HeapCell::BigInteger(_) | HeapCell::Float(_))
+}
+
+/// `?X = ?Y`: unifies X and Y.
+fn unify(wam: &mut WamEmulator) -> Result<bool, WamError> {
+    Ok(wam.unify(wam.registers[1].clone(), wam.registers[2].clone()))
+}
+
+/// `call(+Goal)`: runs Goal. A goal calling a predicate is called directly.
+/// One with control constructs is compiled as the body of a clause for a
+/// predicate of its own, `$call<N>`, taking its variables as arguments, so
+/// a cut inside it only cuts that predicate. The atomic arguments of the
+/// goals inside are passed as arguments too, so goals that only differ in
+/// their variables and constants share one predicate.
+pub(crate) fn call(wam: &mut WamEmulator) -> Result<bool, WamError> {
+    let cell = argument(wam, 1);
+    match cell {
+        _ if wam.is_unbound(&cell) => return Err(instantiation_error()),
+        HeapCell::Integer(_) => return Err(type_error("callable", wam.decode_cell(&cell)?)),
+        HeapCell::Constant(ref name) if !is_control(name, 0) => {
+            let id = wam.predicates.id(name, 0);
+            return wam.call_predicate(id);
+        }
+        _ => {}
+    }
+    if let Some((name, args)) = wam.compound_cell(&cell).filter(|(name, args)| !is_control(name, args.len())) {
+        for (i, &arg) in args.iter().enumerate() {
+            wam.registers[i + 1] = HeapCell::Reference(arg);
+        }
+        let id = wam.predicates.id(&name, args.len());
+        return wam.call_predicate(id);
+    }
+    let goal = wam.decode_cell(&cell)?;
+    let mut names = Vec::new();
+    variables(&goal, &mut names);
+    let addresses: Vec<usize> = names.iter().map(|name| name["_G".len()..].parse().unwrap()).collect();
+    let renamed: Vec<(String, String)> =
+        names.iter().enumerate().map(|(i, name)| (name.clone(), format!("_V{}", i + 1))).collect();
+    let mut constants = Vec::new();
+    let skeleton = abstract_constants(&rename_variables(&goal, &renamed), &mut constants);
+
+    let key = format!("{:?}", skeleton);
+    let arity = addresses.len() + constants.len();
+    let id = match wam.goal_predicates.get(&key) {
+        Some(&id) => id,
+        None => {
+            let name = format!("$call{}", wam.goal_predicates.len() + 1);
+            let args: Vec<Term> = renamed
+                .iter()
+                .map(|(_, name)| Term::Variable(name.clone()))
+                .chain((1..=constants.len()).map(|i| Term::Variable(format!("_C{}", i))))
+                .collect();
+            let head = match arity {
+                0 => Term::Atom(name.clone()),
+                arity => Term::Structure { functor: name.clone(), arity, args },
+            };
+            let id = wam.predicates.id(&name, arity);
+            wam.predicates.get_mut(id).clauses = vec![Clause { head, body: vec![skeleton] }];
+            if wam.load_predicate(id).is_err() {
+                return Err(type_error("callable", goal));
+            }
+            wam.goal_predicates.insert(key, id);
+            id
+        }
+    };
+    let cells: Vec<HeapCell> = addresses
+        .into_iter()
+        .map(HeapCell::Reference)
+        .chain(constants.iter().map(|constant| wam.build_copy(constant)))
+        .collect();
+    for (i, cell) in cells.into_iter().enumerate() {
+        wam.registers[i + 1] = cell;
+    }
+    wam.call_predicate(id)
+}
+
+/// Replaces the atomic subterms in the arguments of the goals in `goal`
+/// by variables `_C1`, `_C2`, ..., appending them to `constants`. Goals
+/// themselves and the goals inside control constructs are kept, so the
+/// result compiles to the same control flow.
+fn abstract_constants(goal: &Term, constants: &mut Vec<Term>) -> Term {
+    fn data(term: &Term, constants: &mut Vec<Term>) -> Term {
+        match term {
+            Term::Variable(_) => term.clone(),
+            Term::Structure { functor, arity, args } => Term::Structure {
+                functor: functor.clone(),
+                arity: *arity,
+                args: args.iter().map(|arg| data(arg, constants)).collect(),
+            },
+            Term::List(elements) if !elements.is_empty() => {
+                Term::List(elements.iter().map(|element| data(element, constants)).collect())
+            }
+            atomic => {
+                constants.push(atomic.clone());
+                Term::Variable(format!("_C{}", constants.len()))
+            }
+        }
+    }
+    match goal {
+        Term::Structure { functor, arity, args } => Term::Structure {
+            functor: functor.clone(),
+            arity: *arity,
+            args: args
+                .iter()
+                .map(|arg| match is_control(functor, *arity) {
+                    true => abstract_constants(arg, constants),
+                    false => data(arg, constants),
+                })
+                .collect(),
+        },
+        other => other.clone(),
+    }
+}
+
+/// True for the control constructs the compiler lays out inline rather
+/// than calling, which `call/1` has to compile.
+fn is_control(name: &str, arity: usize) -> bool {
+    matches!(
+        (name, arity),
+        ("!", 0) | (",", 2) | (";", 2) | ("->", 2) | ("\\+", 1) | ("once", 1) | ("ignore", 1)
+    )
+}
+
+/// `goal` with `extra` appended to its arguments, as `call/N` calls it.
+pub(crate) fn add_arguments(wam: &mut WamEmulator, goal: &HeapCell, extra: Vec<HeapCell>) -> Result<HeapCell, WamError> {
+    let goal = wam.deref(goal);
+    match &goal {
+        _ if wam.is_unbound(&goal) => Err(instantiation_error()),
+        HeapCell::Constant(name) => {
+            let name = name.clone();
+            Ok(wam.push_built_structure(&name, extra))
+        }
+        _ => match wam.compound_cell(&goal) {
+            Some((functor, args)) => {
+                let args = args.into_iter().map(HeapCell::Reference).chain(extra).collect();
+                Ok(wam.push_built_structure(&functor, args))
+            }
+            None => Err(type_error("callable", wam.decode_cell(&goal)?)),
+        },
+    }
+}
+
+/// Renames the variables of `term` by the `(old, new)` pairs in `names`.
+fn rename_variables(term: &Term, names: &[(String, String)]) -> Term {
+    match term {
+        Term::Variable(name) => match names.iter().find(|(old, _)| old == name) {
+            Some((_, new)) => Term::Variable(new.clone()),
+            None => term.clone(),
+        },
+        Term::Structure { functor, arity, args } => Term::Structure {
+            functor: functor.clone(),
+            arity: *arity,
+            args: args.iter().map(|arg| rename_variables(arg, names)).collect(),
+        },
+        Term::List(elements) => Term::List(elements.iter().map(|element| rename_variables(element, names)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// `succ(?Int1, ?Int2)`: Int2 is Int1 + 1 and both are natural numbers.
+fn succ(wam: &mut WamEmulator) -> Result<bool, WamError> {
+    let x = argument(wam, 1);
+    let y = argument(wam, 2);
+    natural_or_variable(wam, &x)?;
+    natural_or_variable(wam, &y)?;
+    match (x, y) {
+        (HeapCell::Integer(x), y) => {
+            let next = x.checked_add(1).ok_or_else(|| representation_error("max_integer"))?;
+            Ok(wam.unify(y, HeapCell::Integer(next)))
+        }
+        (_, HeapCell::Integer(0)) => Ok(false),
+        (x, HeapCell::Integer(y)) => Ok(wam.unify(x, HeapCell::Integer(y - 1))),
+        _ => Err(instantiation_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::emulator::tests::{answers, exception};
+
+    #[test]
+    fn test_succ() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "succ(3, X)"), vec!["X = 4"]);
+        assert_eq!(answers(&mut wam, "succ(X, 4)"), vec!["X = 3"]);
+        assert_eq!(answers(&mut wam, "succ(3, 4)"), vec!["true"]);
+        assert!(answers(&mut wam, "succ(X, 0)").is_empty());
+    }
+
+    #[test]
+    fn test_type_tests() {
+        let mut wam = WamEmulator::new();
+        let goals = [
+            ("var(X), nonvar(f(X)), \\+ var(a), \\+ nonvar(_)", true),
+            ("integer(3), integer(99999999999999999999), \\+ integer(3.0), float(3.0), number(1), number(1.5)", true),
+            ("atom(a), atom([]), \\+ atom(1), \\+ atom(f(a)), atomic(a), atomic(1.5), \\+ atomic(f(a))", true),
+            ("compound(f(a)), compound([a]), \\+ compound(a), \\+ compound(_)", true),
+            ("callable(a), callable(f(a)), \\+ callable(1), \\+ callable(_)", true),
+            ("is_list([]), is_list([a, b]), \\+ is_list([a|_]), \\+ is_list([a|b]), \\+ is_list(_)", true),
+            ("X = a, var(X)", false),
+        ];
+        for (goal, succeeds) in goals {
+            assert_eq!(!answers(&mut wam, goal).is_empty(), succeeds, "{}", goal);
+        }
+    }
+
+    #[test]
+    fn test_succ_errors() {
+        let mut wam = WamEmulator::new();
+        assert!(exception(&mut wam, "succ(X, Y)").starts_with("error(instantiation_error"));
+        assert!(exception(&mut wam, "succ(a, X)").starts_with("error(type_error(integer, a)"));
+        assert!(exception(&mut wam, "succ(X, -1)").contains("not_less_than_zero"));
+    }
+}
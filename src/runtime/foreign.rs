@@ -0,0 +1,288 @@
+// src/runtime/foreign.rs
+use std::any::Any;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::parser::ast::Term;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::error::{domain_error, indicator, permission_error, system_error, WamError};
+use crate::wam::instruction::Instruction;
+
+/// What a call of a nondeterministic foreign predicate did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nondet<S> {
+    /// No (more) solutions.
+    Fail,
+    /// Succeeded for the last time.
+    Last,
+    /// Succeeded, and on backtracking the predicate is called again with
+    /// this state.
+    Redo(S),
+}
+
+type Deterministic = dyn Fn(&mut Context) -> Result<bool, WamError>;
+type Nondeterministic = dyn Fn(&mut Context, Option<Box<dyn Any>>) -> Result<Nondet<Box<dyn Any>>, WamError>;
+
+#[derive(Clone)]
+pub(crate) enum Function {
+    Deterministic(Rc<Deterministic>),
+    Nondeterministic(Rc<Nondeterministic>),
+}
+
+/// A predicate implemented by a Rust closure, registered with
+/// `WamEmulator::register_foreign` or `register_foreign_nondet`.
+pub(crate) struct Foreign {
+    pub(crate) arity: usize,
+    pub(crate) function: Function,
+    /// Address of the `retry_foreign` stub choice points of a
+    /// nondeterministic predicate continue at.
+    pub(crate) retry: usize,
+}
+
+/// The arguments of a foreign predicate call and the machine they live in.
+/// Arguments are numbered from 1. Terms handed to `unify` may name
+/// variables: within one call, a name stands for the same variable, and a
+/// variable of a term returned by `argument` is that argument's variable.
+pub struct Context<'a> {
+    machine: &'a mut WamEmulator,
+    arity: usize,
+    variables: HashMap<String, HeapCell>,
+}
+
+impl Context<'_> {
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Argument `i` as it currently stands. An argument nested too deeply
+    /// to decode raises a resource error.
+    pub fn argument(&self, i: usize) -> Result<Term, WamError> {
+        self.machine.decode_cell(self.register(i)?)
+    }
+
+    /// Whether argument `i` is an unbound variable.
+    pub fn is_unbound(&self, i: usize) -> Result<bool, WamError> {
+        let cell = self.machine.deref(self.register(i)?);
+        Ok(self.machine.is_unbound(&cell))
+    }
+
+    /// Unifies argument `i` with `term`. Bindings made by a call that then
+    /// fails are undone.
+    pub fn unify(&mut self, i: usize, term: &Term) -> Result<bool, WamError> {
+        let arg = self.register(i)?.clone();
+        let cell = self.machine.build_term(term, &mut self.variables);
+        Ok(self.machine.unify(arg, cell))
+    }
+
+    /// Unifies two terms, e.g. parts of arguments.
+    pub fn unify_terms(&mut self, a: &Term, b: &Term) -> bool {
+        let a = self.machine.build_term(a, &mut self.variables);
+        let b = self.machine.build_term(b, &mut self.variables);
+        self.machine.unify(a, b)
+    }
+
+    /// The register holding argument `i`; an `i` outside 1..=arity raises
+    /// `domain_error(argument_index, I)`.
+    fn register(&self, i: usize) -> Result<&HeapCell, WamError> {
+        if !(1..=self.arity).contains(&i) {
+            return Err(domain_error("argument_index", Term::Integer(i as i64)));
+        }
+        Ok(&self.machine.registers[i])
+    }
+}
+
+impl WamEmulator {
+    /// Defines `name/arity` as a deterministic predicate implemented by
+    /// `function`, which returns whether the call succeeded. An error it
+    /// returns is raised in Prolog as an exception; `WamError::Exception`
+    /// raises any term, and the functions of `wam::error` build ISO errors.
+    /// Registering a predicate again replaces it.
+    pub fn register_foreign<F>(&mut self, name: &str, arity: usize, function: F) -> Result<(), WamError>
+    where
+        F: Fn(&mut Context) -> Result<bool, WamError> + 'static,
+    {
+        self.define_foreign(name, arity, Function::Deterministic(Rc::new(function)))
+    }
+
+    /// Defines `name/arity` as a nondeterministic predicate. `function` is
+    /// first called with no state; when it returns `Nondet::Redo(state)`,
+    /// backtracking into the call calls it again with that state, until it
+    /// returns `Fail` or `Last`. A cut discards the state.
+    pub fn register_foreign_nondet<S, F>(&mut self, name: &str, arity: usize, function: F) -> Result<(), WamError>
+    where
+        S: 'static,
+        F: Fn(&mut Context, Option<S>) -> Result<Nondet<S>, WamError> + 'static,
+    {
+        let erased = move |context: &mut Context, state: Option<Box<dyn Any>>| {
+            let state = match state.map(|state| state.downcast::<S>()) {
+                Some(Ok(state)) => Some(*state),
+                Some(Err(_)) => return Err(system_error("foreign_redo_state")),
+                None => None,
+            };
+            Ok(match function(context, state)? {
+                Nondet::Fail => Nondet::Fail,
+                Nondet::Last => Nondet::Last,
+                Nondet::Redo(state) => Nondet::Redo(Box::new(state) as Box<dyn Any>),
+            })
+        };
+        self.define_foreign(name, arity, Function::Nondeterministic(Rc::new(erased)))
+    }
+
+    fn define_foreign(&mut self, name: &str, arity: usize, function: Function) -> Result<(), WamError> {
+        let id = self.predicates.id(name, arity);
+        let predicate = self.predicates.get(id);
+        if predicate.builtin.is_some() || predicate.entry.is_some() {
+            return Err(permission_error("modify", "static_procedure", indicator(name, arity)));
+        }
+        let index = match predicate.foreign {
+            Some(index) => index,
+            None => {
+                self.foreign.push(Foreign { arity, function: function.clone(), retry: self.code.len() });
+                self.code.push(Instruction::RetryForeign(self.foreign.len() - 1));
+                self.foreign.len() - 1
+            }
+        };
+        self.foreign[index].function = function;
+        self.predicates.get_mut(id).foreign = Some(index);
+        Ok(())
+    }
+
+    /// Calls foreign predicate `index` on the arguments in A1..An, with the
+    /// state its previous call left when it is being retried. A
+    /// nondeterministic predicate gets a choice point of its own, which
+    /// stays only while it has more solutions.
+    pub(crate) fn call_foreign(&mut self, index: usize, state: Option<Box<dyn Any>>) -> Result<bool, WamError> {
+        let Foreign { arity, function, retry } = &self.foreign[index];
+        let (arity, function, retry) = (*arity, function.clone(), *retry);
+        let function = match function {
+            Function::Deterministic(function) => {
+                return function(&mut Context { machine: self, arity, variables: HashMap::new() });
+            }
+            Function::Nondeterministic(function) => function,
+        };
+        let depth = self.choicepoints.len();
+        self.push_choicepoint(retry, arity);
+        let outcome = function(&mut Context { machine: self, arity, variables: HashMap::new() }, state);
+        match outcome {
+            Ok(Nondet::Redo(state)) => {
+                self.redo_states.retain(|(level, _)| *level < depth);
+                self.redo_states.push((depth, state));
+                Ok(true)
+            }
+            outcome => {
+                self.choicepoints.truncate(depth);
+                outcome.map(|outcome| matches!(outcome, Nondet::Last))
+            }
+        }
+    }
+
+    /// Backtracks into foreign predicate `index` through its choice point,
+    /// the newest one.
+    pub(crate) fn retry_foreign(&mut self, index: usize) -> Result<bool, WamError> {
+        let depth = self.choicepoints.len() - 1;
+        self.redo_states.retain(|(level, _)| *level <= depth);
+        let (_, state) = self.redo_states.pop().ok_or_else(|| system_error("foreign_redo_state"))?;
+        self.choicepoints.truncate(depth);
+        self.p = self.cp;
+        self.num_args = self.foreign[index].arity;
+        self.call_foreign(index, Some(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::data_structures::HALT;
+    use crate::wam::error::type_error;
+
+    /// `between(+Low, +High, ?X)` on small integers.
+    fn register_between(wam: &mut WamEmulator) {
+        wam.register_foreign_nondet("int_between", 3, |context, state: Option<i64>| {
+            let (low, high) = match (context.argument(1)?, context.argument(2)?) {
+                (Term::Integer(low), Term::Integer(high)) => (low, high),
+                (Term::Integer(_), culprit) | (culprit, _) => return Err(type_error("integer", culprit)),
+            };
+            let mut next = state.unwrap_or(low);
+            while next <= high {
+                let bound = context.unify(3, &Term::Integer(next))?;
+                next += 1;
+                if bound {
+                    return Ok(if next > high { Nondet::Last } else { Nondet::Redo(next) });
+                }
+            }
+            Ok(Nondet::Fail)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_deterministic_foreign_predicate() {
+        let mut wam = WamEmulator::new();
+        wam.register_foreign("wrap", 2, |context| {
+            let inner = context.argument(1)?;
+            let wrapped = Term::Structure { functor: "w".into(), arity: 2, args: vec![inner, Term::Variable("Fresh".into())] };
+            context.unify(2, &wrapped)
+        })
+        .unwrap();
+        wam.consult("twice(X, Z) :- wrap(X, Y), wrap(Y, Z).").unwrap();
+        assert_eq!(answers(&mut wam, "twice(a, Z), Z = w(w(A, b), c)"), vec!["Z = w(w(a, b), c),\nA = a"]);
+        assert_eq!(answers(&mut wam, "wrap(X, w(Y, b)), X = c"), vec!["X = c,\nY = c"]);
+        assert!(answers(&mut wam, "wrap(a, b)").is_empty());
+    }
+
+    #[test]
+    fn test_nondeterministic_foreign_predicate() {
+        let mut wam = WamEmulator::new();
+        register_between(&mut wam);
+        wam.consult("first_even(X) :- int_between(1, 9, X), 0 is X mod 2, !.").unwrap();
+        assert_eq!(answers(&mut wam, "int_between(1, 3, X)"), vec!["X = 1", "X = 2", "X = 3"]);
+        assert_eq!(answers(&mut wam, "int_between(1, 3, 2)"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "int_between(1, 2, X), int_between(X, 2, Y)"), vec![
+            "X = 1,\nY = 1",
+            "X = 1,\nY = 2",
+            "X = 2,\nY = 2",
+        ]);
+        assert_eq!(answers(&mut wam, "first_even(X), int_between(1, X, Y)"), vec!["X = 2,\nY = 1", "X = 2,\nY = 2"]);
+        assert!(answers(&mut wam, "int_between(3, 1, _)").is_empty());
+    }
+
+    #[test]
+    fn test_foreign_errors_are_exceptions() {
+        let mut wam = WamEmulator::new();
+        register_between(&mut wam);
+        assert_eq!(exception(&mut wam, "int_between(1, a, X)"), "error(type_error(integer, a), _)");
+        wam.register_foreign("raise", 1, |context| Err(WamError::Exception(context.argument(1)?))).unwrap();
+        assert_eq!(exception(&mut wam, "raise(oops(1))"), "oops(1)");
+    }
+
+    #[test]
+    fn test_foreign_misuse_raises_errors() {
+        let mut wam = WamEmulator::new();
+        wam.register_foreign("second", 1, |context| context.argument(2).map(|_| true)).unwrap();
+        wam.register_foreign("bind_none", 1, |context| context.unify(0, &Term::Atom("a".into()))).unwrap();
+        wam.register_foreign("unbound_third", 2, |context| context.is_unbound(3)).unwrap();
+        assert_eq!(exception(&mut wam, "second(a)"), "error(domain_error(argument_index, 2), _)");
+        assert_eq!(exception(&mut wam, "bind_none(X)"), "error(domain_error(argument_index, 0), _)");
+        assert_eq!(exception(&mut wam, "unbound_third(X, Y)"), "error(domain_error(argument_index, 3), _)");
+
+        // Retrying a foreign predicate that left no state behind.
+        register_between(&mut wam);
+        wam.push_choicepoint(HALT, 0);
+        let error = wam.retry_foreign(0).unwrap_err();
+        assert_eq!(error.to_string(), system_error("foreign_redo_state").to_string());
+    }
+
+    #[test]
+    fn test_foreign_registration_is_checked() {
+        let mut wam = WamEmulator::new();
+        assert!(wam.register_foreign("is", 2, |_| Ok(true)).is_err());
+        wam.consult("p(1).").unwrap();
+        assert!(wam.register_foreign("p", 1, |_| Ok(true)).is_err());
+        wam.register_foreign("q", 0, |_| Ok(true)).unwrap();
+        assert!(matches!(wam.consult("q."), Err(WamError::Exception(_))));
+        // A registered predicate can be replaced.
+        wam.register_foreign("q", 0, |_| Ok(false)).unwrap();
+        assert!(answers(&mut wam, "q").is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+// src/runtime/format.rs
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use crate::runtime::atoms::character;
+use crate::runtime::writer::WriteOptions;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::decoder::{LIST_FUNCTOR, NIL};
+use crate::wam::error::{domain_error, format_error, instantiation_error, type_error, WamError};
+
+/// The largest numeric argument a directive takes. Several directives
+/// write as many characters as their argument says.
+const MAX_NUMERIC_ARGUMENT: usize = 1 << 20;
+
+/// Where `format/3` and `with_output_to/2` put their text instead of a
+/// stream: `atom(A)`, `string(S)`, `codes(Cs)`, `codes(Cs, Tail)`,
+/// `chars(Cs)` or `chars(Cs, Tail)`. Strings are lists of codes, as double
+/// quoted text reads.
+enum Sink {
+    Atom(HeapCell),
+    Codes(HeapCell, HeapCell),
+    Chars(HeapCell, HeapCell),
+}
+
+/// The text of one `format/2` call as it is built, with what is needed
+/// to pad it out at column stops.
+struct Formatter {
+    out: String,
+    /// The column of the stream when formatting started.
+    start_column: usize,
+    /// The column of the last column stop, where the current segment
+    /// starts.
+    stop: usize,
+    /// The fill points of the current segment: where in `out` they are
+    /// and the character they pad with.
+    fills: Vec<(usize, char)>,
+}
+
+impl Formatter {
+    fn column(&self) -> usize {
+        match self.out.rfind('\n') {
+            Some(i) => self.out[i + 1..].chars().count(),
+            None => self.start_column + self.out.chars().count(),
+        }
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.stop = 0;
+        self.fills.clear();
+    }
+
+    /// Ends the current segment at `column`, spreading the padding it needs
+    /// over its fill points, or after its text if it has none. A segment
+    /// already past `column` is left as it is.
+    fn column_stop(&mut self, column: usize) {
+        let current = self.column();
+        if current < column {
+            let padding = column - current;
+            if self.fills.is_empty() {
+                self.fills.push((self.out.len(), ' '));
+            }
+            let count = self.fills.len();
+            for (i, &(at, fill)) in self.fills.iter().enumerate().rev() {
+                let width = padding / count + usize::from(i < padding % count);
+                self.out.insert_str(at, &fill.to_string().repeat(width));
+            }
+        }
+        self.stop = column.max(current);
+        self.fills.clear();
+    }
+}
+
+/// The digits of `value` with a decimal point inserted `point` digits from
+/// the right, as `~Nd` writes it, and with `group` a comma between each
+/// three digits before the point, as `~ND` does.
+fn decimal(value: &BigInt, point: usize, group: bool) -> String {
+    let digits = value.magnitude().to_string();
+    let digits = format!("{:0>width$}", digits, width = point + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - point);
+    let mut text = String::new();
+    if value.sign() == num_bigint::Sign::Minus {
+        text.push('-');
+    }
+    for (i, digit) in whole.chars().enumerate() {
+        if group && i > 0 && (whole.len() - i) % 3 == 0 {
+            text.push(',');
+        }
+        text.push(digit);
+    }
+    if point > 0 {
+        text.push('.');
+        text.push_str(fraction);
+    }
+    text
+}
+
+/// `value` in exponential notation with `digits` after the point, as C's
+/// `%e` writes it: `1.500000e+00`.
+fn exponential(value: f64, digits: usize) -> String {
+    let text = format!("{:.*e}", digits, value);
+    match text.split_once('e') {
+        Some((mantissa, exponent)) => {
+            let exponent: i32 = exponent.parse().unwrap_or(0);
+            let sign = if exponent < 0 { '-' } else { '+' };
+            format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+        }
+        None => text,
+    }
+}
+
+/// `value` with `digits` significant digits, as C's `%g` writes it: in
+/// exponential notation only when the exponent is below -4 or not below
+/// `digits`, and without trailing zeros.
+fn general(value: f64, digits: usize) -> String {
+    let digits = digits.max(1);
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    // The exponent after rounding, which can carry into the next power of
+    // ten, as 9.99 to 1.0e+01.
+    let rounded = exponential(value, digits - 1);
+    let exponent: i32 = rounded.split_once('e').and_then(|(_, e)| e.parse().ok()).unwrap_or(0);
+    let text = if exponent < -4 || exponent >= digits as i32 {
+        rounded
+    } else {
+        format!("{:.*}", (digits as i32 - 1 - exponent) as usize, value)
+    };
+    let (mantissa, exponent) = match text.split_once('e') {
+        Some((mantissa, exponent)) => (mantissa.to_string(), format!("e{}", exponent)),
+        None => (text, String::new()),
+    };
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => &mantissa,
+    };
+    format!("{}{}", mantissa, exponent)
+}
+
+impl WamEmulator {
+    /// The sink `cell` stands for, if it is one.
+    fn sink(&self, cell: &HeapCell) -> Option<Sink> {
+        let (functor, args) = self.compound_cell(cell)?;
+        let arg = |i: usize| HeapCell::Reference(args[i]);
+        let nil = || HeapCell::Constant(NIL.to_string());
+        match (functor.as_str(), args.len()) {
+            ("atom", 1) => Some(Sink::Atom(arg(0))),
+            ("string" | "codes", 1) => Some(Sink::Codes(arg(0), nil())),
+            ("codes", 2) => Some(Sink::Codes(arg(0), arg(1))),
+            ("chars", 1) => Some(Sink::Chars(arg(0), nil())),
+            ("chars", 2) => Some(Sink::Chars(arg(0), arg(1))),
+            _ => None,
+        }
+    }
+
+    /// Unifies `sink` with `text`.
+    fn put_sink(&mut self, sink: Sink, text: &str) -> bool {
+        let (target, value) = match sink {
+            Sink::Atom(target) => (target, HeapCell::Constant(text.to_string())),
+            Sink::Codes(target, tail) => {
+                let cells = text.chars().map(|c| HeapCell::Integer(c as i64)).collect();
+                (target, self.build_list(cells, tail))
+            }
+            Sink::Chars(target, tail) => {
+                let cells = text.chars().map(|c| HeapCell::Constant(c.to_string())).collect();
+                (target, self.build_list(cells, tail))
+            }
+        };
+        self.unify(target, value)
+    }
+
+    /// The text `cell` stands for as a format or a `~s` argument: an atom,
+    /// a number, or a list of codes or characters.
+    fn format_text(&self, cell: &HeapCell) -> Result<String, WamError> {
+        let cell = self.deref(cell);
+        match &cell {
+            HeapCell::Constant(name) if name == NIL => Ok(String::new()),
+            _ if self.is_unbound(&cell) => Err(instantiation_error()),
+            _ => match self.compound_cell(&cell) {
+                Some((functor, args)) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                    let chars = matches!(self.deref(&HeapCell::Reference(args[0])), HeapCell::Constant(_));
+                    self.list_text(&cell, chars)
+                }
+                Some(_) => Err(type_error("text", self.decode_cell(&cell)?)),
+                None => self.atomic_text(&cell),
+            },
+        }
+    }
+
+    /// The integer `cell` stands for, as a `BigInt`.
+    fn format_integer(&self, cell: &HeapCell) -> Result<BigInt, WamError> {
+        match self.deref(cell) {
+            HeapCell::Integer(value) => Ok(BigInt::from(value)),
+            HeapCell::BigInteger(value) => Ok(value),
+            cell if self.is_unbound(&cell) => Err(instantiation_error()),
+            cell => Err(type_error("integer", self.decode_cell(&cell)?)),
+        }
+    }
+
+    /// The number `cell` stands for, as a float.
+    fn format_float(&self, cell: &HeapCell) -> Result<f64, WamError> {
+        match self.deref(cell) {
+            HeapCell::Integer(value) => Ok(value as f64),
+            HeapCell::BigInteger(value) => Ok(value.to_f64().unwrap_or(f64::INFINITY)),
+            HeapCell::Float(value) => Ok(value),
+            cell if self.is_unbound(&cell) => Err(instantiation_error()),
+            cell => Err(type_error("number", self.decode_cell(&cell)?)),
+        }
+    }
+
+    /// The text `format` and `args` stand for, formatted from `column` of
+    /// the line on. Args is a list of arguments, or a single argument that
+    /// is not a list.
+    fn formatted(&self, format: &HeapCell, args: &HeapCell, column: usize) -> Result<String, WamError> {
+        let format = self.format_text(format)?;
+        let args = match self.deref(args) {
+            HeapCell::Constant(name) if name == NIL => Vec::new(),
+            cell => match self.compound_cell(&cell) {
+                Some((functor, list)) if functor == LIST_FUNCTOR && list.len() == 2 => self.list_elements(&cell)?,
+                _ => vec![cell],
+            },
+        };
+        let mut args = args.into_iter();
+        let mut next_argument = || args.next().ok_or_else(|| format_error("not enough arguments"));
+        let mut formatter = Formatter { out: String::new(), start_column: column, stop: column, fills: Vec::new() };
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '~' {
+                match c {
+                    '\n' => formatter.newline(),
+                    _ => formatter.out.push(c),
+                }
+                continue;
+            }
+            let numeric = match chars.peek() {
+                Some('*') => {
+                    chars.next();
+                    match self.deref(&next_argument()?) {
+                        HeapCell::Integer(n) if n >= 0 => Some(n.to_usize().unwrap_or(usize::MAX)),
+                        _ => return Err(format_error("no or negative integer for `*' argument")),
+                    }
+                }
+                Some('`') => {
+                    chars.next();
+                    Some(chars.next().ok_or_else(|| format_error("truncated format"))? as usize)
+                }
+                Some(c) if c.is_ascii_digit() => {
+                    let mut n = 0usize;
+                    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                        n = n.checked_mul(10).and_then(|n| n.checked_add(digit as usize)).unwrap_or(usize::MAX);
+                        chars.next();
+                    }
+                    Some(n)
+                }
+                _ => None,
+            };
+            // A fill character given as ~`c is not a count.
+            if numeric.is_some_and(|n| n > MAX_NUMERIC_ARGUMENT && !matches!(chars.peek(), Some('t'))) {
+                return Err(format_error("numeric argument too large"));
+            }
+            let directive = chars.next().ok_or_else(|| format_error("truncated format"))?;
+            let out = &mut formatter.out;
+            match directive {
+                '~' => out.push('~'),
+                'w' | 'p' | 'q' => {
+                    let options = match directive {
+                        'w' => WriteOptions::write(),
+                        _ => WriteOptions::writeq(),
+                    };
+                    out.push_str(&self.write_cell(&next_argument()?, &options)?);
+                }
+                'a' => out.push_str(&self.atomic_text(&next_argument()?)?),
+                'd' | 'D' => {
+                    let value = self.format_integer(&next_argument()?)?;
+                    out.push_str(&decimal(&value, numeric.unwrap_or(0), directive == 'D'));
+                }
+                'f' | 'e' | 'g' => {
+                    let value = self.format_float(&next_argument()?)?;
+                    let digits = numeric.unwrap_or(6);
+                    out.push_str(&match directive {
+                        'f' => format!("{:.*}", digits, value),
+                        'e' => exponential(value, digits),
+                        _ => general(value, digits),
+                    });
+                }
+                's' => out.push_str(&self.format_text(&next_argument()?)?),
+                'c' => {
+                    let code = self.format_integer(&next_argument()?)?;
+                    let c = character(code.to_i64().unwrap_or(-1))?;
+                    out.extend(std::iter::repeat_n(c, numeric.unwrap_or(1)));
+                }
+                'r' => {
+                    let value = self.format_integer(&next_argument()?)?;
+                    match numeric {
+                        Some(radix @ 2..=36) => out.push_str(&value.to_str_radix(radix as u32)),
+                        _ => return Err(format_error("no or invalid radix for ~r")),
+                    }
+                }
+                'i' => {
+                    next_argument()?;
+                }
+                'n' => {
+                    for _ in 0..numeric.unwrap_or(1) {
+                        formatter.newline();
+                    }
+                }
+                't' => {
+                    let fill = character(numeric.unwrap_or(' ' as usize) as i64)?;
+                    formatter.fills.push((formatter.out.len(), fill));
+                }
+                '|' => {
+                    let column = numeric.unwrap_or_else(|| formatter.column());
+                    formatter.column_stop(column);
+                }
+                '+' => {
+                    let column = formatter.stop + numeric.unwrap_or(8);
+                    formatter.column_stop(column);
+                }
+                _ => return Err(format_error(&format!("unknown directive ~{}", directive))),
+            }
+        }
+        if args.next().is_some() {
+            return Err(format_error("too many arguments"));
+        }
+        Ok(formatter.out)
+    }
+
+    /// `format(+Format)`, `format(+Format, :Args)` and `format(+Output,
+    /// +Format, :Args)`: writes the text of Format with the directives in
+    /// it replaced by Args, to Output, a stream or a sink such as
+    /// `atom(A)`, or to the current output.
+    pub(crate) fn format(&mut self, arity: usize) -> Result<bool, WamError> {
+        if arity == 3 {
+            if let Some(sink) = self.sink(&self.registers[1]) {
+                return self.format_sink(sink);
+            }
+        }
+        let (format, args) = match arity {
+            1 => (self.registers[1].clone(), HeapCell::Constant(NIL.to_string())),
+            _ => (self.registers[arity - 1].clone(), self.registers[arity].clone()),
+        };
+        let id = self.stream_argument(arity, 3, false, false)?;
+        let column = self.streams.column(id);
+        let text = self.formatted(&format, &args, column)?;
+        self.write_text(id, &text)?;
+        Ok(true)
+    }
+
+    /// Formats A2 with A3 into `sink`.
+    fn format_sink(&mut self, sink: Sink) -> Result<bool, WamError> {
+        let text = self.formatted(&self.registers[2], &self.registers[3], 0)?;
+        Ok(self.put_sink(sink, &text))
+    }
+
+    /// `format_atom(-Atom, +Format, :Args)`: `format(atom(Atom), Format,
+    /// Args)`.
+    pub(crate) fn format_atom(&mut self) -> Result<bool, WamError> {
+        self.format_sink(Sink::Atom(self.registers[1].clone()))
+    }
+
+    /// `sformat(-String, +Format, :Args)`: `format(string(String), Format,
+    /// Args)`.
+    pub(crate) fn sformat(&mut self) -> Result<bool, WamError> {
+        self.format_sink(Sink::Codes(self.registers[1].clone(), HeapCell::Constant(NIL.to_string())))
+    }
+
+    /// `with_output_to(+Sink, :Goal)`: runs Goal once with the current
+    /// output collected into Sink. Fails if Goal does.
+    pub(crate) fn with_output_to(&mut self) -> Result<bool, WamError> {
+        let cell = self.deref(&self.registers[1]);
+        let goal = self.registers[2].clone();
+        let sink = match self.sink(&cell) {
+            Some(sink) => sink,
+            None if self.is_unbound(&cell) => return Err(instantiation_error()),
+            None => return Err(domain_error("output_sink", self.decode_cell(&cell)?)),
+        };
+        let id = self.streams.memory_output();
+        let output = std::mem::replace(&mut self.streams.output, id);
+        let mut instance = None;
+        let result = self.solve(goal.clone(), |wam| {
+            instance = Some(wam.decode_cell(&goal)?);
+            Ok(false)
+        });
+        self.streams.output = output;
+        let text = self.streams.take_memory(id);
+        result?;
+        let instance = match instance {
+            Some(instance) => self.build_copy(&instance),
+            None => return Ok(false),
+        };
+        Ok(self.unify(goal, instance) && self.put_sink(sink, &text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::emulator::tests::{answers, exception};
+
+    #[test]
+    fn test_format_directives() {
+        let mut wam = WamEmulator::new();
+        let goal = "format(atom(A), \"~w ~q ~p ~a ~d~~~n\", [f('X', \"ab\"), 'A b', 'c d', abc, -42])";
+        assert_eq!(answers(&mut wam, goal), vec!["A = 'f(X,[97,98]) \\'A b\\' \\'c d\\' abc -42~\\n'"]);
+        let goal = "format(atom(A), '~2d ~D ~2D ~0d', [1234, 1234567, -1234567, 5])";
+        assert_eq!(answers(&mut wam, goal), vec!["A = '12.34 1,234,567 -12,345.67 5'"]);
+        let goal = "format(atom(A), '~2f ~f ~3e ~e ~g ~g ~3g', [3.14159, 2, 1234.56, 0.0, 0.0001, 123456789.0, 100.0])";
+        assert_eq!(answers(&mut wam, goal), vec!["A = '3.14 2.000000 1.235e+03 0.000000e+00 0.0001 1.23457e+08 100'"]);
+        let goal = "format(atom(A), '~s~s ~c~3c ~8r ~16r ~i~w ~*c', [\"ab\", [c], 0'x, 0'y, 255, -255, skipped, shown, 2, 0'z])";
+        assert_eq!(answers(&mut wam, goal), vec!["A = 'abc xyyy 377 -ff shown zz'"]);
+        assert_eq!(answers(&mut wam, "format(codes(C, T), hi, []), format(chars(Cs), '~a', [é])"), vec![
+            "C = [104, 105|T],\nCs = [é]",
+        ]);
+        assert_eq!(answers(&mut wam, "format_atom(A, '~w-~w', [a, b]), sformat(S, '~a', x)"), vec!["A = 'a-b',\nS = [120]"]);
+    }
+
+    #[test]
+    fn test_column_stops() {
+        let mut wam = WamEmulator::new();
+        let goals = [
+            ("format(atom(A), '~w~10|~w', [abc, def])", "A = 'abc       def'"),
+            ("format(atom(A), '~t~w~10|', [abc])", "A = '       abc'"),
+            ("format(atom(A), '~t~w~t~11|', [hello])", "A = '   hello   '"),
+            ("format(atom(A), '~`-t~30|', [])", "A = ------------------------------"),
+            ("format(atom(A), '~w~t~8+~w~t~8+~w', [a, b, c])", "A = 'a       b       c'"),
+            ("format(atom(A), '~t~d~6|~t~d~6+', [1, 23])", "A = '     1    23'"),
+            ("format(atom(A), '~w~3|~w~n~t~w~3|', [abcdef, x, y])", "A = 'abcdefx\\n  y'"),
+        ];
+        for (goal, answer) in goals {
+            assert_eq!(answers(&mut wam, goal), vec![answer], "{}", goal);
+        }
+
+        // Column stops count from the start of the line on the stream.
+        let id = wam.streams.memory_output();
+        wam.streams.output = id;
+        answers(&mut wam, "write(abc), format('~t~w~10|~n', [x]), current_output(S), format(S, '~w~t~4|.', [ab])");
+        assert_eq!(wam.streams.take_memory(id), "abc      x\nab  .");
+    }
+
+    #[test]
+    fn test_with_output_to() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "with_output_to(atom(A), ((X = 1 ; X = 2), write(X), nl))"), vec!["A = '1\\n',\nX = 1"]);
+        assert_eq!(answers(&mut wam, "with_output_to(chars(C), (write(h), with_output_to(atom(_), write(x)), write(i)))"), vec![
+            "C = [h, i]",
+        ]);
+        assert_eq!(answers(&mut wam, "with_output_to(string(S), format('~a', [ab]))"), vec!["S = [97, 98]"]);
+        assert!(answers(&mut wam, "with_output_to(atom(_), fail)").is_empty());
+
+        // The current output is restored after a failure or an error.
+        let id = wam.streams.memory_output();
+        wam.streams.output = id;
+        answers(&mut wam, "catch(with_output_to(atom(_), (write(a), throw(oops))), _, true), write(b)");
+        assert_eq!(wam.streams.take_memory(id), "b");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(exception(&mut wam, "format(atom(_), '~d', [a])"), "error(type_error(integer, a), _)");
+        assert_eq!(exception(&mut wam, "format(atom(_), '~a ~a', [a])"), "error(format('not enough arguments'), _)");
+        assert_eq!(exception(&mut wam, "format(atom(_), '~w', [a, b])"), "error(format('too many arguments'), _)");
+        assert_eq!(exception(&mut wam, "format(atom(_), '~r', [10])"), "error(format('no or invalid radix for ~r'), _)");
+        assert_eq!(exception(&mut wam, "format(atom(_), '~j', [])"), "error(format('unknown directive ~j'), _)");
+        for goal in ["format(atom(_), \"~99999999999999999999999d\", [1])", "format(atom(_), '~9999999c', [0'x])", "format(atom(_), '~*c', [99999999999, 0'x])"] {
+            assert_eq!(exception(&mut wam, goal), "error(format('numeric argument too large'), _)", "{}", goal);
+        }
+        assert_eq!(exception(&mut wam, "with_output_to(foo, true)"), "error(domain_error(output_sink, foo), _)");
+        assert_eq!(exception(&mut wam, "format(_, [])"), "error(instantiation_error, _)");
+    }
+}
@@ -0,0 +1,849 @@
+// src/runtime/io.rs
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+
+use crate::parser::ast::{parse_term_text, Term};
+use crate::parser::lexer::is_symbol_char;
+use crate::runtime::aggregate::alternatives;
+use crate::runtime::atoms::character;
+use crate::runtime::writer::WriteOptions;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::decoder::NIL;
+use crate::wam::error::{
+    domain_error, instantiation_error, object_existence_error, permission_error, structure,
+    syntax_error, type_error, uninstantiation_error, WamError,
+};
+
+/// The streams every emulator starts with, which cannot be closed.
+pub const USER_INPUT: usize = 0;
+pub const USER_OUTPUT: usize = 1;
+pub const USER_ERROR: usize = 2;
+
+/// What a stream was opened for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StreamMode {
+    Read,
+    Write,
+    Append,
+}
+
+/// What reading past the end of an input stream does: raise a permission
+/// error, return the end-of-file value again, or start reading again.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EofAction {
+    Error,
+    EofCode,
+    Reset,
+}
+
+enum Backing {
+    Input(Box<dyn BufRead>),
+    Output(Box<dyn Write>),
+    /// Output kept in memory until the stream is taken with
+    /// `Streams::take_memory`.
+    Memory(Vec<u8>),
+}
+
+/// An open stream: where it reads or writes, and its ISO properties.
+pub struct Stream {
+    backing: Backing,
+    mode: StreamMode,
+    binary: bool,
+    alias: Option<String>,
+    file_name: Option<String>,
+    eof_action: EofAction,
+    /// Whether the end of the stream has already been read.
+    past_end: bool,
+    /// A character read ahead by `peek_char/2`.
+    peeked: Option<char>,
+    /// Standard input, which may block, so is never read ahead to find
+    /// its end.
+    interactive: bool,
+    /// The column the next character written goes to, for the column
+    /// stops of `format/2`.
+    column: usize,
+}
+
+impl Stream {
+    fn input(reader: Box<dyn BufRead>) -> Self {
+        Self::new(Backing::Input(reader), StreamMode::Read)
+    }
+
+    fn output(writer: Box<dyn Write>, mode: StreamMode) -> Self {
+        Self::new(Backing::Output(writer), mode)
+    }
+
+    fn new(backing: Backing, mode: StreamMode) -> Self {
+        Stream {
+            backing,
+            mode,
+            binary: false,
+            alias: None,
+            file_name: None,
+            eof_action: EofAction::EofCode,
+            past_end: false,
+            peeked: None,
+            interactive: false,
+            column: 0,
+        }
+    }
+
+    fn is_input(&self) -> bool {
+        self.mode == StreamMode::Read
+    }
+
+    fn reader(&mut self) -> &mut dyn BufRead {
+        match &mut self.backing {
+            Backing::Input(reader) => reader.as_mut(),
+            _ => unreachable!("reading from an output stream"),
+        }
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        if let Some(c) = self.peeked.take() {
+            return Ok(Some(c));
+        }
+        let reader = self.reader();
+        let mut bytes = [0u8; 4];
+        if reader.read(&mut bytes[..1])? == 0 {
+            return Ok(None);
+        }
+        let width = match bytes[0] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        let read = reader.read(&mut bytes[1..width])?;
+        let c = std::str::from_utf8(&bytes[..1 + read]).ok().and_then(|text| text.chars().next());
+        Ok(Some(c.unwrap_or(char::REPLACEMENT_CHARACTER)))
+    }
+
+    fn peek_char(&mut self) -> io::Result<Option<char>> {
+        if self.peeked.is_none() {
+            self.peeked = self.read_char()?;
+        }
+        Ok(self.peeked)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        Ok(match self.reader().read(&mut byte)? {
+            0 => None,
+            _ => Some(byte[0]),
+        })
+    }
+
+    fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader().fill_buf()?.first().copied())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let text = String::from_utf8_lossy(bytes);
+        self.column = match text.rfind('\n') {
+            Some(i) => text[i + 1..].chars().count(),
+            None => self.column + text.chars().count(),
+        };
+        match &mut self.backing {
+            Backing::Output(writer) => writer.write_all(bytes),
+            Backing::Memory(buffer) => {
+                buffer.extend_from_slice(bytes);
+                Ok(())
+            }
+            Backing::Input(_) => unreachable!("writing to an input stream"),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.backing {
+            Backing::Output(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// `not`, `at` or `past`, as the `end_of_stream/1` property says.
+    fn end_of_stream(&mut self) -> &'static str {
+        if self.past_end {
+            "past"
+        } else if self.interactive || self.binary && self.peek_byte().is_ok_and(|byte| byte.is_some()) {
+            "not"
+        } else if self.binary || self.peek_char().is_ok_and(|c| c.is_none()) {
+            "at"
+        } else {
+            "not"
+        }
+    }
+
+    /// Reads the text of the next clause: up to and including an end `.`
+    /// followed by layout, skipping over quoted text and comments. `None`
+    /// when only layout is left.
+    fn read_clause_text(&mut self) -> io::Result<Option<String>> {
+        #[derive(Clone, Copy)]
+        enum State {
+            Code,
+            LineComment,
+            BlockComment,
+            Quoted(char),
+        }
+        let mut text = String::new();
+        let mut state = State::Code;
+        let mut previous = ' ';
+        while let Some(c) = self.read_char()? {
+            text.push(c);
+            match state {
+                State::Code => match c {
+                    '%' => state = State::LineComment,
+                    '/' if self.peek_char()? == Some('*') => {
+                        text.extend(self.read_char()?);
+                        state = State::BlockComment;
+                    }
+                    '\'' if previous == '0' => {
+                        // A character code such as 0'a or 0'\n.
+                        if let Some(quoted) = self.read_char()? {
+                            text.push(quoted);
+                            if quoted == '\\' || quoted == '\'' && self.peek_char()? == Some('\'') {
+                                text.extend(self.read_char()?);
+                            }
+                        }
+                    }
+                    '\'' | '"' | '`' => state = State::Quoted(c),
+                    '.' if !is_symbol_char(previous) => {
+                        let next = self.peek_char()?;
+                        if next.is_none_or(|next| next.is_whitespace() || next == '%') {
+                            return Ok(Some(text));
+                        }
+                    }
+                    _ => {}
+                },
+                State::LineComment if c == '\n' => state = State::Code,
+                State::BlockComment if c == '*' && self.peek_char()? == Some('/') => {
+                    text.extend(self.read_char()?);
+                    state = State::Code;
+                }
+                State::Quoted(quote) if c == '\\' => {
+                    text.extend(self.read_char()?);
+                    state = State::Quoted(quote);
+                }
+                State::Quoted(quote) if c == quote => {
+                    if self.peek_char()? == Some(quote) {
+                        text.extend(self.read_char()?);
+                    } else {
+                        state = State::Code;
+                    }
+                }
+                _ => {}
+            }
+            previous = c;
+        }
+        Ok(if text.trim().is_empty() { None } else { Some(text) })
+    }
+}
+
+/// The stream table: every stream opened so far, by number, with the
+/// current input and output.
+pub struct Streams {
+    streams: Vec<Option<Stream>>,
+    pub(crate) input: usize,
+    pub(crate) output: usize,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        let mut user_input = Stream::input(Box::new(BufReader::new(io::stdin())));
+        user_input.alias = Some("user_input".to_string());
+        user_input.interactive = true;
+        user_input.eof_action = EofAction::Reset;
+        let mut user_output = Stream::output(Box::new(io::stdout()), StreamMode::Append);
+        user_output.alias = Some("user_output".to_string());
+        let mut user_error = Stream::output(Box::new(io::stderr()), StreamMode::Append);
+        user_error.alias = Some("user_error".to_string());
+        Streams {
+            streams: vec![Some(user_input), Some(user_output), Some(user_error)],
+            input: USER_INPUT,
+            output: USER_OUTPUT,
+        }
+    }
+}
+
+impl Streams {
+    fn add(&mut self, stream: Stream) -> usize {
+        self.streams.push(Some(stream));
+        self.streams.len() - 1
+    }
+
+    fn get(&mut self, id: usize) -> &mut Stream {
+        self.streams[id].as_mut().expect("stream is closed")
+    }
+
+    fn is_open(&self, id: usize) -> bool {
+        self.streams.get(id).is_some_and(Option::is_some)
+    }
+
+    fn alias(&self, name: &str) -> Option<usize> {
+        self.streams.iter().position(|stream| stream.as_ref().is_some_and(|stream| stream.alias.as_deref() == Some(name)))
+    }
+
+    /// Opens an input stream reading `text`.
+    pub fn memory_input(&mut self, text: &str) -> usize {
+        self.add(Stream::input(Box::new(Cursor::new(text.as_bytes().to_vec()))))
+    }
+
+    /// Opens an output stream collecting what is written to it in memory.
+    pub fn memory_output(&mut self) -> usize {
+        self.add(Stream::new(Backing::Memory(Vec::new()), StreamMode::Write))
+    }
+
+    /// The column the next character written to stream `id` goes to.
+    pub(crate) fn column(&mut self, id: usize) -> usize {
+        self.get(id).column
+    }
+
+    /// Closes the memory output stream `id`, returning its text.
+    pub fn take_memory(&mut self, id: usize) -> String {
+        self.close(id);
+        match self.streams[id].take().map(|stream| stream.backing) {
+            Some(Backing::Memory(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// Closes stream `id`, except for the standard streams. The current
+    /// input or output falls back to the standard one.
+    fn close(&mut self, id: usize) {
+        if id <= USER_ERROR {
+            return;
+        }
+        if let Some(stream) = self.streams[id].as_mut() {
+            let _ = stream.flush();
+            if !matches!(stream.backing, Backing::Memory(_)) {
+                self.streams[id] = None;
+            }
+        }
+        if self.input == id {
+            self.input = USER_INPUT;
+        }
+        if self.output == id {
+            self.output = USER_OUTPUT;
+        }
+    }
+}
+
+/// The term standing for stream `id`, `'$stream'(Id)`.
+fn stream_term(id: usize) -> Term {
+    structure("$stream", vec![Term::Integer(id as i64)])
+}
+
+impl WamEmulator {
+    /// The stream the stream term or alias `cell` stands for.
+    fn stream_id(&self, cell: &HeapCell) -> Result<usize, WamError> {
+        let cell = self.deref(cell);
+        let id = match &cell {
+            _ if self.is_unbound(&cell) => return Err(instantiation_error()),
+            HeapCell::Constant(name) => self.streams.alias(name),
+            _ => match self.compound_cell(&cell) {
+                Some((functor, args)) if functor == "$stream" && args.len() == 1 => match self.heap[args[0]] {
+                    HeapCell::Integer(id) if id >= 0 => Some(id as usize),
+                    _ => return Err(domain_error("stream_or_alias", self.decode_cell(&cell)?)),
+                },
+                _ => return Err(domain_error("stream_or_alias", self.decode_cell(&cell)?)),
+            },
+        };
+        match id {
+            Some(id) if self.streams.is_open(id) => Ok(id),
+            _ => Err(object_existence_error("stream", self.decode_cell(&cell)?)),
+        }
+    }
+
+    /// The stream a predicate of `arity` reads or writes: its first
+    /// argument when it has `full` arguments, otherwise the current input
+    /// or output. Checks the stream's direction and, for `binary`, its
+    /// type.
+    pub(crate) fn stream_argument(&mut self, arity: usize, full: usize, input: bool, binary: bool) -> Result<usize, WamError> {
+        let id = match arity == full {
+            true => self.stream_id(&self.registers[1].clone())?,
+            false if input => self.streams.input,
+            false => self.streams.output,
+        };
+        let direction = if input { "input" } else { "output" };
+        let stream = self.streams.get(id);
+        if stream.is_input() != input {
+            return Err(permission_error(direction, "stream", stream_term(id)));
+        }
+        if stream.binary != binary {
+            let kind = if stream.binary { "binary_stream" } else { "text_stream" };
+            return Err(permission_error(direction, kind, stream_term(id)));
+        }
+        Ok(id)
+    }
+
+    /// The end-of-file value read from stream `id` at its end: it is
+    /// marked as past its end, or, if it already was, its `eof_action`
+    /// applies.
+    fn end_of_stream(&mut self, id: usize) -> Result<(), WamError> {
+        let stream = self.streams.get(id);
+        if !stream.past_end {
+            stream.past_end = true;
+            return Ok(());
+        }
+        match stream.eof_action {
+            EofAction::Error => Err(permission_error("input", "past_end_of_stream", stream_term(id))),
+            EofAction::EofCode => Ok(()),
+            EofAction::Reset => {
+                stream.past_end = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that stream `id` is not past its end before reading from it.
+    fn check_past_end(&mut self, id: usize) -> Result<(), WamError> {
+        let stream = self.streams.get(id);
+        match stream.eof_action {
+            EofAction::Error if stream.past_end => Err(permission_error("input", "past_end_of_stream", stream_term(id))),
+            EofAction::Reset => {
+                stream.past_end = false;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes `text` to stream `id`.
+    pub(crate) fn write_text(&mut self, id: usize, text: &str) -> Result<(), WamError> {
+        self.streams.get(id).write_bytes(text.as_bytes()).map_err(|_| permission_error("output", "stream", stream_term(id)))
+    }
+
+    /// `open(+Source, +Mode, -Stream)` and `open(+Source, +Mode, -Stream,
+    /// +Options)`: opens the file Source for `read`, `write` or `append`.
+    /// The options are `type(text)` or `type(binary)`, `alias(Alias)`,
+    /// `eof_action(Action)` and `reposition(Bool)`.
+    pub(crate) fn open(&mut self, arity: usize) -> Result<bool, WamError> {
+        let source = self.deref(&self.registers[1]);
+        let mode = self.deref(&self.registers[2]);
+        let stream = self.deref(&self.registers[3]);
+        if self.is_unbound(&source) || self.is_unbound(&mode) {
+            return Err(instantiation_error());
+        }
+        if !self.is_unbound(&stream) {
+            return Err(uninstantiation_error(self.decode_cell(&stream)?));
+        }
+        let mode = match &mode {
+            HeapCell::Constant(name) if name == "read" => StreamMode::Read,
+            HeapCell::Constant(name) if name == "write" => StreamMode::Write,
+            HeapCell::Constant(name) if name == "append" => StreamMode::Append,
+            HeapCell::Constant(_) => return Err(domain_error("io_mode", self.decode_cell(&mode)?)),
+            _ => return Err(type_error("atom", self.decode_cell(&mode)?)),
+        };
+        let path = match &source {
+            HeapCell::Constant(path) => path.clone(),
+            _ => return Err(domain_error("source_sink", self.decode_cell(&source)?)),
+        };
+        let options = match arity {
+            4 => self.list_elements(&self.registers[4].clone())?,
+            _ => Vec::new(),
+        };
+        let (mut binary, mut alias, mut eof_action) = (false, None, EofAction::EofCode);
+        for option in options {
+            let option = self.deref(&option);
+            let value = match self.compound_cell(&option) {
+                Some((name, args)) if args.len() == 1 => (name, self.deref(&HeapCell::Reference(args[0]))),
+                _ if self.is_unbound(&option) => return Err(instantiation_error()),
+                _ => return Err(domain_error("stream_option", self.decode_cell(&option)?)),
+            };
+            match value {
+                (_, value) if self.is_unbound(&value) => return Err(instantiation_error()),
+                (name, HeapCell::Constant(value)) if name == "type" && (value == "text" || value == "binary") => {
+                    binary = value == "binary";
+                }
+                (name, HeapCell::Constant(value)) if name == "alias" => alias = Some(value),
+                (name, HeapCell::Constant(value)) if name == "eof_action" && value == "error" => eof_action = EofAction::Error,
+                (name, HeapCell::Constant(value)) if name == "eof_action" && value == "eof_code" => eof_action = EofAction::EofCode,
+                (name, HeapCell::Constant(value)) if name == "eof_action" && value == "reset" => eof_action = EofAction::Reset,
+                (name, HeapCell::Constant(value)) if name == "reposition" && (value == "true" || value == "false") => {}
+                _ => return Err(domain_error("stream_option", self.decode_cell(&option)?)),
+            }
+        }
+        if let Some(alias) = alias.as_ref().filter(|alias| self.streams.alias(alias).is_some()) {
+            let culprit = structure("alias", vec![Term::Atom(alias.clone())]);
+            return Err(permission_error("open", "source_sink", culprit));
+        }
+        let opened = match mode {
+            StreamMode::Read => File::open(&path).map(|file| Stream::input(Box::new(BufReader::new(file)))),
+            StreamMode::Write => File::create(&path).map(|file| Stream::output(Box::new(io::BufWriter::new(file)), mode)),
+            StreamMode::Append => OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .map(|file| Stream::output(Box::new(io::BufWriter::new(file)), mode)),
+        };
+        let mut opened = match opened {
+            Ok(opened) => opened,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Err(object_existence_error("source_sink", Term::Atom(path)));
+            }
+            Err(_) => return Err(permission_error("open", "source_sink", Term::Atom(path))),
+        };
+        opened.binary = binary;
+        opened.alias = alias;
+        opened.eof_action = eof_action;
+        opened.file_name = Some(path);
+        let id = self.streams.add(opened);
+        let term = self.build_copy(&stream_term(id));
+        Ok(self.unify(stream, term))
+    }
+
+    /// `open_string(+Text, -Stream)`: opens an input stream reading the
+    /// text of the atomic term Text, or of a list of codes or characters.
+    pub(crate) fn open_string(&mut self) -> Result<bool, WamError> {
+        let text = self.registers[1].clone();
+        let text = match self.text_of(&text) {
+            Some(text) => text,
+            None => {
+                let codes = self.list_elements(&text)?;
+                let mut chars = String::new();
+                for code in codes {
+                    match self.deref(&code) {
+                        HeapCell::Integer(code) => chars.push(character(code)?),
+                        HeapCell::Constant(c) if c.chars().count() == 1 => chars.push_str(&c),
+                        _ => return Err(type_error("text", self.decode_cell(&text)?)),
+                    }
+                }
+                chars
+            }
+        };
+        let id = self.streams.memory_input(&text);
+        let term = self.build_copy(&stream_term(id));
+        Ok(self.unify(self.registers[2].clone(), term))
+    }
+
+    /// `close(+Stream)` and `close(+Stream, +Options)`: closes Stream. The
+    /// standard streams stay open.
+    pub(crate) fn close(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = self.stream_id(&self.registers[1].clone())?;
+        if arity == 2 {
+            for option in self.list_elements(&self.registers[2].clone())? {
+                let option = self.decode_cell(&option)?;
+                let force = |value: &str| structure("force", vec![Term::Atom(value.to_string())]);
+                if option != force("true") && option != force("false") {
+                    return Err(domain_error("close_option", option));
+                }
+            }
+        }
+        self.streams.close(id);
+        // A memory output stream closed from Prolog is not taken later.
+        if self.streams.is_open(id) && id > USER_ERROR {
+            self.streams.streams[id] = None;
+        }
+        Ok(true)
+    }
+
+    /// `current_input(?Stream)` and `current_output(?Stream)`.
+    pub(crate) fn current_stream(&mut self, input: bool) -> Result<bool, WamError> {
+        let stream = self.deref(&self.registers[1]);
+        if !self.is_unbound(&stream) && self.compound_cell(&stream).is_none_or(|(functor, _)| functor != "$stream") {
+            return Err(domain_error("stream", self.decode_cell(&stream)?));
+        }
+        let id = if input { self.streams.input } else { self.streams.output };
+        let term = self.build_copy(&stream_term(id));
+        Ok(self.unify(stream, term))
+    }
+
+    /// `set_input(+Stream)` and `set_output(+Stream)`.
+    pub(crate) fn set_stream(&mut self, input: bool) -> Result<bool, WamError> {
+        let id = self.stream_id(&self.registers[1].clone())?;
+        let direction = if input { "input" } else { "output" };
+        if self.streams.get(id).is_input() != input {
+            return Err(permission_error(direction, "stream", stream_term(id)));
+        }
+        if input {
+            self.streams.input = id;
+        } else {
+            self.streams.output = id;
+        }
+        Ok(true)
+    }
+
+    /// `get_char(?Char)`, `get_char(+Stream, ?Char)` and, with `peek`,
+    /// `peek_char/1,2`: Char is the next character of the stream, or
+    /// `end_of_file` at its end.
+    pub(crate) fn get_char(&mut self, arity: usize, peek: bool) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, true, false)?;
+        let char = self.registers[arity].clone();
+        let checked = self.deref(&char);
+        match &checked {
+            HeapCell::Constant(name) if name.chars().count() == 1 || name == "end_of_file" => {}
+            _ if self.is_unbound(&checked) => {}
+            _ => return Err(type_error("in_character", self.decode_cell(&checked)?)),
+        }
+        self.check_past_end(id)?;
+        let stream = self.streams.get(id);
+        let read = match peek {
+            true => stream.peek_char(),
+            false => stream.read_char(),
+        };
+        let value = match read.map_err(|_| permission_error("input", "stream", stream_term(id)))? {
+            Some(c) => c.to_string(),
+            None => {
+                if !peek {
+                    self.end_of_stream(id)?;
+                }
+                "end_of_file".to_string()
+            }
+        };
+        Ok(self.unify(char, HeapCell::Constant(value)))
+    }
+
+    /// `get_byte(?Byte)`, `get_byte(+Stream, ?Byte)` and, with `peek`,
+    /// `peek_byte/1,2`: Byte is the next byte of the binary stream, or -1
+    /// at its end.
+    pub(crate) fn get_byte(&mut self, arity: usize, peek: bool) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, true, true)?;
+        let byte = self.registers[arity].clone();
+        let checked = self.deref(&byte);
+        match checked {
+            HeapCell::Integer(-1..=255) => {}
+            _ if self.is_unbound(&checked) => {}
+            _ => return Err(type_error("in_byte", self.decode_cell(&checked)?)),
+        }
+        self.check_past_end(id)?;
+        let stream = self.streams.get(id);
+        let read = match peek {
+            true => stream.peek_byte(),
+            false => stream.read_byte(),
+        };
+        let value = match read.map_err(|_| permission_error("input", "stream", stream_term(id)))? {
+            Some(byte) => byte as i64,
+            None => {
+                if !peek {
+                    self.end_of_stream(id)?;
+                }
+                -1
+            }
+        };
+        Ok(self.unify(byte, HeapCell::Integer(value)))
+    }
+
+    /// `put_char(+Char)` and `put_char(+Stream, +Char)`.
+    pub(crate) fn put_char(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, false, false)?;
+        let char = self.deref(&self.registers[arity]);
+        match &char {
+            HeapCell::Constant(name) if name.chars().count() == 1 => {
+                let name = name.clone();
+                self.write_text(id, &name)?;
+                Ok(true)
+            }
+            _ if self.is_unbound(&char) => Err(instantiation_error()),
+            _ => Err(type_error("character", self.decode_cell(&char)?)),
+        }
+    }
+
+    /// `put_byte(+Byte)` and `put_byte(+Stream, +Byte)`.
+    pub(crate) fn put_byte(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, false, true)?;
+        let byte = self.deref(&self.registers[arity]);
+        match byte {
+            HeapCell::Integer(byte @ 0..=255) => {
+                let written = self.streams.get(id).write_bytes(&[byte as u8]);
+                written.map_err(|_| permission_error("output", "stream", stream_term(id)))?;
+                Ok(true)
+            }
+            _ if self.is_unbound(&byte) => Err(instantiation_error()),
+            _ => Err(type_error("byte", self.decode_cell(&byte)?)),
+        }
+    }
+
+    /// `nl` and `nl(+Stream)`.
+    pub(crate) fn nl(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 1, false, false)?;
+        self.write_text(id, "\n")?;
+        Ok(true)
+    }
+
+    /// `write(@Term)`, `writeq/1`, `print/1` and `write_canonical/1`, and
+    /// their forms with a stream first: writes Term with `options`.
+    pub(crate) fn write(&mut self, arity: usize, options: WriteOptions) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, false, false)?;
+        let text = self.write_cell(&self.registers[arity], &options)?;
+        self.write_text(id, &text)?;
+        Ok(true)
+    }
+
+    /// `flush_output` and `flush_output(+Stream)`.
+    pub(crate) fn flush_output(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = match arity {
+            1 => self.stream_id(&self.registers[1].clone())?,
+            _ => self.streams.output,
+        };
+        if self.streams.get(id).is_input() {
+            return Err(permission_error("output", "stream", stream_term(id)));
+        }
+        self.streams.get(id).flush().map_err(|_| permission_error("output", "stream", stream_term(id)))?;
+        Ok(true)
+    }
+
+    /// `read(?Term)` and `read(+Stream, ?Term)`: Term is the next clause
+    /// of the stream, ended by `.`, or `end_of_file` at its end.
+    pub(crate) fn read(&mut self, arity: usize) -> Result<bool, WamError> {
+        let id = self.stream_argument(arity, 2, true, false)?;
+        self.check_past_end(id)?;
+        let text = self.streams.get(id).read_clause_text().map_err(|_| permission_error("input", "stream", stream_term(id)))?;
+        let term = match text {
+            Some(text) => {
+                let term = parse_term_text(&text).map_err(|error| syntax_error(&error))?;
+                self.build_copy(&term)
+            }
+            None => {
+                self.end_of_stream(id)?;
+                HeapCell::Constant("end_of_file".to_string())
+            }
+        };
+        Ok(self.unify(self.registers[arity].clone(), term))
+    }
+
+    /// `stream_property(?Stream, ?Property)`: Stream is an open stream
+    /// with Property, enumerated on backtracking.
+    pub(crate) fn stream_property(&mut self) -> Result<bool, WamError> {
+        let stream = self.deref(&self.registers[1]);
+        let ids: Vec<usize> = match self.is_unbound(&stream) {
+            true => (0..self.streams.streams.len()).filter(|&id| self.streams.is_open(id)).collect(),
+            false => vec![self.stream_id(&stream)?],
+        };
+        let mut pairs = Vec::new();
+        for id in ids {
+            let term = stream_term(id);
+            for property in self.properties(id) {
+                pairs.push(structure("-", vec![term.clone(), property]));
+            }
+        }
+        let pairs = pairs.iter().map(|pair| self.build_copy(pair)).collect();
+        // An alias matches the properties of the stream it names.
+        let stream = match self.is_unbound(&stream) {
+            true => stream,
+            false => self.build_copy(&stream_term(self.stream_id(&stream)?)),
+        };
+        let property = self.registers[2].clone();
+        self.registers[1] = self.push_built_structure("-", vec![stream, property]);
+        self.registers[2] = self.build_list(pairs, HeapCell::Constant(NIL.to_string()));
+        alternatives(self)
+    }
+
+    fn properties(&mut self, id: usize) -> Vec<Term> {
+        let stream = self.streams.get(id);
+        let property = |name: &str, value: &str| structure(name, vec![Term::Atom(value.to_string())]);
+        let mut properties = Vec::new();
+        if let Some(file_name) = &stream.file_name {
+            properties.push(property("file_name", file_name));
+        }
+        let mode = match stream.mode {
+            StreamMode::Read => "read",
+            StreamMode::Write => "write",
+            StreamMode::Append => "append",
+        };
+        properties.push(property("mode", mode));
+        properties.push(Term::Atom(if stream.is_input() { "input" } else { "output" }.to_string()));
+        if let Some(alias) = &stream.alias {
+            properties.push(property("alias", alias));
+        }
+        if stream.is_input() {
+            properties.push(property("end_of_stream", stream.end_of_stream()));
+            let action = match stream.eof_action {
+                EofAction::Error => "error",
+                EofAction::EofCode => "eof_code",
+                EofAction::Reset => "reset",
+            };
+            properties.push(property("eof_action", action));
+        }
+        properties.push(property("type", if stream.binary { "binary" } else { "text" }));
+        properties.push(property("reposition", "false"));
+        properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    /// Runs `goal` with the current output going to memory, returning what
+    /// it wrote.
+    fn output(wam: &mut WamEmulator, goal: &str) -> String {
+        let id = wam.streams.memory_output();
+        wam.streams.output = id;
+        answers(wam, goal);
+        wam.streams.take_memory(id)
+    }
+
+    #[test]
+    fn test_reading_text_streams() {
+        let mut wam = WamEmulator::new();
+        let goal = "open_string('hé', S), get_char(S, A), peek_char(S, B), get_char(S, C), get_char(S, D), close(S)";
+        assert_eq!(answers(&mut wam, goal), vec!["S = '$stream'(3),\nA = h,\nB = é,\nC = é,\nD = end_of_file"]);
+        let goal = "open_string(\"f(X, 'a. b'). % c\\n g(0'., [a|T]). \", S), read(S, A), read(S, B), read(S, C), A = f(X, _), B = g(_, [_|T])";
+        assert_eq!(answers(&mut wam, goal), vec![
+            "S = '$stream'(4),\nA = f(X, 'a. b'),\nB = g(46, [a|T]),\nC = end_of_file"
+        ]);
+        assert_eq!(exception(&mut wam, "open_string('f(a b). ', S), read(S, _)"), "error(syntax_error(unexpected_token), position(1, 5))");
+        // Variables read are always new, whatever their names.
+        let goal = "X = a, open_string('foo(_G0, _G1, _S0). ', S), read(S, T), T = foo(A, B, C), A \\== B, B \\== C";
+        assert_eq!(answers(&mut wam, goal), vec!["X = a,\nS = '$stream'(6),\nT = foo(A, B, C)"]);
+    }
+
+    #[test]
+    fn test_writing_and_current_output() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(output(&mut wam, "write(f(x, 'A b')), nl, put_char(c)"), "f(x,A b)\nc");
+        let written = output(&mut wam, "current_output(S), write(S, a), nl(S)");
+        assert_eq!(written, "a\n");
+        assert_eq!(exception(&mut wam, "put_char(_)"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "put_char(ab)"), "error(type_error(character, ab), _)");
+        assert_eq!(exception(&mut wam, "write(user_input, a)"), "error(permission_error(output, stream, '$stream'(0)), _)");
+        assert_eq!(exception(&mut wam, "get_char(user_output, _)"), "error(permission_error(input, stream, '$stream'(1)), _)");
+        assert_eq!(exception(&mut wam, "write(nowhere, a)"), "error(existence_error(stream, nowhere), _)");
+        assert_eq!(exception(&mut wam, "write(foo(1), a)"), "error(domain_error(stream_or_alias, foo(1)), _)");
+    }
+
+    #[test]
+    fn test_files_and_end_of_stream() {
+        let path = std::env::temp_dir().join(format!("prolog_wam_io_{}.txt", std::process::id()));
+        let file = path.to_str().unwrap().replace('\\', "/");
+        let mut wam = WamEmulator::new();
+        let goal = format!("open('{}', write, S, [alias(out)]), write(out, hello(world)), put_char(out, '.'), nl(out), close(S)", file);
+        assert_eq!(answers(&mut wam, &goal).len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello(world).\n");
+        let goal = format!(
+            "open('{}', read, S, [eof_action(error)]), read(S, T), stream_property(S, end_of_stream(E0)), \
+             get_char(S, N), get_char(S, End), stream_property(S, end_of_stream(E)), close(S)",
+            file
+        );
+        assert_eq!(answers(&mut wam, &goal), vec![
+            "S = '$stream'(4),\nT = hello(world),\nE0 = not,\nN = '\\n',\nEnd = end_of_file,\nE = past"
+        ]);
+        let goal = format!("open('{}', read, S, [eof_action(error)]), read(S, _), read(S, _), read(S, _)", file);
+        assert_eq!(exception(&mut wam, &goal), "error(permission_error(input, past_end_of_stream, '$stream'(5)), _)");
+        let goal = format!("open('{}', read, S, [type(binary)]), get_byte(S, B), peek_byte(S, C)", file);
+        assert_eq!(answers(&mut wam, &goal), vec!["S = '$stream'(6),\nB = 104,\nC = 101"]);
+        let goal = format!("open('{}', read, S, [type(binary)]), get_char(S, _)", file);
+        assert_eq!(exception(&mut wam, &goal), "error(permission_error(input, binary_stream, '$stream'(7)), _)");
+        fs::remove_file(&path).unwrap();
+        let goal = format!("open('{}', read, _)", file);
+        assert_eq!(exception(&mut wam, &goal), format!("error(existence_error(source_sink, '{}'), _)", file));
+        assert_eq!(exception(&mut wam, "open(f, update, _)"), "error(domain_error(io_mode, update), _)");
+    }
+
+    #[test]
+    fn test_stream_properties() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "stream_property(S, alias(user_error))"), vec!["S = '$stream'(2)"]);
+        assert_eq!(answers(&mut wam, "stream_property(user_input, mode(M)), stream_property(user_output, type(T))"), vec![
+            "M = read,\nT = text"
+        ]);
+        assert_eq!(answers(&mut wam, "open_string(abc, S), set_input(S), get_char(C), current_input(S)"), vec![
+            "S = '$stream'(3),\nC = a"
+        ]);
+        assert_eq!(answers(&mut wam, "close('$stream'(3)), current_input(S)"), vec!["S = '$stream'(0)"]);
+        assert_eq!(exception(&mut wam, "close('$stream'(3))"), "error(existence_error(stream, '$stream'(3)), _)");
+    }
+}
@@ -0,0 +1,164 @@
+// src/runtime/meta.rs
+use crate::compiler::clause::variables;
+use crate::runtime::builtins::{add_arguments, call};
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::error::WamError;
+
+/// `call(+Closure, ?A1, ...)`, `call/2` to `call/8`: calls Closure with
+/// A1, ... appended to its arguments.
+pub(crate) fn call_n(wam: &mut WamEmulator, arity: usize) -> Result<bool, WamError> {
+    let extra = wam.registers[2..=arity].to_vec();
+    wam.registers[1] = add_arguments(wam, &wam.registers[1].clone(), extra)?;
+    call(wam)
+}
+
+/// `Params>>Lambda` called with A3, ... (library(yall)): a copy of the
+/// lambda is made, the parameters of the copy are unified with the
+/// arguments and the body is called with the arguments left over appended.
+/// In `Free/Params>>Lambda` the variables of Free are shared with the
+/// lambda rather than copied. The body is called as by `call/1`, so a cut
+/// in it is local to it.
+pub(crate) fn lambda(wam: &mut WamEmulator, arity: usize) -> Result<bool, WamError> {
+    let extra = wam.registers[3..=arity].to_vec();
+    let (params, body) = (wam.registers[1].clone(), wam.registers[2].clone());
+    let (free, params) = match wam.compound_cell(&params) {
+        Some((functor, args)) if functor == "/" && args.len() == 2 => {
+            (Some(HeapCell::Reference(args[0])), HeapCell::Reference(args[1]))
+        }
+        _ => (None, params),
+    };
+    let lambda = wam.push_built_structure(">>", vec![params, body]);
+    let copy = wam.copy_lambda(&lambda, free.as_ref())?;
+    let (_, args) = wam.compound_cell(&copy).expect("a copy of a lambda");
+    let mut extra = extra.into_iter();
+    for param in wam.list_elements(&HeapCell::Reference(args[0]))? {
+        match extra.next() {
+            Some(arg) => {
+                if !wam.unify(param, arg) {
+                    return Ok(false);
+                }
+            }
+            None => break,
+        }
+    }
+    wam.registers[1] = add_arguments(wam, &HeapCell::Reference(args[1]), extra.collect())?;
+    call(wam)
+}
+
+/// `Free/Lambda` called with A3, ... (library(yall)): calls a copy of
+/// Lambda that shares only the variables of Free, with A3, ... appended.
+pub(crate) fn free_lambda(wam: &mut WamEmulator, arity: usize) -> Result<bool, WamError> {
+    let (free, lambda) = (wam.registers[1].clone(), wam.registers[2].clone());
+    if let Some((_, args)) = wam.compound_cell(&lambda).filter(|(functor, args)| functor == ">>" && args.len() == 2) {
+        wam.registers[1] = wam.push_built_structure("/", vec![free, HeapCell::Reference(args[0])]);
+        wam.registers[2] = HeapCell::Reference(args[1]);
+        return self::lambda(wam, arity);
+    }
+    let mut copy = wam.copy_lambda(&lambda, Some(&free))?;
+    if let Some((_, args)) = wam.compound_cell(&copy).filter(|(functor, args)| functor == "\\" && args.len() == 1) {
+        // Already copied: calling `\X^Body` itself would copy Free too.
+        copy = HeapCell::Reference(args[0]);
+    }
+    let extra = wam.registers[3..=arity].to_vec();
+    wam.registers[1] = add_arguments(wam, &copy, extra)?;
+    call(wam)
+}
+
+/// `\X^Body` called with A2, ... (library(yall)): calls `X^Body` on a
+/// copy of the lambda with A2, ... appended, so that X and the other
+/// variables of Body are local to each call.
+pub(crate) fn local_lambda(wam: &mut WamEmulator, arity: usize) -> Result<bool, WamError> {
+    let copy = wam.copy_lambda(&wam.registers[1].clone(), None)?;
+    let extra = wam.registers[2..=arity].to_vec();
+    wam.registers[1] = add_arguments(wam, &copy, extra)?;
+    call(wam)
+}
+
+/// `X^Body` called with A3, ... (library(yall)): unifies X with A3 and
+/// calls Body with the arguments left over appended.
+pub(crate) fn lambda_parameter(wam: &mut WamEmulator, arity: usize) -> Result<bool, WamError> {
+    if !wam.unify(wam.registers[1].clone(), wam.registers[3].clone()) {
+        return Ok(false);
+    }
+    let extra = wam.registers[4..=arity].to_vec();
+    wam.registers[1] = add_arguments(wam, &wam.registers[2].clone(), extra)?;
+    call(wam)
+}
+
+impl WamEmulator {
+    /// A copy of `lambda` sharing only the variables of `free` with it.
+    fn copy_lambda(&mut self, lambda: &HeapCell, free: Option<&HeapCell>) -> Result<HeapCell, WamError> {
+        let mut shared = Vec::new();
+        if let Some(free) = free {
+            variables(&self.decode_cell(free)?, &mut shared);
+        }
+        let term = self.decode_cell(lambda)?;
+        Ok(self.build_copy_sharing(&term, &shared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    #[test]
+    fn test_call_with_extra_arguments() {
+        let mut wam = WamEmulator::new();
+        wam.consult("add(X, Y, Z) :- Z is X + Y. first(X) :- member(X, [a, b]). member(X, [X|_]). member(X, [_|T]) :- member(X, T).").unwrap();
+        assert_eq!(answers(&mut wam, "call(add(1), 2, X), call(add, 3, 4, Y), G = format(atom(A)), call(G, '~w', [x])"), vec![
+            "X = 3,\nY = 7,\nG = format(atom(x)),\nA = x",
+        ]);
+        assert_eq!(answers(&mut wam, "call(first, X)"), vec!["X = a", "X = b"]);
+        assert_eq!(answers(&mut wam, "once(first(X)), ignore(fail), \\+ first(c), call(\\+, fail)"), vec!["X = a"]);
+        assert_eq!(answers(&mut wam, "call((first(X), !)), ignore(X = b)"), vec!["X = a"]);
+        assert_eq!(answers(&mut wam, "first(X), call(!), X == b"), vec!["X = b"]);
+        assert_eq!(exception(&mut wam, "call(foo, 1)"), "error(existence_error(procedure, foo/1), foo/1)");
+        assert_eq!(exception(&mut wam, "call(1, a)"), "error(type_error(callable, 1), _)");
+        assert_eq!(exception(&mut wam, "call(_, a)"), "error(instantiation_error, _)");
+    }
+
+    #[test]
+    fn test_apply_predicates() {
+        let mut wam = WamEmulator::new();
+        wam.consult("double(X, Y) :- Y is 2 * X. even(X) :- 0 is X mod 2.").unwrap();
+        let goals = [
+            ("maplist(double, [1, 2, 3], L)", "L = [2, 4, 6]"),
+            ("L = [A, B], maplist(=(x), L)", "L = [x, x],\nA = x,\nB = x"),
+            ("maplist([X, Y, Z]>>(Z is X + Y), [1, 2], [3, 4], L)", "L = [4, 6]"),
+            ("foldl([X, A0, A]>>(A is A0 + X), [1, 2, 3], 0, S)", "S = 6"),
+            ("foldl([X, Y, A0, A]>>(A is A0 + X * Y), [1, 2], [3, 4], 0, S)", "S = 11"),
+            ("include(even, [1, 2, 3, 4], I), exclude(even, [1, 2, 3, 4], E)", "I = [2, 4],\nE = [1, 3]"),
+            ("partition([X]>>(X < 3), [1, 5, 2, 4], I, E)", "I = [1, 2],\nE = [5, 4]"),
+            ("maplist(double, [1, 2], [2, 5])", ""),
+        ];
+        for (goal, answer) in goals {
+            let expected: Vec<String> = Some(answer.to_string()).filter(|answer| !answer.is_empty()).into_iter().collect();
+            assert_eq!(answers(&mut wam, goal), expected, "{}", goal);
+        }
+        assert_eq!(exception(&mut wam, "maplist(double, [a], _)"), "error(type_error(evaluable, a/0), _)");
+    }
+
+    #[test]
+    fn test_lambdas() {
+        let mut wam = WamEmulator::new();
+        wam.consult("member(X, [X|_]). member(X, [_|T]) :- member(X, T).").unwrap();
+        // Variables not declared free are copied; bound ones keep their value.
+        assert_eq!(answers(&mut wam, "N = 10, maplist([X, Y]>>(Y is X + N), [1, 2], L)"), vec!["N = 10,\nL = [11, 12]"]);
+        assert_eq!(answers(&mut wam, "call([X]>>(Y = X), 1), Y = 2"), vec!["Y = 2"]);
+        assert_eq!(answers(&mut wam, "call(Y/[X]>>(Y = X), 1)"), vec!["Y = 1"]);
+        assert_eq!(answers(&mut wam, "F = abc, call(F/atom_length(F), N)"), vec!["F = abc,\nN = 3"]);
+        // Arguments beyond the lambda's parameters are passed on to its body.
+        assert_eq!(answers(&mut wam, "call([X]>>atom_length(X), abc, N)"), vec!["N = 3"]);
+        assert_eq!(answers(&mut wam, "call([]>>atom_length(abc), N)"), vec!["N = 3"]);
+        // A cut in the body is local to it.
+        assert_eq!(answers(&mut wam, "findall(Y, (call([X]>>(member(X, [1, 2]), !), Y) ; Y = 3), L)"), vec!["L = [1, 3]"]);
+        assert_eq!(answers(&mut wam, "findall(X, call([X]>>member(X, [1, 2]), X), L)"), vec!["L = [1, 2]"]);
+        // `\X^Body` takes its parameters one at a time and keeps them local.
+        assert_eq!(answers(&mut wam, "maplist(\\X^Y^(Y is X * 2), [1, 2], L)"), vec!["L = [2, 4]"]);
+        assert_eq!(answers(&mut wam, "call(\\X^atom_length(X), abc, N), call(\\X^(X = 1), A)"), vec!["N = 3,\nA = 1"]);
+        assert_eq!(answers(&mut wam, "N = 10, call(N/(\\X^Y^(Y is X + N)), 1, Z)"), vec!["N = 10,\nZ = 11"]);
+        assert_eq!(answers(&mut wam, "call(Y/(\\X^(Y = X)), 1)"), vec!["Y = 1"]);
+        assert!(answers(&mut wam, "call(\\X^(X = 1), 2)").is_empty());
+    }
+}
@@ -0,0 +1,12 @@
+// src/runtime/mod.rs
+pub mod aggregate;
+pub mod arithmetic;
+pub mod atoms;
+pub mod builtins;
+pub mod foreign;
+pub mod format;
+pub mod io;
+pub mod meta;
+pub mod order;
+pub mod terms;
+pub mod writer;
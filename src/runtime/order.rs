@@ -0,0 +1,412 @@
+// src/runtime/order.rs
+use std::cmp::Ordering;
+
+use crate::parser::ast::Term;
+use crate::runtime::arithmetic::Number;
+use crate::runtime::builtins::add_arguments;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::decoder::{LIST_FUNCTOR, NIL};
+use crate::wam::error::{domain_error, instantiation_error, type_error, WamError};
+
+impl WamEmulator {
+    /// Compares the terms `a` and `b` stand for in the standard order of
+    /// terms: variables, by age, before numbers, by value, before atoms,
+    /// alphabetically, before compound terms, by arity, then name, then
+    /// arguments from left to right. A float comes before an integer of the
+    /// same value.
+    pub(crate) fn compare_cells(&self, a: &HeapCell, b: &HeapCell) -> Ordering {
+        let mut pending = vec![(a.clone(), b.clone())];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.deref(&a), self.deref(&b));
+            if a == b {
+                continue;
+            }
+            let order = self.rank(&a).cmp(&self.rank(&b)).then_with(|| match (&a, &b) {
+                _ if self.is_unbound(&a) => variable_key(&a).cmp(&variable_key(&b)),
+                (HeapCell::Constant(x), HeapCell::Constant(y)) => x.cmp(y),
+                (HeapCell::Reference(x), HeapCell::Reference(y)) => {
+                    let (f, xs) = self.structure(*x);
+                    let (g, ys) = self.structure(*y);
+                    let order = xs.len().cmp(&ys.len()).then_with(|| f.cmp(g));
+                    if order == Ordering::Equal {
+                        // Arguments are compared left to right, so the last is pushed first.
+                        pending.extend(xs.iter().zip(ys).rev().map(|(&x, &y)| (HeapCell::Reference(x), HeapCell::Reference(y))));
+                    }
+                    order
+                }
+                _ => {
+                    let (x, y) = (number(&a), number(&b));
+                    let by_value = x.compare(&y).unwrap_or(Ordering::Equal);
+                    by_value.then_with(|| matches!(y, Number::Float(_)).cmp(&matches!(x, Number::Float(_))))
+                }
+            });
+            if order != Ordering::Equal {
+                return order;
+            }
+        }
+        Ordering::Equal
+    }
+
+    fn rank(&self, cell: &HeapCell) -> u8 {
+        match cell {
+            _ if self.is_unbound(cell) => 0,
+            HeapCell::Integer(_) | HeapCell::BigInteger(_) | HeapCell::Float(_) => 1,
+            HeapCell::Constant(_) => 2,
+            _ => 3,
+        }
+    }
+
+    fn structure(&self, address: usize) -> (&str, &[usize]) {
+        match &self.heap[address] {
+            HeapCell::Structure(functor, args) => (functor, args),
+            cell => unreachable!("dereferenced to {:?}", cell),
+        }
+    }
+
+    /// `compare(?Order, @X, @Y)`: Order is `<`, `=` or `>` as X comes
+    /// before, is identical to or comes after Y.
+    pub(crate) fn compare(&mut self) -> Result<bool, WamError> {
+        let order = self.deref(&self.registers[1]);
+        match &order {
+            HeapCell::Constant(name) if ["<", "=", ">"].contains(&name.as_str()) => {}
+            HeapCell::Constant(_) => return Err(domain_error("order", self.decode_cell(&order)?)),
+            _ if self.is_unbound(&order) => {}
+            _ => return Err(type_error("atom", self.decode_cell(&order)?)),
+        }
+        let symbol = match self.compare_cells(&self.registers[2], &self.registers[3]) {
+            Ordering::Less => "<",
+            Ordering::Equal => "=",
+            Ordering::Greater => ">",
+        };
+        Ok(self.unify(order, HeapCell::Constant(symbol.to_string())))
+    }
+
+    /// `msort(+List, ?Sorted)`: Sorted is List in standard order, keeping
+    /// duplicates.
+    pub(crate) fn msort(&mut self) -> Result<bool, WamError> {
+        self.sort_list(false)
+    }
+
+    /// `sort(+List, ?Sorted)`: Sorted is List in standard order without
+    /// duplicates.
+    pub(crate) fn sort2(&mut self) -> Result<bool, WamError> {
+        self.sort_list(true)
+    }
+
+    fn sort_list(&mut self, unique: bool) -> Result<bool, WamError> {
+        let sorted = self.registers[2].clone();
+        let mut elements = self.list_elements(&self.registers[1])?;
+        self.list_or_partial_list(&sorted)?;
+        elements.sort_by(|a, b| self.compare_cells(a, b));
+        if unique {
+            elements.dedup_by(|a, b| self.compare_cells(a, b) == Ordering::Equal);
+        }
+        let list = self.build_list(elements, HeapCell::Constant(NIL.to_string()));
+        Ok(self.unify(sorted, list))
+    }
+
+    /// `sort(+Key, +Order, +List, ?Sorted)`: Sorted is List ordered on the
+    /// Keyth argument of its elements, or on the elements themselves when
+    /// Key is 0. Order is `@<` or `@>` to drop elements with equal keys, or
+    /// `@=<` or `@>=` to keep them in their original order.
+    pub(crate) fn sort4(&mut self) -> Result<bool, WamError> {
+        let key = self.deref(&self.registers[1]);
+        let order = self.deref(&self.registers[2]);
+        if self.is_unbound(&key) || self.is_unbound(&order) {
+            return Err(instantiation_error());
+        }
+        let key = match key {
+            HeapCell::Integer(key) if key >= 0 => key as usize,
+            HeapCell::Integer(key) => return Err(domain_error("not_less_than_zero", Term::Integer(key))),
+            _ => return Err(type_error("integer", self.decode_cell(&key)?)),
+        };
+        let (descending, unique) = match &order {
+            HeapCell::Constant(name) if name == "@<" => (false, true),
+            HeapCell::Constant(name) if name == "@=<" => (false, false),
+            HeapCell::Constant(name) if name == "@>" => (true, true),
+            HeapCell::Constant(name) if name == "@>=" => (true, false),
+            HeapCell::Constant(_) => return Err(domain_error("order", self.decode_cell(&order)?)),
+            _ => return Err(type_error("atom", self.decode_cell(&order)?)),
+        };
+        let sorted = self.registers[4].clone();
+        let elements = self.list_elements(&self.registers[3])?;
+        self.list_or_partial_list(&sorted)?;
+        let mut keyed = Vec::with_capacity(elements.len());
+        for element in elements {
+            let sort_key = match key {
+                0 => element.clone(),
+                _ => self.argument_of(&element, key)?,
+            };
+            keyed.push((sort_key, element));
+        }
+        keyed.sort_by(|(a, _), (b, _)| {
+            let order = self.compare_cells(a, b);
+            if descending { order.reverse() } else { order }
+        });
+        if unique {
+            keyed.dedup_by(|(a, _), (b, _)| self.compare_cells(a, b) == Ordering::Equal);
+        }
+        let list = self.build_list(keyed.into_iter().map(|(_, element)| element).collect(), HeapCell::Constant(NIL.to_string()));
+        Ok(self.unify(sorted, list))
+    }
+
+    /// `keysort(+Pairs, ?Sorted)`: Sorted is the `Key-Value` pairs of Pairs
+    /// ordered on their keys, keeping pairs with equal keys in their
+    /// original order.
+    pub(crate) fn keysort(&mut self) -> Result<bool, WamError> {
+        let sorted = self.registers[2].clone();
+        let elements = self.list_elements(&self.registers[1])?;
+        self.list_or_partial_list(&sorted)?;
+        let mut keyed = Vec::with_capacity(elements.len());
+        for element in elements {
+            let pair = self.deref(&element);
+            match self.compound_cell(&pair) {
+                Some((functor, args)) if functor == "-" && args.len() == 2 => keyed.push((HeapCell::Reference(args[0]), pair)),
+                _ if self.is_unbound(&pair) => return Err(instantiation_error()),
+                _ => return Err(type_error("pair", self.decode_cell(&pair)?)),
+            }
+        }
+        keyed.sort_by(|(a, _), (b, _)| self.compare_cells(a, b));
+        let list = self.build_list(keyed.into_iter().map(|(_, pair)| pair).collect(), HeapCell::Constant(NIL.to_string()));
+        Ok(self.unify(sorted, list))
+    }
+
+    /// `predsort(+Pred, +List, ?Sorted)`: Sorted is List ordered by calling
+    /// `call(Pred, Order, A, B)`, which binds Order to `<`, `>` or `=`;
+    /// elements comparing `=` to an earlier one are dropped. Fails when Pred
+    /// does.
+    pub(crate) fn predsort(&mut self) -> Result<bool, WamError> {
+        let predicate = self.registers[1].clone();
+        let sorted = self.registers[3].clone();
+        let elements = self.list_elements(&self.registers[2])?;
+        self.list_or_partial_list(&sorted)?;
+        match self.merge_sort(elements, &predicate)? {
+            Some(elements) => {
+                let list = self.build_list(elements, HeapCell::Constant(NIL.to_string()));
+                Ok(self.unify(sorted, list))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sorts `elements` by `predicate`, as `predsort/3` does; `None` when
+    /// the predicate fails.
+    fn merge_sort(&mut self, mut elements: Vec<HeapCell>, predicate: &HeapCell) -> Result<Option<Vec<HeapCell>>, WamError> {
+        if elements.len() < 2 {
+            return Ok(Some(elements));
+        }
+        let second = elements.split_off(elements.len() / 2);
+        let (first, second) = match (self.merge_sort(elements, predicate)?, self.merge_sort(second, predicate)?) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Ok(None),
+        };
+        let mut merged = Vec::with_capacity(first.len() + second.len());
+        let (mut first, mut second) = (first.into_iter().peekable(), second.into_iter().peekable());
+        while let (Some(a), Some(b)) = (first.peek(), second.peek()) {
+            match self.call_order(predicate, a.clone(), b.clone())? {
+                Some(Ordering::Greater) => merged.extend(second.next()),
+                Some(Ordering::Less) => merged.extend(first.next()),
+                Some(Ordering::Equal) => {
+                    merged.extend(first.next());
+                    second.next();
+                }
+                None => return Ok(None),
+            }
+        }
+        merged.extend(first.chain(second));
+        Ok(Some(merged))
+    }
+
+    /// Calls `call(Predicate, Order, A, B)` for its first solution.
+    fn call_order(&mut self, predicate: &HeapCell, a: HeapCell, b: HeapCell) -> Result<Option<Ordering>, WamError> {
+        let h = self.heap.len();
+        self.heap.push(HeapCell::Reference(h));
+        let goal = add_arguments(self, predicate, vec![HeapCell::Reference(h), a, b])?;
+        let mut order = None;
+        self.solve(goal, |wam| {
+            order = Some(wam.deref(&HeapCell::Reference(h)));
+            Ok(false)
+        })?;
+        match order {
+            None => Ok(None),
+            Some(HeapCell::Constant(name)) if name == "<" => Ok(Some(Ordering::Less)),
+            Some(HeapCell::Constant(name)) if name == "=" => Ok(Some(Ordering::Equal)),
+            Some(HeapCell::Constant(name)) if name == ">" => Ok(Some(Ordering::Greater)),
+            Some(cell) if self.is_unbound(&cell) => Err(instantiation_error()),
+            Some(cell) => Err(domain_error("order", self.decode_cell(&cell)?)),
+        }
+    }
+
+    /// The Nth argument of `element`, for `sort/4`.
+    fn argument_of(&self, element: &HeapCell, n: usize) -> Result<HeapCell, WamError> {
+        match self.compound_cell(element) {
+            Some((_, args)) if n <= args.len() => Ok(HeapCell::Reference(args[n - 1])),
+            _ if self.is_unbound(&self.deref(element)) => Err(instantiation_error()),
+            _ => Err(type_error("compound", self.decode_cell(element)?)),
+        }
+    }
+}
+
+/// `@X == @Y`, `@X @< @Y` and the rest of the term comparisons: succeeds
+/// when the standard order of X and Y is one `accept` takes.
+pub(crate) fn compare_terms(wam: &mut WamEmulator, accept: fn(Ordering) -> bool) -> Result<bool, WamError> {
+    Ok(accept(wam.compare_cells(&wam.registers[1], &wam.registers[2])))
+}
+
+/// Compares two terms in the standard order of terms, as `compare/3` does,
+/// for Rust callers. Variables are ordered by name, and a `Term::List` is
+/// compared as the `'.'/2` cells it stands for.
+pub fn standard_order(a: &Term, b: &Term) -> Ordering {
+    let rank = term_rank(a).cmp(&term_rank(b));
+    if rank != Ordering::Equal {
+        return rank;
+    }
+    match (a, b) {
+        (Term::Variable(x), Term::Variable(y)) => x.cmp(y),
+        _ if term_rank(a) == 2 => atom_name(a).cmp(atom_name(b)),
+        (Term::List(xs), Term::List(ys)) => xs
+            .iter()
+            .zip(ys)
+            .map(|(x, y)| standard_order(x, y))
+            .find(|&order| order != Ordering::Equal)
+            .unwrap_or_else(|| xs.len().cmp(&ys.len())),
+        (Term::List(_), _) | (_, Term::List(_)) => {
+            let (f, xs) = compound_parts(a);
+            let (g, ys) = compound_parts(b);
+            xs.len().cmp(&ys.len()).then_with(|| f.cmp(&g)).then_with(|| {
+                xs.iter().zip(&ys).map(|(x, y)| standard_order(x, y)).find(|&order| order != Ordering::Equal).unwrap_or(Ordering::Equal)
+            })
+        }
+        (Term::Structure { functor: f, args: xs, .. }, Term::Structure { functor: g, args: ys, .. }) => {
+            xs.len().cmp(&ys.len()).then_with(|| f.cmp(g)).then_with(|| {
+                xs.iter().zip(ys).map(|(x, y)| standard_order(x, y)).find(|&order| order != Ordering::Equal).unwrap_or(Ordering::Equal)
+            })
+        }
+        _ => {
+            let (x, y) = (term_number(a), term_number(b));
+            let by_value = x.compare(&y).unwrap_or(Ordering::Equal);
+            by_value.then_with(|| matches!(y, Number::Float(_)).cmp(&matches!(x, Number::Float(_))))
+        }
+    }
+}
+
+fn term_rank(term: &Term) -> u8 {
+    match term {
+        Term::Variable(_) => 0,
+        Term::Integer(_) | Term::BigInteger(_) | Term::Float(_) => 1,
+        Term::Atom(_) => 2,
+        Term::List(elements) if elements.is_empty() => 2,
+        _ => 3,
+    }
+}
+
+fn atom_name(term: &Term) -> &str {
+    match term {
+        Term::Atom(name) => name,
+        _ => NIL,
+    }
+}
+
+/// Name and arguments of a compound term, with a non-empty list split
+/// into its head and tail.
+fn compound_parts(term: &Term) -> (String, Vec<Term>) {
+    match term {
+        Term::Structure { functor, args, .. } => (functor.clone(), args.clone()),
+        Term::List(elements) => (LIST_FUNCTOR.to_string(), vec![elements[0].clone(), Term::List(elements[1..].to_vec())]),
+        term => unreachable!("{:?} is not compound", term),
+    }
+}
+
+fn term_number(term: &Term) -> Number {
+    match term {
+        Term::Integer(value) => Number::Integer(*value),
+        Term::BigInteger(value) => Number::BigInteger(value.clone()),
+        Term::Float(value) => Number::Float(*value),
+        term => unreachable!("{:?} is not a number", term),
+    }
+}
+
+/// Orders unbound variables by where they live: heap variables by
+/// address, then stack variables by slot.
+fn variable_key(cell: &HeapCell) -> (bool, usize) {
+    match cell {
+        HeapCell::Reference(a) => (false, *a),
+        HeapCell::StackReference(a) => (true, *a),
+        cell => unreachable!("{:?} is not a variable", cell),
+    }
+}
+
+fn number(cell: &HeapCell) -> Number {
+    match cell {
+        HeapCell::Integer(value) => Number::Integer(*value),
+        HeapCell::BigInteger(value) => Number::BigInteger(value.clone()),
+        HeapCell::Float(value) => Number::Float(*value),
+        cell => unreachable!("{:?} is not a number", cell),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::standard_order;
+    use crate::parser::ast::Term;
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    #[test]
+    fn test_standard_order() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "compare(O, 1, a)"), vec!["O = <"]);
+        assert_eq!(answers(&mut wam, "compare(O, f(b), g(a))"), vec!["O = <"]);
+        assert_eq!(answers(&mut wam, "compare(O, g(a, b), f(a))"), vec!["O = >"]);
+        assert_eq!(answers(&mut wam, "compare(O, 1.0, 1)"), vec!["O = <"]);
+        assert_eq!(answers(&mut wam, "X = f(Y), compare(O, X, f(Y))"), vec!["X = f(Y),\nO = ="]);
+        assert_eq!(answers(&mut wam, "X == X, X \\== Y, a @< f(a), 2 @> 1.5, Z @=< a, b @>= b"), vec!["true"]);
+        assert!(answers(&mut wam, "X == Y").is_empty());
+        assert!(answers(&mut wam, "a @> b").is_empty());
+        assert_eq!(exception(&mut wam, "compare(1, a, b)"), "error(type_error(atom, 1), _)");
+        assert_eq!(exception(&mut wam, "compare(less, a, b)"), "error(domain_error(order, less), _)");
+
+        // Long lists are compared without recursing on their tails.
+        let long = "numlist(1, 200000, L), numlist(1, 200000, M), L == M, append(M, [x], N), compare(O, L, N), O == <";
+        assert_eq!(answers(&mut wam, &format!("\\+ \\+ ({})", long)), vec!["true"]);
+    }
+
+    #[test]
+    fn test_sorting() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "sort([c, a, f(x), 2, b, a, 1.0], L)"), vec!["L = [1.0, 2, a, b, c, f(x)]"]);
+        assert_eq!(answers(&mut wam, "msort([b, a, b], L)"), vec!["L = [a, b, b]"]);
+        assert_eq!(answers(&mut wam, "keysort([b-1, a-2, b-0, a-1], L)"), vec!["L = [a-2, a-1, b-1, b-0]"]);
+        assert_eq!(answers(&mut wam, "sort(1, @>=, [f(1, a), f(2, b), f(1, c)], L)"), vec!["L = [f(2, b), f(1, a), f(1, c)]"]);
+        assert_eq!(answers(&mut wam, "sort(1, @<, [f(1, a), f(2, b), f(1, c)], L)"), vec!["L = [f(1, a), f(2, b)]"]);
+        assert_eq!(answers(&mut wam, "sort(0, @>, [a, c, b, c], L)"), vec!["L = [c, b, a]"]);
+        assert_eq!(exception(&mut wam, "sort([a|_], _)"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "keysort([a], _)"), "error(type_error(pair, a), _)");
+        assert_eq!(exception(&mut wam, "sort(1, @<, [a], _)"), "error(type_error(compound, a), _)");
+        assert_eq!(exception(&mut wam, "sort(0, <, [a], _)"), "error(domain_error(order, <), _)");
+    }
+
+    #[test]
+    fn test_predsort() {
+        let mut wam = WamEmulator::new();
+        wam.consult("by_length(O, A-_, B-_) :- compare(O, A, B). never(_, _, _) :- fail.").unwrap();
+        assert_eq!(answers(&mut wam, "predsort(by_length, [3-c, 1-a, 2-b, 1-d], L)"), vec!["L = [1-a, 2-b, 3-c]"]);
+        assert!(answers(&mut wam, "predsort(never, [a, b], L)").is_empty());
+        assert_eq!(answers(&mut wam, "predsort(never, [a], L)"), vec!["L = [a]"]);
+    }
+
+    #[test]
+    fn test_standard_order_of_terms() {
+        let atom = |name: &str| Term::Atom(name.to_string());
+        let list = Term::List(vec![atom("a")]);
+        let dotted = Term::Structure { functor: ".".to_string(), arity: 2, args: vec![atom("a"), atom("[]")] };
+        assert_eq!(standard_order(&list, &dotted), Ordering::Equal);
+        assert_eq!(standard_order(&Term::List(vec![]), &atom("[]")), Ordering::Equal);
+        assert_eq!(standard_order(&Term::Variable("X".to_string()), &Term::Integer(1)), Ordering::Less);
+        assert_eq!(standard_order(&Term::Float(2.0), &Term::Integer(1)), Ordering::Greater);
+        assert_eq!(standard_order(&list, &Term::List(vec![atom("a"), atom("b")])), Ordering::Less);
+        assert_eq!(standard_order(&atom("zebra"), &list), Ordering::Less);
+    }
+}
@@ -0,0 +1,334 @@
+// src/runtime/terms.rs
+use crate::parser::ast::Term;
+use crate::runtime::aggregate::alternatives;
+use crate::wam::data_structures::{HeapCell, TrailEntry, WamEmulator};
+use crate::wam::decoder::{LIST_FUNCTOR, NIL};
+use crate::wam::error::{domain_error, instantiation_error, representation_error, type_error, WamError};
+use crate::wam::verifier::MAX_ARITY;
+
+impl WamEmulator {
+    /// Functor and argument addresses of the compound term `cell` stands
+    /// for, `None` for variables and atomic terms.
+    pub(crate) fn compound_cell(&self, cell: &HeapCell) -> Option<(String, Vec<usize>)> {
+        match self.deref(cell) {
+            HeapCell::Reference(a) => match &self.heap[a] {
+                HeapCell::Structure(functor, args) => Some((functor.clone(), args.clone())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The elements of the list `cell` stands for. A partial list is an
+    /// instantiation error and any other term a type error.
+    pub(crate) fn list_elements(&self, cell: &HeapCell) -> Result<Vec<HeapCell>, WamError> {
+        let mut elements = Vec::new();
+        let mut current = self.deref(cell);
+        loop {
+            if self.is_unbound(&current) {
+                return Err(instantiation_error());
+            }
+            if current == HeapCell::Constant(NIL.to_string()) {
+                return Ok(elements);
+            }
+            match self.compound_cell(&current) {
+                Some((functor, args)) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                    elements.push(HeapCell::Reference(args[0]));
+                    current = self.deref(&HeapCell::Reference(args[1]));
+                }
+                _ => return Err(type_error("list", self.decode_cell(cell)?)),
+            }
+        }
+    }
+
+    /// Pushes `functor(_, ..., _)` with `arity` new variables.
+    fn push_fresh_structure(&mut self, functor: &str, arity: usize) -> HeapCell {
+        let h = self.heap.len();
+        self.heap.push(HeapCell::Structure(functor.to_string(), (h + 1..=h + arity).collect()));
+        self.heap.extend((h + 1..=h + arity).map(HeapCell::Reference));
+        HeapCell::Reference(h)
+    }
+
+    /// `cell` as a value that can be stored in the heap: an unbound stack
+    /// variable is bound to a new heap variable first.
+    fn globalize(&mut self, cell: &HeapCell) -> HeapCell {
+        let cell = self.deref(cell);
+        if let HeapCell::StackReference(_) = cell {
+            let h = self.heap.len();
+            self.heap.push(HeapCell::Reference(h));
+            self.bind(&cell, &HeapCell::Reference(h));
+            return HeapCell::Reference(h);
+        }
+        cell
+    }
+
+    /// `functor(?Term, ?Name, ?Arity)`: Term has name Name and arity Arity.
+    /// With Term unbound, it is bound to a term with new variables as
+    /// arguments.
+    pub(crate) fn functor(&mut self) -> Result<bool, WamError> {
+        let term = self.deref(&self.registers[1]);
+        if !self.is_unbound(&term) {
+            let (name, arity) = match self.compound_cell(&term) {
+                Some((functor, args)) => (HeapCell::Constant(functor), args.len()),
+                None => (term, 0),
+            };
+            let (name_argument, arity_argument) = (self.registers[2].clone(), self.registers[3].clone());
+            return Ok(self.unify(name_argument, name) && self.unify(arity_argument, HeapCell::Integer(arity as i64)));
+        }
+        let name = self.deref(&self.registers[2]);
+        let arity = self.deref(&self.registers[3]);
+        if self.is_unbound(&name) || self.is_unbound(&arity) {
+            return Err(instantiation_error());
+        }
+        let arity = match arity {
+            HeapCell::Integer(arity) => arity,
+            _ => return Err(type_error("integer", self.decode_cell(&arity)?)),
+        };
+        if self.compound_cell(&name).is_some() {
+            return Err(type_error("atomic", self.decode_cell(&name)?));
+        }
+        if arity < 0 {
+            return Err(domain_error("not_less_than_zero", Term::Integer(arity)));
+        }
+        if arity as u64 > MAX_ARITY as u64 {
+            return Err(representation_error("max_arity"));
+        }
+        if arity == 0 {
+            return Ok(self.unify(term, name));
+        }
+        let structure = match &name {
+            HeapCell::Constant(functor) => self.push_fresh_structure(functor, arity as usize),
+            _ => return Err(type_error("atomic", self.decode_cell(&name)?)),
+        };
+        Ok(self.unify(term, structure))
+    }
+
+    /// `arg(?N, +Term, ?Arg)`: Arg is the Nth argument of Term. With N
+    /// unbound, the arguments are enumerated on backtracking.
+    pub(crate) fn arg(&mut self) -> Result<bool, WamError> {
+        let n = self.deref(&self.registers[1]);
+        let term = self.deref(&self.registers[2]);
+        if self.is_unbound(&term) {
+            return Err(instantiation_error());
+        }
+        let (_, args) = match self.compound_cell(&term) {
+            Some(compound) => compound,
+            None => return Err(type_error("compound", self.decode_cell(&term)?)),
+        };
+        match n {
+            HeapCell::Integer(n) => {
+                let found = n >= 1 && n as usize <= args.len();
+                Ok(found && self.unify(self.registers[3].clone(), HeapCell::Reference(args[n as usize - 1])))
+            }
+            HeapCell::BigInteger(_) => Ok(false),
+            _ if self.is_unbound(&n) => {
+                let pairs = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &arg)| self.push_built_structure("-", vec![HeapCell::Integer(i as i64 + 1), HeapCell::Reference(arg)]))
+                    .collect();
+                let argument = self.registers[3].clone();
+                self.registers[1] = self.push_built_structure("-", vec![n, argument]);
+                self.registers[2] = self.build_list(pairs, HeapCell::Constant(NIL.to_string()));
+                alternatives(self)
+            }
+            _ => Err(type_error("integer", self.decode_cell(&n)?)),
+        }
+    }
+
+    /// `?Term =.. ?List`: List is the name of Term followed by its
+    /// arguments.
+    pub(crate) fn univ(&mut self) -> Result<bool, WamError> {
+        let term = self.deref(&self.registers[1]);
+        let list = self.registers[2].clone();
+        if !self.is_unbound(&term) {
+            self.list_or_partial_list(&list)?;
+            let cells = match self.compound_cell(&term) {
+                Some((functor, args)) => {
+                    std::iter::once(HeapCell::Constant(functor)).chain(args.into_iter().map(HeapCell::Reference)).collect()
+                }
+                None => vec![term],
+            };
+            let elements = self.build_list(cells, HeapCell::Constant(NIL.to_string()));
+            return Ok(self.unify(list, elements));
+        }
+        let elements = self.list_elements(&list)?;
+        let (head, args) = match elements.split_first() {
+            Some((head, args)) => (self.deref(head), args.to_vec()),
+            None => return Err(domain_error("non_empty_list", Term::Atom(NIL.to_string()))),
+        };
+        if self.is_unbound(&head) {
+            return Err(instantiation_error());
+        }
+        let built = match &head {
+            _ if args.is_empty() && self.compound_cell(&head).is_some() => {
+                return Err(type_error("atomic", self.decode_cell(&head)?));
+            }
+            _ if args.is_empty() => head,
+            _ if args.len() > MAX_ARITY => return Err(representation_error("max_arity")),
+            HeapCell::Constant(functor) => self.push_built_structure(functor, args),
+            _ => return Err(type_error("atom", self.decode_cell(&head)?)),
+        };
+        Ok(self.unify(term, built))
+    }
+
+    /// `copy_term(+Term, ?Copy)`: Copy is Term with new variables.
+    pub(crate) fn copy_term(&mut self) -> Result<bool, WamError> {
+        let term = self.store_term(&self.registers[1]);
+        let copy = self.restore_term(&term);
+        Ok(self.unify(self.registers[2].clone(), copy))
+    }
+
+    /// `setarg(+N, +Term, +Value)` and `nb_setarg/3`: replaces the Nth
+    /// argument of Term with Value. `setarg/3` is undone on backtracking;
+    /// `nb_setarg/3` stores a copy of Value that backtracking keeps.
+    fn set_argument(&mut self, backtrackable: bool) -> Result<bool, WamError> {
+        let n = self.deref(&self.registers[1]);
+        let term = self.deref(&self.registers[2]);
+        if self.is_unbound(&n) || self.is_unbound(&term) {
+            return Err(instantiation_error());
+        }
+        let n = match n {
+            HeapCell::Integer(n) => n,
+            HeapCell::BigInteger(_) => return Ok(false),
+            _ => return Err(type_error("integer", self.decode_cell(&n)?)),
+        };
+        let (_, args) = match self.compound_cell(&term) {
+            Some(compound) => compound,
+            None => return Err(type_error("compound", self.decode_cell(&term)?)),
+        };
+        if n < 1 || n as usize > args.len() {
+            return Ok(false);
+        }
+        let address = args[n as usize - 1];
+        let value = if backtrackable {
+            let value = self.globalize(&self.registers[3].clone());
+            if address < self.heap_boundary() {
+                self.trail.push(TrailEntry::Value(address, self.heap[address].clone()));
+            }
+            value
+        } else {
+            let top = self.heap.len();
+            let value = self.build_copy(&self.decode_cell(&self.registers[3])?);
+            // Backtracking must not free the copy.
+            if self.heap.len() > top {
+                let top = self.heap.len();
+                for b in &mut self.choicepoints {
+                    b.heap_top = top;
+                }
+            }
+            value
+        };
+        self.heap[address] = value;
+        Ok(true)
+    }
+
+    pub(crate) fn setarg(&mut self) -> Result<bool, WamError> {
+        self.set_argument(true)
+    }
+
+    pub(crate) fn nb_setarg(&mut self) -> Result<bool, WamError> {
+        self.set_argument(false)
+    }
+
+    /// `term_variables(+Term, ?Variables)`: Variables is the list of the
+    /// distinct variables of Term in depth-first, left-to-right order.
+    pub(crate) fn term_variables2(&mut self) -> Result<bool, WamError> {
+        let list = self.registers[2].clone();
+        self.list_or_partial_list(&list)?;
+        let variables = self.term_variables(&self.registers[1]);
+        let variables = self.build_list(variables, HeapCell::Constant(NIL.to_string()));
+        Ok(self.unify(list, variables))
+    }
+
+    /// `numbervars(+Term, +Start, ?End)`: binds the variables of Term to
+    /// `'$VAR'(Start)`, `'$VAR'(Start + 1)`, ..., with End the next number.
+    pub(crate) fn numbervars(&mut self) -> Result<bool, WamError> {
+        let start = self.deref(&self.registers[2]);
+        let mut n = match start {
+            HeapCell::Integer(start) => start,
+            _ if self.is_unbound(&start) => return Err(instantiation_error()),
+            _ => return Err(type_error("integer", self.decode_cell(&start)?)),
+        };
+        for variable in self.term_variables(&self.registers[1]) {
+            let value = self.push_built_structure("$VAR", vec![HeapCell::Integer(n)]);
+            self.bind(&variable, &value);
+            n += 1;
+        }
+        Ok(self.unify(self.registers[3].clone(), HeapCell::Integer(n)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    #[test]
+    fn test_functor_arg_and_univ() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "functor(foo(a, b), N, A)"), vec!["N = foo,\nA = 2"]);
+        assert_eq!(answers(&mut wam, "functor([a], _, A)"), vec!["A = 2"]);
+        assert_eq!(answers(&mut wam, "functor(3, N, A)"), vec!["N = 3,\nA = 0"]);
+        assert_eq!(answers(&mut wam, "functor(T, foo, 2), T = foo(X, Y), X = 1"), vec!["T = foo(1, Y),\nX = 1"]);
+        assert_eq!(answers(&mut wam, "functor(T, abc, 0)"), vec!["T = abc"]);
+        assert_eq!(answers(&mut wam, "arg(2, f(a, b, c), X)"), vec!["X = b"]);
+        assert!(answers(&mut wam, "arg(4, f(a, b, c), X)").is_empty());
+        assert_eq!(answers(&mut wam, "arg(N, f(a, b), X)"), vec!["N = 1,\nX = a", "N = 2,\nX = b"]);
+        assert_eq!(answers(&mut wam, "arg(N, f(a, b, a), a)"), vec!["N = 1", "N = 3"]);
+        assert_eq!(answers(&mut wam, "foo(a, B) =.. L"), vec!["L = [foo, a, B]"]);
+        assert_eq!(answers(&mut wam, "T =.. [bar, 1, X]"), vec!["T = bar(1, X)"]);
+        assert_eq!(answers(&mut wam, "T =.. [1.5], a =.. L"), vec!["T = 1.5,\nL = [a]"]);
+    }
+
+    #[test]
+    fn test_term_inspection_errors() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(exception(&mut wam, "functor(_, _, 1)"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "functor(_, foo(a), 1)"), "error(type_error(atomic, foo(a)), _)");
+        assert_eq!(exception(&mut wam, "functor(_, 1.5, 1)"), "error(type_error(atomic, 1.5), _)");
+        assert_eq!(exception(&mut wam, "functor(_, foo, -1)"), "error(domain_error(not_less_than_zero, -1), _)");
+        assert_eq!(exception(&mut wam, "functor(_, foo, a)"), "error(type_error(integer, a), _)");
+        assert_eq!(exception(&mut wam, "arg(1, atom, _)"), "error(type_error(compound, atom), _)");
+        assert_eq!(exception(&mut wam, "arg(a, f(b), _)"), "error(type_error(integer, a), _)");
+        assert_eq!(exception(&mut wam, "_ =.. [foo|_]"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "_ =.. []"), "error(domain_error(non_empty_list, []), _)");
+        assert_eq!(exception(&mut wam, "_ =.. [1, a]"), "error(type_error(atom, 1), _)");
+        assert_eq!(exception(&mut wam, "_ =.. [f(a)]"), "error(type_error(atomic, f(a)), _)");
+        assert_eq!(exception(&mut wam, "a =.. b"), "error(type_error(list, b), _)");
+        assert_eq!(exception(&mut wam, "functor(_, foo, 99999999999)"), "error(representation_error(max_arity), _)");
+        assert_eq!(exception(&mut wam, "numlist(0, 65536, L), _ =.. [f|L]"), "error(representation_error(max_arity), _)");
+    }
+
+    #[test]
+    fn test_copy_term_and_term_variables() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "copy_term(f(X, Y, X), f(A, B, C)), A = 1, B = 2"), vec!["A = 1,\nB = 2,\nC = 1"]);
+        assert_eq!(answers(&mut wam, "copy_term(f(X), f(a)), X = b"), vec!["X = b"]);
+        assert_eq!(answers(&mut wam, "term_variables(f(X, g(Y, X), _Z), Vs), X = 1, Y = 2"), vec![
+            "X = 1,\nY = 2,\nVs = [1, 2, _Z]"
+        ]);
+        assert_eq!(answers(&mut wam, "T = f(X, Y, X), numbervars(T, 0, End)"), vec![
+            "T = f(A, B, A),\nX = A,\nY = B,\nEnd = 2"
+        ]);
+        // Deep and cyclic terms are copied without recursion.
+        wam.consult("deep(0, L, L) :- !. deep(N, L, f(T)) :- N1 is N - 1, deep(N1, L, T).").unwrap();
+        let goal = "deep(300000, X, T), copy_term(X-T, Y-C), Y = z, var(X), deep(300000, z, D), C == D, \
+            term_variables(g(T, X), Vs), Vs == [X]";
+        assert_eq!(answers(&mut wam, &format!("\\+ \\+ ({})", goal)), vec!["true"]);
+        assert_eq!(answers(&mut wam, "\\+ \\+ (X = f(X, Y), copy_term(X, C), C = f(A, B), A == C, B \\== Y)"), vec!["true"]);
+    }
+
+    #[test]
+    fn test_setarg() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "T = f(a, b), setarg(1, T, c)"), vec!["T = f(c, b)"]);
+        // setarg/3 is undone on backtracking, nb_setarg/3 is not.
+        assert_eq!(answers(&mut wam, "T = f(a), (setarg(1, T, b), fail ; true)"), vec!["T = f(a)"]);
+        assert_eq!(answers(&mut wam, "T = f(a), (nb_setarg(1, T, g(c)), fail ; true), T = f(g(C))"), vec![
+            "T = f(g(c)),\nC = c"
+        ]);
+        assert!(answers(&mut wam, "setarg(3, f(a), b)").is_empty());
+        assert_eq!(exception(&mut wam, "setarg(1, a, b)"), "error(type_error(compound, a), _)");
+    }
+}
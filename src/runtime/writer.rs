@@ -0,0 +1,622 @@
+// src/runtime/writer.rs
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parser::ast::Term;
+use crate::parser::lexer::{is_symbol_char, is_valid_atom};
+use crate::parser::operators::{infix, prefix};
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::error::{domain_error, instantiation_error, WamError};
+
+/// How `write_term/2,3` writes a term. `write/1`, `writeq/1`, `print/1` and
+/// `write_canonical/1` each use a fixed set of these.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Quote atoms that would not read back as themselves.
+    pub quoted: bool,
+    /// Write operator terms in functional notation, as `+(1, 2)`.
+    pub ignore_ops: bool,
+    /// Write `'$VAR'(N)` as the variable name `A`, ..., `Z`, `A1`, ...
+    pub numbervars: bool,
+    /// Write terms nested deeper than this, and list elements past it, as
+    /// `...`; 0 for no limit.
+    pub max_depth: usize,
+    /// Names to write variables with, keyed by the name they decode with.
+    pub variable_names: HashMap<String, String>,
+    /// Write a space after each comma between arguments and list elements.
+    pub spacing: bool,
+}
+
+impl WriteOptions {
+    /// The options of `write/1` and `print/1` without quoting.
+    pub fn write() -> Self {
+        WriteOptions { numbervars: true, ..WriteOptions::default() }
+    }
+
+    /// The options of `writeq/1` and `print/1`.
+    pub fn writeq() -> Self {
+        WriteOptions { quoted: true, numbervars: true, ..WriteOptions::default() }
+    }
+
+    /// The options of `write_canonical/1`.
+    pub fn canonical() -> Self {
+        WriteOptions { quoted: true, ignore_ops: true, ..WriteOptions::default() }
+    }
+}
+
+/// Terms display as `writeq/1` writes them, with a space after each
+/// argument separator.
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = WriteOptions { spacing: true, ..WriteOptions::writeq() };
+        write!(f, "{}", write_term(self, &options))
+    }
+}
+
+/// The text of `term` written with `options`. With `quoted` set and
+/// `max_depth` 0, the parser reads it back as the same term.
+pub fn write_term(term: &Term, options: &WriteOptions) -> String {
+    let mut writer = Writer { options, out: String::new() };
+    writer.write(term, 1200, 1);
+    writer.out
+}
+
+/// Formats a float so it reads back as a float: `1.0`, `1.0e20`, `inf`.
+pub fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        return "nan".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let text = format!("{:?}", value);
+    match text.find('e') {
+        Some(e) if !text[..e].contains('.') => format!("{}.0{}", &text[..e], &text[e..]),
+        _ => text,
+    }
+}
+
+struct Writer<'a> {
+    options: &'a WriteOptions,
+    out: String,
+}
+
+impl Writer<'_> {
+    /// Appends `text`, with a space first if it would otherwise run into
+    /// what is already written as one token, as `- -1` or `a rem b`.
+    fn emit(&mut self, text: &str) {
+        if let (Some(last), Some(first)) = (self.out.chars().last(), text.chars().next()) {
+            let word = |c: char| c.is_alphanumeric() || c == '_';
+            let glues = word(last) && (word(first) || first == '(') || is_symbol_char(last) && is_symbol_char(first);
+            if glues {
+                self.out.push(' ');
+            }
+        }
+        self.out.push_str(text);
+    }
+
+    fn separator(&mut self) {
+        self.out.push_str(if self.options.spacing { ", " } else { "," });
+    }
+
+    /// Writes `term` as an operand of priority at most `priority`, `depth`
+    /// levels into the term being written.
+    fn write(&mut self, term: &Term, priority: usize, depth: usize) {
+        if self.options.max_depth > 0 && depth > self.options.max_depth {
+            self.emit("...");
+            return;
+        }
+        match term {
+            Term::Variable(name) => {
+                let name = self.options.variable_names.get(name).unwrap_or(name).clone();
+                self.emit(&name);
+            }
+            Term::Integer(value) => self.emit(&value.to_string()),
+            Term::BigInteger(value) => self.emit(&value.to_string()),
+            Term::Float(value) => self.emit(&format_float(*value)),
+            Term::Atom(name) => {
+                let operator = infix(name).or_else(|| prefix(name)).map_or(0, |op| op.priority);
+                if operator > priority {
+                    self.emit("(");
+                    self.write_atom(name);
+                    self.out.push(')');
+                } else {
+                    self.write_atom(name);
+                }
+            }
+            Term::List(_) => self.write_list(term, depth),
+            Term::Structure { functor, args, .. } => self.write_structure(functor, args, priority, depth),
+        }
+    }
+
+    fn write_structure(&mut self, functor: &str, args: &[Term], priority: usize, depth: usize) {
+        if functor == "." && args.len() == 2 {
+            return self.write_list(&Term::Structure { functor: functor.to_string(), arity: 2, args: args.to_vec() }, depth);
+        }
+        if self.options.numbervars && functor == "$VAR" && args.len() == 1 {
+            match &args[0] {
+                Term::Integer(n) if *n >= 0 => {
+                    let letter = (b'A' + (n % 26) as u8) as char;
+                    let name = match n / 26 {
+                        0 => letter.to_string(),
+                        suffix => format!("{}{}", letter, suffix),
+                    };
+                    return self.emit(&name);
+                }
+                Term::Atom(name) => return self.emit(name),
+                _ => {}
+            }
+        }
+        if !self.options.ignore_ops {
+            if functor == "{}" && args.len() == 1 {
+                self.emit("{");
+                self.write(&args[0], 1200, depth + 1);
+                self.out.push('}');
+                return;
+            }
+            if let (Some(op), [left, right]) = (infix(functor).filter(|_| functor != "|"), args) {
+                let (left_priority, right_priority) = op.argument_priorities();
+                let open = op.priority > priority;
+                if open {
+                    self.emit("(");
+                }
+                self.write_operand(left, left_priority, depth + 1);
+                match functor {
+                    "," => self.separator(),
+                    _ => {
+                        let name = self.atom_text(functor);
+                        self.emit(&name);
+                    }
+                }
+                self.write_operand(right, right_priority, depth + 1);
+                if open {
+                    self.out.push(')');
+                }
+                return;
+            }
+            if let (Some(op), [arg]) = (prefix(functor), args) {
+                let mut operand = Writer { options: self.options, out: String::new() };
+                operand.write(arg, op.argument_priorities().1, depth + 1);
+                // `- 1` and `-(1)` would read back as the number -1, an
+                // operator atom as the operand as a different operator term,
+                // and an operand starting with an infix operator name, as
+                // `'|'(a)`, with the prefix operator read as an atom.
+                let numeric = matches!(functor, "-" | "+") && operand.out.starts_with(|c: char| c.is_ascii_digit());
+                let operator_atom = matches!(arg, Term::Atom(name) if infix(name).is_some() || prefix(name).is_some());
+                let infix_start = matches!(leftmost(arg), Term::Structure { functor: name, .. }
+                    if infix(name).is_some() && prefix(name).is_none());
+                if !numeric && !operator_atom && !infix_start {
+                    let open = op.priority > priority;
+                    if open {
+                        self.emit("(");
+                    }
+                    let name = self.atom_text(functor);
+                    self.emit(&name);
+                    // `-(a)` would read back as the compound in functional notation.
+                    if operand.out.starts_with('(') {
+                        self.out.push(' ');
+                    }
+                    self.write_operand(arg, op.argument_priorities().1, depth + 1);
+                    if open {
+                        self.out.push(')');
+                    }
+                    return;
+                }
+            }
+        }
+        // `{}(X)` and `[](X)` do not read back as compounds; `'{}'(X)` does.
+        if self.options.quoted && matches!(functor, "{}" | "[]") {
+            self.emit(&format!("'{}'", functor));
+        } else {
+            self.write_atom(functor);
+        }
+        self.out.push('(');
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.separator();
+            }
+            self.write(arg, 999, depth + 1);
+        }
+        self.out.push(')');
+    }
+
+    /// Writes an operand of an infix operator, bracketing an operator atom
+    /// so that it does not read back as a prefix operator applied to the
+    /// rest, as in `(-) = a`, and a negative number after a word operator,
+    /// as in `a mod (-1)`.
+    fn write_operand(&mut self, term: &Term, priority: usize, depth: usize) {
+        let after_word = self.out.ends_with(|c: char| c.is_alphanumeric() || c == '_');
+        let negative = match term {
+            Term::Integer(value) => *value < 0,
+            Term::BigInteger(value) => value.sign() == num_bigint::Sign::Minus,
+            Term::Float(value) => value.is_sign_negative(),
+            _ => false,
+        };
+        match term {
+            Term::Atom(name) if infix(name).is_some() || prefix(name).is_some() => {
+                self.emit("(");
+                self.write_atom(name);
+                self.out.push(')');
+            }
+            _ if negative && after_word => {
+                self.emit("(");
+                self.write(term, 1200, depth);
+                self.out.push(')');
+            }
+            _ => self.write(term, priority, depth),
+        }
+    }
+
+    /// Writes a list, or a chain of `'.'/2` cells ending in something other
+    /// than `[]` as `[a, b|Tail]`.
+    fn write_list(&mut self, term: &Term, depth: usize) {
+        let mut elements = Vec::new();
+        let mut current = term;
+        let tail = loop {
+            match current {
+                Term::Structure { functor, args, .. } if functor == "." && args.len() == 2 => {
+                    elements.push(&args[0]);
+                    current = &args[1];
+                }
+                Term::List(rest) => {
+                    elements.extend(rest);
+                    break None;
+                }
+                Term::Atom(name) if name == "[]" => break None,
+                tail => break Some(tail),
+            }
+        };
+        if elements.is_empty() && tail.is_none() {
+            return self.emit("[]");
+        }
+        self.emit("[");
+        for (i, element) in elements.iter().enumerate() {
+            // Each element is one level deeper than the one before it.
+            if i > 0 && self.options.max_depth > 0 && depth + i >= self.options.max_depth {
+                self.out.push_str("|...]");
+                return;
+            }
+            if i > 0 {
+                self.separator();
+            }
+            self.write(element, 999, depth + i + 1);
+        }
+        if let Some(tail) = tail {
+            self.out.push('|');
+            self.write(tail, 999, depth + 1);
+        }
+        self.out.push(']');
+    }
+
+    fn write_atom(&mut self, name: &str) {
+        let text = self.atom_text(name);
+        self.emit(&text);
+    }
+
+    /// `name` as written, in quotes if needed and asked for.
+    fn atom_text(&self, name: &str) -> String {
+        if !self.options.quoted || !atom_needs_quotes(name) {
+            return name.to_string();
+        }
+        let mut text = String::from("'");
+        for c in name.chars() {
+            match c {
+                '\'' => text.push_str("\\'"),
+                '\\' => text.push_str("\\\\"),
+                '\n' => text.push_str("\\n"),
+                '\t' => text.push_str("\\t"),
+                _ => text.push(c),
+            }
+        }
+        text.push('\'');
+        text
+    }
+}
+
+/// The term written first when `term` is written with operators: the left
+/// operand of its left operand, and so on.
+fn leftmost(mut term: &Term) -> &Term {
+    while let Term::Structure { functor, args, .. } = term {
+        match args.as_slice() {
+            [left, _] if functor != "." && functor != "|" && infix(functor).is_some() => term = left,
+            _ => break,
+        }
+    }
+    term
+}
+
+fn atom_needs_quotes(name: &str) -> bool {
+    let solo = matches!(name, "[]" | "{}" | "!" | ";");
+    let symbolic = !name.is_empty() && name.chars().all(is_symbol_char);
+    !(is_valid_atom(name) || solo || symbolic)
+}
+
+impl WamEmulator {
+    /// The text of the term `cell` stands for written with `options`. With
+    /// `max_depth` set, the term is only decoded as deep as it is written,
+    /// so any term can be written; otherwise one nested deeper than
+    /// `MAX_DECODE_DEPTH`, or cyclic, raises a resource error.
+    pub(crate) fn write_cell(&self, cell: &HeapCell, options: &WriteOptions) -> Result<String, WamError> {
+        let term = match options.max_depth {
+            0 => self.decode_cell(cell)?,
+            depth => self.decode_abbreviated(cell, depth),
+        };
+        Ok(write_term(&term, options))
+    }
+
+    /// `write_term(@Term, +Options)` and `write_term(+Stream, @Term,
+    /// +Options)`.
+    pub(crate) fn write_term(&mut self, arity: usize) -> Result<bool, WamError> {
+        let options = self.write_options(&self.registers[arity].clone())?;
+        self.write(arity - 1, options)
+    }
+
+    /// The options of `write_term/2,3` in the list `cell` stands for.
+    fn write_options(&self, cell: &HeapCell) -> Result<WriteOptions, WamError> {
+        let mut options = WriteOptions::default();
+        for option in self.list_elements(cell)? {
+            let option = self.deref(&option);
+            let invalid = || self.decode_cell(&option).map_or_else(|error| error, |option| domain_error("write_option", option));
+            let (name, value) = match self.compound_cell(&option) {
+                Some((name, args)) if args.len() == 1 => (name, self.deref(&HeapCell::Reference(args[0]))),
+                _ if self.is_unbound(&option) => return Err(instantiation_error()),
+                _ => return Err(invalid()),
+            };
+            if self.is_unbound(&value) {
+                return Err(instantiation_error());
+            }
+            let flag = match &value {
+                HeapCell::Constant(value) if value == "true" => Some(true),
+                HeapCell::Constant(value) if value == "false" => Some(false),
+                _ => None,
+            };
+            match (name.as_str(), flag, &value) {
+                ("quoted", Some(flag), _) => options.quoted = flag,
+                ("ignore_ops", Some(flag), _) => options.ignore_ops = flag,
+                ("numbervars", Some(flag), _) => options.numbervars = flag,
+                ("max_depth", _, HeapCell::Integer(depth)) if *depth >= 0 => options.max_depth = *depth as usize,
+                ("spacing", _, HeapCell::Constant(spacing)) if spacing == "next_argument" => options.spacing = true,
+                ("spacing", _, HeapCell::Constant(spacing)) if spacing == "standard" => options.spacing = false,
+                ("variable_names", _, _) => {
+                    for pair in self.list_elements(&value)? {
+                        let (name, variable) = match self.compound_cell(&pair) {
+                            Some((functor, args)) if functor == "=" && args.len() == 2 => {
+                                (self.deref(&HeapCell::Reference(args[0])), HeapCell::Reference(args[1]))
+                            }
+                            _ => return Err(invalid()),
+                        };
+                        match (name, self.decode_cell(&variable)?) {
+                            (HeapCell::Constant(name), Term::Variable(variable)) => {
+                                options.variable_names.insert(variable, name);
+                            }
+                            (HeapCell::Constant(_), _) => {}
+                            _ => return Err(invalid()),
+                        }
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Formats the answer to a query the way the toplevel prints it, e.g.
+/// `X = f(a, _G12)`. `bindings` pairs each query variable name with the heap
+/// address of that variable. Unbound query variables keep their own names and
+/// are left out of the answer; if nothing is bound the answer is `true`.
+pub fn format_answer(emulator: &WamEmulator, bindings: &[(String, usize)]) -> String {
+    let mut names = HashMap::new();
+    for (name, index) in bindings {
+        names.entry(emulator.deref_address(*index)).or_insert_with(|| name.clone());
+    }
+
+    let mut lines = Vec::new();
+    for (name, index) in bindings {
+        match emulator.decode_term_with_names(*index, &names) {
+            Some(Term::Variable(ref var)) if var == name => {}
+            Some(term) => lines.push(format!("{} = {}", name, term)),
+            None => {}
+        }
+    }
+
+    if lines.is_empty() {
+        "true".to_string()
+    } else {
+        lines.join(",\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::parse_term_text;
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::Term as HeapTerm;
+
+    #[test]
+    fn test_display_terms() {
+        let term = Term::Structure {
+            functor: "f".to_string(),
+            arity: 3,
+            args: vec![
+                Term::Atom("a".to_string()),
+                Term::Variable("_G12".to_string()),
+                Term::List(vec![Term::Atom("Hello world".to_string()), Term::Atom("[]".to_string())]),
+            ],
+        };
+        assert_eq!(term.to_string(), "f(a, _G12, ['Hello world', []])");
+
+        let partial = Term::Structure {
+            functor: ".".to_string(),
+            arity: 2,
+            args: vec![Term::Atom("a".to_string()), Term::Variable("T".to_string())],
+        };
+        assert_eq!(partial.to_string(), "[a|T]");
+        assert_eq!(Term::Atom("it's".to_string()).to_string(), "'it\\'s'");
+        assert_eq!(Term::Atom("+".to_string()).to_string(), "+");
+    }
+
+    #[test]
+    fn test_format_answer_with_fresh_variables() {
+        let mut wam = WamEmulator::new();
+        let x = wam.push_term(&HeapTerm::Variable);
+        let value = wam.push_term(&HeapTerm::Compound("f".into(), vec![HeapTerm::Atom("a".into()), HeapTerm::Variable]));
+        wam.heap[x] = HeapCell::Reference(value);
+
+        let answer = format_answer(&wam, &[("X".to_string(), x)]);
+        assert_eq!(answer, format!("X = f(a, _G{})", value - 1));
+    }
+
+    #[test]
+    fn test_format_answer_shares_query_variable_names() {
+        let mut wam = WamEmulator::new();
+        let x = wam.push_term(&HeapTerm::Variable);
+        let y = wam.push_term(&HeapTerm::Variable);
+        let z = wam.push_term(&HeapTerm::Variable);
+        let value = wam.push_term(&HeapTerm::Compound("g".into(), vec![HeapTerm::Atom("b".into())]));
+        wam.heap[y] = HeapCell::Reference(x);
+        wam.heap[z] = HeapCell::Reference(value);
+
+        let bindings = vec![("X".to_string(), x), ("Y".to_string(), y), ("Z".to_string(), z)];
+        assert_eq!(format_answer(&wam, &bindings), "Y = X,\nZ = g(b)");
+    }
+
+    #[test]
+    fn test_format_answer_without_bindings() {
+        let mut wam = WamEmulator::new();
+        let x = wam.push_term(&HeapTerm::Variable);
+        assert_eq!(format_answer(&wam, &[("X".to_string(), x)]), "true");
+    }
+
+    #[test]
+    fn test_operators_and_quoting() {
+        let write = |text: &str, options: &WriteOptions| write_term(&parse_term_text(text).unwrap(), options);
+        let writeq = WriteOptions::writeq();
+        assert_eq!(write("1 + 2 * 3 - (4 - 5)", &writeq), "1+2*3-(4-5)");
+        assert_eq!(write("(a :- b, c ; d -> e)", &writeq), "a:-b,c;d->e");
+        assert_eq!(write("f((a, b), (c :- d), -(1), - a, -(-(1)), 1 - -1)", &writeq), "f((a,b),(c:-d),-(1),-a,- -(1),1- -1)");
+        assert_eq!(write("X is 7 mod 2 rem 3", &writeq), "X is 7 mod 2 rem 3");
+        assert_eq!(write("a = (\\+ b)", &writeq), "a=(\\+b)");
+        assert_eq!(write("[- , 'A', 'it''s', [], '[]', {x, y}, 'hello world']", &writeq), "[-,'A','it\\'s',[],[],{x,y},'hello world']");
+        assert_eq!(write("- (1 ^ 2)", &writeq), "-(1^2)");
+        assert_eq!(write("f(:-, (-), \\+ (-))", &writeq), "f((:-),-,\\+(-))");
+        assert_eq!(write("['A'|T]", &WriteOptions::write()), "[A|T]");
+    }
+
+    #[test]
+    fn test_write_options() {
+        let term = parse_term_text("f('$VAR'(1), '$VAR'(27), 'A'-[x, y, z], g(h(i(j))), X)").unwrap();
+        let options = WriteOptions { numbervars: true, ..WriteOptions::default() };
+        assert_eq!(write_term(&term, &options), "f(B,B1,A-[x,y,z],g(h(i(j))),X)");
+        let options = WriteOptions { quoted: true, ignore_ops: true, ..WriteOptions::default() };
+        assert_eq!(write_term(&term, &options), "f('$VAR'(1),'$VAR'(27),-('A',[x,y,z]),g(h(i(j))),X)");
+        let options = WriteOptions { max_depth: 3, spacing: true, ..WriteOptions::default() };
+        assert_eq!(write_term(&term, &options), "f($VAR(1), $VAR(27), A-[...|...], g(h(...)), X)");
+        let mut variable_names = HashMap::new();
+        variable_names.insert("X".to_string(), "Input".to_string());
+        let options = WriteOptions { variable_names, ..WriteOptions::default() };
+        assert_eq!(write_term(&parse_term_text("p(X, Y)").unwrap(), &options), "p(Input,Y)");
+    }
+
+    /// Runs `goal` with current output sent to memory and returns the text written.
+    fn written(wam: &mut WamEmulator, goal: &str) -> String {
+        let id = wam.streams.memory_output();
+        wam.streams.output = id;
+        answers(wam, goal);
+        wam.streams.take_memory(id)
+    }
+
+    #[test]
+    fn test_write_builtins() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(written(&mut wam, "writeq(['A'-1, 'b c']), print(' '), write('A'-[1])"), "['A'-1,'b c']' 'A-[1]");
+        assert_eq!(written(&mut wam, "write_canonical([a, 'B'|1+2])"), "[a,'B'|+(1,2)]");
+        let goal = "write_term(f(X, 'a b', [1, 2, 3]), [quoted(true), max_depth(3), spacing(next_argument), variable_names(['Y'=X])])";
+        assert_eq!(written(&mut wam, goal), "f(Y, 'a b', [1|...])");
+        assert_eq!(written(&mut wam, "numbervars(g(A, B), 0, _), print(g(A, B))"), "g(A,B)");
+        assert_eq!(exception(&mut wam, "write_term(a, [bogus])"), "error(domain_error(write_option, bogus), _)");
+        assert_eq!(exception(&mut wam, "write_term(a, [quoted(_)])"), "error(instantiation_error, _)");
+        // Terms too deep to decode can still be written down to a depth.
+        wam.consult("deep(0, L, L) :- !. deep(N, L, f(T)) :- N1 is N - 1, deep(N1, L, T).").unwrap();
+        for goal in ["writeq(T)", "print(T)", "format(\"~w\", [T])", "term_to_atom(T, _)"] {
+            let goal = format!("deep(100000, a, T), {}", goal);
+            assert_eq!(exception(&mut wam, &goal), "error(resource_error(term_depth), _)", "{}", goal);
+        }
+        assert_eq!(written(&mut wam, "deep(100000, a, T), write_term(T, [max_depth(3)])"), "f(f(f(...)))");
+        assert_eq!(written(&mut wam, "X = f(X), write_term(X, [max_depth(5)])"), "f(...)");
+        assert_eq!(written(&mut wam, "write_term([a, b, c, d|_], [max_depth(3)])"), "[a,b|...]");
+    }
+
+    /// A xorshift generator, so the round-trip test checks the same terms on
+    /// every run.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn structure(functor: &str, args: Vec<Term>) -> Term {
+        Term::Structure { functor: functor.to_string(), arity: args.len(), args }
+    }
+
+    fn random_term(random: &mut Random, depth: usize) -> Term {
+        const ATOMS: &[&str] = &[
+            "a", "foo_Bar1", "'", "A b", "it's", "é", "[a]", "{}", "!", ";", ",", "|", "-", "+", "\\+", ":-",
+            "=..", "mod", "dynamic", "\\", "x\ny", "", "$VAR", "[]x",
+        ];
+        const INFIX: &[&str] = &["+", "-", "*", "^", "**", ":-", ",", ";", "->", "=", "is", "mod", ":", "=..", "\\="];
+        const PREFIX: &[&str] = &["-", "+", "\\+", "\\", "dynamic", ":-"];
+        let leaf = depth == 0 || random.below(3) == 0;
+        match random.below(if leaf { 5 } else { 10 }) {
+            0 => Term::Atom(ATOMS[random.below(ATOMS.len())].to_string()),
+            1 => Term::Integer(random.below(200) as i64 - 100),
+            2 => Term::Float((random.below(2000) as f64 - 1000.0) / 8.0),
+            3 => Term::Variable(format!("_G{}", random.below(4))),
+            4 => Term::List(vec![]),
+            5 | 6 => {
+                let functor = INFIX[random.below(INFIX.len())];
+                structure(functor, vec![random_term(random, depth - 1), random_term(random, depth - 1)])
+            }
+            7 => structure(PREFIX[random.below(PREFIX.len())], vec![random_term(random, depth - 1)]),
+            8 => {
+                let elements = (0..random.below(3) + 1).map(|_| random_term(random, depth - 1)).collect();
+                if random.below(2) == 0 {
+                    Term::List(elements)
+                } else {
+                    let tail = Term::Variable("_T".to_string());
+                    elements.into_iter().rev().fold(tail, |tail, element| structure(".", vec![element, tail]))
+                }
+            }
+            _ => {
+                let functor = ATOMS[random.below(ATOMS.len())];
+                let args = (0..random.below(3) + 1).map(|_| random_term(random, depth - 1)).collect();
+                structure(functor, args)
+            }
+        }
+    }
+
+    #[test]
+    fn test_quoted_terms_read_back() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        // Without numbervars, which writes `'$VAR'(1)` as `B`.
+        let quoted = WriteOptions { quoted: true, ..WriteOptions::default() };
+        let spaced = WriteOptions { spacing: true, ..quoted.clone() };
+        let canonical = WriteOptions::canonical();
+        for _ in 0..2000 {
+            let term = random_term(&mut random, 4);
+            for options in [&quoted, &spaced, &canonical] {
+                let text = write_term(&term, options);
+                match parse_term_text(&text) {
+                    Ok(read) => assert_eq!(read, term, "{} read back differently", text),
+                    Err(error) => panic!("{} does not read back: {}", text, error),
+                }
+            }
+        }
+    }
+}
@@ -1,24 +1,279 @@
 // src/wam/data_structures.rs
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use num_bigint::BigInt;
+
+use crate::compiler::CompileOptions;
+use crate::parser::ast::Clause;
+use crate::runtime::foreign::Foreign;
+use crate::runtime::io::Streams;
+use crate::wam::database::{DynamicClauses, DynamicRetry};
+use crate::wam::error::WamError;
+use crate::wam::instruction::Instruction;
+use crate::wam::jit_index::ArgumentIndex;
+
 pub enum Term {
     Atom(String),
     Compound(String, Vec<Term>), // Changed from Compound(String, Vec<Term>)
     Variable,
 }
 
-#[derive(Debug, PartialEq)]
+/// A memory cell. `Reference` points into the heap and `StackReference` into
+/// the environment stack; a reference to itself is an unbound variable and a
+/// reference to a `Structure` cell stands for that structure.
+#[derive(Debug, Clone, PartialEq)]
 pub enum HeapCell {
     Reference(usize),
     Structure(String, Vec<usize>),
     Constant(String),
+    Integer(i64),
+    /// An integer outside the range of `i64`; smaller ones are always `Integer`.
+    BigInteger(BigInt),
+    Float(f64),
+    StackReference(usize),
+}
+
+/// Address of the code area's `halt` instruction, used as the continuation of queries.
+pub const HALT: usize = 0;
+/// Address of the `exit_catch` stub the goal of a `catch/3` returns to.
+pub const CATCH_EXIT: usize = 1;
+/// Address of the stub a `catch/3` choice point continues at, which drops
+/// the choice point and fails. Choice points continuing here are catchers.
+pub const CATCH_FAIL: usize = 2;
+/// Address of the `retry_dynamic` stub the choice points of walks over the
+/// clauses of dynamic predicates continue at.
+pub const RETRY_DYNAMIC: usize = 4;
+/// Address of the stub the choice points of `'$member'/2` continue at,
+/// which drops the choice point and calls `'$member'/2` on the rest of
+/// the list.
+pub const ALTERNATIVES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Read,
+    Write,
+}
+
+/// An environment: the continuation of the clause that allocated it and the
+/// location of its permanent variables on the stack.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub ce: Option<usize>,
+    pub cp: usize,
+    pub base: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChoicePoint {
+    pub args: Vec<HeapCell>,
+    pub e: Option<usize>,
+    pub cp: usize,
+    pub b0: usize,
+    pub next: usize,
+    pub trail_top: usize,
+    pub heap_top: usize,
+    pub frame_top: usize,
+    pub stack_top: usize,
+    /// The walk over dynamic clauses to go on with, for choice points
+    /// continuing at `RETRY_DYNAMIC`.
+    pub(crate) dynamic: Option<DynamicRetry>,
+}
+
+/// A variable that was bound while a choice point protected it and must be
+/// reset on backtracking.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrailEntry {
+    Heap(usize),
+    Stack(usize),
+    /// A heap cell overwritten by `setarg/3`, with the value it had.
+    Value(usize, HeapCell),
+}
+
+/// A predicate implemented in Rust. It finds its arguments in A1..An and
+/// returns whether it succeeded; execution then continues at `CP`.
+pub type Builtin = fn(&mut WamEmulator) -> Result<bool, WamError>;
+
+/// Where a clause was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    pub name: String,
+    pub arity: usize,
+    pub clauses: Vec<Clause>,
+    /// Source location of each clause, when the clauses came from source text.
+    pub locations: Vec<SourceLocation>,
+    pub(crate) entry: Option<usize>,
+    pub(crate) clause_entries: Vec<usize>,
+    /// Indexes on argument positions other than the first, keyed by position.
+    pub(crate) argument_indexes: HashMap<usize, ArgumentIndex>,
+    pub(crate) builtin: Option<Builtin>,
+    /// Index of the foreign predicate implementing this one, if any.
+    pub(crate) foreign: Option<usize>,
+    /// The clauses of a dynamic predicate; `None` for static ones.
+    pub(crate) dynamic: Option<DynamicClauses>,
+}
+
+impl Predicate {
+    /// Whether the predicate is implemented in Rust, as a builtin or a
+    /// foreign predicate, and so cannot be defined by clauses.
+    pub fn is_builtin(&self) -> bool {
+        self.builtin.is_some() || self.foreign.is_some()
+    }
+}
+
+/// All predicates known to the emulator, including ones that are only
+/// referenced so far. Compiled code refers to predicates by their index here.
+#[derive(Debug, Default)]
+pub struct PredicateTable {
+    predicates: Vec<Predicate>,
+    ids: HashMap<(String, usize), usize>,
+}
+
+impl PredicateTable {
+    /// Returns the id of `name/arity`, adding an undefined entry if needed.
+    pub fn id(&mut self, name: &str, arity: usize) -> usize {
+        if let Some(&id) = self.ids.get(&(name.to_string(), arity)) {
+            return id;
+        }
+        let id = self.predicates.len();
+        self.predicates.push(Predicate {
+            name: name.to_string(),
+            arity,
+            clauses: Vec::new(),
+            locations: Vec::new(),
+            entry: None,
+            clause_entries: Vec::new(),
+            argument_indexes: HashMap::new(),
+            builtin: None,
+            foreign: None,
+            dynamic: None,
+        });
+        self.ids.insert((name.to_string(), arity), id);
+        id
+    }
+
+    pub fn lookup(&self, name: &str, arity: usize) -> Option<usize> {
+        self.ids.get(&(name.to_string(), arity)).copied()
+    }
+
+    pub fn get(&self, id: usize) -> &Predicate {
+        &self.predicates[id]
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> &mut Predicate {
+        &mut self.predicates[id]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Predicate> {
+        self.predicates.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.predicates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.predicates.is_empty()
+    }
 }
 
 pub struct WamEmulator {
-    heap: Vec<HeapCell>,
+    pub(crate) heap: Vec<HeapCell>,
+    pub(crate) stack: Vec<HeapCell>,
+    pub(crate) frames: Vec<Frame>,
+    pub(crate) choicepoints: Vec<ChoicePoint>,
+    pub(crate) trail: Vec<TrailEntry>,
+    pub(crate) registers: Vec<HeapCell>,
+    pub(crate) code: Vec<Instruction>,
+    pub(crate) predicates: PredicateTable,
+    pub(crate) p: usize,
+    pub(crate) cp: usize,
+    pub(crate) e: Option<usize>,
+    pub(crate) b0: usize,
+    pub(crate) mode: Mode,
+    pub(crate) s: (usize, usize),
+    pub(crate) num_args: usize,
+    pub(crate) query_base: usize,
+    /// Predicates compiled by call/1, keyed by the goal they run with its
+    /// variables renamed apart.
+    pub(crate) goal_predicates: HashMap<String, usize>,
+    pub(crate) compile_options: CompileOptions,
+    pub(crate) foreign: Vec<Foreign>,
+    /// States of the nondeterministic foreign calls that can be retried,
+    /// with the number of choice points below each one's own.
+    pub(crate) redo_states: Vec<(usize, Box<dyn Any>)>,
+    /// Counts changes to dynamic predicates; each clause records the
+    /// generations it exists in.
+    pub(crate) generation: u64,
+    /// The open streams, with the current input and output.
+    pub(crate) streams: Streams,
+    /// Names of the bundled libraries loaded so far.
+    pub(crate) libraries: Vec<&'static str>,
+    /// Blocks of the code area free for reuse, by their length.
+    pub(crate) free_code: BTreeMap<usize, Vec<usize>>,
+    /// Code given up while a query ran, which may still be running it.
+    pub(crate) retired_code: Vec<Range<usize>>,
+}
+
+impl Default for WamEmulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WamEmulator {
     pub fn new() -> Self {
-        Self { heap: Vec::new() }
+        let mut predicates = PredicateTable::default();
+        crate::runtime::builtins::register(&mut predicates);
+        let member = predicates.lookup("$member", 2).unwrap();
+        Self {
+            heap: Vec::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            choicepoints: Vec::new(),
+            trail: Vec::new(),
+            registers: vec![HeapCell::Integer(0); 256],
+            code: vec![
+                Instruction::Halt,
+                Instruction::ExitCatch,
+                Instruction::TrustMe,
+                Instruction::Fail,
+                Instruction::RetryDynamic,
+                Instruction::TrustMe,
+                Instruction::Execute(member),
+            ],
+            predicates,
+            p: HALT,
+            cp: HALT,
+            e: None,
+            b0: 0,
+            mode: Mode::Read,
+            s: (0, 0),
+            num_args: 0,
+            query_base: 0,
+            goal_predicates: HashMap::new(),
+            compile_options: CompileOptions::default(),
+            foreign: Vec::new(),
+            redo_states: Vec::new(),
+            generation: 0,
+            streams: Streams::default(),
+            libraries: Vec::new(),
+            free_code: BTreeMap::new(),
+            retired_code: Vec::new(),
+        }
+    }
+
+    /// Sets how predicates loaded from now on are compiled.
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
     }
 
     pub fn push_term(&mut self, term: &Term) -> usize {
@@ -0,0 +1,641 @@
+// src/wam/database.rs
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+
+use crate::compiler::{compile_clause, predicate_key};
+use crate::parser::ast::{conjunction, Clause, Term};
+use crate::wam::data_structures::{HeapCell, WamEmulator, HALT, RETRY_DYNAMIC};
+use crate::wam::error::{indicator, instantiation_error, permission_error, type_error, WamError};
+use crate::wam::jit_index::IndexKey;
+
+/// The clauses of a dynamic predicate, including removed ones that a walk
+/// over the clauses may still reach.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DynamicClauses {
+    /// The clauses by position. A clause added at the front is given a
+    /// position before every other one and a clause added at the back one
+    /// after, so positions never change.
+    pub(crate) clauses: BTreeMap<i64, DynamicClause>,
+    /// Positions of the clauses by the key of their first argument.
+    keyed: HashMap<IndexKey, BTreeSet<i64>>,
+    /// Positions of the clauses whose first argument is a variable.
+    unkeyed: BTreeSet<i64>,
+    /// Positions of the removed clauses that are not dropped yet.
+    removed: Vec<i64>,
+}
+
+impl DynamicClauses {
+    fn add(&mut self, clause: DynamicClause, first: bool) {
+        let position = match first {
+            true => self.clauses.keys().next().map_or(0, |position| position - 1),
+            false => self.clauses.keys().next_back().map_or(0, |position| position + 1),
+        };
+        match &clause.key {
+            Some(key) => self.keyed.entry(key.clone()).or_default().insert(position),
+            None => self.unkeyed.insert(position),
+        };
+        self.clauses.insert(position, clause);
+    }
+
+    /// Removes the clause at `position` from `generation` on.
+    fn remove(&mut self, position: i64, generation: u64) {
+        let clause = self.clauses.get_mut(&position).expect("removing a clause that is not stored");
+        if clause.died.is_none() {
+            clause.died = Some(generation);
+            self.removed.push(position);
+        }
+    }
+
+    /// Drops the removed clauses and returns their code.
+    fn drop_removed(&mut self) -> Vec<Range<usize>> {
+        let mut dropped = Vec::new();
+        for position in std::mem::take(&mut self.removed) {
+            let clause = self.clauses.remove(&position).expect("a removed clause is stored until dropped");
+            match &clause.key {
+                Some(key) => {
+                    let positions = self.keyed.get_mut(key).unwrap();
+                    positions.remove(&position);
+                    if positions.is_empty() {
+                        self.keyed.remove(key);
+                    }
+                }
+                None => {
+                    self.unkeyed.remove(&position);
+                }
+            }
+            dropped.push(clause.code);
+        }
+        dropped
+    }
+
+    /// Position of the first clause from `start` on that `accept` takes and
+    /// whose first argument can match one keyed on `key`.
+    fn next(&self, start: i64, key: Option<&IndexKey>, accept: impl Fn(&DynamicClause) -> bool) -> Option<i64> {
+        let accepted = |position: &i64| accept(&self.clauses[position]);
+        match key {
+            None => self.clauses.range(start..).find(|(_, clause)| accept(clause)).map(|(&position, _)| position),
+            Some(key) => {
+                let keyed = self.keyed.get(key).and_then(|positions| positions.range(start..).find(|p| accepted(p)));
+                let unkeyed = self.unkeyed.range(start..).find(|p| accepted(p));
+                keyed.into_iter().chain(unkeyed).min().copied()
+            }
+        }
+    }
+}
+
+/// A clause of a dynamic predicate, with the generations it is part of the
+/// predicate in: from `born` up to, but not including, `died`.
+#[derive(Debug, Clone)]
+pub(crate) struct DynamicClause {
+    pub(crate) clause: Clause,
+    /// Where its code is; it is compiled as a predicate of one clause.
+    code: Range<usize>,
+    /// What its first argument is keyed on, `None` for a variable.
+    key: Option<IndexKey>,
+    born: u64,
+    pub(crate) died: Option<u64>,
+}
+
+impl DynamicClause {
+    fn visible(&self, generation: u64) -> bool {
+        self.born <= generation && self.died.is_none_or(|died| generation < died)
+    }
+}
+
+/// What a walk over the clauses of a dynamic predicate does with a clause.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DynamicMode {
+    /// Runs it, for a call of the predicate on A1..An.
+    Call,
+    /// Unifies A1 and A2 with a copy of its head and body, for `clause/2`.
+    Clause,
+    /// Unifies A1 with a copy of it and removes it, for `retract/1`.
+    Retract,
+}
+
+/// Where a walk over the clauses of dynamic predicate `predicate` goes on
+/// when it is backtracked into. The walk sees the clauses of `generation`,
+/// the logical update view: clauses added or removed while it runs do not
+/// change what it finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DynamicRetry {
+    pub(crate) predicate: usize,
+    generation: u64,
+    /// Position of the next clause to try.
+    clause: i64,
+    mode: DynamicMode,
+}
+
+impl WamEmulator {
+    /// Calls dynamic predicate `id` on the arguments in A1..An.
+    pub(crate) fn call_dynamic(&mut self, id: usize) -> Result<bool, WamError> {
+        self.num_args = self.predicates.get(id).arity;
+        self.b0 = self.choicepoints.len();
+        self.start_walk(id, DynamicMode::Call)
+    }
+
+    /// Backtracks into the walk over dynamic clauses saved in the newest
+    /// choice point.
+    pub(crate) fn retry_dynamic(&mut self) -> Result<bool, WamError> {
+        let b = self.choicepoints.pop().expect("retry_dynamic without a choice point");
+        self.walk(b.dynamic.expect("retry_dynamic on another choice point"))
+    }
+
+    fn start_walk(&mut self, id: usize, mode: DynamicMode) -> Result<bool, WamError> {
+        self.walk(DynamicRetry { predicate: id, generation: self.generation, clause: i64::MIN, mode })
+    }
+
+    /// Takes the first clause from `retry.clause` on that the walk sees and
+    /// whose first argument can match, leaving a choice point for the rest
+    /// when another such clause follows.
+    fn walk(&mut self, retry: DynamicRetry) -> Result<bool, WamError> {
+        let key = match retry.mode {
+            DynamicMode::Call if self.num_args > 0 => self.cell_key(&self.registers[1]),
+            DynamicMode::Call => None,
+            DynamicMode::Clause => self.first_argument(&self.registers[1]),
+            DynamicMode::Retract => self.first_argument(&self.clause_parts(&self.registers[1]).0),
+        };
+        let dynamic = match &self.predicates.get(retry.predicate).dynamic {
+            Some(dynamic) => dynamic,
+            None => return Ok(false),
+        };
+        let candidate = |clause: &DynamicClause| {
+            clause.visible(retry.generation) && (retry.mode != DynamicMode::Retract || clause.died.is_none())
+        };
+        let found = match dynamic.next(retry.clause, key.as_ref(), candidate) {
+            Some(found) => found,
+            None => {
+                self.reclaim(retry.predicate);
+                return Ok(false);
+            }
+        };
+        let next = dynamic.next(found + 1, key.as_ref(), candidate);
+        let (entry, clause) = (dynamic.clauses[&found].code.start, dynamic.clauses[&found].clause.clone());
+
+        if let Some(next) = next {
+            let registers = match retry.mode {
+                DynamicMode::Call => self.num_args,
+                DynamicMode::Clause => 2,
+                DynamicMode::Retract => 1,
+            };
+            self.push_choicepoint(RETRY_DYNAMIC, registers);
+            self.choicepoints.last_mut().unwrap().dynamic = Some(DynamicRetry { clause: next, ..retry });
+        }
+        if retry.mode == DynamicMode::Call {
+            self.p = entry;
+            return Ok(true);
+        }
+        self.p = self.cp;
+        let copy = self.build_copy(&clause_term(&clause));
+        let (head, body) = match retry.mode {
+            DynamicMode::Clause => (self.registers[1].clone(), self.registers[2].clone()),
+            _ => self.clause_parts(&self.registers[1]),
+        };
+        let target = self.push_built_structure(":-", vec![head, body]);
+        if !self.unify(copy, target) {
+            return Ok(false);
+        }
+        if retry.mode == DynamicMode::Retract {
+            self.generation += 1;
+            let generation = self.generation;
+            self.predicates.get_mut(retry.predicate).dynamic.as_mut().unwrap().remove(found, generation);
+            self.reclaim(retry.predicate);
+        }
+        Ok(true)
+    }
+
+    /// Head and body of the clause term `cell`; a term other than `:-/2` is
+    /// a fact, with body `true`.
+    fn clause_parts(&self, cell: &HeapCell) -> (HeapCell, HeapCell) {
+        let cell = self.deref(cell);
+        if let HeapCell::Reference(a) = cell {
+            if let HeapCell::Structure(functor, args) = &self.heap[a] {
+                if functor == ":-" && args.len() == 2 {
+                    return (HeapCell::Reference(args[0]), HeapCell::Reference(args[1]));
+                }
+            }
+        }
+        (cell, HeapCell::Constant("true".to_string()))
+    }
+
+    /// The key of the first argument of the callable term `head`.
+    fn first_argument(&self, head: &HeapCell) -> Option<IndexKey> {
+        match self.deref(head) {
+            HeapCell::Reference(a) => match &self.heap[a] {
+                HeapCell::Structure(_, args) => self.cell_key(&HeapCell::Reference(args[0])),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Drops the removed clauses of dynamic predicate `id` once no walk over
+    /// its clauses can be backtracked into any more, and retires their code.
+    fn reclaim(&mut self, id: usize) {
+        if self.choicepoints.iter().any(|b| b.dynamic.is_some_and(|retry| retry.predicate == id)) {
+            return;
+        }
+        let dropped = self.predicates.get_mut(id).dynamic.as_mut().map(DynamicClauses::drop_removed).unwrap_or_default();
+        dropped.into_iter().for_each(|code| self.retire_code(code));
+    }
+
+    /// Drops the removed clauses of every dynamic predicate and frees the
+    /// code retired so far, for when the choice points and environments of
+    /// a query are gone.
+    pub(crate) fn reclaim_clauses(&mut self) {
+        for id in 0..self.predicates.len() {
+            self.reclaim(id);
+        }
+        self.release_retired_code();
+    }
+
+    /// Whether `cell` unifies with a copy of `term`, leaving the machine as
+    /// it was.
+    fn unifies_with_copy(&mut self, cell: &HeapCell, term: &Term) -> bool {
+        self.push_choicepoint(HALT, 0);
+        let copy = self.build_copy(term);
+        let unifies = self.unify(cell.clone(), copy);
+        let b = self.choicepoints.pop().unwrap();
+        self.unwind_trail(b.trail_top);
+        self.heap.truncate(b.heap_top);
+        unifies
+    }
+
+    /// The id of dynamic predicate `name/arity`, declaring it if it is not
+    /// defined yet. Other defined predicates cannot be modified.
+    fn dynamic_predicate(&mut self, name: &str, arity: usize) -> Result<usize, WamError> {
+        let id = self.predicates.id(name, arity);
+        let predicate = self.predicates.get_mut(id);
+        if predicate.dynamic.is_none() {
+            if predicate.is_builtin() || predicate.entry.is_some() {
+                return Err(permission_error("modify", "static_procedure", indicator(name, arity)));
+            }
+            predicate.dynamic = Some(DynamicClauses::default());
+        }
+        Ok(id)
+    }
+
+    /// Declares the predicates `spec` names dynamic: a predicate indicator
+    /// such as `foo/1`, or a conjunction or list of them.
+    pub(crate) fn declare_dynamic(&mut self, spec: &Term) -> Result<(), WamError> {
+        for (name, arity) in predicate_indicators(spec)? {
+            self.dynamic_predicate(&name, arity)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles `clause` and adds it to dynamic predicate `id`, as its first
+    /// or last clause.
+    fn add_dynamic_clause(&mut self, id: usize, clause: Clause, first: bool) -> Result<(), WamError> {
+        let code = compile_clause(&clause, &mut self.predicates, self.compile_options)?;
+        let code = self.install_reusing(code, &[(0, self.predicates.get(id).arity)]);
+        let key = match &clause.head {
+            Term::Structure { args, .. } => IndexKey::of_term(&args[0]),
+            _ => None,
+        };
+        self.generation += 1;
+        let clause = DynamicClause { clause, code, key, born: self.generation, died: None };
+        let dynamic = self.predicates.get_mut(id).dynamic.as_mut().expect("adding a clause to a static predicate");
+        dynamic.add(clause, first);
+        Ok(())
+    }
+
+    /// Replaces the clauses of dynamic predicate `id`, as consulting does.
+    pub(crate) fn redefine_dynamic(&mut self, id: usize, clauses: Vec<Clause>) -> Result<(), WamError> {
+        self.remove_clauses(id);
+        clauses.into_iter().try_for_each(|clause| self.add_dynamic_clause(id, clause, false))
+    }
+
+    fn remove_clauses(&mut self, id: usize) {
+        self.generation += 1;
+        let generation = self.generation;
+        if let Some(dynamic) = self.predicates.get_mut(id).dynamic.as_mut() {
+            let positions: Vec<i64> = dynamic.clauses.keys().copied().collect();
+            positions.into_iter().for_each(|position| dynamic.remove(position, generation));
+        }
+        self.reclaim(id);
+    }
+
+    /// `asserta(+Clause)` and `assertz(+Clause)`: adds a copy of Clause as
+    /// the first or last clause of its predicate, which must be dynamic or
+    /// undefined.
+    fn assert_clause(&mut self, first: bool) -> Result<bool, WamError> {
+        let clause = match self.decode_cell(&self.registers[1])? {
+            Term::Structure { functor, mut args, .. } if functor == ":-" && args.len() == 2 => {
+                let body = args.pop().unwrap();
+                Clause { head: args.pop().unwrap(), body: conjunction(body) }
+            }
+            head => Clause { head, body: Vec::new() },
+        };
+        if let Term::Variable(_) = clause.head {
+            return Err(instantiation_error());
+        }
+        let (name, arity) = predicate_key(&clause.head).map_err(|_| type_error("callable", clause.head.clone()))?;
+        let invalid = |goal: &&Term| matches!(goal, Term::Integer(_) | Term::BigInteger(_) | Term::Float(_));
+        if let Some(goal) = clause.body.iter().find(invalid) {
+            return Err(type_error("callable", goal.clone()));
+        }
+        let id = self.dynamic_predicate(&name, arity)?;
+        self.add_dynamic_clause(id, clause, first)?;
+        Ok(true)
+    }
+
+    pub(crate) fn asserta(&mut self) -> Result<bool, WamError> {
+        self.assert_clause(true)
+    }
+
+    pub(crate) fn assertz(&mut self) -> Result<bool, WamError> {
+        self.assert_clause(false)
+    }
+
+    /// The dynamic predicate the callable term `head` calls, `None` if it is
+    /// not defined. A defined static predicate is a permission error for
+    /// `action` on `kind`.
+    fn dynamic_of(&self, head: &HeapCell, action: &str, kind: &str) -> Result<Option<usize>, WamError> {
+        let head = self.deref(head);
+        let (name, arity) = match &head {
+            HeapCell::Constant(name) => (name.clone(), 0),
+            HeapCell::Reference(a) => match &self.heap[*a] {
+                HeapCell::Structure(functor, args) => (functor.clone(), args.len()),
+                _ => return Err(instantiation_error()),
+            },
+            HeapCell::StackReference(_) => return Err(instantiation_error()),
+            _ => return Err(type_error("callable", self.decode_cell(&head)?)),
+        };
+        let id = match self.predicates.lookup(&name, arity) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let predicate = self.predicates.get(id);
+        if predicate.dynamic.is_none() && (predicate.is_builtin() || predicate.entry.is_some()) {
+            return Err(permission_error(action, kind, indicator(&name, arity)));
+        }
+        Ok(predicate.dynamic.as_ref().map(|_| id))
+    }
+
+    /// `clause(+Head, ?Body)`: Head and Body unify with the head and body of
+    /// a clause of a dynamic predicate, one clause after another on
+    /// backtracking.
+    pub(crate) fn clause(&mut self) -> Result<bool, WamError> {
+        let body = self.deref(&self.registers[2]);
+        if let HeapCell::Integer(_) | HeapCell::BigInteger(_) | HeapCell::Float(_) = body {
+            return Err(type_error("callable", self.decode_cell(&body)?));
+        }
+        match self.dynamic_of(&self.registers[1], "access", "private_procedure")? {
+            Some(id) => {
+                self.b0 = self.choicepoints.len();
+                self.start_walk(id, DynamicMode::Clause)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// `retract(+Clause)`: removes the first clause that unifies with
+    /// Clause, and the next ones on backtracking.
+    pub(crate) fn retract(&mut self) -> Result<bool, WamError> {
+        let (head, _) = self.clause_parts(&self.registers[1]);
+        match self.dynamic_of(&head, "modify", "static_procedure")? {
+            Some(id) => self.start_walk(id, DynamicMode::Retract),
+            None => Ok(false),
+        }
+    }
+
+    /// `retractall(+Head)`: removes every clause whose head unifies with
+    /// Head. An undefined predicate becomes a dynamic one without clauses.
+    pub(crate) fn retractall(&mut self) -> Result<bool, WamError> {
+        let head = self.registers[1].clone();
+        let id = match self.dynamic_of(&head, "modify", "static_procedure")? {
+            Some(id) => id,
+            None => {
+                let (name, arity) = predicate_key(&self.decode_cell(&head)?).unwrap();
+                self.dynamic_predicate(&name, arity)?;
+                return Ok(true);
+            }
+        };
+        let key = self.first_argument(&head);
+        let dynamic = self.predicates.get(id).dynamic.as_ref().unwrap();
+        let mut heads: Vec<(i64, Term)> = Vec::new();
+        let mut start = i64::MIN;
+        while let Some(position) = dynamic.next(start, key.as_ref(), |clause| clause.died.is_none()) {
+            heads.push((position, dynamic.clauses[&position].clause.head.clone()));
+            start = position + 1;
+        }
+        self.generation += 1;
+        let generation = self.generation;
+        for (position, clause_head) in heads {
+            if self.unifies_with_copy(&head, &clause_head) {
+                self.predicates.get_mut(id).dynamic.as_mut().unwrap().remove(position, generation);
+            }
+        }
+        self.reclaim(id);
+        Ok(true)
+    }
+
+    /// `abolish(+Name/Arity)`: removes all clauses of dynamic predicate
+    /// Name/Arity, and with them its dynamic declaration.
+    pub(crate) fn abolish(&mut self) -> Result<bool, WamError> {
+        let spec = self.decode_cell(&self.registers[1])?;
+        let (name, arity) = match &spec {
+            Term::Variable(_) => return Err(instantiation_error()),
+            Term::Structure { functor, args, .. } if functor == "/" && args.len() == 2 => match (&args[0], &args[1]) {
+                (Term::Variable(_), _) | (_, Term::Variable(_)) => return Err(instantiation_error()),
+                (Term::Atom(name), &Term::Integer(arity)) if arity >= 0 => (name.clone(), arity as usize),
+                (Term::Atom(_), Term::Integer(_)) => return Err(type_error("not_less_than_zero", args[1].clone())),
+                (Term::Atom(_), culprit) => return Err(type_error("integer", culprit.clone())),
+                (culprit, _) => return Err(type_error("atom", culprit.clone())),
+            },
+            _ => return Err(type_error("predicate_indicator", spec)),
+        };
+        let id = match self.predicates.lookup(&name, arity) {
+            Some(id) => id,
+            None => return Ok(true),
+        };
+        let predicate = self.predicates.get(id);
+        if predicate.dynamic.is_none() && (predicate.is_builtin() || predicate.entry.is_some()) {
+            return Err(permission_error("modify", "static_procedure", indicator(&name, arity)));
+        }
+        self.remove_clauses(id);
+        let predicate = self.predicates.get_mut(id);
+        if predicate.dynamic.as_ref().is_some_and(|dynamic| dynamic.clauses.is_empty()) {
+            predicate.dynamic = None;
+        }
+        Ok(true)
+    }
+
+    /// `dynamic(+Spec)`: declares the predicates of Spec dynamic.
+    pub(crate) fn dynamic(&mut self) -> Result<bool, WamError> {
+        let spec = self.decode_cell(&self.registers[1])?;
+        self.declare_dynamic(&spec)?;
+        Ok(true)
+    }
+}
+
+/// The term `Head :- Body` for `clause`, with body `true` for a fact.
+/// The `name/arity` of each predicate indicator in `spec`, which is one
+/// indicator or a conjunction or list of them.
+pub(crate) fn predicate_indicators(spec: &Term) -> Result<Vec<(String, usize)>, WamError> {
+    match spec {
+        Term::Variable(_) => Err(instantiation_error()),
+        Term::List(specs) => specs.iter().try_fold(Vec::new(), |mut indicators, spec| {
+            indicators.extend(predicate_indicators(spec)?);
+            Ok(indicators)
+        }),
+        Term::Structure { functor, args, .. } if functor == "," && args.len() == 2 => {
+            let mut indicators = predicate_indicators(&args[0])?;
+            indicators.extend(predicate_indicators(&args[1])?);
+            Ok(indicators)
+        }
+        Term::Structure { functor, args, .. } if functor == "/" && args.len() == 2 => match (&args[0], &args[1]) {
+            (Term::Variable(_), _) | (_, Term::Variable(_)) => Err(instantiation_error()),
+            (Term::Atom(name), &Term::Integer(arity)) if arity >= 0 => Ok(vec![(name.clone(), arity as usize)]),
+            _ => Err(type_error("predicate_indicator", spec.clone())),
+        },
+        _ => Err(type_error("predicate_indicator", spec.clone())),
+    }
+}
+
+fn clause_term(clause: &Clause) -> Term {
+    let body = clause.body.iter().rev().cloned().reduce(|right, left| Term::Structure {
+        functor: ",".to_string(),
+        arity: 2,
+        args: vec![left, right],
+    });
+    Term::Structure {
+        functor: ":-".to_string(),
+        arity: 2,
+        args: vec![clause.head.clone(), body.unwrap_or_else(|| Term::Atom("true".to_string()))],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::parse;
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    /// Clauses stored for `name/arity`, removed ones not yet dropped included.
+    fn stored(wam: &WamEmulator, name: &str, arity: usize) -> usize {
+        let id = wam.predicates.lookup(name, arity).unwrap();
+        wam.predicates.get(id).dynamic.as_ref().map_or(0, |dynamic| dynamic.clauses.len())
+    }
+
+    #[test]
+    fn test_assert_and_retract() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "assertz(fact(b)), asserta(fact(a)), assertz(fact(c))"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "fact(X)"), vec!["X = a", "X = b", "X = c"]);
+        assert_eq!(answers(&mut wam, "fact(b)"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "retract(fact(b))"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "fact(X)"), vec!["X = a", "X = c"]);
+        assert_eq!(answers(&mut wam, "retract(fact(X))"), vec!["X = a", "X = c"]);
+        assert!(answers(&mut wam, "fact(_)").is_empty());
+
+        assert_eq!(answers(&mut wam, "assertz((double(X, Y) :- Y is X * 2)), double(4, Y)"), vec!["Y = 8"]);
+        assert_eq!(answers(&mut wam, "clause(double(2, B), Body)"), vec!["Body = B is 2*2"]);
+        assert!(answers(&mut wam, "retract((double(_, _) :- true))").is_empty());
+        assert_eq!(answers(&mut wam, "retract((double(_, _) :- is(_, _))), \\+ double(1, _)"), vec!["true"]);
+        // A cut in an asserted clause cuts the other clauses of its predicate.
+        assert_eq!(answers(&mut wam, "assertz((first(X) :- X = 1, !)), assertz(first(2)), first(X)"), vec!["X = 1"]);
+    }
+
+    #[test]
+    fn test_logical_update_view() {
+        let mut wam = WamEmulator::new();
+        wam.consult(":- dynamic(counter/1). counter(0). count(N) :- counter(N).").unwrap();
+        // A call sees the clauses of when it started, whatever it changes.
+        assert_eq!(answers(&mut wam, "counter(X), Y is X + 1, assertz(counter(Y))"), vec!["X = 0,\nY = 1"]);
+        assert_eq!(answers(&mut wam, "count(X)"), vec!["X = 0", "X = 1"]);
+        assert_eq!(answers(&mut wam, "counter(X), retractall(counter(_))"), vec!["X = 0", "X = 1"]);
+        assert!(answers(&mut wam, "counter(_)").is_empty());
+        assert_eq!(answers(&mut wam, "assertz(n(1)), assertz(n(2)), n(X), asserta(n(0))"), vec!["X = 1", "X = 2"]);
+        assert_eq!(answers(&mut wam, "n(X)"), vec!["X = 0", "X = 0", "X = 1", "X = 2"]);
+    }
+
+    #[test]
+    fn test_retracted_clauses_are_reclaimed() {
+        let mut wam = WamEmulator::new();
+        wam.consult(":- dynamic item/1. item(1). item(2). item(3).").unwrap();
+        assert_eq!(stored(&wam, "item", 1), 3);
+        // The walk over item/1 can still reach item(1) after retract/1 ran.
+        let clauses = parse("query :- item(X), retract(item(1)).").unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+        assert!(wam.run().unwrap());
+        assert_eq!(stored(&wam, "item", 1), 3);
+        assert_eq!(answers(&mut wam, "retract(item(2))"), vec!["true"]);
+        assert_eq!(stored(&wam, "item", 1), 1);
+        assert_eq!(answers(&mut wam, "retractall(item(_)), assertz(item(4))"), vec!["true"]);
+        assert_eq!(stored(&wam, "item", 1), 1);
+    }
+
+    #[test]
+    fn test_first_argument_is_hashed() {
+        let mut wam = WamEmulator::new();
+        let rows: String = (0..20_000).map(|i| format!("row({}, v{}). ", i, i)).collect();
+        wam.consult(&format!(":- dynamic row/2. {}", rows)).unwrap();
+        let start = std::time::Instant::now();
+        let lookups = "numlist(1, 2000, Ns), member(N, Ns), \\+ row(N, _), fail";
+        assert!(answers(&mut wam, lookups).is_empty());
+        assert!(start.elapsed().as_secs() < 5, "took {:?}", start.elapsed());
+        // Only the clauses keyed on the argument are tried, so none is left to try.
+        let clauses = parse("query :- row(7, V).").unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+        assert!(wam.run().unwrap());
+        assert!(wam.choicepoints.iter().all(|b| b.dynamic.is_none()));
+        // Clauses added at the front come first, in the reverse of the order they were added in.
+        assert_eq!(answers(&mut wam, "asserta(row(7, a)), asserta(row(7, b)), findall(V, row(7, V), Vs)"), vec!["Vs = [b, a, v7]"]);
+        // Clauses with a variable first argument match every key, in order with the keyed ones.
+        let goal = "asserta(row(_, first)), assertz(row(_, last)), findall(V, row(7, V), Vs)";
+        assert_eq!(answers(&mut wam, goal), vec!["Vs = [first, b, a, v7, last]"]);
+    }
+
+    #[test]
+    fn test_code_of_retracted_clauses_is_reused() {
+        let mut wam = WamEmulator::new();
+        wam.consult(":- dynamic counter/1. counter(0).").unwrap();
+        let bump = parse("bump :- retract((counter(N) :- _)), M is N + 1, assertz((counter(M) :- M > 0, atom(a))).").unwrap();
+        // Queries run through `query_goals` give their own code back.
+        let run = |wam: &mut WamEmulator| assert_eq!(wam.query_goals(&bump[0].body).unwrap().count(), 1);
+        run(&mut wam);
+        run(&mut wam);
+        let size = wam.code.len();
+        for _ in 0..100 {
+            run(&mut wam);
+        }
+        assert_eq!(wam.code.len(), size);
+        assert_eq!(answers(&mut wam, "counter(N)"), vec!["N = 102"]);
+    }
+
+    #[test]
+    fn test_retractall_abolish_and_declarations() {
+        let mut wam = WamEmulator::new();
+        wam.consult(":- dynamic((seen/2, flag/0)). seen(a, 1). seen(b, 2). seen(a, 3). :- assertz(seen(c, 4)).").unwrap();
+        assert_eq!(answers(&mut wam, "seen(c, X)"), vec!["X = 4"]);
+        assert!(answers(&mut wam, "flag").is_empty());
+        assert_eq!(answers(&mut wam, "retractall(seen(a, _)), seen(X, Y)"), vec!["X = b,\nY = 2", "X = c,\nY = 4"]);
+        assert_eq!(answers(&mut wam, "retractall(fresh(_)), \\+ fresh(_)"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "abolish(seen/2)"), vec!["true"]);
+        assert_eq!(exception(&mut wam, "seen(_, _)"), "error(existence_error(procedure, seen/2), seen/2)");
+        // Consulting clauses of a dynamic predicate replaces them.
+        wam.consult("flag.").unwrap();
+        wam.consult("flag.").unwrap();
+        assert_eq!(answers(&mut wam, "flag"), vec!["true"]);
+        assert_eq!(answers(&mut wam, "clause(flag, B)"), vec!["B = true"]);
+    }
+
+    #[test]
+    fn test_static_procedures_are_protected() {
+        let mut wam = WamEmulator::new();
+        wam.consult("static(1).").unwrap();
+        let modify = "error(permission_error(modify, static_procedure, static/1), _)";
+        assert_eq!(exception(&mut wam, "assertz(static(2))"), modify);
+        assert_eq!(exception(&mut wam, "retract(static(1))"), modify);
+        assert_eq!(exception(&mut wam, "abolish(static/1)"), modify);
+        assert_eq!(exception(&mut wam, "asserta(succ(a, b))"), "error(permission_error(modify, static_procedure, succ/2), _)");
+        assert_eq!(exception(&mut wam, "clause(static(_), _)"), "error(permission_error(access, private_procedure, static/1), _)");
+        assert_eq!(exception(&mut wam, "assertz(_)"), "error(instantiation_error, _)");
+        assert_eq!(exception(&mut wam, "assertz((foo :- 1))"), "error(type_error(callable, 1), _)");
+        assert_eq!(exception(&mut wam, "assertz(3)"), "error(type_error(callable, 3), _)");
+        assert_eq!(exception(&mut wam, "dynamic(foo/bar)"), "error(type_error(predicate_indicator, foo/bar), _)");
+        assert!(answers(&mut wam, "clause(unknown, _)").is_empty());
+    }
+}
@@ -0,0 +1,503 @@
+// src/wam/decoder.rs
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ast;
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::error::{resource_error, WamError};
+
+/// Functor used for list cells on the heap, `'.'(Head, Tail)`.
+pub const LIST_FUNCTOR: &str = ".";
+/// The empty list atom.
+pub const NIL: &str = "[]";
+/// How deeply a term may nest for `decode_cell`. The code walking decoded
+/// terms, the writer and the compiler among it, recurses on their
+/// arguments, and a cyclic term, as `X = f(X)` makes, nests without end.
+pub const MAX_DECODE_DEPTH: usize = 1_000;
+
+impl WamEmulator {
+    /// Follows a chain of references starting at `index` and returns the
+    /// address of the last cell in the chain: either an unbound variable
+    /// (a self-reference) or a non-reference cell.
+    pub fn deref_address(&self, mut index: usize) -> usize {
+        while let Some(HeapCell::Reference(next)) = self.heap.get(index) {
+            if *next == index {
+                break;
+            }
+            index = *next;
+        }
+        index
+    }
+
+    /// Rebuilds the term stored at heap address `index`. Unbound variables are
+    /// named `_G<address>`, so the same variable always gets the same name.
+    /// Subterms nested deeper than `MAX_DECODE_DEPTH` are rebuilt as the atom
+    /// `...`, as a toplevel abbreviates the terms it prints, and so are
+    /// cyclic ones where they go round.
+    pub fn decode_term(&self, index: usize) -> Option<ast::Term> {
+        self.decode_term_with_names(index, &HashMap::new())
+    }
+
+    /// Like `decode_term`, but unbound variables whose (dereferenced) address
+    /// appears in `names` are given that name instead of a generated one, and
+    /// so is a cycle back to such a variable's value, as in `X = f(X)`.
+    pub fn decode_term_with_names(&self, index: usize, names: &HashMap<usize, String>) -> Option<ast::Term> {
+        if index >= self.heap.len() {
+            return None;
+        }
+        self.decode_at(index, names, Some(MAX_DECODE_DEPTH)).ok()
+    }
+
+    /// Rebuilds the term a register or environment cell stands for. Unbound
+    /// stack variables are named `_S<slot>`. A term nested deeper than
+    /// `MAX_DECODE_DEPTH` or cyclic raises a resource error.
+    pub(crate) fn decode_cell(&self, cell: &HeapCell) -> Result<ast::Term, WamError> {
+        self.decode(cell, None)
+    }
+
+    /// Like `decode_cell`, but subterms nested `depth` deep or more, and
+    /// cyclic ones where they go round, are rebuilt as the atom `...`.
+    pub(crate) fn decode_abbreviated(&self, cell: &HeapCell, depth: usize) -> ast::Term {
+        self.decode(cell, Some(depth.min(MAX_DECODE_DEPTH))).expect("abbreviated terms decode")
+    }
+
+    fn decode(&self, cell: &HeapCell, abbreviate: Option<usize>) -> Result<ast::Term, WamError> {
+        Ok(match self.deref(cell) {
+            HeapCell::Reference(index) => return self.decode_at(index, &HashMap::new(), abbreviate),
+            HeapCell::StackReference(slot) => ast::Term::Variable(format!("_S{}", slot)),
+            HeapCell::Constant(name) => ast::Term::Atom(name),
+            HeapCell::Integer(value) => ast::Term::Integer(value),
+            HeapCell::BigInteger(value) => ast::Term::BigInteger(value),
+            HeapCell::Float(value) => ast::Term::Float(value),
+            HeapCell::Structure(..) => unreachable!("deref returns structures as references"),
+        })
+    }
+
+    // Terms are decoded with a stack of pending work rather than by
+    // recursion: `Decode` tasks push the terms they rebuild onto `done`, and
+    // the tasks left behind by a structure or list collect them from there.
+    // `path` holds the structures being decoded, so that one met again
+    // inside itself is known to be cyclic. With `abbreviate`, a subterm that
+    // deep or cyclic is decoded as `...` rather than raising an error.
+    fn decode_at(&self, index: usize, names: &HashMap<usize, String>, abbreviate: Option<usize>) -> Result<ast::Term, WamError> {
+        let limit = abbreviate.unwrap_or(MAX_DECODE_DEPTH);
+        enum Task {
+            Decode(usize, usize),
+            Structure(usize),
+            List(usize, usize, bool),
+            Ellipsis,
+        }
+        let mut tasks = vec![Task::Decode(index, 0)];
+        let mut done = Vec::new();
+        let mut path = HashSet::new();
+        while let Some(task) = tasks.pop() {
+            let (index, depth) = match task {
+                Task::Decode(index, depth) => (self.deref_address(index), depth),
+                Task::Ellipsis => {
+                    done.push(ast::Term::Atom("...".to_string()));
+                    continue;
+                }
+                Task::Structure(index) => {
+                    path.remove(&index);
+                    if let HeapCell::Structure(functor, args) = &self.heap[index] {
+                        let args = done.split_off(done.len() - args.len());
+                        done.push(ast::Term::Structure { functor: functor.clone(), arity: args.len(), args });
+                    }
+                    continue;
+                }
+                Task::List(index, length, partial) => {
+                    path.remove(&index);
+                    let tail = if partial { done.pop() } else { None };
+                    let elements = done.split_off(done.len() - length);
+                    done.push(match tail {
+                        None => ast::Term::List(elements),
+                        // Not a proper list: rebuild it as nested '.'/2 structures around the tail.
+                        Some(tail) => elements.into_iter().rev().fold(tail, |tail, head| ast::Term::Structure {
+                            functor: LIST_FUNCTOR.to_string(),
+                            arity: 2,
+                            args: vec![head, tail],
+                        }),
+                    });
+                    continue;
+                }
+            };
+            let term = match &self.heap[index] {
+                HeapCell::Reference(_) => ast::Term::Variable(variable_name(index, names)),
+                HeapCell::Constant(name) => ast::Term::Atom(name.clone()),
+                HeapCell::Integer(value) => ast::Term::Integer(*value),
+                HeapCell::BigInteger(value) => ast::Term::BigInteger(value.clone()),
+                HeapCell::Float(value) => ast::Term::Float(*value),
+                HeapCell::StackReference(_) => unreachable!("heap cells never point into the stack"),
+                HeapCell::Structure(..) if path.contains(&index) || depth >= limit => {
+                    if abbreviate.is_none() {
+                        return Err(resource_error("term_depth"));
+                    }
+                    // A cycle back to a named variable's value reads as that variable.
+                    match names.get(&index) {
+                        Some(name) if path.contains(&index) => ast::Term::Variable(name.clone()),
+                        _ => ast::Term::Atom("...".to_string()),
+                    }
+                }
+                HeapCell::Structure(functor, args) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                    let (mut elements, tail) = self.list_cells(index);
+                    let mut tail = tail.map(|tail| Task::Decode(tail, depth + elements.len() + 1));
+                    // The elements of a partial list end up nested one in the next.
+                    let step = tail.is_some() as usize;
+                    if depth + step * elements.len() >= limit {
+                        if abbreviate.is_none() {
+                            return Err(resource_error("term_depth"));
+                        }
+                        elements.truncate(limit - depth - 1);
+                        tail = Some(Task::Ellipsis);
+                    }
+                    path.insert(index);
+                    tasks.push(Task::List(index, elements.len(), tail.is_some()));
+                    tasks.extend(tail);
+                    tasks.extend(elements.iter().enumerate().rev().map(|(i, &element)| Task::Decode(element, depth + step * i + 1)));
+                    continue;
+                }
+                HeapCell::Structure(_, args) => {
+                    path.insert(index);
+                    tasks.push(Task::Structure(index));
+                    tasks.extend(args.iter().rev().map(|&arg| Task::Decode(arg, depth + 1)));
+                    continue;
+                }
+            };
+            done.push(term);
+        }
+        Ok(done.pop().expect("a decoded term"))
+    }
+
+    /// The addresses of the elements of the list cell at `index` and of the
+    /// tail ending it, `None` for `[]`. A list whose tail leads back into
+    /// it ends at the first cell met again.
+    fn list_cells(&self, index: usize) -> (Vec<usize>, Option<usize>) {
+        let mut elements = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = index;
+        loop {
+            match &self.heap[current] {
+                HeapCell::Structure(functor, args) if functor == LIST_FUNCTOR && args.len() == 2 => {
+                    if !seen.insert(current) {
+                        return (elements, Some(current));
+                    }
+                    elements.push(args[0]);
+                    current = self.deref_address(args[1]);
+                }
+                HeapCell::Constant(name) if name == NIL => return (elements, None),
+                _ => return (elements, Some(current)),
+            }
+        }
+    }
+
+    /// Builds `term` on the heap and returns a cell standing for it, the
+    /// inverse of `decode_cell`. Variables named `_G<address>` or
+    /// `_S<slot>`, as decoding names them, are the existing variables at
+    /// those places; other variables are looked up in `variables` by name
+    /// and created when missing, so a name stands for one variable.
+    pub(crate) fn build_term(&mut self, term: &ast::Term, variables: &mut HashMap<String, HeapCell>) -> HeapCell {
+        self.build(term, variables, true)
+    }
+
+    /// Builds a copy of `term` on the heap in which every variable is new,
+    /// e.g. for a term decoded before the cells it came from were freed.
+    pub(crate) fn build_copy(&mut self, term: &ast::Term) -> HeapCell {
+        self.build(term, &mut HashMap::new(), false)
+    }
+
+    /// Like `build_copy`, but the variables named in `shared`, as decoding
+    /// names them, stay the existing variables, as the free variables of a
+    /// lambda do.
+    pub(crate) fn build_copy_sharing(&mut self, term: &ast::Term, shared: &[String]) -> HeapCell {
+        let mut variables =
+            shared.iter().filter_map(|name| Some((name.clone(), self.existing_variable(name)?))).collect();
+        self.build(term, &mut variables, false)
+    }
+
+    // Like decoding, building keeps a stack of pending work: the cells built
+    // for the arguments of a structure or the elements of a list wait in
+    // `built` for the task that puts them together.
+    fn build(&mut self, term: &ast::Term, variables: &mut HashMap<String, HeapCell>, existing: bool) -> HeapCell {
+        enum Task<'t> {
+            Build(&'t ast::Term),
+            Structure(&'t str, usize),
+            List(usize),
+        }
+        let mut tasks = vec![Task::Build(term)];
+        let mut built = Vec::new();
+        while let Some(task) = tasks.pop() {
+            let term = match task {
+                Task::Build(term) => term,
+                Task::Structure(functor, arity) => {
+                    let args = built.split_off(built.len() - arity);
+                    let cell = self.push_built_structure(functor, args);
+                    built.push(cell);
+                    continue;
+                }
+                Task::List(length) => {
+                    let cells = built.split_off(built.len() - length);
+                    let cell = self.build_list(cells, HeapCell::Constant(NIL.to_string()));
+                    built.push(cell);
+                    continue;
+                }
+            };
+            let cell = match term {
+                ast::Term::Atom(name) => HeapCell::Constant(name.clone()),
+                ast::Term::Integer(value) => HeapCell::Integer(*value),
+                ast::Term::BigInteger(value) => HeapCell::BigInteger(value.clone()),
+                ast::Term::Float(value) => HeapCell::Float(*value),
+                ast::Term::Variable(name) => match self.existing_variable(name).filter(|_| existing) {
+                    Some(cell) => cell,
+                    None => match variables.get(name) {
+                        Some(cell) => cell.clone(),
+                        None => {
+                            let h = self.heap.len();
+                            self.heap.push(HeapCell::Reference(h));
+                            if name != "_" {
+                                variables.insert(name.clone(), HeapCell::Reference(h));
+                            }
+                            HeapCell::Reference(h)
+                        }
+                    },
+                },
+                ast::Term::Structure { functor, args, .. } if args.is_empty() => HeapCell::Constant(functor.clone()),
+                ast::Term::Structure { functor, args, .. } => {
+                    tasks.push(Task::Structure(functor, args.len()));
+                    tasks.extend(args.iter().rev().map(Task::Build));
+                    continue;
+                }
+                ast::Term::List(elements) => {
+                    tasks.push(Task::List(elements.len()));
+                    tasks.extend(elements.iter().rev().map(Task::Build));
+                    continue;
+                }
+            };
+            built.push(cell);
+        }
+        built.pop().expect("a built term")
+    }
+
+    /// Builds the list of `cells` ending in `tail`.
+    pub(crate) fn build_list(&mut self, cells: Vec<HeapCell>, tail: HeapCell) -> HeapCell {
+        cells.into_iter().rev().fold(tail, |tail, cell| self.push_built_structure(LIST_FUNCTOR, vec![cell, tail]))
+    }
+
+    /// A copy of the term `cell` stands for kept off the heap, with new
+    /// variables. Subterms shared in the term are shared in the copy, so a
+    /// cyclic term is copied as it is, and the term is walked without
+    /// recursion however deeply it nests.
+    pub(crate) fn store_term(&self, cell: &HeapCell) -> StoredTerm {
+        // Cell 0 holds the copy of the term; each pending cell is copied to
+        // the place given with it.
+        let mut cells = vec![HeapCell::Reference(0)];
+        let mut copies = HashMap::new();
+        let mut pending = vec![(cell.clone(), 0)];
+        while let Some((cell, place)) = pending.pop() {
+            let cell = self.deref(&cell);
+            let key = match &cell {
+                HeapCell::StackReference(slot) => (true, *slot),
+                HeapCell::Reference(a) => (false, *a),
+                _ => {
+                    cells[place] = cell;
+                    continue;
+                }
+            };
+            if let Some(copy) = copies.get(&key) {
+                cells[place] = HeapCell::clone(copy);
+                continue;
+            }
+            let copy = match &cell {
+                HeapCell::Reference(a) => match &self.heap[*a] {
+                    HeapCell::Structure(functor, args) => {
+                        let h = cells.len();
+                        cells.push(HeapCell::Structure(functor.clone(), (h + 1..=h + args.len()).collect()));
+                        cells.extend((h + 1..=h + args.len()).map(HeapCell::Reference));
+                        pending.extend(args.iter().enumerate().rev().map(|(i, &arg)| (HeapCell::Reference(arg), h + 1 + i)));
+                        HeapCell::Reference(h)
+                    }
+                    _ => HeapCell::Reference(place),
+                },
+                _ => HeapCell::Reference(place),
+            };
+            copies.insert(key, copy.clone());
+            cells[place] = copy;
+        }
+        StoredTerm { cells }
+    }
+
+    /// Pushes `stored` onto the heap and returns a cell for it.
+    pub(crate) fn restore_term(&mut self, stored: &StoredTerm) -> HeapCell {
+        let base = self.heap.len();
+        let relocate = |cell: &HeapCell| match cell {
+            HeapCell::Reference(a) => HeapCell::Reference(a + base),
+            HeapCell::Structure(functor, args) => HeapCell::Structure(functor.clone(), args.iter().map(|a| a + base).collect()),
+            cell => cell.clone(),
+        };
+        self.heap.extend(stored.cells.iter().map(relocate));
+        relocate(&stored.cells[0])
+    }
+
+    /// The unbound variables of the term `cell` stands for, each once, in
+    /// depth-first, left-to-right order.
+    pub(crate) fn term_variables(&self, cell: &HeapCell) -> Vec<HeapCell> {
+        let mut variables = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![cell.clone()];
+        while let Some(cell) = pending.pop() {
+            let cell = self.deref(&cell);
+            let key = match &cell {
+                HeapCell::StackReference(slot) => (true, *slot),
+                HeapCell::Reference(a) => match &self.heap[*a] {
+                    HeapCell::Structure(_, args) => {
+                        pending.extend(args.iter().rev().map(|&arg| HeapCell::Reference(arg)));
+                        continue;
+                    }
+                    _ => (false, *a),
+                },
+                _ => continue,
+            };
+            if seen.insert(key) {
+                variables.push(cell);
+            }
+        }
+        variables
+    }
+
+    fn existing_variable(&self, name: &str) -> Option<HeapCell> {
+        let address = |prefix: &str| name.strip_prefix(prefix).and_then(|digits| digits.parse::<usize>().ok());
+        match (address("_G"), address("_S")) {
+            (Some(a), _) if a < self.heap.len() => Some(HeapCell::Reference(a)),
+            (_, Some(a)) if a < self.stack.len() => Some(HeapCell::StackReference(a)),
+            _ => None,
+        }
+    }
+
+    /// Pushes the structure `functor(args...)` and returns a cell for it.
+    /// Unbound stack variables among `args` are moved to the heap.
+    pub(crate) fn push_built_structure(&mut self, functor: &str, args: Vec<HeapCell>) -> HeapCell {
+        let h = self.heap.len();
+        self.heap.push(HeapCell::Structure(functor.to_string(), (h + 1..=h + args.len()).collect()));
+        for arg in args {
+            self.push_local_argument(arg);
+        }
+        HeapCell::Reference(h)
+    }
+}
+
+/// A term copied off the heap by `store_term`, as `findall/3` keeps its
+/// solutions while backtracking frees the cells they were built from. Its
+/// cells refer to each other by their place in `cells`.
+#[derive(Clone, Debug)]
+pub(crate) struct StoredTerm {
+    cells: Vec<HeapCell>,
+}
+
+fn variable_name(index: usize, names: &HashMap<usize, String>) -> String {
+    match names.get(&index) {
+        Some(name) => name.clone(),
+        None => format!("_G{}", index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::data_structures::Term;
+    use crate::wam::emulator::tests::{answers, exception};
+
+    #[test]
+    fn test_decode_atom_and_structure() {
+        let mut wam = WamEmulator::new();
+        let index = wam.push_term(&Term::Compound("f".into(), vec![
+            Term::Atom("a".into()),
+            Term::Variable,
+        ]));
+
+        let expected = ast::Term::Structure {
+            functor: "f".to_string(),
+            arity: 2,
+            args: vec![
+                ast::Term::Atom("a".to_string()),
+                ast::Term::Variable("_G1".to_string()),
+            ],
+        };
+        assert_eq!(wam.decode_term(index), Some(expected));
+    }
+
+    #[test]
+    fn test_decode_follows_reference_chains() {
+        let mut wam = WamEmulator::new();
+        let atom = wam.push_term(&Term::Atom("a".into()));
+        wam.heap.push(HeapCell::Reference(atom));
+        wam.heap.push(HeapCell::Reference(1));
+
+        assert_eq!(wam.deref_address(2), atom);
+        assert_eq!(wam.decode_term(2), Some(ast::Term::Atom("a".to_string())));
+    }
+
+    #[test]
+    fn test_decode_uses_variable_names() {
+        let mut wam = WamEmulator::new();
+        let var = wam.push_term(&Term::Variable);
+        wam.heap.push(HeapCell::Reference(var));
+
+        let mut names = HashMap::new();
+        names.insert(var, "X".to_string());
+        assert_eq!(wam.decode_term_with_names(1, &names), Some(ast::Term::Variable("X".to_string())));
+    }
+
+    #[test]
+    fn test_decode_lists() {
+        let mut wam = WamEmulator::new();
+        let proper = wam.push_term(&Term::Compound(".".into(), vec![
+            Term::Atom("a".into()),
+            Term::Compound(".".into(), vec![Term::Atom("b".into()), Term::Atom("[]".into())]),
+        ]));
+        assert_eq!(wam.decode_term(proper), Some(ast::Term::List(vec![
+            ast::Term::Atom("a".to_string()),
+            ast::Term::Atom("b".to_string()),
+        ])));
+
+        let partial = wam.push_term(&Term::Compound(".".into(), vec![Term::Atom("a".into()), Term::Variable]));
+        let tail = partial - 1;
+        assert_eq!(wam.decode_term(partial), Some(ast::Term::Structure {
+            functor: ".".to_string(),
+            arity: 2,
+            args: vec![ast::Term::Atom("a".to_string()), ast::Term::Variable(format!("_G{}", tail))],
+        }));
+    }
+
+    #[test]
+    fn test_decode_invalid_index() {
+        let wam = WamEmulator::new();
+        assert_eq!(wam.decode_term(0), None);
+    }
+
+    #[test]
+    fn test_decode_deep_and_cyclic_terms() {
+        let mut wam = WamEmulator::new();
+        let mut term = wam.push_term(&Term::Atom("a".into()));
+        for _ in 0..=MAX_DECODE_DEPTH {
+            wam.heap.push(HeapCell::Structure("f".into(), vec![term]));
+            term = wam.heap.len() - 1;
+        }
+        let error = wam.decode_cell(&HeapCell::Reference(term)).unwrap_err();
+        assert_eq!(error.to_string(), "uncaught exception: error(resource_error(term_depth), _)");
+        assert!(wam.decode_term(term).is_some());
+
+        // X = f(X) and L = [a|L].
+        let x = wam.heap.len();
+        wam.heap.push(HeapCell::Structure("f".into(), vec![x]));
+        let l = wam.heap.len();
+        wam.heap.push(HeapCell::Structure(".".into(), vec![l + 1, l]));
+        wam.heap.push(HeapCell::Constant("a".into()));
+        for cyclic in [x, l] {
+            assert!(wam.decode_cell(&HeapCell::Reference(cyclic)).is_err());
+        }
+        let names = vec![(x, "X".to_string()), (l, "L".to_string())].into_iter().collect();
+        assert_eq!(wam.decode_term_with_names(x, &names).unwrap().to_string(), "f(X)");
+        assert_eq!(wam.decode_term_with_names(l, &names).unwrap().to_string(), "[a|L]");
+        assert_eq!(wam.decode_term(l).unwrap().to_string(), "[a|...]");
+        assert_eq!(answers(&mut wam, "X = f(X), L = [a, b|L]"), vec!["X = f(X),\nL = [a, b|L]"]);
+        assert_eq!(exception(&mut wam, "X = f(X), atom_length(X, _)"), "error(resource_error(term_depth), _)");
+    }
+}
@@ -0,0 +1,873 @@
+// src/wam/emulator.rs
+use std::collections::HashSet;
+
+use crate::compiler::compile_predicate;
+use crate::parser::ast::{self, Clause};
+use crate::runtime::arithmetic::binary;
+use crate::wam::data_structures::{ChoicePoint, Frame, HeapCell, Mode, TrailEntry, WamEmulator, HALT};
+use crate::wam::decoder::{LIST_FUNCTOR, NIL};
+use crate::wam::error::{existence_error, WamError};
+use crate::wam::instruction::{Constant, Instruction, Reg};
+
+impl WamEmulator {
+    pub(crate) fn register(&self, reg: Reg) -> HeapCell {
+        match reg {
+            Reg::X(i) => self.registers[i].clone(),
+            Reg::Y(i) => self.stack[self.y_address(i)].clone(),
+        }
+    }
+
+    pub(crate) fn set_register(&mut self, reg: Reg, cell: HeapCell) {
+        match reg {
+            Reg::X(i) => self.registers[i] = cell,
+            Reg::Y(i) => {
+                let address = self.y_address(i);
+                self.stack[address] = cell;
+            }
+        }
+    }
+
+    fn y_address(&self, i: usize) -> usize {
+        let e = self.e.expect("permanent variable used outside an environment");
+        self.frames[e].base + i - 1
+    }
+
+    /// Follows references until reaching an unbound variable, a structure
+    /// (returned as a `Reference` to its cell) or an atomic value.
+    pub(crate) fn deref(&self, cell: &HeapCell) -> HeapCell {
+        let mut cell = cell.clone();
+        loop {
+            let target = match cell {
+                HeapCell::Reference(a) => &self.heap[a],
+                HeapCell::StackReference(a) => &self.stack[a],
+                _ => return cell,
+            };
+            match target {
+                _ if *target == cell => return cell,
+                HeapCell::Reference(_) | HeapCell::StackReference(_) => cell = target.clone(),
+                HeapCell::Structure(..) => return cell,
+                atomic => return atomic.clone(),
+            }
+        }
+    }
+
+    /// True if a dereferenced cell is an unbound variable.
+    pub(crate) fn is_unbound(&self, cell: &HeapCell) -> bool {
+        match cell {
+            HeapCell::Reference(a) => !matches!(self.heap[*a], HeapCell::Structure(..)),
+            HeapCell::StackReference(_) => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn heap_boundary(&self) -> usize {
+        self.choicepoints.last().map_or(0, |b| b.heap_top)
+    }
+
+    fn stack_boundary(&self) -> usize {
+        self.choicepoints.last().map_or(0, |b| b.stack_top)
+    }
+
+    /// Binds the unbound variable `var` to `value` (both dereferenced). When
+    /// both are unbound, the stack variable or the younger heap variable is
+    /// the one that gets bound, so the heap never points into the stack.
+    pub(crate) fn bind(&mut self, var: &HeapCell, value: &HeapCell) {
+        let (target, value) = match (var, value) {
+            (HeapCell::Reference(a), HeapCell::Reference(b)) if self.is_unbound(value) && a < b => (value, var),
+            (HeapCell::Reference(_), HeapCell::StackReference(_)) => (value, var),
+            (HeapCell::StackReference(a), HeapCell::StackReference(b)) if a < b => (value, var),
+            _ => (var, value),
+        };
+        match *target {
+            HeapCell::Reference(a) => {
+                self.heap[a] = value.clone();
+                if a < self.heap_boundary() {
+                    self.trail.push(TrailEntry::Heap(a));
+                }
+            }
+            HeapCell::StackReference(a) => {
+                self.stack[a] = value.clone();
+                if a < self.stack_boundary() {
+                    self.trail.push(TrailEntry::Stack(a));
+                }
+            }
+            _ => unreachable!("bind called on a bound cell"),
+        }
+    }
+
+    pub(crate) fn unify(&mut self, a: HeapCell, b: HeapCell) -> bool {
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let a = self.deref(&a);
+            let b = self.deref(&b);
+            if a == b {
+                continue;
+            }
+            if self.is_unbound(&a) {
+                self.bind(&a, &b);
+                continue;
+            }
+            if self.is_unbound(&b) {
+                self.bind(&b, &a);
+                continue;
+            }
+            match (&a, &b) {
+                (HeapCell::Reference(x), HeapCell::Reference(y)) => match (&self.heap[*x], &self.heap[*y]) {
+                    (HeapCell::Structure(f, xs), HeapCell::Structure(g, ys)) if f == g && xs.len() == ys.len() => {
+                        for (&x, &y) in xs.iter().zip(ys.iter()).rev() {
+                            pending.push((HeapCell::Reference(x), HeapCell::Reference(y)));
+                        }
+                    }
+                    _ => return false,
+                },
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    pub(crate) fn unwind_trail(&mut self, top: usize) {
+        while self.trail.len() > top {
+            match self.trail.pop() {
+                Some(TrailEntry::Heap(a)) => self.heap[a] = HeapCell::Reference(a),
+                // Slots above the live stack were freed after the binding was trailed.
+                Some(TrailEntry::Stack(a)) if a < self.stack.len() => self.stack[a] = HeapCell::StackReference(a),
+                Some(TrailEntry::Value(a, cell)) => self.heap[a] = cell,
+                _ => {}
+            }
+        }
+    }
+
+    /// Number of frames that must survive the next allocation: the current
+    /// environment and everything protected by the newest choice point.
+    fn protected_frames(&self) -> usize {
+        let e_top = self.e.map_or(0, |e| e + 1);
+        let b_top = self.choicepoints.last().map_or(0, |b| b.frame_top);
+        e_top.max(b_top)
+    }
+
+    fn stack_top(&self, frame_top: usize) -> usize {
+        match frame_top {
+            0 => 0,
+            n => self.frames[n - 1].base + self.frames[n - 1].size,
+        }
+    }
+
+    pub(crate) fn allocate(&mut self, size: usize) {
+        let index = self.protected_frames();
+        let base = self.stack_top(index);
+        self.frames.truncate(index);
+        self.stack.truncate(base);
+        self.stack.extend((base..base + size).map(HeapCell::StackReference));
+        self.frames.push(Frame { ce: self.e, cp: self.cp, base, size });
+        self.e = Some(index);
+    }
+
+    /// Pushes a choice point continuing at `next` that restores the first
+    /// `registers` registers.
+    pub(crate) fn push_choicepoint(&mut self, next: usize, registers: usize) {
+        let frame_top = self.protected_frames();
+        self.choicepoints.push(ChoicePoint {
+            args: self.registers[1..=registers].to_vec(),
+            e: self.e,
+            cp: self.cp,
+            b0: self.b0,
+            next,
+            trail_top: self.trail.len(),
+            heap_top: self.heap.len(),
+            frame_top,
+            stack_top: self.stack_top(frame_top),
+            dynamic: None,
+        });
+    }
+
+    /// Restores the machine to the newest choice point and continues at its
+    /// alternative. Returns false when no choice point is left for the query.
+    pub(crate) fn backtrack(&mut self) -> bool {
+        if self.choicepoints.len() <= self.query_base {
+            return false;
+        }
+        let b = self.choicepoints.last().unwrap().clone();
+        for (i, arg) in b.args.iter().enumerate() {
+            self.registers[i + 1] = arg.clone();
+        }
+        self.num_args = b.args.len();
+        self.e = b.e;
+        self.cp = b.cp;
+        self.b0 = b.b0;
+        self.unwind_trail(b.trail_top);
+        self.heap.truncate(b.heap_top);
+        self.p = b.next;
+        true
+    }
+
+    /// Transfers control to predicate `id`. Returns false if the call fails
+    /// outright because an argument index shows no clause can match.
+    pub(crate) fn call_predicate(&mut self, id: usize) -> Result<bool, WamError> {
+        let predicate = self.predicates.get(id);
+        if let Some(builtin) = predicate.builtin {
+            // Execution continues at CP unless the builtin transfers control
+            // itself, as call/1 does.
+            self.num_args = predicate.arity;
+            self.p = self.cp;
+            return builtin(self);
+        }
+        if let Some(index) = predicate.foreign {
+            self.num_args = predicate.arity;
+            self.p = self.cp;
+            return self.call_foreign(index, None);
+        }
+        if predicate.dynamic.is_some() {
+            return self.call_dynamic(id);
+        }
+        let entry = match predicate.entry {
+            Some(entry) => entry,
+            None => {
+                let (name, arity) = (predicate.name.clone(), predicate.arity);
+                if self.autoload(&name, arity)? {
+                    return self.call_predicate(id);
+                }
+                return Err(existence_error(&name, arity));
+            }
+        };
+        self.num_args = predicate.arity;
+        self.b0 = self.choicepoints.len();
+        match self.jit_dispatch(id) {
+            None => self.p = entry,
+            Some(Some(label)) => self.p = label,
+            Some(None) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Removes every choice point made after the first `level` ones.
+    pub(crate) fn cut(&mut self, level: usize) {
+        if self.choicepoints.len() > level {
+            self.choicepoints.truncate(level);
+        }
+    }
+
+    fn structure_argument(&self) -> usize {
+        match &self.heap[self.s.0] {
+            HeapCell::Structure(_, args) => args[self.s.1],
+            cell => unreachable!("S register points at {:?}", cell),
+        }
+    }
+
+    /// Matches the term in Ai against `name/arity`, entering read mode on
+    /// an existing structure or building a new one in write mode.
+    fn get_structure(&mut self, name: &str, arity: usize, ai: usize) -> bool {
+        let cell = self.deref(&self.registers[ai]);
+        if self.is_unbound(&cell) {
+            let h = self.push_structure(name.to_string(), arity);
+            self.bind(&cell, &HeapCell::Reference(h));
+            return true;
+        }
+        let address = self.structure_address(&cell, name, arity);
+        self.read_structure(address)
+    }
+
+    /// Address of the structure `name/arity` that bound `cell` is, if it is one.
+    fn structure_address(&self, cell: &HeapCell, name: &str, arity: usize) -> Option<usize> {
+        match cell {
+            HeapCell::Reference(a) => match &self.heap[*a] {
+                HeapCell::Structure(f, args) if f == name && args.len() == arity => Some(*a),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Enters read mode on the structure at `address`, failing without one.
+    fn read_structure(&mut self, address: Option<usize>) -> bool {
+        match address {
+            Some(a) => {
+                self.s = (a, 0);
+                self.mode = Mode::Read;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push_structure(&mut self, name: String, arity: usize) -> usize {
+        let h = self.heap.len();
+        self.heap.push(HeapCell::Structure(name, (h + 1..=h + arity).collect()));
+        self.mode = Mode::Write;
+        h
+    }
+
+    /// Keeps only the first `live` permanent variables of the current
+    /// environment, unless a choice point still needs the whole frame.
+    fn trim(&mut self, live: usize) {
+        if let Some(e) = self.e {
+            if self.choicepoints.last().map_or(0, |b| b.frame_top) <= e {
+                let frame = &mut self.frames[e];
+                frame.size = frame.size.min(live);
+            }
+        }
+    }
+
+    /// Pushes `cell` as the next argument of a structure being written. An
+    /// unbound stack variable is moved to the heap first.
+    pub(crate) fn push_local_argument(&mut self, cell: HeapCell) {
+        let cell = self.deref(&cell);
+        if let HeapCell::StackReference(_) = cell {
+            let h = self.heap.len();
+            self.heap.push(HeapCell::Reference(h));
+            self.bind(&cell, &HeapCell::Reference(h));
+        } else {
+            self.heap.push(cell);
+        }
+    }
+
+    /// Runs code from `P` until the query succeeds (`halt`) or fails.
+    pub fn run(&mut self) -> Result<bool, WamError> {
+        loop {
+            let at = self.p;
+            self.p += 1;
+            let succeeded = match self.code[at] {
+                Instruction::Halt => return Ok(true),
+                _ => match self.step(at) {
+                    Err(WamError::Exception(ball)) => self.handle_exception(ball)?,
+                    result => result?,
+                },
+            };
+            if !succeeded && !self.backtrack() {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Runs the instruction at `at`. It is matched in place, so only the
+    /// names and constants that go on the heap are copied.
+    fn step(&mut self, at: usize) -> Result<bool, WamError> {
+        match self.code[at] {
+            Instruction::GetVariable(reg, ai) => {
+                let cell = self.registers[ai].clone();
+                self.set_register(reg, cell);
+            }
+            Instruction::GetValue(reg, ai) => {
+                let cell = self.register(reg);
+                return Ok(self.unify(cell, self.registers[ai].clone()));
+            }
+            Instruction::GetStructure(ref name, arity, ai) => {
+                let cell = self.deref(&self.registers[ai]);
+                if self.is_unbound(&cell) {
+                    let h = self.push_structure(name.clone(), arity);
+                    self.bind(&cell, &HeapCell::Reference(h));
+                    return Ok(true);
+                }
+                let address = self.structure_address(&cell, name, arity);
+                return Ok(self.read_structure(address));
+            }
+            Instruction::GetList(ai) => return Ok(self.get_structure(LIST_FUNCTOR, 2, ai)),
+            Instruction::GetConstant(ref constant, ai) => {
+                let cell = self.deref(&self.registers[ai]);
+                if self.is_unbound(&cell) {
+                    let value = constant.to_cell();
+                    self.bind(&cell, &value);
+                    return Ok(true);
+                }
+                return Ok(constant.matches(&cell));
+            }
+            Instruction::GetNil(ai) => {
+                let cell = self.deref(&self.registers[ai]);
+                if self.is_unbound(&cell) {
+                    self.bind(&cell, &HeapCell::Constant(NIL.to_string()));
+                    return Ok(true);
+                }
+                return Ok(matches!(&cell, HeapCell::Constant(name) if name == NIL));
+            }
+            Instruction::UnifyVariable(reg) => match self.mode {
+                Mode::Read => {
+                    let arg = self.structure_argument();
+                    self.set_register(reg, HeapCell::Reference(arg));
+                    self.s.1 += 1;
+                }
+                Mode::Write => {
+                    let h = self.heap.len();
+                    self.heap.push(HeapCell::Reference(h));
+                    self.set_register(reg, HeapCell::Reference(h));
+                }
+            },
+            Instruction::UnifyValue(reg) => match self.mode {
+                Mode::Read => {
+                    let arg = self.structure_argument();
+                    self.s.1 += 1;
+                    let cell = self.register(reg);
+                    return Ok(self.unify(cell, HeapCell::Reference(arg)));
+                }
+                Mode::Write => {
+                    let cell = self.deref(&self.register(reg));
+                    debug_assert!(!matches!(cell, HeapCell::StackReference(_)), "unify_value on a stack variable");
+                    self.heap.push(cell);
+                }
+            },
+            Instruction::UnifyLocalValue(reg) => match self.mode {
+                Mode::Read => {
+                    let arg = self.structure_argument();
+                    self.s.1 += 1;
+                    let cell = self.register(reg);
+                    return Ok(self.unify(cell, HeapCell::Reference(arg)));
+                }
+                Mode::Write => {
+                    let cell = self.register(reg);
+                    self.push_local_argument(cell);
+                }
+            },
+            Instruction::UnifyConstant(ref constant) => match self.mode {
+                Mode::Read => {
+                    let value = constant.to_cell();
+                    let arg = self.structure_argument();
+                    self.s.1 += 1;
+                    return Ok(self.unify(HeapCell::Reference(arg), value));
+                }
+                Mode::Write => self.heap.push(constant.to_cell()),
+            },
+            Instruction::UnifyVoid(n) => match self.mode {
+                Mode::Read => self.s.1 += n,
+                Mode::Write => {
+                    for _ in 0..n {
+                        let h = self.heap.len();
+                        self.heap.push(HeapCell::Reference(h));
+                    }
+                }
+            },
+            Instruction::PutVariable(Reg::X(i), ai) => {
+                let h = self.heap.len();
+                self.heap.push(HeapCell::Reference(h));
+                self.registers[i] = HeapCell::Reference(h);
+                self.registers[ai] = HeapCell::Reference(h);
+            }
+            Instruction::PutVariable(Reg::Y(i), ai) => {
+                let address = self.y_address(i);
+                self.stack[address] = HeapCell::StackReference(address);
+                self.registers[ai] = HeapCell::StackReference(address);
+            }
+            Instruction::PutValue(reg, ai) => self.registers[ai] = self.register(reg),
+            Instruction::PutUnsafeValue(y, ai) => {
+                let cell = self.deref(&self.register(Reg::Y(y)));
+                let base = self.frames[self.e.unwrap()].base;
+                self.registers[ai] = match cell {
+                    HeapCell::StackReference(a) if a >= base => {
+                        let h = self.heap.len();
+                        self.heap.push(HeapCell::Reference(h));
+                        self.bind(&cell, &HeapCell::Reference(h));
+                        HeapCell::Reference(h)
+                    }
+                    cell => cell,
+                };
+            }
+            Instruction::PutStructure(ref name, arity, ai) => {
+                let h = self.push_structure(name.clone(), arity);
+                self.registers[ai] = HeapCell::Reference(h);
+            }
+            Instruction::PutConstant(ref constant, ai) => self.registers[ai] = constant.to_cell(),
+            Instruction::Allocate(size) => self.allocate(size),
+            Instruction::Deallocate => {
+                let frame = &self.frames[self.e.expect("deallocate without an environment")];
+                self.cp = frame.cp;
+                self.e = frame.ce;
+            }
+            Instruction::Call(id, live) => {
+                self.trim(live);
+                self.cp = self.p;
+                return self.call_predicate(id);
+            }
+            Instruction::Execute(id) => return self.call_predicate(id),
+            Instruction::Proceed => self.p = self.cp,
+            Instruction::NeckCut => self.cut(self.b0),
+            Instruction::GetLevel(reg) => self.set_register(reg, HeapCell::Integer(self.b0 as i64)),
+            Instruction::GetChoice(reg) => self.set_register(reg, HeapCell::Integer(self.choicepoints.len() as i64)),
+            Instruction::Cut(reg) => match self.register(reg) {
+                HeapCell::Integer(level) => self.cut(level as usize),
+                cell => unreachable!("cut level is {:?}", cell),
+            },
+            Instruction::Evaluate(operand, reg) => {
+                let value = self.operand(&operand)?;
+                self.set_register(reg, value.into_cell());
+            }
+            Instruction::Arith(op, x, y, reg) => {
+                let value = binary(op, self.operand(&x)?, self.operand(&y)?)?;
+                self.set_register(reg, value.into_cell());
+            }
+            Instruction::Compare(comparison, x, y) => return self.compare_operands(comparison, &x, &y),
+            Instruction::TryMeElse(label, registers) => self.push_choicepoint(label, registers),
+            Instruction::RetryMeElse(label) => self.choicepoints.last_mut().unwrap().next = label,
+            Instruction::TrustMe => {
+                self.choicepoints.pop();
+            }
+            Instruction::Try(label) => {
+                self.push_choicepoint(self.p, self.num_args);
+                self.p = label;
+            }
+            Instruction::Retry(label) => {
+                self.choicepoints.last_mut().unwrap().next = self.p;
+                self.p = label;
+            }
+            Instruction::Trust(label) => {
+                self.choicepoints.pop();
+                self.p = label;
+            }
+            Instruction::SwitchOnTerm { variable, constant, list, structure } => {
+                let cell = self.deref(&self.registers[1]);
+                let target = match cell {
+                    _ if self.is_unbound(&cell) => Some(variable),
+                    HeapCell::Reference(a) => match &self.heap[a] {
+                        HeapCell::Structure(f, args) if f == LIST_FUNCTOR && args.len() == 2 => list,
+                        _ => structure,
+                    },
+                    _ => constant,
+                };
+                return Ok(self.jump(target));
+            }
+            Instruction::SwitchOnConstant(ref table) => {
+                let cell = self.deref(&self.registers[1]);
+                let target = Constant::from_cell(&cell).and_then(|key| table.target(&key.key()));
+                return Ok(self.jump(target));
+            }
+            Instruction::SwitchOnStructure(ref table) => {
+                let cell = self.deref(&self.registers[1]);
+                let target = match cell {
+                    HeapCell::Reference(a) => match &self.heap[a] {
+                        HeapCell::Structure(f, args) => table.target(&(f.clone(), args.len())),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                return Ok(self.jump(target));
+            }
+            Instruction::Jump(label) => self.p = label,
+            Instruction::InitVariable(reg) => {
+                let h = self.heap.len();
+                self.heap.push(HeapCell::Reference(h));
+                self.set_register(reg, HeapCell::Reference(h));
+            }
+            Instruction::RetryForeign(index) => return self.retry_foreign(index),
+            Instruction::ExitCatch => self.exit_catch(),
+            Instruction::RetryDynamic => return self.retry_dynamic(),
+            Instruction::Fail => return Ok(false),
+            Instruction::Halt => unreachable!("halt is handled by run"),
+        }
+        Ok(true)
+    }
+
+    fn jump(&mut self, target: Option<usize>) -> bool {
+        match target {
+            Some(label) => {
+                self.p = label;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Compiles `goals` as a query and positions the machine at its start.
+    /// Returns each named query variable with the heap address it lives at;
+    /// after `run` succeeds those addresses hold the answer.
+    pub fn start_query(&mut self, goals: &[ast::Term]) -> Result<Vec<(String, usize)>, WamError> {
+        let mut names = Vec::new();
+        let mut seen = HashSet::new();
+        for goal in goals {
+            collect_variables(goal, &mut seen, &mut names);
+        }
+
+        let head = ast::Term::Structure {
+            functor: "$query".to_string(),
+            arity: names.len(),
+            args: names.iter().map(|name| ast::Term::Variable(name.clone())).collect(),
+        };
+        let compiled = compile_predicate(&[Clause { head, body: goals.to_vec() }], &mut self.predicates, self.compile_options)?;
+        let entry = self.install_code(compiled.code, &[(0, names.len())]);
+
+        self.choicepoints.clear();
+        self.redo_states.clear();
+        self.reclaim_clauses();
+        self.trail.clear();
+        self.frames.clear();
+        self.stack.clear();
+        self.e = None;
+        self.cp = HALT;
+        self.b0 = 0;
+        self.query_base = 0;
+        self.num_args = names.len();
+        self.p = entry;
+
+        let mut bindings = Vec::new();
+        for (i, name) in names.into_iter().enumerate() {
+            let h = self.heap.len();
+            self.heap.push(HeapCell::Reference(h));
+            self.registers[i + 1] = HeapCell::Reference(h);
+            bindings.push((name, h));
+        }
+        Ok(bindings)
+    }
+
+    /// Backtracks into the current query for its next answer.
+    pub fn next_solution(&mut self) -> Result<bool, WamError> {
+        if !self.backtrack() {
+            return Ok(false);
+        }
+        self.run()
+    }
+}
+
+fn collect_variables(term: &ast::Term, seen: &mut HashSet<String>, names: &mut Vec<String>) {
+    match term {
+        ast::Term::Variable(name) if name != "_" && seen.insert(name.clone()) => names.push(name.clone()),
+        ast::Term::Structure { args, .. } | ast::Term::List(args) => {
+            for arg in args {
+                collect_variables(arg, seen, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::parser::ast::parse;
+    use crate::runtime::writer::format_answer;
+
+    /// Runs `goal` and returns every answer as the toplevel would print it.
+    pub(crate) fn answers(wam: &mut WamEmulator, goal: &str) -> Vec<String> {
+        let clauses = parse(&format!("query :- {}.", goal)).unwrap();
+        let bindings = wam.start_query(&clauses[0].body).unwrap();
+        let mut answers = Vec::new();
+        let mut found = wam.run().unwrap();
+        while found {
+            answers.push(format_answer(wam, &bindings));
+            found = wam.next_solution().unwrap();
+        }
+        answers
+    }
+
+    /// Runs `goal`, which must raise an error, and returns the error term.
+    pub(crate) fn exception(wam: &mut WamEmulator, goal: &str) -> String {
+        let clauses = parse(&format!("query :- {}.", goal)).unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+        match wam.run() {
+            Err(WamError::Exception(term)) => term.to_string(),
+            other => panic!("expected an exception, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_facts_and_backtracking() {
+        let mut wam = WamEmulator::new();
+        wam.consult("parent(tom, bob). parent(tom, liz). parent(bob, ann).").unwrap();
+
+        assert_eq!(answers(&mut wam, "parent(tom, X)"), vec!["X = bob", "X = liz"]);
+        assert_eq!(answers(&mut wam, "parent(X, ann)"), vec!["X = bob"]);
+        assert!(answers(&mut wam, "parent(ann, X)").is_empty());
+    }
+
+    #[test]
+    fn test_rules_with_permanent_variables() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            parent(tom, bob). parent(bob, ann). parent(bob, pat).
+            grandparent(X, Z) :- parent(X, Y), parent(Y, Z).
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "grandparent(tom, Who)"), vec!["Who = ann", "Who = pat"]);
+    }
+
+    #[test]
+    fn test_structures_in_heads_and_bodies() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            app(nil, L, L).
+            app(cons(H, T), L, cons(H, R)) :- app(T, L, R).
+        ").unwrap();
+
+        assert_eq!(
+            answers(&mut wam, "app(cons(a, cons(b, nil)), cons(c, nil), X)"),
+            vec!["X = cons(a, cons(b, cons(c, nil)))"]
+        );
+        assert_eq!(answers(&mut wam, "app(X, Y, cons(a, nil))"), vec![
+            "X = nil,\nY = cons(a, nil)",
+            "X = cons(a, nil),\nY = nil",
+        ]);
+    }
+
+    #[test]
+    fn test_unbound_answer_variables() {
+        let mut wam = WamEmulator::new();
+        wam.consult("same(X, X). wrap(X, f(X, Y), Y).").unwrap();
+
+        assert_eq!(answers(&mut wam, "same(A, B)"), vec!["B = A"]);
+        let answer = &answers(&mut wam, "wrap(a, T, U)")[0];
+        assert!(answer.starts_with("T = f(a, U)"), "{}", answer);
+    }
+
+    #[test]
+    fn test_lists_and_integers() {
+        let mut wam = WamEmulator::new();
+        wam.consult("first([X, Y], X, Y). age(bob, 42).").unwrap();
+
+        assert_eq!(answers(&mut wam, "first([1, 2], A, B)"), vec!["A = 1,\nB = 2"]);
+        assert_eq!(answers(&mut wam, "age(bob, N)"), vec!["N = 42"]);
+        assert!(answers(&mut wam, "age(bob, 41)").is_empty());
+    }
+
+    #[test]
+    fn test_unknown_procedure() {
+        let mut wam = WamEmulator::new();
+        wam.consult("p :- missing(1).").unwrap();
+        let clauses = parse("query :- p.").unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+
+        match (wam.run(), existence_error("missing", 1)) {
+            (Err(WamError::Exception(term)), WamError::Exception(expected)) => assert_eq!(term, expected),
+            (other, _) => panic!("expected an existence error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tail_recursion_runs_in_constant_stack() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            count(0).
+            count(N) :- N > 0, down(N, M), count(M).
+            down(N, M) :- M is N - 1.
+        ").unwrap();
+
+        // The most frames, stack cells, heap cells and choice points in use
+        // at once while counting down from `n`.
+        let mut high_water = |n: usize| {
+            let clauses = parse(&format!("query :- count({}).", n)).unwrap();
+            wam.start_query(&clauses[0].body).unwrap();
+            let mut high = (0, 0, 0, 0);
+            while wam.code[wam.p] != Instruction::Halt {
+                let at = wam.p;
+                wam.p += 1;
+                assert!(wam.step(at).unwrap() || wam.backtrack());
+                high.0 = high.0.max(wam.frames.len());
+                high.1 = high.1.max(wam.stack.len());
+                high.2 = high.2.max(wam.heap.len());
+                high.3 = high.3.max(wam.choicepoints.len());
+            }
+            high
+        };
+        assert_eq!(high_water(10), high_water(10000));
+    }
+
+    #[test]
+    fn test_unsafe_variables_survive_deallocation() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            id(X, X).
+            wrap(X, f(X)).
+            p(R) :- id(A, B), wrap(A, R), id(B, z).
+            q(R) :- id(a, a), wrap(V, R), id(V, _).
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "p(R)"), vec!["R = f(z)"]);
+        assert_eq!(answers(&mut wam, "q(f(b))"), vec!["true"]);
+    }
+
+    #[test]
+    fn test_cut_commits_to_clause() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            ge(2, 1). ge(2, 2). ge(3, 2).
+            max(X, Y, X) :- ge(X, Y), !.
+            max(X, Y, Y).
+            first(X, [X|_]) :- !.
+            first(X, [_|T]) :- first(X, T).
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "max(3, 2, M)"), vec!["M = 3"]);
+        assert_eq!(answers(&mut wam, "max(2, 3, M)"), vec!["M = 3"]);
+        assert_eq!(answers(&mut wam, "first(X, [a, b, c])"), vec!["X = a"]);
+    }
+
+    #[test]
+    fn test_call_is_a_cut_barrier() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            member(X, [X|_]).
+            member(X, [_|T]) :- member(X, T).
+            my_once(G) :- call(G), !.
+            local(X) :- call((member(X, [a, b]), !)).
+            local(c).
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "my_once(member(X, [a, b, c]))"), vec!["X = a"]);
+        assert_eq!(answers(&mut wam, "local(X)"), vec!["X = a", "X = c"]);
+        assert_eq!(answers(&mut wam, "G = member(X, [1, 2]), call(G)"), vec![
+            "G = member(1, [1, 2]),\nX = 1",
+            "G = member(2, [1, 2]),\nX = 2",
+        ]);
+    }
+
+    #[test]
+    fn test_call_shares_predicates_between_constants() {
+        let mut wam = WamEmulator::new();
+        wam.consult("p(1). p(2). p(3).").unwrap();
+        assert_eq!(answers(&mut wam, "findall(X, (p(X), X > 1), L)"), vec!["L = [2, 3]"]);
+        assert_eq!(answers(&mut wam, "findall(X, (p(X), X > 2.5 ; X = a), L)"), vec!["L = [3, a]"]);
+        let goal = |n: usize| {
+            format!("numlist(1, {}, Is), member(I, Is), findall(X, (p(X), X > I, \\+ X = f(I, a)), _), fail", n)
+        };
+        assert!(answers(&mut wam, &goal(1)).is_empty());
+        let predicates = wam.predicates.len();
+
+        // Goals that only differ in constants share the predicate compiled
+        // for the first one.
+        assert!(answers(&mut wam, &goal(200)).is_empty());
+        assert_eq!(answers(&mut wam, "findall(X, (p(X), X > 1), L)"), vec!["L = [2, 3]"]);
+        assert_eq!(answers(&mut wam, "findall(X, (p(X), X > 0 ; X = b), L)"), vec!["L = [1, 2, 3, b]"]);
+        assert_eq!(wam.predicates.len(), predicates);
+    }
+
+    #[test]
+    fn test_cut_is_transparent_to_control_constructs() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            p(1). p(2). p(3).
+            a(X) :- p(X), (X = 2, ! ; true).
+            b(X) :- (p(X), X = 2 -> true ; X = none).
+            c(X) :- (p(X) -> ! ; true).
+            c(late).
+            d(X) :- p(X), \\+ X = 2.
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "a(X)"), vec!["X = 1", "X = 2"]);
+        assert_eq!(answers(&mut wam, "b(X)"), vec!["X = 2"]);
+        assert_eq!(answers(&mut wam, "c(X)"), vec!["X = 1"]);
+        assert_eq!(answers(&mut wam, "d(X)"), vec!["X = 1", "X = 3"]);
+    }
+
+    #[test]
+    fn test_disjunction_branches_share_variables() {
+        let mut wam = WamEmulator::new();
+        wam.consult("
+            q(1, a). r(2, b).
+            s(a, x). s(b, y).
+            color(C) :- (C = red ; C = green ; C = blue).
+            sign(N, S) :- (N < 0 -> S = negative ; N =:= 0 -> S = zero ; S = positive).
+            via(X, Z) :- (q(X, Y) ; r(X, Y)), s(Y, Z).
+            maybe(X, Y) :- (X = 1 ; true), Y = f(X).
+            pick(X, Y) :- (X = 1 ; X = 2), (Y = a ; Y = b).
+            first(R) :- ((p(X), !, X > 1) -> R = yes ; R = no).
+            p(1). p(2).
+            count(N, Acc, R) :- (N =:= 0 -> R = Acc ; M is N - 1, B is Acc + 1, count(M, B, R)).
+        ").unwrap();
+
+        assert_eq!(answers(&mut wam, "color(C)"), vec!["C = red", "C = green", "C = blue"]);
+        assert_eq!(answers(&mut wam, "sign(-3, S)"), vec!["S = negative"]);
+        assert_eq!(answers(&mut wam, "sign(0, S)"), vec!["S = zero"]);
+        assert_eq!(answers(&mut wam, "sign(7, S)"), vec!["S = positive"]);
+        assert_eq!(answers(&mut wam, "via(X, Z)"), vec!["X = 1,\nZ = x", "X = 2,\nZ = y"]);
+        // The second branch leaves X unbound, but Y still shares it.
+        assert_eq!(answers(&mut wam, "maybe(X, Y), Y = f(Z), Z = 3"), vec!["X = 3,\nY = f(3),\nZ = 3"]);
+        assert_eq!(answers(&mut wam, "pick(X, Y)").len(), 4);
+        // The cut in the condition only cuts p/1, so the condition fails.
+        assert_eq!(answers(&mut wam, "first(R)"), vec!["R = no"]);
+        assert_eq!(answers(&mut wam, "count(100000, 0, R)"), vec!["R = 100000"]);
+    }
+}
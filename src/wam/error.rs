@@ -0,0 +1,163 @@
+// src/wam/error.rs
+use std::fmt;
+
+use crate::compiler::CompileError;
+use crate::parser::ast::{ParseError, Term};
+use crate::wam::object::ObjectError;
+use crate::wam::verifier::VerifyError;
+
+#[derive(Debug)]
+pub enum WamError {
+    Parse(ParseError),
+    Compile(CompileError),
+    /// A Prolog error term raised while running code, e.g.
+    /// `error(existence_error(procedure, foo/0), foo/0)`.
+    Exception(Term),
+    Object(ObjectError),
+    /// Code rejected by the verifier before it was installed.
+    InvalidCode(VerifyError),
+}
+
+impl From<ParseError> for WamError {
+    fn from(error: ParseError) -> Self {
+        WamError::Parse(error)
+    }
+}
+
+impl From<CompileError> for WamError {
+    fn from(error: CompileError) -> Self {
+        WamError::Compile(error)
+    }
+}
+
+impl From<ObjectError> for WamError {
+    fn from(error: ObjectError) -> Self {
+        WamError::Object(error)
+    }
+}
+
+impl From<VerifyError> for WamError {
+    fn from(error: VerifyError) -> Self {
+        WamError::InvalidCode(error)
+    }
+}
+
+impl fmt::Display for WamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WamError::Parse(error) => write!(f, "syntax error: {}", error),
+            WamError::Compile(error) => write!(f, "compilation error: {:?}", error),
+            WamError::Exception(term) => write!(f, "uncaught exception: {}", term),
+            WamError::Object(error) => write!(f, "invalid object file: {:?}", error),
+            WamError::InvalidCode(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for WamError {}
+
+pub(crate) fn structure(functor: &str, args: Vec<Term>) -> Term {
+    Term::Structure {
+        functor: functor.to_string(),
+        arity: args.len(),
+        args,
+    }
+}
+
+/// `Name/Arity` as a term.
+pub(crate) fn indicator(name: &str, arity: usize) -> Term {
+    structure("/", vec![Term::Atom(name.to_string()), Term::Integer(arity as i64)])
+}
+
+fn iso_error(formal: Term, context: Term) -> WamError {
+    WamError::Exception(structure("error", vec![formal, context]))
+}
+
+/// ISO `existence_error(procedure, Name/Arity)` for a call to an unknown predicate.
+pub fn existence_error(name: &str, arity: usize) -> WamError {
+    let culprit = indicator(name, arity);
+    iso_error(structure("existence_error", vec![Term::Atom("procedure".to_string()), culprit.clone()]), culprit)
+}
+
+/// ISO `existence_error(Kind, Culprit)` for anything but a procedure, e.g.
+/// `source_sink` for a file that cannot be read.
+pub fn object_existence_error(kind: &str, culprit: Term) -> WamError {
+    iso_error(structure("existence_error", vec![Term::Atom(kind.to_string()), culprit]), Term::Variable("_".to_string()))
+}
+
+/// ISO `instantiation_error`: an argument was unbound where a value was needed.
+pub fn instantiation_error() -> WamError {
+    iso_error(Term::Atom("instantiation_error".to_string()), Term::Variable("_".to_string()))
+}
+
+/// ISO `uninstantiation_error(Culprit)`: an argument was bound where a
+/// variable was needed, e.g. the stream argument of `open/3`.
+pub fn uninstantiation_error(culprit: Term) -> WamError {
+    iso_error(structure("uninstantiation_error", vec![culprit]), Term::Variable("_".to_string()))
+}
+
+/// ISO `type_error(Type, Culprit)`.
+pub fn type_error(kind: &str, culprit: Term) -> WamError {
+    iso_error(structure("type_error", vec![Term::Atom(kind.to_string()), culprit]), Term::Variable("_".to_string()))
+}
+
+/// ISO `domain_error(Domain, Culprit)`: an argument of the right type
+/// outside the values allowed.
+pub fn domain_error(domain: &str, culprit: Term) -> WamError {
+    iso_error(structure("domain_error", vec![Term::Atom(domain.to_string()), culprit]), Term::Variable("_".to_string()))
+}
+
+/// ISO `permission_error(Action, Type, Culprit)`, e.g. redefining a built-in.
+pub fn permission_error(action: &str, kind: &str, culprit: Term) -> WamError {
+    let formal = structure("permission_error", vec![
+        Term::Atom(action.to_string()),
+        Term::Atom(kind.to_string()),
+        culprit,
+    ]);
+    iso_error(formal, Term::Variable("_".to_string()))
+}
+
+/// ISO `representation_error(Flag)`, e.g. an integer beyond `max_integer`.
+pub fn representation_error(flag: &str) -> WamError {
+    iso_error(structure("representation_error", vec![Term::Atom(flag.to_string())]), Term::Variable("_".to_string()))
+}
+
+/// ISO `evaluation_error(Error)`, e.g. `zero_divisor` from `X is 1 // 0`.
+pub fn evaluation_error(error: &str) -> WamError {
+    iso_error(structure("evaluation_error", vec![Term::Atom(error.to_string())]), Term::Variable("_".to_string()))
+}
+
+/// ISO `resource_error(Resource)`, e.g. `memory` for a result too large to build.
+pub fn resource_error(resource: &str) -> WamError {
+    iso_error(structure("resource_error", vec![Term::Atom(resource.to_string())]), Term::Variable("_".to_string()))
+}
+
+/// `system_error(Message)`: the machine itself went wrong rather than the
+/// program, e.g. a foreign predicate retried without the state it left.
+pub fn system_error(message: &str) -> WamError {
+    iso_error(structure("system_error", vec![Term::Atom(message.to_string())]), Term::Variable("_".to_string()))
+}
+
+/// ISO `syntax_error(illegal_number)`: text converted to a number is not
+/// the syntax of one.
+pub fn illegal_number() -> WamError {
+    iso_error(structure("syntax_error", vec![Term::Atom("illegal_number".to_string())]), Term::Variable("_".to_string()))
+}
+
+/// `format(Message)` for a format text that does not fit its arguments,
+/// e.g. `format('not enough arguments')`, as SWI-Prolog raises it.
+pub fn format_error(message: &str) -> WamError {
+    iso_error(structure("format", vec![Term::Atom(message.to_string())]), Term::Variable("_".to_string()))
+}
+
+/// ISO `syntax_error(Message)` for a parse error, with the context
+/// `position(Line, Column)` when the error has a position.
+pub fn syntax_error(error: &ParseError) -> WamError {
+    let context = match error {
+        ParseError::At { line, column, .. } => {
+            structure("position", vec![Term::Integer(*line as i64), Term::Integer(*column as i64)])
+        }
+        _ => Term::Variable("_".to_string()),
+    };
+    iso_error(structure("syntax_error", vec![Term::Atom(error.message().to_string())]), context)
+}
@@ -0,0 +1,172 @@
+// src/wam/exception.rs
+use crate::parser::ast::Term;
+use crate::runtime::builtins::call;
+use crate::wam::data_structures::{WamEmulator, CATCH_EXIT, CATCH_FAIL};
+use crate::wam::error::{instantiation_error, WamError};
+
+impl WamEmulator {
+    /// `catch(+Goal, ?Catcher, ?Recovery)`: runs Goal as `call/1` does. If
+    /// Goal raises an exception whose ball unifies with Catcher, everything
+    /// Goal did is undone and Recovery runs instead.
+    ///
+    /// The catch gets an environment of its own, which Goal returns through
+    /// (`exit_catch`), so an exception is inside Goal exactly when that
+    /// environment is on its continuation. Below Goal sits a choice point
+    /// continuing at `CATCH_FAIL`, which saves Goal, Catcher and Recovery in
+    /// A1..A3 and marks the catcher.
+    pub(crate) fn catch(&mut self) -> Result<bool, WamError> {
+        self.allocate(0);
+        self.push_choicepoint(CATCH_FAIL, 3);
+        self.cp = CATCH_EXIT;
+        call(self)
+    }
+
+    /// `throw(+Ball)`: raises a copy of Ball as an exception.
+    pub(crate) fn throw(&mut self) -> Result<bool, WamError> {
+        let ball = self.deref(&self.registers[1]);
+        if self.is_unbound(&ball) {
+            return Err(instantiation_error());
+        }
+        Err(WamError::Exception(self.decode_cell(&ball)?))
+    }
+
+    /// Returns from the goal of the `catch/3` owning the current
+    /// environment to the catch's continuation. The catcher's choice point
+    /// goes too when the goal left no other.
+    pub(crate) fn exit_catch(&mut self) {
+        let e = self.e.expect("exit_catch outside catch/3");
+        if self.choicepoints.last().is_some_and(|b| b.next == CATCH_FAIL && b.e == Some(e)) {
+            self.choicepoints.pop();
+        }
+        let frame = &self.frames[e];
+        self.cp = frame.cp;
+        self.e = frame.ce;
+        self.p = self.cp;
+    }
+
+    /// Unwinds to the innermost `catch/3` running its goal whose catcher
+    /// unifies with a copy of `ball`, restoring the machine to how it was
+    /// when the catch was called, and runs its recovery. Returns the
+    /// exception when no catcher takes it.
+    pub(crate) fn handle_exception(&mut self, mut ball: Term) -> Result<bool, WamError> {
+        while let Some(catcher) = self.active_catcher() {
+            self.choicepoints.truncate(catcher + 1);
+            self.backtrack();
+            self.choicepoints.pop();
+            let frame = &self.frames[self.e.unwrap()];
+            self.cp = frame.cp;
+            self.e = frame.ce;
+            let copy = self.build_copy(&ball);
+            if !self.unify(self.registers[2].clone(), copy) {
+                continue;
+            }
+            self.registers[1] = self.registers[3].clone();
+            self.num_args = 1;
+            self.p = self.cp;
+            match call(self) {
+                // Raised by calling the recovery itself, outside the catch.
+                Err(WamError::Exception(error)) => ball = error,
+                result => return result,
+            }
+        }
+        Err(WamError::Exception(ball))
+    }
+
+    /// The newest catcher choice point whose goal is still running.
+    fn active_catcher(&self) -> Option<usize> {
+        (self.query_base..self.choicepoints.len()).rev().find(|&i| {
+            let b = &self.choicepoints[i];
+            b.next == CATCH_FAIL && self.in_continuation(b.e.unwrap())
+        })
+    }
+
+    /// Whether environment `frame` is the current one or one the current
+    /// continuation returns through. An environment's continuation always
+    /// lies below it on the frame stack.
+    fn in_continuation(&self, frame: usize) -> bool {
+        let mut e = self.e;
+        while let Some(index) = e.filter(|&index| index >= frame) {
+            if index == frame {
+                return true;
+            }
+            e = self.frames[index].ce;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::{parse, Term};
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::error::syntax_error;
+    use crate::wam::{WamEmulator, WamError};
+
+    const PROGRAM: &str = "
+        p(1). p(2). p(3).
+        q(X) :- p(X), X > 1, throw(found(X)).
+        safe(X, R) :- catch(q(X), found(Y), R = caught(Y)).
+    ";
+
+    #[test]
+    fn test_catch_and_throw() {
+        let mut wam = WamEmulator::new();
+        wam.consult(PROGRAM).unwrap();
+        assert_eq!(answers(&mut wam, "catch(throw(a), B, true)"), vec!["B = a"]);
+        // Bindings made by the goal are undone before the recovery runs.
+        assert_eq!(answers(&mut wam, "safe(X, R)"), vec!["R = caught(2)"]);
+        assert_eq!(answers(&mut wam, "catch(p(X), _, true)"), vec!["X = 1", "X = 2", "X = 3"]);
+        assert_eq!(answers(&mut wam, "catch((p(X), !), _, true)"), vec!["X = 1"]);
+        // The ball is a copy.
+        assert_eq!(answers(&mut wam, "catch(throw(f(X)), f(Y), true), X = 1, Y = 2"), vec!["X = 1,\nY = 2"]);
+    }
+
+    #[test]
+    fn test_exceptions_reach_the_right_catcher() {
+        let mut wam = WamEmulator::new();
+        wam.consult(PROGRAM).unwrap();
+        assert_eq!(answers(&mut wam, "catch(catch(throw(b), a, true), E, true)"), vec!["E = b"]);
+        assert_eq!(answers(&mut wam, "catch(catch(throw(a), a, throw(b)), b, true)"), vec!["true"]);
+        // A catch whose goal has exited no longer catches, even while its
+        // goal can still be retried.
+        assert_eq!(exception(&mut wam, "catch(p(_), _, true), throw(late)"), "late");
+        assert_eq!(exception(&mut wam, "catch(throw(a), b, true)"), "a");
+        assert_eq!(exception(&mut wam, "throw(_)"), "error(instantiation_error, _)");
+    }
+
+    #[test]
+    fn test_builtin_errors_are_iso_terms() {
+        let mut wam = WamEmulator::new();
+        assert_eq!(answers(&mut wam, "catch(succ(a, _), error(E, _), true)"), vec!["E = type_error(integer, a)"]);
+        assert_eq!(answers(&mut wam, "catch(X is 1 // 0, error(E, _), true)"), vec!["E = evaluation_error(zero_divisor)"]);
+        assert_eq!(answers(&mut wam, "catch(undefined, error(E, _), true)"), vec![
+            "E = existence_error(procedure, undefined/0)"
+        ]);
+        assert_eq!(answers(&mut wam, "catch(call(1), error(E, _), true)"), vec!["E = type_error(callable, 1)"]);
+    }
+
+    #[test]
+    fn test_syntax_errors_have_positions() {
+        let term = |source: &str| match syntax_error(&parse(source).unwrap_err()) {
+            WamError::Exception(term) => term,
+            other => panic!("expected an exception, got {:?}", other),
+        };
+        let error = |message: &str, line: i64, column: i64| {
+            let formal = Term::Structure {
+                functor: "syntax_error".into(),
+                arity: 1,
+                args: vec![Term::Atom(message.into())],
+            };
+            let position = Term::Structure {
+                functor: "position".into(),
+                arity: 2,
+                args: vec![Term::Integer(line), Term::Integer(column)],
+            };
+            Term::Structure { functor: "error".into(), arity: 2, args: vec![formal, position] }
+        };
+        assert_eq!(term("p :- q(a b)."), error("unexpected_token", 1, 10));
+        assert_eq!(term("p.\nq(."), error("end_of_clause", 2, 3));
+        assert_eq!(term("p :- 'abc"), error("end_of_file", 1, 10));
+        assert_eq!(term("p :- q\n  # r."), error("unexpected_token", 2, 3));
+    }
+}
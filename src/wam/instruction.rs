@@ -0,0 +1,348 @@
+// src/wam/instruction.rs
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use crate::wam::data_structures::HeapCell;
+
+/// Code address inside the emulator's code area.
+pub type Label = usize;
+
+/// A register operand: `X(i)` is a temporary (argument) register, `Y(i)` a
+/// permanent variable in the current environment. Both are numbered from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    X(usize),
+    Y(usize),
+}
+
+/// Atomic values that can appear directly in instructions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Constant {
+    Atom(String),
+    Integer(i64),
+    BigInteger(BigInt),
+    /// A float, held as its bit pattern so constants can be hashed.
+    Float(u64),
+}
+
+impl Constant {
+    pub fn to_cell(&self) -> HeapCell {
+        match self {
+            Constant::Atom(name) => HeapCell::Constant(name.clone()),
+            Constant::Integer(value) => HeapCell::Integer(*value),
+            Constant::BigInteger(value) => HeapCell::BigInteger(value.clone()),
+            Constant::Float(bits) => HeapCell::Float(f64::from_bits(*bits)),
+        }
+    }
+
+    /// The constant as a switch or index key. Keys compare bit patterns,
+    /// so `-0.0`, which unifies with `0.0`, is keyed as `0.0`.
+    pub fn key(self) -> Constant {
+        match self {
+            Constant::Float(bits) if f64::from_bits(bits) == 0.0 => Constant::Float(0),
+            constant => constant,
+        }
+    }
+
+    /// Returns the constant an atomic cell holds, or `None` for variables and structures.
+    /// Whether bound `cell` is this constant. Floats compare by value, so
+    /// `0.0` is `-0.0`.
+    pub fn matches(&self, cell: &HeapCell) -> bool {
+        match (self, cell) {
+            (Constant::Atom(name), HeapCell::Constant(atom)) => name == atom,
+            (Constant::Integer(a), HeapCell::Integer(b)) => a == b,
+            (Constant::BigInteger(a), HeapCell::BigInteger(b)) => a == b,
+            (Constant::Float(bits), HeapCell::Float(value)) => f64::from_bits(*bits) == *value,
+            _ => false,
+        }
+    }
+
+    pub fn from_cell(cell: &HeapCell) -> Option<Constant> {
+        match cell {
+            HeapCell::Constant(name) => Some(Constant::Atom(name.clone())),
+            HeapCell::Integer(value) => Some(Constant::Integer(*value)),
+            HeapCell::BigInteger(value) => Some(Constant::BigInteger(value.clone())),
+            HeapCell::Float(value) => Some(Constant::Float(value.to_bits())),
+            _ => None,
+        }
+    }
+}
+
+/// The binary evaluable functors. Arithmetic built from these is compiled
+/// inline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IntDiv,
+    FloorDiv,
+    Mod,
+    Rem,
+    Min,
+    Max,
+    Gcd,
+    BitAnd,
+    BitOr,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
+    Power,
+    IntPower,
+    Atan2,
+    LogBase,
+}
+
+impl ArithOp {
+    /// The operation an evaluable functor of arity 2 names.
+    pub fn from_name(name: &str) -> Option<ArithOp> {
+        Some(match name {
+            "+" => ArithOp::Add,
+            "-" => ArithOp::Sub,
+            "*" => ArithOp::Mul,
+            "/" => ArithOp::Div,
+            "//" => ArithOp::IntDiv,
+            "div" => ArithOp::FloorDiv,
+            "mod" => ArithOp::Mod,
+            "rem" => ArithOp::Rem,
+            "min" => ArithOp::Min,
+            "max" => ArithOp::Max,
+            "gcd" => ArithOp::Gcd,
+            "/\\" => ArithOp::BitAnd,
+            "\\/" => ArithOp::BitOr,
+            "xor" => ArithOp::Xor,
+            "<<" => ArithOp::ShiftLeft,
+            ">>" => ArithOp::ShiftRight,
+            "**" => ArithOp::Power,
+            "^" => ArithOp::IntPower,
+            "atan2" | "atan" => ArithOp::Atan2,
+            "log" => ArithOp::LogBase,
+            _ => return None,
+        })
+    }
+}
+
+/// Arithmetic comparisons: `=:=`, `=\=`, `<`, `>`, `=<` and `>=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn from_name(name: &str) -> Option<Comparison> {
+        Some(match name {
+            "=:=" => Comparison::Equal,
+            "=\\=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "=<" => Comparison::LessOrEqual,
+            ">=" => Comparison::GreaterOrEqual,
+            _ => return None,
+        })
+    }
+
+    /// Whether the comparison holds for two values ordered as `ordering`;
+    /// `None` (a NaN) is only unequal to anything.
+    pub fn holds(self, ordering: Option<std::cmp::Ordering>) -> bool {
+        use std::cmp::Ordering::*;
+        match (self, ordering) {
+            (Comparison::NotEqual, ordering) => ordering != Some(Equal),
+            (_, None) => false,
+            (Comparison::Equal, Some(ordering)) => ordering == Equal,
+            (Comparison::Less, Some(ordering)) => ordering == Less,
+            (Comparison::Greater, Some(ordering)) => ordering == Greater,
+            (Comparison::LessOrEqual, Some(ordering)) => ordering != Greater,
+            (Comparison::GreaterOrEqual, Some(ordering)) => ordering != Less,
+        }
+    }
+}
+
+/// An operand of an arithmetic instruction: a register holding a number or
+/// a term still to be evaluated, or an integer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Integer(i64),
+}
+
+/// Jump table used by `switch_on_constant` and `switch_on_structure`. Keys that
+/// are not in `cases` go to `default`, or fail when there is no default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchTable<K: Eq + std::hash::Hash> {
+    pub cases: HashMap<K, Label>,
+    pub default: Option<Label>,
+}
+
+impl<K: Eq + std::hash::Hash> SwitchTable<K> {
+    pub fn target(&self, key: &K) -> Option<Label> {
+        self.cases.get(key).copied().or(self.default)
+    }
+
+    fn relocate(&self, base: usize) -> Self
+    where
+        K: Clone,
+    {
+        SwitchTable {
+            cases: self.cases.iter().map(|(key, label)| (key.clone(), label + base)).collect(),
+            default: self.default.map(|label| label + base),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    // Head unification against argument register Ai.
+    GetVariable(Reg, usize),
+    GetValue(Reg, usize),
+    GetStructure(String, usize, usize),
+    GetConstant(Constant, usize),
+    /// `get_structure '.'/2, Ai`.
+    GetList(usize),
+    /// `get_constant [], Ai`.
+    GetNil(usize),
+
+    // Arguments of the structure selected by get_structure/put_structure.
+    UnifyVariable(Reg),
+    UnifyValue(Reg),
+    /// Like `UnifyValue`, but moves the variable to the heap first if it is
+    /// an unbound variable on the stack, so the heap never points into it.
+    UnifyLocalValue(Reg),
+    UnifyConstant(Constant),
+    /// Skips n arguments in read mode, or pushes n fresh variables in write
+    /// mode, for argument variables that occur nowhere else.
+    UnifyVoid(usize),
+
+    // Loading argument register Ai for a call.
+    PutVariable(Reg, usize),
+    PutValue(Reg, usize),
+    /// Last use of permanent variable Yn that may still be an unbound
+    /// variable in the environment about to be discarded.
+    PutUnsafeValue(usize, usize),
+    PutStructure(String, usize, usize),
+    PutConstant(Constant, usize),
+
+    // Control. `Call` names a predicate by its id in the predicate table and
+    // carries the number of permanent variables still in use after the call,
+    // so the environment can be trimmed. `Execute` is the last call of a
+    // clause, made after its environment has been deallocated.
+    Allocate(usize),
+    Deallocate,
+    Call(usize, usize),
+    Execute(usize),
+    Proceed,
+
+    // Cut. `NeckCut` removes the choice points made since the predicate was
+    // called, before any body goal has run. A later cut needs that cut
+    // barrier saved by `GetLevel` at clause entry and restored by `Cut`.
+    // `GetChoice` saves the current choice point level instead, which an
+    // if-then-else cuts back to once its condition succeeds.
+    NeckCut,
+    GetLevel(Reg),
+    GetChoice(Reg),
+    Cut(Reg),
+
+    // Arithmetic. Register operands are evaluated as arithmetic expressions;
+    // results are numbers written to the destination register, which can be
+    // the first occurrence of a variable.
+    Evaluate(Operand, Reg),
+    Arith(ArithOp, Operand, Operand, Reg),
+    Compare(Comparison, Operand, Operand),
+
+    // Clause selection, and the alternatives of a disjunction inside a
+    // clause. `TryMeElse` saves the first n registers in its choice point.
+    TryMeElse(Label, usize),
+    RetryMeElse(Label),
+    TrustMe,
+    Try(Label),
+    Retry(Label),
+    Trust(Label),
+
+    // First-argument indexing on A1. `None` targets fail.
+    SwitchOnTerm {
+        variable: Label,
+        constant: Option<Label>,
+        list: Option<Label>,
+        structure: Option<Label>,
+    },
+    SwitchOnConstant(Rc<SwitchTable<Constant>>),
+    SwitchOnStructure(Rc<SwitchTable<(String, usize)>>),
+
+    /// Continues at a label of the same clause, after a branch of a disjunction.
+    Jump(Label),
+    /// Makes a register a fresh unbound variable, for a variable that not
+    /// every branch of a disjunction sets.
+    InitVariable(Reg),
+    /// Calls nondeterministic foreign predicate n again with the state its
+    /// last call left. Only the stubs the emulator makes for foreign
+    /// predicates hold it; their choice points continue there.
+    RetryForeign(usize),
+    /// Leaves the goal of the `catch/3` whose environment is current, and
+    /// its choice point too when the goal left no other. Only the
+    /// emulator's own stub holds it.
+    ExitCatch,
+    /// Goes on with the walk over the clauses of a dynamic predicate saved
+    /// in the newest choice point. Only the emulator's own stub holds it.
+    RetryDynamic,
+    Fail,
+    Halt,
+}
+
+impl Instruction {
+    /// Shifts every code label by `base`, turning predicate-relative code into
+    /// code that can live at `base` in the code area.
+    pub fn relocate(&self, base: usize) -> Instruction {
+        match self {
+            Instruction::TryMeElse(label, n) => Instruction::TryMeElse(label + base, *n),
+            Instruction::RetryMeElse(label) => Instruction::RetryMeElse(label + base),
+            Instruction::Try(label) => Instruction::Try(label + base),
+            Instruction::Retry(label) => Instruction::Retry(label + base),
+            Instruction::Trust(label) => Instruction::Trust(label + base),
+            Instruction::Jump(label) => Instruction::Jump(label + base),
+            Instruction::SwitchOnTerm { variable, constant, list, structure } => Instruction::SwitchOnTerm {
+                variable: variable + base,
+                constant: constant.map(|label| label + base),
+                list: list.map(|label| label + base),
+                structure: structure.map(|label| label + base),
+            },
+            Instruction::SwitchOnConstant(table) => Instruction::SwitchOnConstant(Rc::new(table.relocate(base))),
+            Instruction::SwitchOnStructure(table) => Instruction::SwitchOnStructure(Rc::new(table.relocate(base))),
+            other => other.clone(),
+        }
+    }
+
+    /// Highest X register this instruction reads or writes.
+    pub fn max_register(&self) -> usize {
+        let reg = |reg: &Reg| match reg {
+            Reg::X(i) => *i,
+            Reg::Y(_) => 0,
+        };
+        let operand = |operand: &Operand| match operand {
+            Operand::Reg(r) => reg(r),
+            Operand::Integer(_) => 0,
+        };
+        match self {
+            Instruction::GetVariable(r, a) | Instruction::GetValue(r, a) => reg(r).max(*a),
+            Instruction::PutVariable(r, a) | Instruction::PutValue(r, a) => reg(r).max(*a),
+            Instruction::PutUnsafeValue(_, a) => *a,
+            Instruction::GetStructure(_, _, a) | Instruction::PutStructure(_, _, a) => *a,
+            Instruction::GetConstant(_, a) | Instruction::PutConstant(_, a) => *a,
+            Instruction::GetList(a) | Instruction::GetNil(a) => *a,
+            Instruction::UnifyVariable(r) | Instruction::UnifyValue(r) | Instruction::UnifyLocalValue(r) => reg(r),
+            Instruction::GetLevel(r) | Instruction::GetChoice(r) | Instruction::Cut(r) => reg(r),
+            Instruction::InitVariable(r) => reg(r),
+            Instruction::Evaluate(a, r) => operand(a).max(reg(r)),
+            Instruction::Arith(_, a, b, r) => operand(a).max(operand(b)).max(reg(r)),
+            Instruction::Compare(_, a, b) => operand(a).max(operand(b)),
+            Instruction::TryMeElse(_, n) => *n,
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,317 @@
+// src/wam/jit_index.rs
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::compiler::{atomic, compound};
+use crate::parser::ast::{Clause, Term};
+use crate::wam::data_structures::{HeapCell, WamEmulator};
+use crate::wam::instruction::{Constant, Instruction, Label};
+
+/// Predicates with fewer clauses than this are never given argument indexes.
+pub const JIT_MIN_CLAUSES: usize = 8;
+
+/// What an argument of a clause head or a call is keyed on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IndexKey {
+    Constant(Constant),
+    Functor(String, usize),
+}
+
+impl IndexKey {
+    pub(crate) fn of_term(term: &Term) -> Option<IndexKey> {
+        if let Some(constant) = atomic(term) {
+            return Some(IndexKey::Constant(constant.key()));
+        }
+        compound(term).map(|(functor, arity)| IndexKey::Functor(functor.to_string(), arity))
+    }
+}
+
+/// A hash index over one argument position of a predicate, built the first
+/// time a call arrives with that argument bound and the first one unbound.
+/// Dynamic predicates are not given these: their clauses are walked with
+/// the index on the first argument kept by `DynamicClauses`.
+#[derive(Debug, Clone)]
+pub struct ArgumentIndex {
+    /// Clause numbers for each key, in source order.
+    buckets: HashMap<IndexKey, Vec<usize>>,
+    /// Clauses whose head has a variable at this position; they match every key.
+    variable_clauses: Vec<usize>,
+    /// Code installed for keys that select more than one clause.
+    chains: HashMap<Option<IndexKey>, Range<usize>>,
+    lookups: usize,
+}
+
+impl ArgumentIndex {
+    fn build(clauses: &[Clause], position: usize) -> ArgumentIndex {
+        let mut buckets: HashMap<IndexKey, Vec<usize>> = HashMap::new();
+        let mut variable_clauses = Vec::new();
+        for (i, clause) in clauses.iter().enumerate() {
+            let arg = match &clause.head {
+                Term::Structure { args, .. } => &args[position - 1],
+                _ => unreachable!("argument index on an atom predicate"),
+            };
+            match IndexKey::of_term(arg) {
+                Some(key) => buckets.entry(key).or_default().push(i),
+                None => variable_clauses.push(i),
+            }
+        }
+        ArgumentIndex { buckets, variable_clauses, chains: HashMap::new(), lookups: 0 }
+    }
+
+    /// Clauses that can match a call whose argument has `key`.
+    fn candidates(&self, key: &IndexKey) -> Vec<usize> {
+        let bucket = self.buckets.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        let mut merged: Vec<usize> = bucket.iter().chain(&self.variable_clauses).copied().collect();
+        merged.sort_unstable();
+        merged
+    }
+
+    fn candidate_count(&self, key: &IndexKey) -> usize {
+        self.buckets.get(key).map_or(0, Vec::len) + self.variable_clauses.len()
+    }
+}
+
+/// One entry of `WamEmulator::index_statistics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStatistics {
+    pub predicate: String,
+    pub arity: usize,
+    /// Argument position the index is on, counted from 1.
+    pub argument: usize,
+    /// Number of distinct keys at that position.
+    pub keys: usize,
+    pub clauses: usize,
+    /// Calls that were dispatched through the index.
+    pub lookups: usize,
+}
+
+impl WamEmulator {
+    /// Picks clauses for a call to predicate `id` using argument indexes.
+    /// Returns `None` when no index helps and the predicate's own entry point
+    /// should be used, `Some(None)` when no clause can match, and otherwise the
+    /// label to continue at: a single clause or a try/retry/trust block.
+    pub(crate) fn jit_dispatch(&mut self, id: usize) -> Option<Option<Label>> {
+        let predicate = self.predicates.get(id);
+        let arity = predicate.arity;
+        let clauses = predicate.clauses.len();
+        if clauses < JIT_MIN_CLAUSES || arity < 2 || !self.is_unbound(&self.deref(&self.registers[1])) {
+            return None;
+        }
+
+        let mut best: Option<(usize, IndexKey, usize)> = None;
+        for position in 2..=arity {
+            let key = match self.argument_key(position) {
+                Some(key) => key,
+                None => continue,
+            };
+            let predicate = self.predicates.get_mut(id);
+            if !predicate.argument_indexes.contains_key(&position) {
+                let index = ArgumentIndex::build(&predicate.clauses, position);
+                predicate.argument_indexes.insert(position, index);
+            }
+            let count = predicate.argument_indexes[&position].candidate_count(&key);
+            if count < clauses && best.as_ref().is_none_or(|(_, _, best)| count < *best) {
+                best = Some((position, key, count));
+            }
+        }
+
+        let (position, key, count) = best?;
+        let predicate = self.predicates.get_mut(id);
+        let index = predicate.argument_indexes.get_mut(&position).unwrap();
+        index.lookups += 1;
+        Some(match count {
+            0 => None,
+            1 => Some(predicate.clause_entries[index.candidates(&key)[0]]),
+            _ => Some(self.chain(id, position, key)),
+        })
+    }
+
+    fn argument_key(&self, position: usize) -> Option<IndexKey> {
+        self.cell_key(&self.registers[position])
+    }
+
+    /// What the term `cell` stands for is keyed on, `None` for a variable.
+    pub(crate) fn cell_key(&self, cell: &HeapCell) -> Option<IndexKey> {
+        let cell = self.deref(cell);
+        if let Some(constant) = Constant::from_cell(&cell) {
+            return Some(IndexKey::Constant(constant.key()));
+        }
+        match cell {
+            HeapCell::Reference(a) => match &self.heap[a] {
+                HeapCell::Structure(f, args) => Some(IndexKey::Functor(f.clone(), args.len())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the try/retry/trust block for the clauses matching `key`,
+    /// installing it the first time the key is looked up. Keys without a
+    /// bucket of their own share the block for the variable clauses.
+    fn chain(&mut self, id: usize, position: usize, key: IndexKey) -> Label {
+        let index = &self.predicates.get(id).argument_indexes[&position];
+        let chain_key = index.buckets.contains_key(&key).then_some(key);
+        if let Some(chain) = index.chains.get(&chain_key) {
+            return chain.start;
+        }
+        let candidates = match &chain_key {
+            Some(key) => index.candidates(key),
+            None => index.variable_clauses.clone(),
+        };
+
+        let entries = &self.predicates.get(id).clause_entries;
+        let block = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, &clause)| match i {
+                0 => Instruction::Try(entries[clause]),
+                i if i + 1 == candidates.len() => Instruction::Trust(entries[clause]),
+                _ => Instruction::Retry(entries[clause]),
+            })
+            .collect();
+        let chain = self.place_code(block);
+        let label = chain.start;
+        let index = self.predicates.get_mut(id).argument_indexes.get_mut(&position).unwrap();
+        index.chains.insert(chain_key, chain);
+        label
+    }
+
+    /// Drops the argument indexes of predicate `id`, for when its clauses
+    /// change, and retires the code of their chains.
+    pub(crate) fn drop_argument_indexes(&mut self, id: usize) {
+        let indexes = std::mem::take(&mut self.predicates.get_mut(id).argument_indexes);
+        for (_, index) in indexes {
+            index.chains.into_values().for_each(|chain| self.retire_code(chain));
+        }
+    }
+
+    /// Lists the argument indexes built so far, like SWI-Prolog's `jiti_list/0`.
+    pub fn index_statistics(&self) -> Vec<IndexStatistics> {
+        let mut statistics = Vec::new();
+        for predicate in self.predicates.iter() {
+            let mut positions: Vec<_> = predicate.argument_indexes.keys().copied().collect();
+            positions.sort_unstable();
+            for position in positions {
+                let index = &predicate.argument_indexes[&position];
+                statistics.push(IndexStatistics {
+                    predicate: predicate.name.clone(),
+                    arity: predicate.arity,
+                    argument: position,
+                    keys: index.buckets.len(),
+                    clauses: predicate.clauses.len(),
+                    lookups: index.lookups,
+                });
+            }
+        }
+        statistics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ast::parse;
+    use crate::wam::emulator::tests::answers;
+
+    fn employees(rows: usize) -> String {
+        (0..rows)
+            .map(|i| format!("employee({}, dept{}, name{}).\n", i, i % 10, i))
+            .collect()
+    }
+
+    #[test]
+    fn test_index_built_on_demand() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(200)).unwrap();
+        assert!(wam.index_statistics().is_empty());
+
+        assert_eq!(answers(&mut wam, "employee(Id, dept3, name53)"), vec!["Id = 53"]);
+        let statistics = wam.index_statistics();
+        assert_eq!(statistics.len(), 2);
+        assert_eq!(statistics[0], IndexStatistics {
+            predicate: "employee".to_string(),
+            arity: 3,
+            argument: 2,
+            keys: 10,
+            clauses: 200,
+            lookups: 0,
+        });
+        assert_eq!((statistics[1].argument, statistics[1].keys, statistics[1].lookups), (3, 200, 1));
+    }
+
+    #[test]
+    fn test_unique_key_leaves_no_choice_point() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(100)).unwrap();
+
+        let clauses = parse("query :- employee(Id, Dept, name42).").unwrap();
+        wam.start_query(&clauses[0].body).unwrap();
+        assert!(wam.run().unwrap());
+        assert!(wam.choicepoints.is_empty());
+    }
+
+    #[test]
+    fn test_shared_key_tries_matching_clauses_in_order() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(40)).unwrap();
+
+        assert_eq!(answers(&mut wam, "employee(Id, dept7, Name)"), vec![
+            "Id = 7,\nName = name7",
+            "Id = 17,\nName = name17",
+            "Id = 27,\nName = name27",
+            "Id = 37,\nName = name37",
+        ]);
+        assert!(answers(&mut wam, "employee(Id, dept99, Name)").is_empty());
+    }
+
+    #[test]
+    fn test_variable_clauses_match_every_key() {
+        let mut source = employees(20);
+        source.push_str("employee(999, Dept, anybody).\n");
+        let mut wam = WamEmulator::new();
+        wam.consult(&source).unwrap();
+
+        assert_eq!(answers(&mut wam, "employee(Id, dept2, Name)"), vec![
+            "Id = 2,\nName = name2",
+            "Id = 12,\nName = name12",
+            "Id = 999,\nName = anybody",
+        ]);
+        assert_eq!(answers(&mut wam, "employee(Id, nowhere, Name)"), vec!["Id = 999,\nName = anybody"]);
+    }
+
+    #[test]
+    fn test_indexes_dropped_when_predicate_changes() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(50)).unwrap();
+        answers(&mut wam, "employee(Id, dept1, Name)");
+        assert!(!wam.index_statistics().is_empty());
+
+        wam.consult(&employees(60)).unwrap();
+        assert!(wam.index_statistics().is_empty());
+        assert_eq!(answers(&mut wam, "employee(Id, Dept, name55)"), vec!["Id = 55,\nDept = dept5"]);
+    }
+
+    #[test]
+    fn test_chains_reused_after_predicate_changes() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(50)).unwrap();
+        answers(&mut wam, "employee(Id, dept1, Name)");
+        let chain = |wam: &WamEmulator| {
+            let id = wam.predicates.lookup("employee", 3).unwrap();
+            wam.predicates.get(id).argument_indexes[&2].chains.values().next().unwrap().clone()
+        };
+        let first = chain(&wam);
+
+        wam.consult(&employees(50)).unwrap();
+        assert_eq!(answers(&mut wam, "employee(Id, dept2, Name)").len(), 5);
+        assert_eq!(chain(&wam), first);
+    }
+
+    #[test]
+    fn test_small_predicates_are_not_indexed() {
+        let mut wam = WamEmulator::new();
+        wam.consult(&employees(JIT_MIN_CLAUSES - 1)).unwrap();
+        assert_eq!(answers(&mut wam, "employee(Id, dept1, Name)"), vec!["Id = 1,\nName = name1"]);
+        assert!(wam.index_statistics().is_empty());
+    }
+}
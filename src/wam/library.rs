@@ -0,0 +1,167 @@
+// src/wam/library.rs
+//! The bundled library: the Prolog files in `library/`, compiled to object
+//! files by `build.rs` with the crate's own compiler and built into the
+//! binary. A library is loaded the first time one of its predicates is
+//! called, so programs use them without consulting anything.
+
+use crate::wam::data_structures::WamEmulator;
+use crate::wam::error::WamError;
+use crate::wam::object::ObjectFile;
+
+/// One file of the library.
+#[derive(Debug)]
+pub struct Library {
+    /// The file's name without `.pl`, e.g. `lists`.
+    pub name: &'static str,
+    /// The object compiled from it.
+    pub object: &'static [u8],
+    /// The `name/arity` of each predicate it defines.
+    pub predicates: &'static [(&'static str, usize)],
+}
+
+// `build.rs` compiles these modules too, to compile the library, and that
+// copy has no library of its own.
+#[cfg(bundled_library)]
+include!(concat!(env!("OUT_DIR"), "/library.rs"));
+
+#[cfg(not(bundled_library))]
+pub const LIBRARIES: &[Library] = &[];
+
+impl WamEmulator {
+    /// Loads the library defining `name/arity`, unless there is none or it
+    /// is already loaded. Returns whether it was loaded. The predicates
+    /// of the library that the program defines itself are left out.
+    pub(crate) fn autoload(&mut self, name: &str, arity: usize) -> Result<bool, WamError> {
+        let defines = |library: &&Library| library.predicates.contains(&(name, arity));
+        let library = match LIBRARIES.iter().find(defines) {
+            Some(library) if !self.libraries.contains(&library.name) => library,
+            _ => return Ok(false),
+        };
+        self.libraries.push(library.name);
+        let mut object = ObjectFile::from_bytes(library.object).expect("the bundled library is a valid object");
+        object.predicates.retain(|predicate| match self.predicates.lookup(&predicate.name, predicate.arity) {
+            Some(id) => {
+                let defined = self.predicates.get(id);
+                !defined.is_builtin() && defined.entry.is_none() && defined.dynamic.is_none()
+            }
+            None => true,
+        });
+        self.load_object(&object)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wam::emulator::tests::{answers, exception};
+    use crate::wam::WamEmulator;
+
+    #[test]
+    fn test_lists() {
+        let mut wam = WamEmulator::new();
+        let goals = [
+            ("append(X, [c], [a, b, c])", vec!["X = [a, b]"]),
+            ("append(X, Y, [a])", vec!["X = [],\nY = [a]", "X = [a],\nY = []"]),
+            ("append([[a], [], [b, c]], L)", vec!["L = [a, b, c]"]),
+            ("member(X, [a, b])", vec!["X = a", "X = b"]),
+            ("memberchk(X, [a, b])", vec!["X = a"]),
+            ("length([a, b, c], N)", vec!["N = 3"]),
+            ("findall(L, length(L, 2), [[a, b]])", vec!["true"]),
+            ("nth0(1, [a, b, c], X), nth1(1, [a, b, c], Y)", vec!["X = b,\nY = a"]),
+            ("nth0(I, [a, b], X)", vec!["I = 0,\nX = a", "I = 1,\nX = b"]),
+            ("last([a, b, c], X), reverse([a, b, c], R)", vec!["X = c,\nR = [c, b, a]"]),
+            ("nextto(X, Y, [1, 2, 3])", vec!["X = 1,\nY = 2", "X = 2,\nY = 3"]),
+            ("select(b, [a, b, c], L), selectchk(a, [a, b, a], M)", vec!["L = [a, c],\nM = [b, a]"]),
+            ("delete([a, b, a, c], a, L)", vec!["L = [b, c]"]),
+            ("subtract([a, b, c], [b], S), intersection([a, b, c], [c, a], I), union([a, b], [b, c], U)", vec![
+                "S = [a, c],\nI = [a, c],\nU = [a, b, c]",
+            ]),
+            ("list_to_set([a, b, a, c, b], S)", vec!["S = [a, b, c]"]),
+            ("flatten([a, [b, [c, []], d], [[e]]], L), flatten(x, M)", vec!["L = [a, b, c, d, e],\nM = [x]"]),
+            ("flatten([a, [X, f([b])]], L), X = c", vec!["X = c,\nL = [a, c, f([b])]"]),
+            ("findall(P, permutation([1, 2, 3], P), Ps)", vec!["Ps = [[1, 2, 3], [1, 3, 2], [2, 1, 3], [2, 3, 1], [3, 1, 2], [3, 2, 1]]"]),
+            ("sum_list([1, 2, 3.5], S), max_list([1, 3, 2], Max), min_list([2, 1, 3], Min)", vec![
+                "S = 6.5,\nMax = 3,\nMin = 1",
+            ]),
+            ("max_member(X, [b, f(a), 3]), min_member(Y, [b, f(a), 3])", vec!["X = f(a),\nY = 3"]),
+            ("numlist(1, 5, L), msort([b, a, b], M)", vec!["L = [1, 2, 3, 4, 5],\nM = [a, b, b]"]),
+        ];
+        for (goal, expected) in goals {
+            assert_eq!(answers(&mut wam, goal), expected, "{}", goal);
+        }
+        assert_eq!(exception(&mut wam, "length(L, -1)"), "error(domain_error(not_less_than_zero, -1), length/2)");
+        assert_eq!(exception(&mut wam, "length(L, a)"), "error(type_error(integer, a), length/2)");
+        assert_eq!(exception(&mut wam, "length([a], 1.0)"), "error(type_error(integer, 1.0), length/2)");
+    }
+
+    #[test]
+    fn test_pairs_and_apply() {
+        let mut wam = WamEmulator::new();
+        let goals = [
+            ("pairs_keys_values(P, [a, b], [1, 2])", "P = [a-1, b-2]"),
+            ("pairs_keys_values([a-1, b-2], K, V)", "K = [a, b],\nV = [1, 2]"),
+            ("pairs_keys([a-1, b-2], K), pairs_values([a-1, b-2], V)", "K = [a, b],\nV = [1, 2]"),
+            ("transpose_pairs([a-2, b-1], T)", "T = [1-b, 2-a]"),
+            ("map_list_to_pairs(atom_length, [abc, d], P)", "P = [3-abc, 1-d]"),
+            ("maplist([X, Y]>>atom_length(X, Y), [ab, c], L)", "L = [2, 1]"),
+        ];
+        for (goal, answer) in goals {
+            assert_eq!(answers(&mut wam, goal), vec![answer], "{}", goal);
+        }
+    }
+
+    #[test]
+    fn test_assoc() {
+        let mut wam = WamEmulator::new();
+        // Each goal builds its tree inside findall/3, so the answers only
+        // show what is looked up in it.
+        let goals = [
+            ("list_to_assoc([b-2, a-1, c-3], A), get_assoc(b, A, X)", "[2]"),
+            ("list_to_assoc([a-1], A), get_assoc(b, A, X)", "[]"),
+            ("empty_assoc(E), put_assoc(k, E, v, A), assoc_to_list(A, X)", "[[k-v]]"),
+            ("list_to_assoc([b-2, a-1], A), put_assoc(a, A, 9, A1), assoc_to_list(A1, X)", "[[a-9, b-2]]"),
+            ("list_to_assoc([b-2, a-1, c-3], A), assoc_to_keys(A, K), assoc_to_values(A, V), X = K-V", "[[a, b, c]-[1, 2, 3]]"),
+            ("list_to_assoc([b-2, a-1, c-3], A), min_assoc(A, K1, _), max_assoc(A, K2, _), X = K1-K2", "[a-c]"),
+            ("list_to_assoc([b-2, a-1, c-3], A), del_assoc(b, A, V, A1), assoc_to_list(A1, L), X = V-L", "[2-[a-1, c-3]]"),
+            ("empty_assoc(A), del_assoc(a, A, _, X)", "[]"),
+        ];
+        for (goal, answer) in goals {
+            let expected = format!("Xs = {}", answer);
+            assert_eq!(answers(&mut wam, &format!("findall(X, ({}), Xs)", goal)), vec![expected], "{}", goal);
+        }
+
+        // Keys put in and deleted in a scrambled order keep the tree
+        // sorted, and depth/2 only succeeds while it is balanced.
+        wam.consult(
+            "depth(t, 0).
+             depth(t(_, _, B, L, R), D) :- depth(L, DL), depth(R, DR), balance(DL, DR, B), D is max(DL, DR) + 1.
+             balance(D, D, =).
+             balance(DL, DR, <) :- DR =:= DL + 1.
+             balance(DL, DR, >) :- DL =:= DR + 1.
+             insert(I, A0, A) :- K is I * 37 mod 101, put_assoc(K, A0, I, A).
+             remove(K, A0, A) :- del_assoc(K, A0, _, A).
+             third(K) :- 0 is K mod 3.
+             check(D, N, D1, N1) :-
+                 numlist(1, 100, Is), empty_assoc(E), foldl(insert, Is, E, A),
+                 depth(A, D), assoc_to_keys(A, Ks), msort(Ks, Ks), length(Ks, N),
+                 include(third, Ks, Thirds), foldl(remove, Thirds, A, A1),
+                 depth(A1, D1), assoc_to_keys(A1, Ks1), msort(Ks1, Ks1), length(Ks1, N1).",
+        )
+        .unwrap();
+        assert_eq!(answers(&mut wam, "check(D, N, D1, N1)"), vec!["D = 8,\nN = 100,\nD1 = 7,\nN1 = 67"]);
+    }
+
+    #[test]
+    fn test_autoload() {
+        let mut wam = WamEmulator::new();
+        // A program's own definition is kept when the library is loaded
+        // for another predicate, and the library uses it.
+        wam.consult("member(X, [X|_]) :- !.").unwrap();
+        assert_eq!(answers(&mut wam, "last([a, b], X), member(Y, [a, b])"), vec!["X = b,\nY = a"]);
+        assert_eq!(answers(&mut wam, "memberchk(b, [a, b])"), Vec::<String>::new());
+        // Consulting a definition replaces the library's.
+        wam.consult("reverse(_, reversed).").unwrap();
+        assert_eq!(answers(&mut wam, "reverse([a], R)"), vec!["R = reversed"]);
+        assert_eq!(exception(&mut wam, "no_such_predicate(1)"), "error(existence_error(procedure, no_such_predicate/1), no_such_predicate/1)");
+    }
+}
@@ -0,0 +1,244 @@
+// src/wam/loader.rs
+use std::ops::Range;
+use std::path::Path;
+
+use crate::compiler::{compile_predicate, predicate_key};
+use crate::parser::ast::{parse_with_lines, Clause, Term};
+use crate::wam::data_structures::{HeapCell, SourceLocation, WamEmulator};
+use crate::wam::error::{indicator, object_existence_error, permission_error, WamError};
+use crate::wam::instruction::Instruction;
+use crate::wam::verifier::verify;
+
+impl WamEmulator {
+    /// Parses `source` and loads every predicate it defines. A predicate
+    /// defined in `source` replaces any earlier definition of the same
+    /// name and arity, as with `reconsult`.
+    pub fn consult(&mut self, source: &str) -> Result<(), WamError> {
+        self.consult_source(source, "user")
+    }
+
+    /// Reads and consults the file at `path`, recording it as the source of
+    /// its clauses.
+    pub fn consult_file(&mut self, path: &Path) -> Result<(), WamError> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|_| object_existence_error("source_sink", Term::Atom(path.display().to_string())))?;
+        self.consult_source(&source, &path.display().to_string())
+    }
+
+    pub(crate) fn consult_source(&mut self, source: &str, file: &str) -> Result<(), WamError> {
+        let clauses = parse_with_lines(source)?
+            .into_iter()
+            .map(|(clause, line)| (clause, Some(SourceLocation { file: file.to_string(), line })))
+            .collect();
+        self.load_located(clauses)
+    }
+
+    /// Loads already parsed clauses, grouping them by predicate.
+    pub fn load_clauses(&mut self, clauses: Vec<Clause>) -> Result<(), WamError> {
+        self.load_located(clauses.into_iter().map(|clause| (clause, None)).collect())
+    }
+
+    /// Loads clauses grouped by predicate. A clause `:- Goal` is a
+    /// directive: `dynamic/1` declarations take effect at once, so the
+    /// clauses that follow are added as dynamic ones, and other goals run
+    /// once everything is loaded. A directive that fails is ignored.
+    fn load_located(&mut self, clauses: Vec<(Clause, Option<SourceLocation>)>) -> Result<(), WamError> {
+        let mut groups: Vec<(usize, Vec<Clause>, Vec<SourceLocation>)> = Vec::new();
+        let mut directives = Vec::new();
+        for (clause, location) in clauses {
+            match directive(&clause) {
+                Some(Term::Structure { functor, args, .. }) if functor == "dynamic" && args.len() == 1 => {
+                    self.declare_dynamic(&args[0])?;
+                    continue;
+                }
+                Some(goal) => {
+                    directives.push(goal.clone());
+                    continue;
+                }
+                None => {}
+            }
+            let (name, arity) = predicate_key(&clause.head)?;
+            let id = self.predicates.id(&name, arity);
+            if self.predicates.get(id).is_builtin() {
+                return Err(permission_error("modify", "static_procedure", indicator(&name, arity)));
+            }
+            let position = match groups.iter().position(|(group, _, _)| *group == id) {
+                Some(position) => position,
+                None => {
+                    groups.push((id, Vec::new(), Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            groups[position].1.push(clause);
+            groups[position].2.extend(location);
+        }
+
+        for (id, clauses, locations) in groups {
+            if self.predicates.get(id).dynamic.is_some() {
+                self.redefine_dynamic(id, clauses)?;
+                continue;
+            }
+            let predicate = self.predicates.get_mut(id);
+            predicate.clauses = clauses;
+            predicate.locations = locations;
+            self.load_predicate(id)?;
+        }
+        for goal in directives {
+            self.query_goals(&[goal])?.next().transpose()?;
+        }
+        Ok(())
+    }
+
+    /// Source location of the clause whose code contains `address`, if it
+    /// was loaded from source text.
+    pub fn source_location(&self, address: usize) -> Option<&SourceLocation> {
+        self.predicates
+            .iter()
+            .flat_map(|predicate| predicate.clause_entries.iter().zip(&predicate.locations))
+            .filter(|(&entry, _)| entry <= address)
+            .max_by_key(|(&entry, _)| entry)
+            .map(|(_, location)| location)
+    }
+
+    /// Compiles the current clauses of predicate `id` and installs the code.
+    pub(crate) fn load_predicate(&mut self, id: usize) -> Result<(), WamError> {
+        let clauses = self.predicates.get(id).clauses.clone();
+        let arity = self.predicates.get(id).arity;
+        let compiled = compile_predicate(&clauses, &mut self.predicates, self.compile_options)?;
+        let entries: Vec<(usize, usize)> =
+            std::iter::once(0).chain(compiled.clause_entries.iter().copied()).map(|entry| (entry, arity)).collect();
+        let base = self.install_code(compiled.code, &entries);
+
+        let predicate = self.predicates.get_mut(id);
+        predicate.entry = Some(base);
+        predicate.clause_entries = compiled.clause_entries.iter().map(|entry| entry + base).collect();
+        self.drop_argument_indexes(id);
+        Ok(())
+    }
+
+    /// Appends predicate-relative `code`, entered at `entries` with as many
+    /// arguments as given, to the code area and returns its address. Code from the compiler is only verified
+    /// in debug builds; other code must be verified by the caller.
+    pub(crate) fn install_code(&mut self, code: Vec<Instruction>, entries: &[(usize, usize)]) -> usize {
+        let base = self.code.len();
+        self.write_code(base, code, entries);
+        base
+    }
+
+    /// Installs `code` as `install_code` does, but over code given up with
+    /// `retire_code` when a block of it big enough is free, and returns
+    /// where it is.
+    pub(crate) fn install_reusing(&mut self, code: Vec<Instruction>, entries: &[(usize, usize)]) -> Range<usize> {
+        let length = code.len();
+        let base = self.allocate_code(length);
+        self.write_code(base, code, entries);
+        base..base + length
+    }
+
+    /// Places `code`, whose labels are already addresses, as `install_reusing`
+    /// does, without relocating or verifying it.
+    pub(crate) fn place_code(&mut self, code: Vec<Instruction>) -> Range<usize> {
+        let length = code.len();
+        let base = self.allocate_code(length);
+        let end = (base + length).min(self.code.len());
+        self.code.splice(base..end, code);
+        base..base + length
+    }
+
+    /// Takes `length` instructions from a free block big enough for them,
+    /// or returns the end of the code area when there is none.
+    fn allocate_code(&mut self, length: usize) -> usize {
+        let size = match self.free_code.range(length..).next() {
+            Some((&size, _)) => size,
+            None => return self.code.len(),
+        };
+        let blocks = self.free_code.get_mut(&size).unwrap();
+        let base = blocks.pop().unwrap();
+        if blocks.is_empty() {
+            self.free_code.remove(&size);
+        }
+        if size > length {
+            self.free_code.entry(size - length).or_default().push(base + length);
+        }
+        base
+    }
+
+    /// Gives up the code in `range`. A running query may still be in it, so
+    /// it is only reused once `release_retired_code` is called.
+    pub(crate) fn retire_code(&mut self, range: Range<usize>) {
+        self.retired_code.push(range);
+    }
+
+    /// Frees the retired code for reuse, for when no query is running.
+    pub(crate) fn release_retired_code(&mut self) {
+        for range in std::mem::take(&mut self.retired_code) {
+            self.free_code.entry(range.len()).or_default().push(range.start);
+        }
+    }
+
+    /// Writes predicate-relative `code` to the code area at `base`, which is
+    /// free code or the end of the area.
+    fn write_code(&mut self, base: usize, code: Vec<Instruction>, entries: &[(usize, usize)]) {
+        #[cfg(debug_assertions)]
+        {
+            let arities: Vec<usize> = self.predicates.iter().map(|predicate| predicate.arity).collect();
+            assert_eq!(verify(&code, entries, &arities), Ok(()));
+        }
+        let registers = code.iter().map(Instruction::max_register).max().unwrap_or(0);
+        if registers >= self.registers.len() {
+            self.registers.resize(registers + 1, HeapCell::Integer(0));
+        }
+        let end = (base + code.len()).min(self.code.len());
+        self.code.splice(base..end, code.iter().map(|instruction| instruction.relocate(base)));
+    }
+}
+
+/// The goal of `clause` if it is a directive, `:- Goal`.
+pub(crate) fn directive(clause: &Clause) -> Option<&Term> {
+    match &clause.head {
+        Term::Structure { functor, args, .. } if functor == ":-" && args.len() == 1 && clause.body.is_empty() => {
+            Some(&args[0])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consult_replaces_definitions() {
+        let mut wam = WamEmulator::new();
+        wam.consult("colour(red). colour(green).").unwrap();
+        let id = wam.predicates.lookup("colour", 1).unwrap();
+        assert_eq!(wam.predicates.get(id).clauses.len(), 2);
+
+        wam.consult("colour(blue).").unwrap();
+        assert_eq!(wam.predicates.get(id).clauses.len(), 1);
+        assert_eq!(crate::wam::emulator::tests::answers(&mut wam, "colour(X)"), vec!["X = blue"]);
+    }
+
+    #[test]
+    fn test_installed_code_is_relocated() {
+        let mut wam = WamEmulator::new();
+        wam.consult("p(a). p(b).").unwrap();
+        let predicate = wam.predicates.get(wam.predicates.lookup("p", 1).unwrap());
+        let entry = predicate.entry.unwrap();
+
+        assert!(entry > 0);
+        for &clause in &predicate.clause_entries {
+            assert!(clause > entry);
+            assert!(matches!(wam.code[clause], Instruction::GetConstant(..)));
+        }
+    }
+
+    #[test]
+    fn test_builtins_cannot_be_redefined() {
+        let mut wam = WamEmulator::new();
+        match (wam.consult("succ(a, b)."), permission_error("modify", "static_procedure", indicator("succ", 2))) {
+            (Err(WamError::Exception(term)), WamError::Exception(expected)) => assert_eq!(term, expected),
+            (other, _) => panic!("expected a permission error, got {:?}", other),
+        }
+    }
+}
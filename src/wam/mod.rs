@@ -1,4 +1,24 @@
 // src/wam/mod.rs
 pub mod data_structures;
+pub mod database;
+pub mod decoder;
+pub mod emulator;
+pub mod error;
+pub mod exception;
+pub mod instruction;
+pub mod jit_index;
+pub mod library;
+pub mod loader;
+pub mod object;
+pub mod query;
+pub mod verifier;
 
 pub use data_structures::{WamEmulator, Term, HeapCell};
+pub use error::WamError;
+pub use instruction::Instruction;
+pub use jit_index::IndexStatistics;
+pub use object::ObjectFile;
+pub use query::{Solution, Solutions};
+
+/// The machine as embedders see it: load programs, then ask it queries.
+pub type Machine = WamEmulator;
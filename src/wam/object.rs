@@ -0,0 +1,1012 @@
+// src/wam/object.rs
+//! Compiled object files (`.wamo`), so a program can be compiled once and
+//! loaded later without parsing or compiling it again.
+//!
+//! All integers are little-endian. A file is
+//!
+//! ```text
+//! magic "WAMO" | format version: u32 | payload length: u64 | checksum: u64 | payload
+//! ```
+//!
+//! where the checksum is the 64-bit FNV-1a hash of the payload, and the
+//! payload holds, in order:
+//!
+//! - the atom table: every name in the file, as `count: u32` and then
+//!   `length: u32, UTF-8 bytes` for each, referred to by index from then on;
+//! - the functor table: the `name/arity` of every predicate the code refers
+//!   to, which `call` and `execute` name by index;
+//! - the source file the predicates were compiled from;
+//! - the predicates: name, arity, flags, entry point, clause entry points,
+//!   and each clause with the line it starts on. The only flag is
+//!   `DYNAMIC`; a dynamic predicate's clauses are compiled when it is
+//!   loaded, so its entry points are not used;
+//! - the goals of the source's directives other than `dynamic/1`;
+//! - the code, whose labels are relative to its start.
+//!
+//! Big integers are only used for values outside the 64-bit range, and
+//! terms are nested at most `MAX_DECODE_DEPTH` deep.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+use crate::compiler::{compile_predicate, predicate_key, CompileOptions};
+use crate::parser::ast::{parse_with_lines, Clause, Term};
+use crate::wam::data_structures::{PredicateTable, SourceLocation, WamEmulator};
+use crate::wam::decoder::MAX_DECODE_DEPTH;
+use crate::wam::database::predicate_indicators;
+use crate::wam::error::{indicator, permission_error, WamError};
+use crate::wam::instruction::{ArithOp, Comparison, Constant, Instruction, Operand, Reg, SwitchTable};
+use crate::wam::loader::directive;
+use crate::wam::verifier::verify;
+
+/// First bytes of every object file.
+pub const MAGIC: &[u8; 4] = b"WAMO";
+
+/// Version of the object format. It changes whenever the layout or the
+/// meaning of an instruction does, so objects written by an older compiler
+/// are rejected rather than run.
+pub const FORMAT_VERSION: u32 = 4;
+
+const HEADER_LENGTH: usize = 24;
+
+/// Predicate flag: the predicate is dynamic.
+const DYNAMIC: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectError {
+    /// The data does not start with `MAGIC`.
+    NotAnObject,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Truncated,
+    /// An entry that cannot be decoded, such as an unknown opcode or a table
+    /// index out of range.
+    Malformed(String),
+}
+
+/// A predicate defined by an object file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectPredicate {
+    pub name: String,
+    pub arity: usize,
+    pub dynamic: bool,
+    /// Where the predicate's code and each of its clauses start in the
+    /// object's code.
+    pub entry: usize,
+    pub clause_entries: Vec<usize>,
+    pub clauses: Vec<Clause>,
+    /// The line each clause starts on.
+    pub lines: Vec<usize>,
+}
+
+/// The compiled predicates of one source file. `call` and `execute` in
+/// `code` name predicates by their index in `functors`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFile {
+    pub file: String,
+    pub functors: Vec<(String, usize)>,
+    pub predicates: Vec<ObjectPredicate>,
+    /// Goals of the source's directives, run once the predicates are loaded.
+    pub directives: Vec<Term>,
+    pub code: Vec<Instruction>,
+}
+
+impl ObjectFile {
+    /// Compiles the Prolog source text of `file`. As with `consult`,
+    /// `dynamic/1` directives make the predicates they name dynamic, and
+    /// the goals of other directives are kept to run when it is loaded.
+    pub fn compile(source: &str, file: &str, options: CompileOptions) -> Result<ObjectFile, WamError> {
+        let mut predicates: Vec<ObjectPredicate> = Vec::new();
+        let mut directives = Vec::new();
+        let position = |predicates: &mut Vec<ObjectPredicate>, name: String, arity: usize| {
+            match predicates.iter().position(|p| p.name == name && p.arity == arity) {
+                Some(position) => position,
+                None => {
+                    let clause_entries = Vec::new();
+                    let (clauses, lines) = (Vec::new(), Vec::new());
+                    predicates.push(ObjectPredicate { name, arity, dynamic: false, entry: 0, clause_entries, clauses, lines });
+                    predicates.len() - 1
+                }
+            }
+        };
+        for (clause, line) in parse_with_lines(source)? {
+            match directive(&clause) {
+                Some(Term::Structure { functor, args, .. }) if functor == "dynamic" && args.len() == 1 => {
+                    for (name, arity) in predicate_indicators(&args[0])? {
+                        let position = position(&mut predicates, name, arity);
+                        predicates[position].dynamic = true;
+                    }
+                    continue;
+                }
+                Some(goal) => {
+                    directives.push(goal.clone());
+                    continue;
+                }
+                None => {}
+            }
+            let (name, arity) = predicate_key(&clause.head)?;
+            let position = position(&mut predicates, name, arity);
+            predicates[position].clauses.push(clause);
+            predicates[position].lines.push(line);
+        }
+
+        let mut table = PredicateTable::default();
+        let mut code = Vec::new();
+        for predicate in &mut predicates {
+            table.id(&predicate.name, predicate.arity);
+            if predicate.dynamic {
+                predicate.clause_entries = vec![0; predicate.clauses.len()];
+                continue;
+            }
+            let compiled = compile_predicate(&predicate.clauses, &mut table, options)?;
+            predicate.entry = code.len();
+            code.extend(compiled.code.iter().map(|instruction| instruction.relocate(predicate.entry)));
+            predicate.clause_entries = compiled.clause_entries.iter().map(|clause| clause + predicate.entry).collect();
+        }
+        let functors = table.iter().map(|predicate| (predicate.name.clone(), predicate.arity)).collect();
+        Ok(ObjectFile { file: file.to_string(), functors, predicates, directives, code })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.atom(&self.file);
+        writer.count(self.predicates.len());
+        for predicate in &self.predicates {
+            writer.atom(&predicate.name);
+            writer.count(predicate.arity);
+            writer.count(if predicate.dynamic { DYNAMIC as usize } else { 0 });
+            writer.count(predicate.entry);
+            writer.count(predicate.clauses.len());
+            for ((clause, &line), &entry) in predicate.clauses.iter().zip(&predicate.lines).zip(&predicate.clause_entries) {
+                writer.count(entry);
+                writer.count(line);
+                writer.term(&clause.head);
+                writer.count(clause.body.len());
+                for goal in &clause.body {
+                    writer.term(goal);
+                }
+            }
+        }
+        writer.count(self.directives.len());
+        for goal in &self.directives {
+            writer.term(goal);
+        }
+        writer.count(self.code.len());
+        for instruction in &self.code {
+            writer.instruction(instruction);
+        }
+
+        // The tables go first, but are only complete once everything else
+        // has been written.
+        let mut payload = Writer::default();
+        payload.count(writer.atoms.len());
+        for atom in &writer.atoms {
+            payload.count(atom.len());
+            payload.bytes.extend(atom.as_bytes());
+        }
+        payload.count(self.functors.len());
+        for (name, arity) in &self.functors {
+            payload.count(name.len());
+            payload.bytes.extend(name.as_bytes());
+            payload.count(*arity);
+        }
+        payload.bytes.extend(writer.bytes);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+        bytes.extend((payload.bytes.len() as u64).to_le_bytes());
+        bytes.extend(checksum(&payload.bytes).to_le_bytes());
+        bytes.extend(payload.bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(ObjectError::NotAnObject);
+        }
+        if bytes.len() < HEADER_LENGTH {
+            return Err(ObjectError::Truncated);
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let payload = &bytes[HEADER_LENGTH..];
+        let length = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if (payload.len() as u64) < length {
+            return Err(ObjectError::Truncated);
+        }
+        if payload.len() as u64 > length {
+            return Err(ObjectError::Malformed("trailing data".to_string()));
+        }
+        if u64::from_le_bytes(bytes[16..24].try_into().unwrap()) != checksum(payload) {
+            return Err(ObjectError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader { bytes: payload, position: 0, atoms: Vec::new(), functors: 0 };
+        for _ in 0..reader.count()? {
+            let atom = reader.string()?;
+            reader.atoms.push(atom);
+        }
+        let mut functors = Vec::new();
+        for _ in 0..reader.count()? {
+            functors.push((reader.string()?, reader.count()?));
+        }
+        reader.functors = functors.len();
+        let file = reader.atom()?;
+        let mut predicates = Vec::new();
+        for _ in 0..reader.count()? {
+            let (name, arity, flags, entry) = (reader.atom()?, reader.count()?, reader.count()? as u32, reader.count()?);
+            if flags & !DYNAMIC != 0 {
+                return Err(ObjectError::Malformed(format!("flags {:#x} of {}/{}", flags, name, arity)));
+            }
+            let dynamic = flags & DYNAMIC != 0;
+            let (clause_entries, clauses, lines) = (Vec::new(), Vec::new(), Vec::new());
+            let mut predicate = ObjectPredicate { name, arity, dynamic, entry, clause_entries, clauses, lines };
+            for _ in 0..reader.count()? {
+                predicate.clause_entries.push(reader.count()?);
+                predicate.lines.push(reader.count()?);
+                let head = reader.term()?;
+                let body = (0..reader.count()?).map(|_| reader.term()).collect::<Result<_, _>>()?;
+                predicate.clauses.push(Clause { head, body });
+            }
+            predicates.push(predicate);
+        }
+        let directives = (0..reader.count()?).map(|_| reader.term()).collect::<Result<Vec<_>, _>>()?;
+        let code = (0..reader.count()?).map(|_| reader.instruction()).collect::<Result<Vec<_>, _>>()?;
+        if reader.position != payload.len() {
+            return Err(ObjectError::Malformed("trailing data".to_string()));
+        }
+        for predicate in predicates.iter().filter(|predicate| !predicate.dynamic) {
+            if std::iter::once(&predicate.entry).chain(&predicate.clause_entries).any(|&entry| entry >= code.len()) {
+                return Err(ObjectError::Malformed(format!("entry of {}/{} outside the code", predicate.name, predicate.arity)));
+            }
+        }
+        Ok(ObjectFile { file, functors, predicates, directives, code })
+    }
+}
+
+impl WamEmulator {
+    /// Loads the predicates of an object file and runs its directives. Like
+    /// `consult`, each predicate replaces any earlier definition of the same
+    /// name and arity, and the clauses of a predicate that is dynamic, in
+    /// the object or already in the emulator, are added as dynamic ones.
+    pub fn load_object(&mut self, object: &ObjectFile) -> Result<(), WamError> {
+        for predicate in &object.predicates {
+            let id = self.predicates.id(&predicate.name, predicate.arity);
+            // As with a `dynamic/1` directive, a static predicate cannot
+            // become dynamic.
+            let target = self.predicates.get(id);
+            if target.is_builtin() || (predicate.dynamic && target.dynamic.is_none() && target.entry.is_some()) {
+                return Err(permission_error("modify", "static_procedure", indicator(&predicate.name, predicate.arity)));
+            }
+        }
+        let entries: Vec<(usize, usize)> = object
+            .predicates
+            .iter()
+            .filter(|predicate| !predicate.dynamic)
+            .flat_map(|predicate| {
+                let entries = std::iter::once(predicate.entry).chain(predicate.clause_entries.iter().copied());
+                entries.map(move |entry| (entry, predicate.arity))
+            })
+            .collect();
+        let arities: Vec<usize> = object.functors.iter().map(|(_, arity)| *arity).collect();
+        verify(&object.code, &entries, &arities)?;
+
+        let ids: Vec<usize> = object.functors.iter().map(|(name, arity)| self.predicates.id(name, *arity)).collect();
+        let code = object
+            .code
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Call(functor, live) => Instruction::Call(ids[*functor], *live),
+                Instruction::Execute(functor) => Instruction::Execute(ids[*functor]),
+                other => other.clone(),
+            })
+            .collect();
+        let base = self.install_code(code, &entries);
+
+        for predicate in &object.predicates {
+            let id = self.predicates.id(&predicate.name, predicate.arity);
+            if predicate.dynamic || self.predicates.get(id).dynamic.is_some() {
+                self.declare_dynamic(&indicator(&predicate.name, predicate.arity))?;
+                self.redefine_dynamic(id, predicate.clauses.clone())?;
+                continue;
+            }
+            let target = self.predicates.get_mut(id);
+            target.clauses = predicate.clauses.clone();
+            target.locations =
+                predicate.lines.iter().map(|&line| SourceLocation { file: object.file.clone(), line }).collect();
+            target.entry = Some(base + predicate.entry);
+            target.clause_entries = predicate.clause_entries.iter().map(|entry| base + entry).collect();
+            target.argument_indexes.clear();
+        }
+        for goal in &object.directives {
+            self.query_goals(std::slice::from_ref(goal))?.next().transpose()?;
+        }
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+const ARITH_OPS: [ArithOp; 20] = [
+    ArithOp::Add,
+    ArithOp::Sub,
+    ArithOp::Mul,
+    ArithOp::Div,
+    ArithOp::IntDiv,
+    ArithOp::FloorDiv,
+    ArithOp::Mod,
+    ArithOp::Rem,
+    ArithOp::Min,
+    ArithOp::Max,
+    ArithOp::Gcd,
+    ArithOp::BitAnd,
+    ArithOp::BitOr,
+    ArithOp::Xor,
+    ArithOp::ShiftLeft,
+    ArithOp::ShiftRight,
+    ArithOp::Power,
+    ArithOp::IntPower,
+    ArithOp::Atan2,
+    ArithOp::LogBase,
+];
+
+const COMPARISONS: [Comparison; 6] = [
+    Comparison::Equal,
+    Comparison::NotEqual,
+    Comparison::Less,
+    Comparison::Greater,
+    Comparison::LessOrEqual,
+    Comparison::GreaterOrEqual,
+];
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    atoms: Vec<String>,
+    atom_ids: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn count(&mut self, value: usize) {
+        let value = u32::try_from(value).expect("object file entry too large");
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn atom(&mut self, name: &str) {
+        let id = match self.atom_ids.get(name) {
+            Some(&id) => id,
+            None => {
+                self.atoms.push(name.to_string());
+                self.atom_ids.insert(name.to_string(), self.atoms.len() - 1);
+                self.atoms.len() - 1
+            }
+        };
+        self.count(id);
+    }
+
+    fn big_integer(&mut self, value: &BigInt) {
+        let bytes = value.to_signed_bytes_le();
+        self.count(bytes.len());
+        self.bytes.extend(bytes);
+    }
+
+    fn reg(&mut self, reg: &Reg) {
+        match reg {
+            Reg::X(i) => {
+                self.byte(0);
+                self.count(*i);
+            }
+            Reg::Y(i) => {
+                self.byte(1);
+                self.count(*i);
+            }
+        }
+    }
+
+    fn constant(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Atom(name) => {
+                self.byte(0);
+                self.atom(name);
+            }
+            Constant::Integer(value) => {
+                self.byte(1);
+                self.bytes.extend(value.to_le_bytes());
+            }
+            Constant::BigInteger(value) => {
+                self.byte(2);
+                self.big_integer(value);
+            }
+            Constant::Float(bits) => {
+                self.byte(3);
+                self.bytes.extend(bits.to_le_bytes());
+            }
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Reg(reg) => {
+                self.byte(0);
+                self.reg(reg);
+            }
+            Operand::Integer(value) => {
+                self.byte(1);
+                self.bytes.extend(value.to_le_bytes());
+            }
+        }
+    }
+
+    fn label(&mut self, label: Option<usize>) {
+        match label {
+            Some(label) => {
+                self.byte(1);
+                self.count(label);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    /// Writes the cases of a switch table sorted by their encoding, so the
+    /// same code always gives the same bytes.
+    fn table<K: Eq + std::hash::Hash>(&mut self, table: &SwitchTable<K>, key: impl Fn(&mut Writer, &K)) {
+        let mut cases: Vec<Vec<u8>> = table
+            .cases
+            .iter()
+            .map(|(k, &label)| {
+                let start = self.bytes.len();
+                key(self, k);
+                self.count(label);
+                self.bytes.split_off(start)
+            })
+            .collect();
+        cases.sort();
+        self.count(cases.len());
+        for case in cases {
+            self.bytes.extend(case);
+        }
+        self.label(table.default);
+    }
+
+    fn term(&mut self, term: &Term) {
+        match term {
+            Term::Atom(name) => {
+                self.byte(0);
+                self.atom(name);
+            }
+            Term::Variable(name) => {
+                self.byte(1);
+                self.atom(name);
+            }
+            Term::Structure { functor, args, .. } => {
+                self.byte(2);
+                self.atom(functor);
+                self.count(args.len());
+                for arg in args {
+                    self.term(arg);
+                }
+            }
+            Term::List(elements) => {
+                self.byte(3);
+                self.count(elements.len());
+                for element in elements {
+                    self.term(element);
+                }
+            }
+            Term::Integer(value) => {
+                self.byte(4);
+                self.bytes.extend(value.to_le_bytes());
+            }
+            Term::BigInteger(value) => {
+                self.byte(5);
+                self.big_integer(value);
+            }
+            Term::Float(value) => {
+                self.byte(6);
+                self.bytes.extend(value.to_bits().to_le_bytes());
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        use Instruction::*;
+        match instruction {
+            GetVariable(reg, ai) | GetValue(reg, ai) | PutVariable(reg, ai) | PutValue(reg, ai) => {
+                self.byte(match instruction {
+                    GetVariable(..) => 0,
+                    GetValue(..) => 1,
+                    PutVariable(..) => 8,
+                    _ => 9,
+                });
+                self.reg(reg);
+                self.count(*ai);
+            }
+            GetStructure(functor, arity, ai) | PutStructure(functor, arity, ai) => {
+                self.byte(if matches!(instruction, GetStructure(..)) { 2 } else { 11 });
+                self.atom(functor);
+                self.count(*arity);
+                self.count(*ai);
+            }
+            GetConstant(constant, ai) | PutConstant(constant, ai) => {
+                self.byte(if matches!(instruction, GetConstant(..)) { 3 } else { 12 });
+                self.constant(constant);
+                self.count(*ai);
+            }
+            UnifyVariable(reg) | UnifyValue(reg) | UnifyLocalValue(reg) | GetLevel(reg) | GetChoice(reg) | Cut(reg)
+            | InitVariable(reg) => {
+                self.byte(match instruction {
+                    UnifyVariable(_) => 4,
+                    UnifyValue(_) => 5,
+                    UnifyLocalValue(_) => 6,
+                    GetLevel(_) => 19,
+                    GetChoice(_) => 20,
+                    Cut(_) => 21,
+                    _ => 35,
+                });
+                self.reg(reg);
+            }
+            UnifyConstant(constant) => {
+                self.byte(7);
+                self.constant(constant);
+            }
+            PutUnsafeValue(y, ai) | Call(y, ai) | TryMeElse(y, ai) => {
+                self.byte(match instruction {
+                    PutUnsafeValue(..) => 10,
+                    Call(..) => 15,
+                    _ => 25,
+                });
+                self.count(*y);
+                self.count(*ai);
+            }
+            Allocate(n) | Execute(n) | RetryMeElse(n) | Try(n) | Retry(n) | Trust(n) | Jump(n) => {
+                self.byte(match instruction {
+                    Allocate(_) => 13,
+                    Execute(_) => 16,
+                    RetryMeElse(_) => 26,
+                    Try(_) => 28,
+                    Retry(_) => 29,
+                    Trust(_) => 30,
+                    _ => 34,
+                });
+                self.count(*n);
+            }
+            GetList(ai) | GetNil(ai) | UnifyVoid(ai) => {
+                self.byte(match instruction {
+                    GetList(_) => 38,
+                    GetNil(_) => 39,
+                    _ => 40,
+                });
+                self.count(*ai);
+            }
+            Deallocate => self.byte(14),
+            Proceed => self.byte(17),
+            NeckCut => self.byte(18),
+            TrustMe => self.byte(27),
+            Fail => self.byte(36),
+            Halt | RetryForeign(_) | ExitCatch | RetryDynamic => unreachable!("{:?} is only made by the emulator", instruction),
+            Evaluate(x, reg) => {
+                self.byte(22);
+                self.operand(x);
+                self.reg(reg);
+            }
+            Arith(op, x, y, reg) => {
+                self.byte(23);
+                self.byte(ARITH_OPS.iter().position(|o| o == op).unwrap() as u8);
+                self.operand(x);
+                self.operand(y);
+                self.reg(reg);
+            }
+            Compare(comparison, x, y) => {
+                self.byte(24);
+                self.byte(COMPARISONS.iter().position(|c| c == comparison).unwrap() as u8);
+                self.operand(x);
+                self.operand(y);
+            }
+            SwitchOnTerm { variable, constant, list, structure } => {
+                self.byte(31);
+                self.count(*variable);
+                self.label(*constant);
+                self.label(*list);
+                self.label(*structure);
+            }
+            SwitchOnConstant(table) => {
+                self.byte(32);
+                self.table(table, |writer, constant| writer.constant(constant));
+            }
+            SwitchOnStructure(table) => {
+                self.byte(33);
+                self.table(table, |writer, (functor, arity)| {
+                    writer.atom(functor);
+                    writer.count(*arity);
+                });
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    atoms: Vec<String>,
+    /// Size of the functor table, which predicate references must stay within.
+    functors: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8], ObjectError> {
+        let end = self.position.checked_add(length).filter(|&end| end <= self.bytes.len());
+        let end = end.ok_or(ObjectError::Truncated)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn count(&mut self) -> Result<usize, ObjectError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, ObjectError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, ObjectError> {
+        let length = self.count()?;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| ObjectError::Malformed("invalid UTF-8".to_string()))
+    }
+
+    fn atom(&mut self) -> Result<String, ObjectError> {
+        let id = self.count()?;
+        self.atoms.get(id).cloned().ok_or_else(|| ObjectError::Malformed(format!("atom {} out of range", id)))
+    }
+
+    fn functor(&mut self) -> Result<usize, ObjectError> {
+        let id = self.count()?;
+        if id < self.functors {
+            Ok(id)
+        } else {
+            Err(ObjectError::Malformed(format!("functor {} out of range", id)))
+        }
+    }
+
+    fn big_integer(&mut self) -> Result<BigInt, ObjectError> {
+        let length = self.count()?;
+        let value = BigInt::from_signed_bytes_le(self.take(length)?);
+        if i64::try_from(&value).is_ok() {
+            return Err(ObjectError::Malformed(format!("big integer {} in 64 bits", value)));
+        }
+        Ok(value)
+    }
+
+    fn reg(&mut self) -> Result<Reg, ObjectError> {
+        match self.byte()? {
+            0 => Ok(Reg::X(self.count()?)),
+            1 => Ok(Reg::Y(self.count()?)),
+            tag => Err(ObjectError::Malformed(format!("register tag {}", tag))),
+        }
+    }
+
+    fn constant(&mut self) -> Result<Constant, ObjectError> {
+        match self.byte()? {
+            0 => Ok(Constant::Atom(self.atom()?)),
+            1 => Ok(Constant::Integer(self.u64()? as i64)),
+            2 => Ok(Constant::BigInteger(self.big_integer()?)),
+            3 => Ok(Constant::Float(self.u64()?)),
+            tag => Err(ObjectError::Malformed(format!("constant tag {}", tag))),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, ObjectError> {
+        match self.byte()? {
+            0 => Ok(Operand::Reg(self.reg()?)),
+            1 => Ok(Operand::Integer(self.u64()? as i64)),
+            tag => Err(ObjectError::Malformed(format!("operand tag {}", tag))),
+        }
+    }
+
+    fn label(&mut self) -> Result<Option<usize>, ObjectError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.count()?)),
+            tag => Err(ObjectError::Malformed(format!("label tag {}", tag))),
+        }
+    }
+
+    fn table<K: Eq + std::hash::Hash>(
+        &mut self,
+        key: impl Fn(&mut Self) -> Result<K, ObjectError>,
+    ) -> Result<Rc<SwitchTable<K>>, ObjectError> {
+        let mut cases = HashMap::new();
+        for _ in 0..self.count()? {
+            let k = key(self)?;
+            cases.insert(k, self.count()?);
+        }
+        Ok(Rc::new(SwitchTable { cases, default: self.label()? }))
+    }
+
+    /// A term, read without recursion. Terms nested more than
+    /// `MAX_DECODE_DEPTH` deep are malformed.
+    fn term(&mut self) -> Result<Term, ObjectError> {
+        // The compound terms being read, innermost last: the functor, or
+        // `None` for a list, the number of arguments and those read so far.
+        let mut open: Vec<(Option<String>, usize, Vec<Term>)> = Vec::new();
+        loop {
+            if open.len() >= MAX_DECODE_DEPTH {
+                return Err(ObjectError::Malformed("term nested too deeply".to_string()));
+            }
+            let mut term = match self.byte()? {
+                0 => Term::Atom(self.atom()?),
+                1 => Term::Variable(self.atom()?),
+                tag @ (2 | 3) => {
+                    let functor = if tag == 2 { Some(self.atom()?) } else { None };
+                    let count = self.count()?;
+                    if count > 0 {
+                        open.push((functor, count, Vec::new()));
+                        continue;
+                    }
+                    compound(functor, Vec::new())
+                }
+                4 => Term::Integer(self.u64()? as i64),
+                5 => Term::BigInteger(self.big_integer()?),
+                6 => Term::Float(f64::from_bits(self.u64()?)),
+                tag => return Err(ObjectError::Malformed(format!("term tag {}", tag))),
+            };
+            // Hand the term to the compound it is an argument of, finishing
+            // each compound whose arguments are all read.
+            loop {
+                let (_, count, args) = match open.last_mut() {
+                    Some(parent) => parent,
+                    None => return Ok(term),
+                };
+                args.push(term);
+                if args.len() < *count {
+                    break;
+                }
+                let (functor, _, args) = open.pop().unwrap();
+                term = compound(functor, args);
+            }
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, ObjectError> {
+        use Instruction::*;
+        Ok(match self.byte()? {
+            0 => GetVariable(self.reg()?, self.count()?),
+            1 => GetValue(self.reg()?, self.count()?),
+            2 => GetStructure(self.atom()?, self.count()?, self.count()?),
+            3 => GetConstant(self.constant()?, self.count()?),
+            4 => UnifyVariable(self.reg()?),
+            5 => UnifyValue(self.reg()?),
+            6 => UnifyLocalValue(self.reg()?),
+            7 => UnifyConstant(self.constant()?),
+            8 => PutVariable(self.reg()?, self.count()?),
+            9 => PutValue(self.reg()?, self.count()?),
+            10 => PutUnsafeValue(self.count()?, self.count()?),
+            11 => PutStructure(self.atom()?, self.count()?, self.count()?),
+            12 => PutConstant(self.constant()?, self.count()?),
+            13 => Allocate(self.count()?),
+            14 => Deallocate,
+            15 => Call(self.functor()?, self.count()?),
+            16 => Execute(self.functor()?),
+            17 => Proceed,
+            18 => NeckCut,
+            19 => GetLevel(self.reg()?),
+            20 => GetChoice(self.reg()?),
+            21 => Cut(self.reg()?),
+            22 => Evaluate(self.operand()?, self.reg()?),
+            23 => {
+                let op = *ARITH_OPS.get(self.byte()? as usize).ok_or(ObjectError::Malformed("arithmetic operation".to_string()))?;
+                Arith(op, self.operand()?, self.operand()?, self.reg()?)
+            }
+            24 => {
+                let comparison =
+                    *COMPARISONS.get(self.byte()? as usize).ok_or(ObjectError::Malformed("comparison".to_string()))?;
+                Compare(comparison, self.operand()?, self.operand()?)
+            }
+            25 => TryMeElse(self.count()?, self.count()?),
+            26 => RetryMeElse(self.count()?),
+            27 => TrustMe,
+            28 => Try(self.count()?),
+            29 => Retry(self.count()?),
+            30 => Trust(self.count()?),
+            31 => SwitchOnTerm {
+                variable: self.count()?,
+                constant: self.label()?,
+                list: self.label()?,
+                structure: self.label()?,
+            },
+            32 => SwitchOnConstant(self.table(|reader| reader.constant())?),
+            33 => SwitchOnStructure(self.table(|reader| Ok((reader.atom()?, reader.count()?)))?),
+            34 => Jump(self.count()?),
+            35 => InitVariable(self.reg()?),
+            36 => Fail,
+            38 => GetList(self.count()?),
+            39 => GetNil(self.count()?),
+            40 => UnifyVoid(self.count()?),
+            opcode => return Err(ObjectError::Malformed(format!("opcode {}", opcode))),
+        })
+    }
+}
+
+/// The structure `functor(args...)`, or the list of `args` without a functor.
+fn compound(functor: Option<String>, args: Vec<Term>) -> Term {
+    match functor {
+        Some(functor) => Term::Structure { functor, arity: args.len(), args },
+        None => Term::List(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::emulator::tests::answers;
+
+    const SOURCE: &str = "
+        app([], L, L).
+        app([H|T], L, [H|R]) :- app(T, L, R).
+        size(small, 1). size(large, 1000000000000000000000).
+        ratio(X, Y) :- (X > 0 -> Y is 1.5 * X ; Y = none).
+    ";
+
+    #[test]
+    fn test_object_round_trip() {
+        let object = ObjectFile::compile(SOURCE, "lists.pl", CompileOptions::default()).unwrap();
+        let bytes = object.to_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(ObjectFile::from_bytes(&bytes).unwrap(), object);
+        // The same program always gives the same bytes.
+        assert_eq!(ObjectFile::compile(SOURCE, "lists.pl", CompileOptions::default()).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_loaded_object_runs() {
+        let bytes = ObjectFile::compile(SOURCE, "lists.pl", CompileOptions::default()).unwrap().to_bytes();
+        let mut wam = WamEmulator::new();
+        wam.consult("helper(x).").unwrap();
+        wam.load_object(&ObjectFile::from_bytes(&bytes).unwrap()).unwrap();
+
+        assert_eq!(answers(&mut wam, "app(X, Y, [a, b])").len(), 3);
+        assert_eq!(answers(&mut wam, "size(large, N)"), vec!["N = 1000000000000000000000"]);
+        assert_eq!(answers(&mut wam, "ratio(2, R)"), vec!["R = 3.0"]);
+        assert_eq!(answers(&mut wam, "helper(X)"), vec!["X = x"]);
+
+        let app = wam.predicates.get(wam.predicates.lookup("app", 3).unwrap());
+        let location = wam.source_location(app.clause_entries[1]).unwrap();
+        assert_eq!((location.file.as_str(), location.line), ("lists.pl", 3));
+    }
+
+    #[test]
+    fn test_stale_or_damaged_objects_are_rejected() {
+        let bytes = ObjectFile::compile(SOURCE, "lists.pl", CompileOptions::default()).unwrap().to_bytes();
+
+        let mut stale = bytes.clone();
+        stale[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(ObjectFile::from_bytes(&stale), Err(ObjectError::UnsupportedVersion(FORMAT_VERSION + 1)));
+
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(ObjectFile::from_bytes(&damaged), Err(ObjectError::ChecksumMismatch));
+
+        assert_eq!(ObjectFile::from_bytes(b"app([], L, L)."), Err(ObjectError::NotAnObject));
+        assert_eq!(ObjectFile::from_bytes(&bytes[..10]), Err(ObjectError::Truncated));
+        assert_eq!(ObjectFile::from_bytes(&bytes[..bytes.len() - 1]), Err(ObjectError::Truncated));
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert_eq!(ObjectFile::from_bytes(&extended), Err(ObjectError::Malformed("trailing data".to_string())));
+    }
+
+    #[test]
+    fn test_malformed_entries_are_rejected() {
+        let object = ObjectFile::compile("p.", "p.pl", CompileOptions::default()).unwrap();
+        let malformed = |message: &str| Err(ObjectError::Malformed(message.to_string()));
+
+        let mut deep = object.clone();
+        deep.directives.push((0..MAX_DECODE_DEPTH).fold(Term::Atom("a".into()), |term, _| Term::Structure {
+            functor: "f".into(),
+            arity: 1,
+            args: vec![term],
+        }));
+        assert_eq!(ObjectFile::from_bytes(&deep.to_bytes()), malformed("term nested too deeply"));
+
+        let mut small = object.clone();
+        small.directives.push(Term::BigInteger(BigInt::from(7)));
+        assert_eq!(ObjectFile::from_bytes(&small.to_bytes()), malformed("big integer 7 in 64 bits"));
+
+        // Halt is the machine's own stub; an object cannot jump out through it.
+        let mut halt = object.clone();
+        halt.code.push(Instruction::Fail);
+        let mut bytes = halt.to_bytes();
+        *bytes.last_mut().unwrap() = 37;
+        let sum = checksum(&bytes[HEADER_LENGTH..]);
+        bytes[16..HEADER_LENGTH].copy_from_slice(&sum.to_le_bytes());
+        assert_eq!(ObjectFile::from_bytes(&bytes), malformed("opcode 37"));
+    }
+
+    #[test]
+    fn test_dynamic_flag() {
+        let mut object = ObjectFile::compile("counter(0).", "counter.pl", CompileOptions::default()).unwrap();
+        object.predicates[0].dynamic = true;
+        let object = ObjectFile::from_bytes(&object.to_bytes()).unwrap();
+        assert!(object.predicates[0].dynamic);
+
+        let mut wam = WamEmulator::new();
+        wam.load_object(&object).unwrap();
+        assert_eq!(answers(&mut wam, "retract(counter(N)), M is N + 1, assertz(counter(M))"), vec!["N = 0,\nM = 1"]);
+        assert_eq!(answers(&mut wam, "counter(N)"), vec!["N = 1"]);
+
+        // A static predicate cannot become dynamic.
+        let mut wam = WamEmulator::new();
+        wam.consult("counter(5).").unwrap();
+        assert!(matches!(wam.load_object(&object), Err(WamError::Exception(_))));
+        assert_eq!(answers(&mut wam, "counter(N)"), vec!["N = 5"]);
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+            :- dynamic counter/1, seen/1.
+            counter(0).
+            bump :- retract(counter(N)), M is N + 1, assertz(counter(M)).
+            :- bump.
+        ";
+        let object = ObjectFile::compile(source, "counter.pl", CompileOptions::default()).unwrap();
+        let object = ObjectFile::from_bytes(&object.to_bytes()).unwrap();
+        assert_eq!(object.directives, vec![Term::Atom("bump".to_string())]);
+        let dynamic: Vec<&str> = object.predicates.iter().filter(|p| p.dynamic).map(|p| p.name.as_str()).collect();
+        assert_eq!(dynamic, vec!["counter", "seen"]);
+
+        let mut wam = WamEmulator::new();
+        wam.load_object(&object).unwrap();
+        assert_eq!(answers(&mut wam, "bump, counter(N)"), vec!["N = 2"]);
+        assert_eq!(answers(&mut wam, "assertz(seen(a)), seen(X)"), vec!["X = a"]);
+
+        // An object can hold nothing but declarations.
+        let object = ObjectFile::compile(":- dynamic empty/1.", "empty.pl", CompileOptions::default()).unwrap();
+        wam.load_object(&ObjectFile::from_bytes(&object.to_bytes()).unwrap()).unwrap();
+        assert_eq!(answers(&mut wam, "empty(X)"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_object_replaces_dynamic_clauses() {
+        let object = ObjectFile::compile("colour(blue).", "colour.pl", CompileOptions::default()).unwrap();
+        let mut wam = WamEmulator::new();
+        wam.consult(":- dynamic colour/1. colour(red).").unwrap();
+        wam.load_object(&object).unwrap();
+        assert_eq!(answers(&mut wam, "colour(X)"), vec!["X = blue"]);
+        assert_eq!(answers(&mut wam, "assertz(colour(green)), colour(X)"), vec!["X = blue", "X = green"]);
+    }
+
+    #[test]
+    fn test_invalid_code_is_not_loaded() {
+        let mut object = ObjectFile::compile("p(X) :- q(X). q(a).", "p.pl", CompileOptions::default()).unwrap();
+        object.code[0] = Instruction::Jump(object.code.len());
+        let mut wam = WamEmulator::new();
+        let size = wam.code.len();
+        assert!(matches!(wam.load_object(&object), Err(WamError::InvalidCode(_))));
+        assert_eq!(wam.code.len(), size);
+        assert!(wam.predicates.lookup("p", 1).is_none_or(|id| wam.predicates.get(id).entry.is_none()));
+
+        // Code reading a register nothing wrote could find anything there.
+        let mut object = ObjectFile::compile("r(X, Z) :- Y is X + 1, Z = f(Y).", "r.pl", CompileOptions::default()).unwrap();
+        let sum = object.code.iter().position(|instruction| matches!(instruction, Instruction::Arith(..)));
+        if let Instruction::Arith(_, _, _, target) = &mut object.code[sum.unwrap()] {
+            *target = Reg::X(196);
+        }
+        assert!(matches!(wam.load_object(&object), Err(WamError::InvalidCode(_))));
+    }
+}
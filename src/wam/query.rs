@@ -0,0 +1,175 @@
+// src/wam/query.rs
+use std::collections::{BTreeMap, HashMap};
+
+use crate::parser::ast::{parse, Term};
+use crate::wam::data_structures::{WamEmulator, HALT};
+use crate::wam::error::WamError;
+
+/// One answer to a query: each named query variable with the term it is
+/// bound to. A variable left unbound maps to itself, or to the first query
+/// variable it was unified with.
+pub type Solution = BTreeMap<String, Term>;
+
+impl WamEmulator {
+    /// Runs `goal`, the text of a query without its final full stop, e.g.
+    /// `ancestor(X, bob)`. Solutions are computed one at a time as the
+    /// returned iterator is advanced; dropping it abandons the rest and
+    /// frees everything the query used.
+    pub fn query(&mut self, goal: &str) -> Result<Solutions<'_>, WamError> {
+        let clauses = parse(&format!("'$query' :- {}.", goal))?;
+        self.query_goals(&clauses[0].body)
+    }
+
+    /// Runs the conjunction of the already parsed `goals`, as `query` does.
+    pub fn query_goals(&mut self, goals: &[Term]) -> Result<Solutions<'_>, WamError> {
+        let heap_top = self.heap.len();
+        let code_top = self.code.len();
+        let bindings = match self.start_query(goals) {
+            Ok(bindings) => bindings,
+            Err(error) => {
+                self.code.truncate(code_top);
+                return Err(error);
+            }
+        };
+        let code_end = self.code.len();
+        Ok(Solutions { machine: self, bindings, heap_top, code: (code_top, code_end), started: false, done: false })
+    }
+}
+
+/// The solutions of a query, from `WamEmulator::query`. An error raised by
+/// the query ends the iteration after being returned.
+pub struct Solutions<'a> {
+    machine: &'a mut WamEmulator,
+    bindings: Vec<(String, usize)>,
+    heap_top: usize,
+    /// Where the query's compiled code starts and ends in the code area.
+    code: (usize, usize),
+    started: bool,
+    done: bool,
+}
+
+impl Solutions<'_> {
+    fn solution(&self) -> Solution {
+        let mut names = HashMap::new();
+        for (name, index) in &self.bindings {
+            names.entry(self.machine.deref_address(*index)).or_insert_with(|| name.clone());
+        }
+        self.bindings
+            .iter()
+            .filter_map(|(name, index)| Some((name.clone(), self.machine.decode_term_with_names(*index, &names)?)))
+            .collect()
+    }
+
+    /// Undoes the query's bindings and drops its choice points, environments
+    /// and heap cells, and its code when nothing was installed after it.
+    fn finish(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        let machine = &mut *self.machine;
+        machine.choicepoints.clear();
+        machine.redo_states.clear();
+        machine.reclaim_clauses();
+        machine.frames.clear();
+        machine.stack.clear();
+        machine.unwind_trail(0);
+        machine.heap.truncate(self.heap_top);
+        machine.e = None;
+        machine.p = HALT;
+        machine.cp = HALT;
+        if machine.code.len() == self.code.1 {
+            machine.code.truncate(self.code.0);
+        }
+    }
+}
+
+impl Iterator for Solutions<'_> {
+    type Item = Result<Solution, WamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let found = if self.started {
+            self.machine.next_solution()
+        } else {
+            self.started = true;
+            self.machine.run()
+        };
+        match found {
+            Ok(true) => Some(Ok(self.solution())),
+            Ok(false) => {
+                self.finish();
+                None
+            }
+            Err(error) => {
+                self.finish();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Drop for Solutions<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wam::Machine;
+
+    const FAMILY: &str = "
+        parent(tom, bob). parent(bob, ann). parent(bob, pat). parent(pat, jim).
+        ancestor(X, Y) :- parent(X, Y).
+        ancestor(X, Y) :- parent(X, Z), ancestor(Z, Y).
+    ";
+
+    fn atom(name: &str) -> Term {
+        Term::Atom(name.to_string())
+    }
+
+    #[test]
+    fn test_query_iterates_solutions() {
+        let mut machine = Machine::new();
+        machine.consult(FAMILY).unwrap();
+
+        let solutions: Vec<Solution> = machine.query("ancestor(X, jim)").unwrap().map(Result::unwrap).collect();
+        let who: Vec<&Term> = solutions.iter().map(|solution| &solution["X"]).collect();
+        assert_eq!(who, vec![&atom("pat"), &atom("tom"), &atom("bob")]);
+
+        let solution = machine.query("parent(tom, X), Y = f(X, Z)").unwrap().next().unwrap().unwrap();
+        assert_eq!(solution["Y"].to_string(), "f(bob, Z)");
+        assert_eq!(solution["Z"], Term::Variable("Z".to_string()));
+        assert!(machine.query("parent(jim, _)").unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_dropped_query_is_cleaned_up() {
+        let mut machine = Machine::new();
+        machine.consult(FAMILY).unwrap();
+        let (heap, code) = (machine.heap.len(), machine.code.len());
+
+        let mut solutions = machine.query("ancestor(tom, X)").unwrap();
+        assert_eq!(solutions.next().unwrap().unwrap()["X"], atom("bob"));
+        drop(solutions);
+        assert!(machine.choicepoints.is_empty() && machine.trail.is_empty());
+        assert_eq!((machine.heap.len(), machine.code.len()), (heap, code));
+
+        // The machine is ready for the next query.
+        assert_eq!(machine.query("ancestor(X, ann)").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_query_errors() {
+        let mut machine = Machine::new();
+        assert!(matches!(machine.query("foo(").err(), Some(WamError::Parse(_))));
+
+        let mut solutions = machine.query("undefined_predicate").unwrap();
+        assert!(matches!(solutions.next(), Some(Err(WamError::Exception(_)))));
+        assert!(solutions.next().is_none());
+    }
+}
@@ -0,0 +1,649 @@
+// src/wam/verifier.rs
+//! Checks code before it is installed, so that code the compiler did not
+//! produce (object files, hand-written assembly) cannot make the emulator
+//! index outside its registers or jump into garbage.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::wam::instruction::{Instruction, Label, Operand, Reg};
+
+/// Highest X register code may use. The register file grows to fit the code
+/// loaded, so this keeps a bad register number from allocating without bound.
+pub const MAX_REGISTERS: usize = 1 << 16;
+
+/// Most permanent variables an environment may hold, so that `allocate`
+/// cannot ask for more stack than there is memory.
+pub const MAX_ENVIRONMENT: usize = 1 << 16;
+
+/// Highest arity a structure instruction may build or match, which also
+/// bounds the count of a `unify_void`.
+pub const MAX_ARITY: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// Address of the offending instruction, relative to the start of the code.
+    pub address: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    /// An X register or argument register numbered 0 or above `MAX_REGISTERS`.
+    RegisterOutOfRange(usize),
+    /// An `allocate` of more than `MAX_ENVIRONMENT` permanent variables.
+    EnvironmentTooLarge(usize),
+    /// A `get_structure` or `put_structure` of arity above `MAX_ARITY`.
+    ArityTooLarge(usize),
+    /// A `unify_void` of more than `MAX_ARITY` arguments.
+    VoidCountTooLarge(usize),
+    /// A Y register used where no environment has been allocated.
+    NoEnvironment(Reg),
+    /// A Y register, or a `call`'s live count, beyond the environment's size.
+    PermanentOutOfRange { index: usize, size: usize },
+    LabelOutOfRange(Label),
+    /// A label pointing at the arguments of a structure instruction.
+    LabelInsideStructure(Label),
+    /// A `get_structure`, `get_list` or `put_structure` not followed by
+    /// unify instructions for exactly its arguments.
+    MissingArguments { functor: String, arity: usize },
+    /// A unify instruction that does not follow a `get_structure`,
+    /// `get_list` or `put_structure`.
+    UnifyOutsideStructure,
+    /// `allocate` while an environment is already allocated.
+    NestedAllocate,
+    /// `deallocate`, `call` or `put_unsafe_value` without an environment.
+    MissingAllocate,
+    /// `execute` or `proceed` without deallocating the environment first.
+    MissingDeallocate,
+    /// Two paths reaching the same instruction with different environments.
+    InconsistentEnvironment { expected: Option<usize>, found: Option<usize> },
+    /// A `call` or `execute` of a predicate outside the functor table.
+    FunctorOutOfRange(usize),
+    /// Execution can run past the last instruction.
+    FallsOffEnd,
+    /// A `retry_foreign` or `exit_catch`, which only the emulator's own
+    /// stubs may hold.
+    ReservedInstruction,
+    /// An X register read before it is written on some path to the read;
+    /// only the arguments are set on entry, and a call sets none.
+    UndefinedRegister(usize),
+    /// A `cut` whose register does not hold a level saved by `get_level`
+    /// or `get_choice` on every path to it.
+    CutWithoutLevel(Reg),
+    /// A `retry_me_else` or `trust_me` reached other than as the
+    /// alternative of a `try_me_else` or `retry_me_else`, a `retry` or
+    /// `trust` reached other than by backtracking from the `try` or `retry`
+    /// before it, or such an alternative that does not start with one, so
+    /// the instruction could run without its choice point.
+    ChoicePointSequence,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid code at {}: {:?}", self.address, self.kind)
+    }
+}
+
+/// Verifies `code`, whose labels are relative to its start and which is
+/// entered without an environment at each of `entries`, given as an
+/// address and the number of arguments there. Calls may name predicates
+/// `0..arities.len()`, of the arities given.
+pub fn verify(code: &[Instruction], entries: &[(usize, usize)], arities: &[usize]) -> Result<(), VerifyError> {
+    let error = |address, kind| Err(VerifyError { address, kind });
+
+    // Sizes: nothing may make the emulator allocate without bound.
+    for (address, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::Allocate(size) if *size > MAX_ENVIRONMENT => {
+                return error(address, VerifyErrorKind::EnvironmentTooLarge(*size))
+            }
+            Instruction::GetStructure(_, arity, _) | Instruction::PutStructure(_, arity, _) if *arity > MAX_ARITY => {
+                return error(address, VerifyErrorKind::ArityTooLarge(*arity))
+            }
+            Instruction::UnifyVoid(n) if *n > MAX_ARITY => return error(address, VerifyErrorKind::VoidCountTooLarge(*n)),
+            _ => {}
+        }
+    }
+
+    // Structure arguments: each get_structure, get_list or put_structure is
+    // followed by unify instructions for exactly its arguments, and nothing
+    // else is.
+    let mut argument = vec![false; code.len()];
+    let mut address = 0;
+    while address < code.len() {
+        let (functor, arity) = match &code[address] {
+            Instruction::GetStructure(functor, arity, _) | Instruction::PutStructure(functor, arity, _) => {
+                (functor.as_str(), *arity)
+            }
+            Instruction::GetList(_) => (".", 2),
+            instruction if unify_arguments(instruction).is_some() => {
+                return error(address, VerifyErrorKind::UnifyOutsideStructure)
+            }
+            _ => {
+                address += 1;
+                continue;
+            }
+        };
+        let start = address;
+        let mut remaining = arity;
+        address += 1;
+        while remaining > 0 {
+            match code.get(address).and_then(unify_arguments) {
+                Some(n) if n <= remaining => remaining -= n,
+                _ => {
+                    let kind = VerifyErrorKind::MissingArguments { functor: functor.to_string(), arity };
+                    return error(start, kind);
+                }
+            }
+            argument[address] = true;
+            address += 1;
+        }
+    }
+
+    for (address, instruction) in code.iter().enumerate() {
+        for label in labels(instruction) {
+            if label >= code.len() {
+                return error(address, VerifyErrorKind::LabelOutOfRange(label));
+            }
+            if argument[label] {
+                return error(address, VerifyErrorKind::LabelInsideStructure(label));
+            }
+        }
+        for index in x_registers(instruction) {
+            if index == 0 || index > MAX_REGISTERS {
+                return error(address, VerifyErrorKind::RegisterOutOfRange(index));
+            }
+        }
+        if let Instruction::Call(id, _) | Instruction::Execute(id) = instruction {
+            if *id >= arities.len() {
+                return error(address, VerifyErrorKind::FunctorOutOfRange(*id));
+            }
+        }
+        if let Instruction::RetryForeign(_) | Instruction::ExitCatch | Instruction::RetryDynamic = instruction {
+            return error(address, VerifyErrorKind::ReservedInstruction);
+        }
+    }
+    for &(entry, _) in entries {
+        if entry >= code.len() {
+            return error(entry, VerifyErrorKind::LabelOutOfRange(entry));
+        }
+    }
+
+    // Environments, cut levels and defined registers: follow every path
+    // from the entries, tracking the size of the environment allocated
+    // (`None` outside one), the registers holding a level to cut to and the
+    // X registers written. A path reaching an instruction with fewer such
+    // registers than seen before is followed again with only those
+    // registers.
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut pending: Vec<(usize, State, Arrival)> = entries
+        .iter()
+        .map(|&(entry, arity)| {
+            let state = State { arity, defined: (1..=arity).collect(), ..State::default() };
+            (entry, state, Arrival::Entry)
+        })
+        .collect();
+    while let Some((address, state, arrival)) = pending.pop() {
+        if address >= code.len() {
+            return error(code.len() - 1, VerifyErrorKind::FallsOffEnd);
+        }
+        if !in_sequence(code, address, arrival) {
+            return error(address, VerifyErrorKind::ChoicePointSequence);
+        }
+        let state = match &mut states[address] {
+            Some(known) if known.environment != state.environment => {
+                let kind = VerifyErrorKind::InconsistentEnvironment { expected: known.environment, found: state.environment };
+                return error(address, kind);
+            }
+            Some(known) if known.levels.is_subset(&state.levels) && known.defined.is_subset(&state.defined) => continue,
+            Some(known) => {
+                known.levels.retain(|reg| state.levels.contains(reg));
+                known.defined.retain(|x| state.defined.contains(x));
+                known.clone()
+            }
+            None => states[address].insert(state).clone(),
+        };
+        let environment = state.environment;
+
+        let instruction = &code[address];
+        for reg in y_registers(instruction) {
+            match environment {
+                None => return error(address, VerifyErrorKind::NoEnvironment(Reg::Y(reg))),
+                Some(size) if reg == 0 || reg > size => {
+                    return error(address, VerifyErrorKind::PermanentOutOfRange { index: reg, size })
+                }
+                _ => {}
+            }
+        }
+        if let Instruction::Cut(reg) = instruction {
+            if !state.levels.contains(reg) {
+                return error(address, VerifyErrorKind::CutWithoutLevel(*reg));
+            }
+        }
+        if let Some(x) = x_reads(instruction, arities).into_iter().find(|x| !state.defined.contains(x)) {
+            return error(address, VerifyErrorKind::UndefinedRegister(x));
+        }
+        let environment = match instruction {
+            Instruction::Allocate(size) => match environment {
+                None => Some(*size),
+                Some(_) => return error(address, VerifyErrorKind::NestedAllocate),
+            },
+            Instruction::Deallocate | Instruction::PutUnsafeValue(..) | Instruction::Call(..) if environment.is_none() => {
+                return error(address, VerifyErrorKind::MissingAllocate)
+            }
+            Instruction::Deallocate => None,
+            Instruction::Call(_, live) => match environment {
+                Some(size) if *live > size => {
+                    return error(address, VerifyErrorKind::PermanentOutOfRange { index: *live, size })
+                }
+                _ => environment,
+            },
+            Instruction::Execute(_) | Instruction::Proceed if environment.is_some() => {
+                return error(address, VerifyErrorKind::MissingDeallocate)
+            }
+            _ => environment,
+        };
+        let next = State {
+            environment,
+            levels: levels_after(instruction, state.levels.clone()),
+            defined: defined_after(instruction, state.defined.clone()),
+            arity: state.arity,
+        };
+        for label in labels(instruction) {
+            // A `try_me_else` alternative runs after backtracking, with only
+            // the registers its choice point saved.
+            let state = match instruction {
+                Instruction::TryMeElse(_, saved) => next.restored(*saved),
+                _ => next.clone(),
+            };
+            pending.push((label, state, Arrival::Label(address)));
+        }
+        if falls_through(instruction) {
+            // As does the instruction after a `try` or `retry`, whose choice
+            // point saves as many registers as the predicate has arguments.
+            let state = match instruction {
+                Instruction::Try(_) | Instruction::Retry(_) => next.restored(state.arity),
+                _ => next.clone(),
+            };
+            pending.push((address + 1, state, Arrival::Next(address)));
+        }
+    }
+    Ok(())
+}
+
+/// What the verifier knows on reaching an instruction.
+#[derive(Debug, Clone, Default)]
+struct State {
+    environment: Option<usize>,
+    /// Registers holding a cut level on every path seen so far.
+    levels: HashSet<Reg>,
+    /// X registers written on every path seen so far.
+    defined: HashSet<usize>,
+    /// Arguments of the entry the path started at.
+    arity: usize,
+}
+
+impl State {
+    /// The state after backtracking to a choice point that saved the first
+    /// `saved` registers.
+    fn restored(&self, saved: usize) -> State {
+        State {
+            levels: self.levels.iter().filter(|reg| !matches!(reg, Reg::X(i) if *i > saved)).copied().collect(),
+            defined: self.defined.iter().filter(|&&x| x <= saved).copied().collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// How a path reached an instruction.
+#[derive(Debug, Clone, Copy)]
+enum Arrival {
+    Entry,
+    /// From the instruction at the address, by one of its labels.
+    Label(usize),
+    /// From the instruction at the address, by falling through.
+    Next(usize),
+}
+
+/// True if the instruction at `address` may be reached by `arrival`: the
+/// alternatives of choice points, and only they, start with the instruction
+/// that updates or removes the choice point. The alternative of a `try` or
+/// `retry` may also jump to a `retry`/`trust` block shared by several.
+fn in_sequence(code: &[Instruction], address: usize, arrival: Arrival) -> bool {
+    let clause_alternative = matches!(
+        arrival,
+        Arrival::Label(from) if matches!(code[from], Instruction::TryMeElse(..) | Instruction::RetryMeElse(_))
+    );
+    let index_alternative = matches!(
+        arrival,
+        Arrival::Next(from) if matches!(code[from], Instruction::Try(_) | Instruction::Retry(_))
+    );
+    let shared_alternative = matches!(
+        arrival,
+        Arrival::Label(from) if matches!(code[from], Instruction::Jump(_))
+            && from > 0
+            && matches!(code[from - 1], Instruction::Try(_) | Instruction::Retry(_))
+    );
+    match code[address] {
+        Instruction::RetryMeElse(_) | Instruction::TrustMe => clause_alternative,
+        Instruction::Retry(_) | Instruction::Trust(_) => index_alternative || shared_alternative,
+        Instruction::Jump(_) => !clause_alternative && !shared_alternative,
+        _ => !clause_alternative && !index_alternative && !shared_alternative,
+    }
+}
+
+/// Registers holding a cut level after `instruction`, given those before it.
+fn levels_after(instruction: &Instruction, mut levels: HashSet<Reg>) -> HashSet<Reg> {
+    match instruction {
+        Instruction::GetLevel(reg) | Instruction::GetChoice(reg) => {
+            levels.insert(*reg);
+        }
+        Instruction::Cut(_) | Instruction::TryMeElse(..) => {}
+        // A call leaves only the environment; allocating or deallocating
+        // replaces it.
+        Instruction::Call(..) => levels.retain(|reg| matches!(reg, Reg::Y(_))),
+        Instruction::Allocate(_) | Instruction::Deallocate => levels.retain(|reg| matches!(reg, Reg::X(_))),
+        _ => {
+            for reg in registers(instruction) {
+                levels.remove(&reg);
+            }
+        }
+    }
+    levels
+}
+
+/// X registers `instruction` reads, with a call reading the arguments of
+/// the predicate it calls.
+fn x_reads(instruction: &Instruction, arities: &[usize]) -> Vec<usize> {
+    let x = |reg: &Reg| match reg {
+        Reg::X(i) => Some(*i),
+        Reg::Y(_) => None,
+    };
+    let operand = |operand: &Operand| match operand {
+        Operand::Reg(reg) => x(reg),
+        Operand::Integer(_) => None,
+    };
+    match instruction {
+        Instruction::GetVariable(_, ai)
+        | Instruction::GetStructure(_, _, ai)
+        | Instruction::GetConstant(_, ai)
+        | Instruction::GetNil(ai)
+        | Instruction::GetList(ai) => vec![*ai],
+        Instruction::GetValue(reg, ai) => x(reg).into_iter().chain(Some(*ai)).collect(),
+        Instruction::UnifyValue(reg) | Instruction::UnifyLocalValue(reg) | Instruction::PutValue(reg, _) | Instruction::Cut(reg) => {
+            x(reg).into_iter().collect()
+        }
+        Instruction::Evaluate(a, _) => operand(a).into_iter().collect(),
+        Instruction::Arith(_, a, b, _) | Instruction::Compare(_, a, b) => operand(a).into_iter().chain(operand(b)).collect(),
+        Instruction::Call(id, _) | Instruction::Execute(id) => (1..=arities[*id]).collect(),
+        Instruction::SwitchOnTerm { .. } | Instruction::SwitchOnConstant(_) | Instruction::SwitchOnStructure(_) => vec![1],
+        _ => Vec::new(),
+    }
+}
+
+/// X registers written after `instruction`, given those written before it.
+fn defined_after(instruction: &Instruction, mut defined: HashSet<usize>) -> HashSet<usize> {
+    let mut write = |reg: &Reg| {
+        if let Reg::X(i) = reg {
+            defined.insert(*i);
+        }
+    };
+    match instruction {
+        Instruction::GetVariable(reg, _)
+        | Instruction::UnifyVariable(reg)
+        | Instruction::GetLevel(reg)
+        | Instruction::GetChoice(reg)
+        | Instruction::InitVariable(reg)
+        | Instruction::Evaluate(_, reg)
+        | Instruction::Arith(_, _, _, reg) => write(reg),
+        Instruction::PutVariable(reg, ai) => {
+            write(reg);
+            write(&Reg::X(*ai));
+        }
+        Instruction::PutValue(_, ai)
+        | Instruction::PutUnsafeValue(_, ai)
+        | Instruction::PutStructure(_, _, ai)
+        | Instruction::PutConstant(_, ai) => write(&Reg::X(*ai)),
+        // The called predicate may leave anything in the X registers.
+        Instruction::Call(..) => defined.clear(),
+        _ => {}
+    }
+    defined
+}
+
+/// Number of structure arguments a unify instruction covers.
+fn unify_arguments(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::UnifyVariable(_)
+        | Instruction::UnifyValue(_)
+        | Instruction::UnifyLocalValue(_)
+        | Instruction::UnifyConstant(_) => Some(1),
+        Instruction::UnifyVoid(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// True if execution can continue with the next instruction.
+fn falls_through(instruction: &Instruction) -> bool {
+    !matches!(
+        instruction,
+        Instruction::Execute(_)
+            | Instruction::Proceed
+            | Instruction::Jump(_)
+            | Instruction::Trust(_)
+            | Instruction::SwitchOnTerm { .. }
+            | Instruction::SwitchOnConstant(_)
+            | Instruction::SwitchOnStructure(_)
+            | Instruction::Fail
+            | Instruction::Halt
+            | Instruction::RetryForeign(_)
+            | Instruction::ExitCatch
+            | Instruction::RetryDynamic
+    )
+}
+
+/// Code labels an instruction can continue at, besides the next instruction.
+fn labels(instruction: &Instruction) -> Vec<Label> {
+    match instruction {
+        Instruction::TryMeElse(label, _)
+        | Instruction::RetryMeElse(label)
+        | Instruction::Try(label)
+        | Instruction::Retry(label)
+        | Instruction::Trust(label)
+        | Instruction::Jump(label) => vec![*label],
+        Instruction::SwitchOnTerm { variable, constant, list, structure } => {
+            std::iter::once(*variable).chain(*constant).chain(*list).chain(*structure).collect()
+        }
+        Instruction::SwitchOnConstant(table) => table.cases.values().copied().chain(table.default).collect(),
+        Instruction::SwitchOnStructure(table) => table.cases.values().copied().chain(table.default).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Registers an instruction names, with argument registers as `X`
+/// registers and `put_unsafe_value`'s variable as a `Y` register.
+pub(crate) fn registers(instruction: &Instruction) -> Vec<Reg> {
+    let operand = |operand: &Operand| match operand {
+        Operand::Reg(reg) => Some(*reg),
+        Operand::Integer(_) => None,
+    };
+    match instruction {
+        Instruction::GetVariable(reg, ai)
+        | Instruction::GetValue(reg, ai)
+        | Instruction::PutVariable(reg, ai)
+        | Instruction::PutValue(reg, ai) => vec![*reg, Reg::X(*ai)],
+        Instruction::PutUnsafeValue(y, ai) => vec![Reg::Y(*y), Reg::X(*ai)],
+        Instruction::GetStructure(_, _, ai)
+        | Instruction::PutStructure(_, _, ai)
+        | Instruction::GetConstant(_, ai)
+        | Instruction::PutConstant(_, ai)
+        | Instruction::GetNil(ai)
+        | Instruction::GetList(ai) => vec![Reg::X(*ai)],
+        Instruction::UnifyVariable(reg)
+        | Instruction::UnifyValue(reg)
+        | Instruction::UnifyLocalValue(reg)
+        | Instruction::GetLevel(reg)
+        | Instruction::GetChoice(reg)
+        | Instruction::Cut(reg)
+        | Instruction::InitVariable(reg) => vec![*reg],
+        Instruction::Evaluate(x, reg) => operand(x).into_iter().chain(Some(*reg)).collect(),
+        Instruction::Arith(_, x, y, reg) => operand(x).into_iter().chain(operand(y)).chain(Some(*reg)).collect(),
+        Instruction::Compare(_, x, y) => operand(x).into_iter().chain(operand(y)).collect(),
+        Instruction::TryMeElse(_, n) if *n > 0 => vec![Reg::X(*n)],
+        _ => Vec::new(),
+    }
+}
+
+fn x_registers(instruction: &Instruction) -> impl Iterator<Item = usize> {
+    registers(instruction).into_iter().filter_map(|reg| match reg {
+        Reg::X(i) => Some(i),
+        Reg::Y(_) => None,
+    })
+}
+
+fn y_registers(instruction: &Instruction) -> impl Iterator<Item = usize> {
+    registers(instruction).into_iter().filter_map(|reg| match reg {
+        Reg::Y(i) => Some(i),
+        Reg::X(_) => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_predicate, predicate_key, CompileOptions};
+    use crate::parser::ast::parse;
+    use crate::wam::data_structures::PredicateTable;
+    use crate::wam::instruction::Constant;
+    use Instruction::*;
+
+    fn kind(code: &[Instruction]) -> VerifyErrorKind {
+        verify(code, &[(0, 1)], &[0]).unwrap_err().kind
+    }
+
+    #[test]
+    fn test_compiled_code_verifies() {
+        let source = "
+            app([], L, L).
+            app([H|T], L, [H|R]) :- app(T, L, R).
+            max(X, Y, Z) :- (X >= Y -> Z = X ; Z = Y).
+            p(X) :- q(X, Y), !, \\+ r(Y), s(f(Y, g(X))).
+        ";
+        let clauses = parse(source).unwrap();
+        let mut table = PredicateTable::default();
+        for group in clauses.chunk_by(|a, b| predicate_key(&a.head) == predicate_key(&b.head)) {
+            let compiled = compile_predicate(group, &mut table, CompileOptions::default()).unwrap();
+            let (_, arity) = predicate_key(&group[0].head).unwrap();
+            let entries: Vec<(usize, usize)> = std::iter::once(0).chain(compiled.clause_entries).map(|entry| (entry, arity)).collect();
+            let arities: Vec<usize> = table.iter().map(|predicate| predicate.arity).collect();
+            assert_eq!(verify(&compiled.code, &entries, &arities), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_bad_registers_and_labels() {
+        assert_eq!(kind(&[GetVariable(Reg::X(0), 1), Proceed]), VerifyErrorKind::RegisterOutOfRange(0));
+        assert_eq!(kind(&[PutValue(Reg::X(1), MAX_REGISTERS + 1), Proceed]), VerifyErrorKind::RegisterOutOfRange(MAX_REGISTERS + 1));
+        assert_eq!(kind(&[TryMeElse(7, 1), Proceed]), VerifyErrorKind::LabelOutOfRange(7));
+        assert_eq!(kind(&[Execute(3)]), VerifyErrorKind::FunctorOutOfRange(3));
+        assert_eq!(kind(&[GetConstant(Constant::Integer(1), 1)]), VerifyErrorKind::FallsOffEnd);
+        assert_eq!(kind(&[RetryForeign(0)]), VerifyErrorKind::ReservedInstruction);
+        assert_eq!(kind(&[ExitCatch]), VerifyErrorKind::ReservedInstruction);
+        assert_eq!(kind(&[RetryDynamic]), VerifyErrorKind::ReservedInstruction);
+    }
+
+    #[test]
+    fn test_sizes_are_bounded() {
+        let huge = u32::MAX as usize;
+        assert_eq!(kind(&[Allocate(huge), Deallocate, Proceed]), VerifyErrorKind::EnvironmentTooLarge(huge));
+        assert_eq!(
+            kind(&[PutStructure("f".to_string(), huge, 1), UnifyVoid(huge), Proceed]),
+            VerifyErrorKind::ArityTooLarge(huge)
+        );
+        assert_eq!(kind(&[GetStructure("f".to_string(), huge, 1), Proceed]), VerifyErrorKind::ArityTooLarge(huge));
+        assert_eq!(kind(&[GetList(1), UnifyVoid(huge), Proceed]), VerifyErrorKind::VoidCountTooLarge(huge));
+        let code = [Allocate(MAX_ENVIRONMENT), PutStructure("f".to_string(), MAX_ARITY, 1), UnifyVoid(MAX_ARITY), Deallocate, Proceed];
+        assert_eq!(verify(&code, &[(0, 1)], &[0]), Ok(()));
+    }
+
+    #[test]
+    fn test_unify_sequencing() {
+        let atom = |name: &str| UnifyConstant(Constant::Atom(name.to_string()));
+        assert_eq!(
+            kind(&[GetStructure("f".to_string(), 2, 1), atom("a"), Proceed]),
+            VerifyErrorKind::MissingArguments { functor: "f".to_string(), arity: 2 }
+        );
+        assert_eq!(kind(&[atom("a"), Proceed]), VerifyErrorKind::UnifyOutsideStructure);
+        assert_eq!(verify(&[GetList(1), UnifyVoid(2), Proceed], &[(0, 1)], &[0]), Ok(()));
+        assert_eq!(
+            kind(&[GetList(1), UnifyVoid(3), Proceed]),
+            VerifyErrorKind::MissingArguments { functor: ".".to_string(), arity: 2 }
+        );
+        let code = [TryMeElse(2, 1), GetStructure("f".to_string(), 1, 1), atom("a"), Proceed];
+        assert_eq!(kind(&code), VerifyErrorKind::LabelInsideStructure(2));
+    }
+
+    #[test]
+    fn test_environment_discipline() {
+        assert_eq!(kind(&[GetVariable(Reg::Y(1), 1), Proceed]), VerifyErrorKind::NoEnvironment(Reg::Y(1)));
+        assert_eq!(
+            kind(&[Allocate(1), GetVariable(Reg::Y(2), 1), Deallocate, Proceed]),
+            VerifyErrorKind::PermanentOutOfRange { index: 2, size: 1 }
+        );
+        assert_eq!(kind(&[Call(0, 0), Proceed]), VerifyErrorKind::MissingAllocate);
+        assert_eq!(kind(&[Allocate(1), Execute(0)]), VerifyErrorKind::MissingDeallocate);
+        assert_eq!(kind(&[Allocate(1), Allocate(1)]), VerifyErrorKind::NestedAllocate);
+        // The alternative is reached with an environment, the fallthrough without.
+        let code = [TryMeElse(3, 0), Allocate(1), Jump(4), TrustMe, Fail];
+        assert!(matches!(kind(&code), VerifyErrorKind::InconsistentEnvironment { .. }));
+    }
+
+    #[test]
+    fn test_cut_levels() {
+        assert_eq!(kind(&[Cut(Reg::X(2)), Proceed]), VerifyErrorKind::CutWithoutLevel(Reg::X(2)));
+        assert_eq!(verify(&[GetLevel(Reg::X(2)), Cut(Reg::X(2)), Proceed], &[(0, 1)], &[0]), Ok(()));
+        // A call clobbers X registers but not the environment.
+        let code = [Allocate(1), GetLevel(Reg::X(2)), GetLevel(Reg::Y(1)), Call(0, 1), Cut(Reg::Y(1)), Cut(Reg::X(2))];
+        assert_eq!(kind(&code), VerifyErrorKind::CutWithoutLevel(Reg::X(2)));
+        // Only one path to the cut sets the level.
+        let code = [TryMeElse(3, 1), GetChoice(Reg::X(2)), Jump(4), TrustMe, Cut(Reg::X(2)), Proceed];
+        assert_eq!(kind(&code), VerifyErrorKind::CutWithoutLevel(Reg::X(2)));
+        // The alternative only gets back the registers the choice point saved.
+        let code = [GetChoice(Reg::X(2)), TryMeElse(4, 1), Cut(Reg::X(2)), Proceed, TrustMe, Cut(Reg::X(2)), Proceed];
+        assert_eq!(kind(&code), VerifyErrorKind::CutWithoutLevel(Reg::X(2)));
+        let code = [GetChoice(Reg::X(2)), TryMeElse(4, 2), Cut(Reg::X(2)), Proceed, TrustMe, Cut(Reg::X(2)), Proceed];
+        assert_eq!(verify(&code, &[(0, 1)], &[0]), Ok(()));
+    }
+
+    #[test]
+    fn test_registers_are_written_before_read() {
+        let undefined = |code: &[Instruction]| verify(code, &[(0, 1)], &[1]).unwrap_err().kind;
+        assert_eq!(undefined(&[PutValue(Reg::X(3), 1), Execute(0)]), VerifyErrorKind::UndefinedRegister(3));
+        assert_eq!(undefined(&[GetValue(Reg::X(1), 2), Proceed]), VerifyErrorKind::UndefinedRegister(2));
+        assert_eq!(undefined(&[PutValue(Reg::Y(1), 1), Deallocate, Execute(0)]), VerifyErrorKind::NoEnvironment(Reg::Y(1)));
+        // A call leaves nothing in the X registers, its arguments included.
+        let code = [Allocate(0), GetVariable(Reg::X(2), 1), Call(0, 0), PutValue(Reg::X(2), 1), Deallocate, Execute(0)];
+        assert_eq!(undefined(&code), VerifyErrorKind::UndefinedRegister(2));
+        assert_eq!(verify(&[PutVariable(Reg::X(2), 1), Execute(0)], &[(0, 0)], &[1]), Ok(()));
+        assert_eq!(verify(&[Execute(0)], &[(0, 0)], &[1]).unwrap_err().kind, VerifyErrorKind::UndefinedRegister(1));
+        // Backtracking restores only the registers the choice point saved.
+        let code = [GetVariable(Reg::X(2), 1), TryMeElse(4, 1), PutValue(Reg::X(2), 1), Execute(0), TrustMe, PutValue(Reg::X(2), 1), Execute(0)];
+        assert_eq!(undefined(&code), VerifyErrorKind::UndefinedRegister(2));
+        let code = [GetVariable(Reg::X(2), 1), TryMeElse(4, 2), PutValue(Reg::X(2), 1), Execute(0), TrustMe, PutValue(Reg::X(2), 1), Execute(0)];
+        assert_eq!(verify(&code, &[(0, 1)], &[1]), Ok(()));
+    }
+
+    #[test]
+    fn test_choice_point_sequence() {
+        assert_eq!(kind(&[TrustMe, Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(kind(&[TryMeElse(2, 0), Proceed, Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(kind(&[TryMeElse(2, 0), Jump(2), TrustMe, Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(kind(&[Retry(2), Trust(2), Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(kind(&[Try(3), Proceed, Proceed, Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(verify(&[Try(3), Retry(3), Trust(3), Proceed], &[(0, 1)], &[0]), Ok(()));
+        assert_eq!(verify(&[Try(3), Jump(4), Proceed, Proceed, Retry(3), Trust(2)], &[(0, 1)], &[0]), Ok(()));
+        assert_eq!(kind(&[Try(3), Jump(2), Proceed, Proceed]), VerifyErrorKind::ChoicePointSequence);
+        assert_eq!(kind(&[Jump(1), Trust(2), Proceed]), VerifyErrorKind::ChoicePointSequence);
+    }
+}